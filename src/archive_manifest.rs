@@ -0,0 +1,432 @@
+use crate::error::{Error, Result};
+use crate::throttle::ReadThrottle;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// A file listed in the `_files.xml` manifest the Internet Archive publishes for each item.
+pub struct ManifestFile {
+    pub name: String,
+    pub size: Option<u64>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+}
+
+/// The result of checking a file in the torrent tree against its manifest entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManifestStatus {
+    Unchecked,
+    Matched,
+    Mismatched,
+    Missing,
+}
+
+impl fmt::Display for ManifestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestStatus::Unchecked => write!(f, "UNCHECKED"),
+            ManifestStatus::Matched => write!(f, "MATCHED"),
+            ManifestStatus::Mismatched => write!(f, "MISMATCHED"),
+            ManifestStatus::Missing => write!(f, "MISSING"),
+        }
+    }
+}
+
+impl ManifestStatus {
+    pub fn from_db_value(value: &str) -> ManifestStatus {
+        match value {
+            "MATCHED" => ManifestStatus::Matched,
+            "MISMATCHED" => ManifestStatus::Mismatched,
+            "MISSING" => ManifestStatus::Missing,
+            _ => ManifestStatus::Unchecked,
+        }
+    }
+}
+
+/// A manifest entry that has been matched to a file in the torrent tree.
+pub struct ManifestEntry {
+    pub file_path: PathBuf,
+    /// The identifier of the Archive item the manifest belongs to.
+    pub item: String,
+    pub file: ManifestFile,
+    pub status: ManifestStatus,
+}
+
+impl ManifestEntry {
+    /// Compares the file on disk against the manifest.
+    ///
+    /// The size is checked first, so the file is only read if it could possibly match. The reads
+    /// go through the throttle.
+    pub fn check(&self, path: &Path, throttle: &ReadThrottle) -> Result<ManifestStatus> {
+        if !path.exists() {
+            return Ok(ManifestStatus::Missing);
+        }
+        if self.file.md5.is_none() && self.file.sha1.is_none() {
+            return Ok(ManifestStatus::Unchecked);
+        }
+        if let Some(size) = self.file.size {
+            if std::fs::metadata(path)?.len() != size {
+                return Ok(ManifestStatus::Mismatched);
+            }
+        }
+        let (md5, sha1) = md5_and_sha1(path, throttle)?;
+        let md5_matches = self
+            .file
+            .md5
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(&md5));
+        let sha1_matches = self
+            .file
+            .sha1
+            .as_ref()
+            .is_none_or(|s| s.eq_ignore_ascii_case(&sha1));
+        if md5_matches && sha1_matches {
+            Ok(ManifestStatus::Matched)
+        } else {
+            Ok(ManifestStatus::Mismatched)
+        }
+    }
+}
+
+/// Parses the content of a `_files.xml` manifest.
+pub fn parse_files_xml(xml: &str) -> Result<Vec<ManifestFile>> {
+    let document =
+        roxmltree::Document::parse(xml).map_err(|e| Error::ManifestParseError(e.to_string()))?;
+    let mut files = Vec::new();
+    for node in document
+        .root_element()
+        .children()
+        .filter(|n| n.has_tag_name("file"))
+    {
+        let name = node
+            .attribute("name")
+            .ok_or_else(|| Error::ManifestParseError("a file has no name".to_string()))?;
+        let child_text = |tag: &str| {
+            node.children()
+                .find(|c| c.has_tag_name(tag))
+                .and_then(|c| c.text())
+                .map(|t| t.trim().to_string())
+        };
+        files.push(ManifestFile {
+            name: name.to_string(),
+            size: child_text("size").and_then(|s| s.parse().ok()),
+            md5: child_text("md5"),
+            sha1: child_text("sha1"),
+        });
+    }
+    Ok(files)
+}
+
+/// Reads a manifest from a local file, or downloads it if the source is a URL.
+///
+/// Returns the identifier of the item, which is taken from the name of the manifest, along with
+/// its content.
+pub async fn read_files_xml(source: &str) -> Result<(String, String)> {
+    let (file_name, xml) = if source.starts_with("http://") || source.starts_with("https://") {
+        let url = Url::parse(source)?;
+        let file_name = url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .ok_or(Error::FilenameFromUrlError)?
+            .to_string();
+        let response = reqwest::get(url).await?;
+        if !response.status().is_success() {
+            return Err(Error::ArchiveDownloadFailed {
+                url: source.to_string(),
+                status: response.status(),
+                retry_after: None,
+            });
+        }
+        (file_name, response.text().await?)
+    } else {
+        let path = Path::new(source);
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        (file_name, std::fs::read_to_string(path)?)
+    };
+    let item = file_name
+        .strip_suffix("_files.xml")
+        .unwrap_or(&file_name)
+        .to_string();
+    Ok((item, xml))
+}
+
+/// Matches the files in a manifest to paths in the torrent tree.
+///
+/// The names in a manifest are relative to the item, which usually corresponds to a directory
+/// somewhere in the tree. If the prefix isn't supplied, it is worked out by finding every path in
+/// the tree that ends with a manifest file's name. Names like `index.html` can be in many
+/// directories, so the prefix that matches the most files wins, and the first one found if there
+/// is a tie.
+pub fn match_manifest_files(
+    item: &str,
+    files: Vec<ManifestFile>,
+    tree: &[(PathBuf, u64)],
+    prefix: Option<&Path>,
+) -> (Vec<ManifestEntry>, Vec<ManifestFile>) {
+    let prefix = prefix
+        .map(|p| p.to_path_buf())
+        .or_else(|| infer_prefix(&files, tree));
+    let Some(prefix) = prefix else {
+        return (Vec::new(), files);
+    };
+
+    let paths = tree.iter().map(|(p, _)| p).collect::<HashSet<&PathBuf>>();
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    for file in files {
+        let path = prefix.join(&file.name);
+        if paths.contains(&path) {
+            matched.push(ManifestEntry {
+                file_path: path,
+                item: item.to_string(),
+                file,
+                status: ManifestStatus::Unchecked,
+            });
+        } else {
+            unmatched.push(file);
+        }
+    }
+    (matched, unmatched)
+}
+
+/// Works out where the item is in the tree, as the directory that the most manifest files are
+/// found under.
+fn infer_prefix(files: &[ManifestFile], tree: &[(PathBuf, u64)]) -> Option<PathBuf> {
+    let mut by_file_name = HashMap::<_, Vec<&PathBuf>>::new();
+    for (path, _) in tree.iter() {
+        by_file_name.entry(path.file_name()).or_default().push(path);
+    }
+    // Each prefix is counted with the order it was first found in, to break ties.
+    let mut counts = HashMap::<PathBuf, (usize, usize)>::new();
+    for file in files.iter() {
+        let name = Path::new(&file.name);
+        let name_depth = name.components().count();
+        let Some(paths) = by_file_name.get(&name.file_name()) else {
+            continue;
+        };
+        for path in paths.iter().filter(|path| path.ends_with(name)) {
+            let depth = path.components().count();
+            let prefix = path.components().take(depth - name_depth).collect();
+            let found = counts.len();
+            counts.entry(prefix).or_insert((found, 0)).1 += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, (found, count))| (*count, std::cmp::Reverse(*found)))
+        .map(|(prefix, _)| prefix)
+}
+
+fn md5_and_sha1(path: &Path, throttle: &ReadThrottle) -> Result<(String, String)> {
+    let mut file = File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha1 = Sha1::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        throttle.consume(read as u64);
+        md5.update(&buffer[..read]);
+        sha1.update(&buffer[..read]);
+    }
+    Ok((
+        format!("{:x}", md5.finalize()),
+        format!("{:x}", sha1.finalize()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const FILES_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<files>
+  <file name="photos/ground zero.jpg" source="original">
+    <mtime>1234567890</mtime>
+    <size>5</size>
+    <md5>5D41402ABC4B2A76B9719D911017C592</md5>
+    <sha1> aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d </sha1>
+    <format>JPEG</format>
+  </file>
+  <file name="notes.txt" source="original">
+    <crc32>3610a686</crc32>
+  </file>
+  <file name="item_meta.xml" source="metadata">
+    <size>not a number</size>
+  </file>
+</files>
+"#;
+
+    fn entry(file: ManifestFile) -> ManifestEntry {
+        ManifestEntry {
+            file_path: PathBuf::from(&file.name),
+            item: "item".to_string(),
+            file,
+            status: ManifestStatus::Unchecked,
+        }
+    }
+
+    #[test]
+    fn parses_files_xml() {
+        let files = parse_files_xml(FILES_XML).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].name, "photos/ground zero.jpg");
+        assert_eq!(files[0].size, Some(5));
+        assert_eq!(
+            files[0].md5.as_deref(),
+            Some("5D41402ABC4B2A76B9719D911017C592")
+        );
+        assert_eq!(
+            files[0].sha1.as_deref(),
+            Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d")
+        );
+        assert_eq!(files[1].size, None);
+        assert!(files[1].md5.is_none() && files[1].sha1.is_none());
+        assert_eq!(files[2].size, None);
+    }
+
+    #[test]
+    fn rejects_malformed_files_xml() {
+        assert!(matches!(
+            parse_files_xml("<files><file name=\"a\"></files>"),
+            Err(Error::ManifestParseError(_))
+        ));
+        assert!(matches!(
+            parse_files_xml("<files><file><size>1</size></file></files>"),
+            Err(Error::ManifestParseError(_))
+        ));
+        assert!(parse_files_xml("<files/>").unwrap().is_empty());
+    }
+
+    #[test]
+    fn works_out_where_the_item_is_in_the_tree() {
+        let tree = vec![
+            (PathBuf::from("Release/Item/photos/ground zero.jpg"), 5),
+            (PathBuf::from("Release/Item/notes.txt"), 10),
+            (PathBuf::from("Release/notes.txt"), 10),
+        ];
+        let files = parse_files_xml(FILES_XML).unwrap();
+        let (matched, unmatched) = match_manifest_files("Item", files, &tree, None);
+        assert_eq!(
+            matched
+                .iter()
+                .map(|entry| entry.file_path.clone())
+                .collect::<Vec<PathBuf>>(),
+            vec![
+                PathBuf::from("Release/Item/photos/ground zero.jpg"),
+                PathBuf::from("Release/Item/notes.txt"),
+            ]
+        );
+        assert!(matched.iter().all(|entry| entry.item == "Item"));
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(unmatched[0].name, "item_meta.xml");
+
+        // A prefix that is given is used as it is.
+        let files = parse_files_xml(FILES_XML).unwrap();
+        let (matched, unmatched) =
+            match_manifest_files("Item", files, &tree, Some(Path::new("Release")));
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].file_path, PathBuf::from("Release/notes.txt"));
+        assert_eq!(unmatched.len(), 2);
+
+        let files = parse_files_xml(FILES_XML).unwrap();
+        let (matched, unmatched) = match_manifest_files("Item", files, &[], None);
+        assert!(matched.is_empty());
+        assert_eq!(unmatched.len(), 3);
+    }
+
+    #[test]
+    fn finds_the_item_when_its_first_file_has_a_common_name() {
+        // notes.txt comes first in the manifest, and is also in other directories, which come
+        // first in the tree.
+        let tree = vec![
+            (PathBuf::from("Release/notes.txt"), 10),
+            (PathBuf::from("Release/Other/notes.txt"), 10),
+            (PathBuf::from("Release/Item/notes.txt"), 10),
+            (PathBuf::from("Release/Item/photos/ground zero.jpg"), 5),
+        ];
+        let mut files = parse_files_xml(FILES_XML).unwrap();
+        files.reverse();
+        let (matched, unmatched) = match_manifest_files("Item", files, &tree, None);
+        assert_eq!(
+            matched
+                .iter()
+                .map(|entry| entry.file_path.clone())
+                .collect::<Vec<PathBuf>>(),
+            vec![
+                PathBuf::from("Release/Item/notes.txt"),
+                PathBuf::from("Release/Item/photos/ground zero.jpg"),
+            ]
+        );
+        assert_eq!(unmatched.len(), 1);
+
+        // With nothing else to go on, the first directory it's found in is used.
+        let files = parse_files_xml(FILES_XML).unwrap().split_off(1);
+        let (matched, _) = match_manifest_files("Item", files, &tree, None);
+        assert_eq!(matched[0].file_path, PathBuf::from("Release/notes.txt"));
+    }
+
+    #[test]
+    fn checks_files_against_the_manifest() {
+        let dir = TempDir::new("manifest").unwrap();
+        let path = dir.path().join("hello.txt");
+        std::fs::write(&path, b"hello").unwrap();
+        let throttle = ReadThrottle::new(None);
+        let mut files = parse_files_xml(FILES_XML).unwrap().into_iter();
+        let mut hello = entry(files.next().unwrap());
+        assert_eq!(
+            hello.check(&path, &throttle).unwrap(),
+            ManifestStatus::Matched
+        );
+        assert_eq!(
+            hello
+                .check(&dir.path().join("absent.txt"), &throttle)
+                .unwrap(),
+            ManifestStatus::Missing
+        );
+        hello.file.sha1 = Some("0".repeat(40));
+        assert_eq!(
+            hello.check(&path, &throttle).unwrap(),
+            ManifestStatus::Mismatched
+        );
+        hello.file.sha1 = None;
+        hello.file.size = Some(6);
+        assert_eq!(
+            hello.check(&path, &throttle).unwrap(),
+            ManifestStatus::Mismatched
+        );
+        // A file with no checksums in the manifest can't be checked.
+        let notes = entry(files.next().unwrap());
+        assert_eq!(
+            notes.check(&path, &throttle).unwrap(),
+            ManifestStatus::Unchecked
+        );
+    }
+
+    #[test]
+    fn reads_statuses_from_the_database() {
+        for status in [
+            ManifestStatus::Unchecked,
+            ManifestStatus::Matched,
+            ManifestStatus::Mismatched,
+            ManifestStatus::Missing,
+        ] {
+            assert_eq!(ManifestStatus::from_db_value(&status.to_string()), status);
+        }
+        assert_eq!(
+            ManifestStatus::from_db_value("SOMETHING ELSE"),
+            ManifestStatus::Unchecked
+        );
+    }
+}
@@ -1,8 +1,58 @@
+use crate::archive_manifest::{ManifestEntry, ManifestFile, ManifestStatus};
 use crate::error::{Error, Result};
+use crate::ignore::{compile_pattern, IgnoreRule};
+use crate::pieces::{Bitfield, FileDigest, FileState, PieceMap};
+use crate::sources::{ReleaseSource, SourceKind};
 use crate::{Release, VerificationOutcome};
 use rusqlite::{params, Connection};
 use std::collections::HashMap;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Rules added when the database is first created, as (pattern, reason) pairs.
+const DEFAULT_IGNORE_RULES: &[(&str, &str)] = &[("Thumbs.db", "Windows thumbnail cache")];
+
+/// The progress of a verification that was interrupted before it finished.
+pub struct VerificationCheckpoint {
+    /// Every piece up to and including this one has been hashed.
+    pub last_piece: usize,
+    /// Whether each piece up to `last_piece` matched its hash in the torrent.
+    pub piece_results: Bitfield,
+    /// The size and modification time (in nanoseconds since the Unix epoch) of each file in the
+    /// release when the checkpoint was first written.
+    pub files: Vec<(PathBuf, u64, i64)>,
+}
+
+/// The state a file was in when it last passed verification.
+///
+/// The piece range is the range of pieces the file was covered by at the time. The `last_piece`
+/// column holds the end of the range, which is exclusive.
+pub struct CachedFile {
+    pub state: FileState,
+    pub pieces: Range<usize>,
+}
+
+/// The result for a single file, as stored in the `incomplete_files` table.
+#[derive(Clone, Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub size: u64,
+    /// One of MISSING, CORRUPTED, VERIFIED, UNVERIFIABLE or WAIVED.
+    pub status: &'static str,
+    /// The ignore rule that waived the file.
+    pub reason: Option<String>,
+}
+
+/// The per-file results stored for a release in the `incomplete_files` table.
+#[derive(Default)]
+pub struct IncompleteFiles {
+    pub missing: Vec<(PathBuf, u64)>,
+    pub corrupted: Vec<(PathBuf, u64)>,
+    pub verified: Vec<(PathBuf, u64)>,
+    pub unverifiable: Vec<(PathBuf, u64)>,
+    pub waived: Vec<(PathBuf, u64, String)>,
+}
 
 pub fn get_db_connection<P: AsRef<Path>>(path: P) -> Result<Connection> {
     Ok(Connection::open(path)?)
@@ -40,6 +90,102 @@ pub fn create_db_schema(conn: &Connection) -> Result<()> {
         );",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS verification_checkpoints (
+            release_id TEXT PRIMARY KEY NOT NULL,
+            last_piece INTEGER NOT NULL,
+            piece_count INTEGER NOT NULL,
+            piece_results BLOB NOT NULL,
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS verification_checkpoint_files (
+            release_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS piece_maps (
+            release_id TEXT PRIMARY KEY NOT NULL,
+            piece_count INTEGER NOT NULL,
+            hashed BLOB NOT NULL,
+            passed BLOB NOT NULL,
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_hashes (
+            release_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            md5 TEXT NOT NULL,
+            PRIMARY KEY (release_id, file_path),
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_cache (
+            release_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            inode INTEGER NOT NULL,
+            first_piece INTEGER NOT NULL,
+            last_piece INTEGER NOT NULL,
+            PRIMARY KEY (release_id, file_path),
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS archive_manifest_files (
+            release_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            item TEXT NOT NULL,
+            name TEXT NOT NULL,
+            size INTEGER,
+            md5 TEXT,
+            sha1 TEXT,
+            status TEXT NOT NULL,
+            PRIMARY KEY (release_id, file_path),
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    // The default rules are only added when the table is first created, so any the user has
+    // removed won't come back when the schema is updated.
+    let has_ignore_rules_table: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'ignore_rules')",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS ignore_rules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            release_id TEXT,
+            pattern TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    if !has_ignore_rules_table {
+        for (pattern, reason) in DEFAULT_IGNORE_RULES.iter() {
+            conn.execute(
+                "INSERT INTO ignore_rules (release_id, pattern, reason) VALUES (NULL, ?1, ?2)",
+                params![pattern, reason],
+            )?;
+        }
+    }
     conn.execute(
         "CREATE TABLE IF NOT EXISTS release_14_links (
             directory_path TEXT NOT NULL,
@@ -54,9 +200,33 @@ pub fn create_db_schema(conn: &Connection) -> Result<()> {
         );",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS release_sources (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            release_id TEXT NOT NULL,
+            priority INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            location TEXT NOT NULL,
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_sources (
+            release_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            source TEXT NOT NULL,
+            downloaded_at TEXT NOT NULL,
+            PRIMARY KEY (release_id, file_path),
+            FOREIGN KEY (release_id) REFERENCES releases(id)
+        );",
+        [],
+    )?;
 
     let mut has_download_url_column = false;
     let mut has_notes_column = false;
+    let mut has_escaped_names_column = false;
+    let mut has_verified_at_column = false;
     let mut statement = conn.prepare("PRAGMA table_info(releases);")?;
     let mut rows = statement.query([])?;
     while let Some(row) = rows.next()? {
@@ -65,6 +235,10 @@ pub fn create_db_schema(conn: &Connection) -> Result<()> {
             has_notes_column = true;
         } else if name == "download_url" {
             has_download_url_column = true;
+        } else if name == "escaped_names" {
+            has_escaped_names_column = true;
+        } else if name == "verified_at" {
+            has_verified_at_column = true;
         }
     }
     if !has_notes_column {
@@ -73,15 +247,26 @@ pub fn create_db_schema(conn: &Connection) -> Result<()> {
     if !has_download_url_column {
         conn.execute("ALTER TABLE releases ADD COLUMN download_url TEXT;", [])?;
     }
+    if !has_escaped_names_column {
+        conn.execute(
+            "ALTER TABLE releases ADD COLUMN escaped_names INTEGER NOT NULL DEFAULT 0;",
+            [],
+        )?;
+    }
+    if !has_verified_at_column {
+        conn.execute("ALTER TABLE releases ADD COLUMN verified_at TEXT;", [])?;
+    }
 
     let mut has_size_column = false;
+    let mut has_reason_column = false;
     let mut statement = conn.prepare("PRAGMA table_info(incomplete_files);")?;
     let mut rows = statement.query([])?;
     while let Some(row) = rows.next()? {
         let name: String = row.get(1)?;
         if name == "size" {
             has_size_column = true;
-            break;
+        } else if name == "reason" {
+            has_reason_column = true;
         }
     }
     if !has_size_column {
@@ -90,6 +275,9 @@ pub fn create_db_schema(conn: &Connection) -> Result<()> {
             [],
         )?;
     }
+    if !has_reason_column {
+        conn.execute("ALTER TABLE incomplete_files ADD COLUMN reason TEXT;", [])?;
+    }
 
     Ok(())
 }
@@ -116,7 +304,7 @@ pub fn save_torrent(
 
 pub fn save_release_14_link(
     conn: &Connection,
-    directory_path: &PathBuf,
+    directory_path: &Path,
     base_url: &str,
 ) -> Result<()> {
     conn.execute(
@@ -126,7 +314,7 @@ pub fn save_release_14_link(
     Ok(())
 }
 
-pub fn save_release_14_file_link(conn: &Connection, file_path: &PathBuf, url: &str) -> Result<()> {
+pub fn save_release_14_file_link(conn: &Connection, file_path: &Path, url: &str) -> Result<()> {
     conn.execute(
         "INSERT OR IGNORE INTO release_14_file_links (path, url) VALUES (?, ?);",
         params![file_path.to_string_lossy(), url],
@@ -134,6 +322,19 @@ pub fn save_release_14_file_link(conn: &Connection, file_path: &PathBuf, url: &s
     Ok(())
 }
 
+/// Records whether a release is stored with names escaped so they are valid on NTFS and exFAT.
+pub fn save_escaped_names(conn: &Connection, release_id: &str, escaped_names: bool) -> Result<()> {
+    conn.execute(
+        "UPDATE releases SET escaped_names = ?1 WHERE id = ?2",
+        params![escaped_names, release_id],
+    )?;
+    Ok(())
+}
+
+/// Saves a release, adding it if it's new.
+///
+/// An existing release only has the columns here updated, so anything else saved for it, like the
+/// time it was verified and its notes, is kept when the releases are reinitialised.
 pub fn save_release(conn: &Connection, release: &Release) -> Result<()> {
     let file_count: Option<i64> = release.file_count.map(|v| v as i64);
     let size: Option<i64> = release.size.map(|v| v as i64);
@@ -157,10 +358,17 @@ pub fn save_release(conn: &Connection, release: &Release) -> Result<()> {
         .unwrap_or("".to_string());
 
     conn.execute(
-        "INSERT OR REPLACE INTO releases (id, date, name, \
-            directory, file_count, size, torrent_url, download_url, verification_outcome) \
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-        &[
+        "INSERT INTO releases (id, date, name, \
+            directory, file_count, size, torrent_url, download_url, verification_outcome, \
+            escaped_names) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) \
+            ON CONFLICT(id) DO UPDATE SET date = excluded.date, name = excluded.name, \
+            directory = excluded.directory, file_count = excluded.file_count, \
+            size = excluded.size, torrent_url = excluded.torrent_url, \
+            download_url = excluded.download_url, \
+            verification_outcome = excluded.verification_outcome, \
+            escaped_names = excluded.escaped_names",
+        [
             &release.id as &dyn rusqlite::ToSql,
             &release.date,
             &release.name,
@@ -170,6 +378,7 @@ pub fn save_release(conn: &Connection, release: &Release) -> Result<()> {
             &torrent_url,
             &download_url,
             &verification_status,
+            &release.escaped_names,
         ],
     )?;
 
@@ -201,20 +410,30 @@ pub fn save_release(conn: &Connection, release: &Release) -> Result<()> {
     Ok(())
 }
 
+fn get_outcome_str(outcome: Option<&VerificationOutcome>) -> &'static str {
+    match outcome {
+        Some(VerificationOutcome::Complete) => "COMPLETE",
+        Some(VerificationOutcome::Verified) => "VERIFIED",
+        Some(VerificationOutcome::TorrentMissing) => "NO TORRENT",
+        Some(VerificationOutcome::Incomplete(_, _)) => "INCOMPLETE",
+        Some(VerificationOutcome::AllFilesMissing) => "MISSING",
+        None => "UNKNOWN",
+    }
+}
+
 pub fn save_verification_result(conn: &mut Connection, release: &Release) -> Result<()> {
     let tx = conn.transaction()?;
     let outcome = release.verification_outcome.as_ref().unwrap();
-    let outcome_str = match outcome {
-        VerificationOutcome::Complete => "COMPLETE",
-        VerificationOutcome::Verified => "VERIFIED",
-        VerificationOutcome::TorrentMissing => "NO TORRENT",
-        VerificationOutcome::Incomplete(_, _) => "INCOMPLETE",
-        VerificationOutcome::AllFilesMissing => "MISSING",
-    };
+    let outcome_str = get_outcome_str(Some(outcome));
     tx.execute(
-        "UPDATE releases SET verification_outcome = ?1 WHERE id = ?2",
+        "UPDATE releases SET verification_outcome = ?1, \
+            verified_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE id = ?2",
         params![outcome_str, release.id],
     )?;
+    tx.execute(
+        "DELETE FROM incomplete_files WHERE release_id = ?1",
+        params![release.id],
+    )?;
 
     if let VerificationOutcome::Incomplete(missing_files, corrupted_files) = outcome {
         for (path, size) in missing_files.iter() {
@@ -231,9 +450,70 @@ pub fn save_verification_result(conn: &mut Connection, release: &Release) -> Res
                 params![release.id, path.to_str().unwrap(), "CORRUPTED", size],
             )?;
         }
-    } else {
+        for (path, size) in release.verified_files.iter() {
+            tx.execute(
+                "INSERT INTO incomplete_files (release_id, file_path, status, size) \
+                    VALUES (?1, ?2, ?3, ?4)",
+                params![release.id, path.to_str().unwrap(), "VERIFIED", size],
+            )?;
+        }
+    }
+    // A verified release can also have unverifiable files, when they share a piece with a missing
+    // file that was waived by an ignore rule.
+    for (path, size) in release.unverifiable_files.iter() {
+        tx.execute(
+            "INSERT INTO incomplete_files (release_id, file_path, status, size) \
+                VALUES (?1, ?2, ?3, ?4)",
+            params![release.id, path.to_str().unwrap(), "UNVERIFIABLE", size],
+        )?;
+    }
+    for (path, size, reason) in release.waived_files.iter() {
+        tx.execute(
+            "INSERT INTO incomplete_files (release_id, file_path, status, size, reason) \
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![release.id, path.to_str().unwrap(), "WAIVED", size, reason],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Replaces the stored results for some of the files in a release and updates its outcome.
+///
+/// The results for every other file are left alone, apart from when the release is now verified,
+/// in which case the verified rows are removed, since they are implied by the outcome.
+pub fn save_file_results(
+    conn: &mut Connection,
+    release: &Release,
+    results: &[FileResult],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    let outcome_str = get_outcome_str(release.verification_outcome.as_ref());
+    tx.execute(
+        "UPDATE releases SET verification_outcome = ?1, \
+            verified_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE id = ?2",
+        params![outcome_str, release.id],
+    )?;
+    for result in results.iter() {
+        tx.execute(
+            "DELETE FROM incomplete_files WHERE release_id = ?1 AND file_path = ?2",
+            params![release.id, result.path.to_str().unwrap()],
+        )?;
         tx.execute(
-            "DELETE FROM incomplete_files WHERE release_id = ?1",
+            "INSERT INTO incomplete_files (release_id, file_path, status, size, reason) \
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                release.id,
+                result.path.to_str().unwrap(),
+                result.status,
+                result.size,
+                result.reason
+            ],
+        )?;
+    }
+    if let Some(VerificationOutcome::Verified) = release.verification_outcome {
+        tx.execute(
+            "DELETE FROM incomplete_files WHERE release_id = ?1 AND status = 'VERIFIED'",
             params![release.id],
         )?;
     }
@@ -241,6 +521,479 @@ pub fn save_verification_result(conn: &mut Connection, release: &Release) -> Res
     Ok(())
 }
 
+/// Saves a checkpoint for a verification that is in progress, replacing any previous one.
+///
+/// The file states are only written if they haven't already been recorded for the release, since
+/// they describe the files as they were when verification started.
+pub fn save_verification_checkpoint(
+    conn: &mut Connection,
+    release_id: &str,
+    checkpoint: &VerificationCheckpoint,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT OR REPLACE INTO verification_checkpoints \
+            (release_id, last_piece, piece_count, piece_results) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            release_id,
+            checkpoint.last_piece as i64,
+            checkpoint.piece_results.len() as i64,
+            checkpoint.piece_results.as_bytes()
+        ],
+    )?;
+    let file_count: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM verification_checkpoint_files WHERE release_id = ?1",
+        params![release_id],
+        |row| row.get(0),
+    )?;
+    if file_count == 0 {
+        for (path, size, mtime) in checkpoint.files.iter() {
+            tx.execute(
+                "INSERT INTO verification_checkpoint_files (release_id, file_path, size, mtime) \
+                    VALUES (?1, ?2, ?3, ?4)",
+                params![release_id, path.to_string_lossy(), *size as i64, mtime],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_verification_checkpoint(
+    conn: &Connection,
+    release_id: &str,
+) -> Result<Option<VerificationCheckpoint>> {
+    let mut statement = conn.prepare(
+        "SELECT last_piece, piece_count, piece_results FROM verification_checkpoints \
+            WHERE release_id = ?1",
+    )?;
+    let mut rows = statement.query(params![release_id])?;
+    let (last_piece, piece_count, piece_results) = match rows.next()? {
+        Some(row) => {
+            let last_piece: i64 = row.get(0)?;
+            let piece_count: i64 = row.get(1)?;
+            let piece_results: Vec<u8> = row.get(2)?;
+            (last_piece, piece_count, piece_results)
+        }
+        None => return Ok(None),
+    };
+
+    let mut files = Vec::new();
+    let mut statement = conn.prepare(
+        "SELECT file_path, size, mtime FROM verification_checkpoint_files \
+            WHERE release_id = ?1 ORDER BY rowid",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok((
+            PathBuf::from(row.get::<_, String>(0)?),
+            row.get::<_, i64>(1)? as u64,
+            row.get::<_, i64>(2)?,
+        ))
+    })?;
+    for row in rows {
+        files.push(row?);
+    }
+    Ok(Some(VerificationCheckpoint {
+        last_piece: last_piece as usize,
+        piece_results: Bitfield::from_bytes(piece_count as usize, &piece_results)?,
+        files,
+    }))
+}
+
+pub fn delete_verification_checkpoint(conn: &mut Connection, release_id: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM verification_checkpoints WHERE release_id = ?1",
+        params![release_id],
+    )?;
+    tx.execute(
+        "DELETE FROM verification_checkpoint_files WHERE release_id = ?1",
+        params![release_id],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Saves the result for each piece from a verification, replacing the previous map.
+pub fn save_piece_map(conn: &Connection, release_id: &str, piece_map: &PieceMap) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO piece_maps (release_id, piece_count, hashed, passed) \
+            VALUES (?1, ?2, ?3, ?4)",
+        params![
+            release_id,
+            piece_map.len() as i64,
+            piece_map.hashed.as_bytes(),
+            piece_map.passed.as_bytes()
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_piece_map(conn: &Connection, release_id: &str) -> Result<Option<PieceMap>> {
+    let mut statement =
+        conn.prepare("SELECT piece_count, hashed, passed FROM piece_maps WHERE release_id = ?1")?;
+    let mut rows = statement.query(params![release_id])?;
+    match rows.next()? {
+        Some(row) => {
+            let piece_count = row.get::<_, i64>(0)? as usize;
+            let hashed: Vec<u8> = row.get(1)?;
+            let passed: Vec<u8> = row.get(2)?;
+            Ok(Some(PieceMap {
+                hashed: Bitfield::from_bytes(piece_count, &hashed)?,
+                passed: Bitfield::from_bytes(piece_count, &passed)?,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn delete_piece_map(conn: &Connection, release_id: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM piece_maps WHERE release_id = ?1",
+        params![release_id],
+    )?;
+    Ok(())
+}
+
+pub fn save_file_hashes(
+    conn: &mut Connection,
+    release_id: &str,
+    hashes: &[(PathBuf, u64, FileDigest)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (path, size, digest) in hashes.iter() {
+        tx.execute(
+            "INSERT OR REPLACE INTO file_hashes (release_id, file_path, size, sha256, md5) \
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                release_id,
+                path.to_string_lossy(),
+                *size as i64,
+                digest.sha256,
+                digest.md5
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_file_hashes(
+    conn: &Connection,
+    release_id: &str,
+) -> Result<HashMap<PathBuf, FileDigest>> {
+    let mut map = HashMap::new();
+    let mut statement =
+        conn.prepare("SELECT file_path, sha256, md5 FROM file_hashes WHERE release_id = ?1")?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok((
+            PathBuf::from(row.get::<_, String>(0)?),
+            FileDigest {
+                sha256: row.get(1)?,
+                md5: row.get(2)?,
+            },
+        ))
+    })?;
+    for row in rows {
+        let (path, digest) = row?;
+        map.insert(path, digest);
+    }
+    Ok(map)
+}
+
+/// Records the state of files that passed verification and removes files that have since failed.
+pub fn update_file_cache(
+    conn: &mut Connection,
+    release_id: &str,
+    verified: &[(PathBuf, CachedFile)],
+    invalidated: &[PathBuf],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (path, cached) in verified.iter() {
+        tx.execute(
+            "INSERT OR REPLACE INTO file_cache \
+                (release_id, file_path, size, mtime, inode, first_piece, last_piece) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                release_id,
+                path.to_string_lossy(),
+                cached.state.size as i64,
+                cached.state.mtime,
+                cached.state.inode as i64,
+                cached.pieces.start as i64,
+                cached.pieces.end as i64
+            ],
+        )?;
+    }
+    for path in invalidated.iter() {
+        tx.execute(
+            "DELETE FROM file_cache WHERE release_id = ?1 AND file_path = ?2",
+            params![release_id, path.to_string_lossy()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_file_cache(conn: &Connection, release_id: &str) -> Result<HashMap<PathBuf, CachedFile>> {
+    let mut map = HashMap::new();
+    let mut statement = conn.prepare(
+        "SELECT file_path, size, mtime, inode, first_piece, last_piece \
+            FROM file_cache WHERE release_id = ?1",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok((
+            PathBuf::from(row.get::<_, String>(0)?),
+            CachedFile {
+                state: FileState {
+                    size: row.get::<_, i64>(1)? as u64,
+                    mtime: row.get(2)?,
+                    inode: row.get::<_, i64>(3)? as u64,
+                },
+                pieces: row.get::<_, i64>(4)? as usize..row.get::<_, i64>(5)? as usize,
+            },
+        ))
+    })?;
+    for row in rows {
+        let (path, cached) = row?;
+        map.insert(path, cached);
+    }
+    Ok(map)
+}
+
+pub fn add_ignore_rule(
+    conn: &Connection,
+    release_id: Option<&str>,
+    pattern: &str,
+    reason: &str,
+) -> Result<i64> {
+    compile_pattern(pattern)?;
+    conn.execute(
+        "INSERT INTO ignore_rules (release_id, pattern, reason) VALUES (?1, ?2, ?3)",
+        params![release_id, pattern, reason],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn remove_ignore_rule(conn: &Connection, rule_id: i64) -> Result<()> {
+    let removed = conn.execute("DELETE FROM ignore_rules WHERE id = ?1", params![rule_id])?;
+    if removed == 0 {
+        return Err(Error::IgnoreRuleNotFound(rule_id));
+    }
+    Ok(())
+}
+
+/// Gets the global ignore rules, plus those for the release if an ID is supplied.
+pub fn get_ignore_rules(conn: &Connection, release_id: Option<&str>) -> Result<Vec<IgnoreRule>> {
+    let mut statement = conn.prepare(
+        "SELECT id, release_id, pattern, reason FROM ignore_rules \
+            WHERE release_id IS NULL OR release_id = ?1 ORDER BY id",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok(IgnoreRule {
+            id: row.get(0)?,
+            release_id: row.get(1)?,
+            pattern: row.get(2)?,
+            reason: row.get(3)?,
+        })
+    })?;
+    let mut rules = Vec::new();
+    for row in rows {
+        rules.push(row?);
+    }
+    Ok(rules)
+}
+
+pub fn get_all_ignore_rules(conn: &Connection) -> Result<Vec<IgnoreRule>> {
+    let mut statement =
+        conn.prepare("SELECT id, release_id, pattern, reason FROM ignore_rules ORDER BY id")?;
+    let rows = statement.query_map([], |row| {
+        Ok(IgnoreRule {
+            id: row.get(0)?,
+            release_id: row.get(1)?,
+            pattern: row.get(2)?,
+            reason: row.get(3)?,
+        })
+    })?;
+    let mut rules = Vec::new();
+    for row in rows {
+        rules.push(row?);
+    }
+    Ok(rules)
+}
+
+pub fn add_release_source(
+    conn: &Connection,
+    release_id: &str,
+    priority: i64,
+    kind: SourceKind,
+    location: &str,
+) -> Result<i64> {
+    if kind != SourceKind::Local {
+        Url::parse(location)?;
+    }
+    conn.execute(
+        "INSERT INTO release_sources (release_id, priority, kind, location) \
+            VALUES (?1, ?2, ?3, ?4)",
+        params![release_id, priority, kind.to_string(), location],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn remove_release_source(conn: &Connection, source_id: i64) -> Result<()> {
+    let removed = conn.execute(
+        "DELETE FROM release_sources WHERE id = ?1",
+        params![source_id],
+    )?;
+    if removed == 0 {
+        return Err(Error::SourceNotFound(source_id));
+    }
+    Ok(())
+}
+
+/// Gets the sources for a release in the order they should be tried. Sources with the same
+/// priority are tried in the order they were added.
+pub fn get_release_sources(conn: &Connection, release_id: &str) -> Result<Vec<ReleaseSource>> {
+    let mut statement = conn.prepare(
+        "SELECT id, release_id, priority, kind, location FROM release_sources \
+            WHERE release_id = ?1 ORDER BY priority, id",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    })?;
+    let mut sources = Vec::new();
+    for row in rows {
+        let (id, release_id, priority, kind, location) = row?;
+        sources.push(ReleaseSource {
+            id,
+            release_id,
+            priority,
+            kind: kind.parse()?,
+            location,
+        });
+    }
+    Ok(sources)
+}
+
+/// Records the source each of the given files was downloaded from, replacing what was recorded
+/// for them before.
+pub fn save_file_sources(
+    conn: &mut Connection,
+    release_id: &str,
+    file_sources: &[(PathBuf, String)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (path, source) in file_sources.iter() {
+        tx.execute(
+            "INSERT OR REPLACE INTO file_sources (release_id, file_path, source, downloaded_at) \
+                VALUES (?1, ?2, ?3, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))",
+            params![release_id, path.to_string_lossy(), source],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Gets the source each file of a release was downloaded from, along with when it was downloaded.
+pub fn get_file_sources(
+    conn: &Connection,
+    release_id: &str,
+) -> Result<HashMap<PathBuf, (String, String)>> {
+    let mut map = HashMap::new();
+    let mut statement = conn.prepare(
+        "SELECT file_path, source, downloaded_at FROM file_sources WHERE release_id = ?1",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok((
+            PathBuf::from(row.get::<_, String>(0)?),
+            (row.get(1)?, row.get(2)?),
+        ))
+    })?;
+    for row in rows {
+        let (path, source) = row?;
+        map.insert(path, source);
+    }
+    Ok(map)
+}
+
+/// Saves manifest entries for a release, replacing any previous entries for the same files.
+///
+/// The status of each entry is reset, since the manifest could have changed.
+pub fn save_manifest_entries(
+    conn: &mut Connection,
+    release_id: &str,
+    entries: &[ManifestEntry],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for entry in entries.iter() {
+        tx.execute(
+            "INSERT OR REPLACE INTO archive_manifest_files \
+                (release_id, file_path, item, name, size, md5, sha1, status) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                release_id,
+                entry.file_path.to_string_lossy(),
+                entry.item,
+                entry.file.name,
+                entry.file.size.map(|s| s as i64),
+                entry.file.md5,
+                entry.file.sha1,
+                entry.status.to_string()
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_manifest_entries(conn: &Connection, release_id: &str) -> Result<Vec<ManifestEntry>> {
+    let mut statement = conn.prepare(
+        "SELECT file_path, item, name, size, md5, sha1, status FROM archive_manifest_files \
+            WHERE release_id = ?1 ORDER BY file_path",
+    )?;
+    let rows = statement.query_map(params![release_id], |row| {
+        Ok(ManifestEntry {
+            file_path: PathBuf::from(row.get::<_, String>(0)?),
+            item: row.get(1)?,
+            file: ManifestFile {
+                name: row.get(2)?,
+                size: row.get::<_, Option<i64>>(3)?.map(|s| s as u64),
+                md5: row.get(4)?,
+                sha1: row.get(5)?,
+            },
+            status: ManifestStatus::from_db_value(&row.get::<_, String>(6)?),
+        })
+    })?;
+    let mut entries = Vec::new();
+    for row in rows {
+        entries.push(row?);
+    }
+    Ok(entries)
+}
+
+pub fn save_manifest_statuses(
+    conn: &mut Connection,
+    release_id: &str,
+    statuses: &[(PathBuf, ManifestStatus)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (path, status) in statuses.iter() {
+        tx.execute(
+            "UPDATE archive_manifest_files SET status = ?1 \
+                WHERE release_id = ?2 AND file_path = ?3",
+            params![status.to_string(), release_id, path.to_string_lossy()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn save_notes(conn: &Connection, release_id: &str, notes: &str) -> Result<()> {
     conn.execute(
         "UPDATE releases SET notes = ?1 WHERE id = ?2",
@@ -249,16 +1002,31 @@ pub fn save_notes(conn: &Connection, release_id: &str, notes: &str) -> Result<()
     Ok(())
 }
 
+/// Gets the time the verification outcome of a release was last saved, as an ISO 8601 UTC
+/// timestamp, or `None` if it hasn't been verified since this was recorded.
+pub fn get_verified_at(conn: &Connection, release_id: &str) -> Result<Option<String>> {
+    let verified_at = conn.query_row(
+        "SELECT verified_at FROM releases WHERE id = ?1",
+        params![release_id],
+        |row| row.get(0),
+    )?;
+    Ok(verified_at)
+}
+
 pub fn reset_verification_result(conn: &mut Connection, release_id: &str) -> Result<()> {
     let tx = conn.transaction()?;
     tx.execute(
-        "UPDATE releases SET verification_outcome = 'UNKNOWN' WHERE id = ?1",
+        "UPDATE releases SET verification_outcome = 'UNKNOWN', verified_at = NULL WHERE id = ?1",
         params![release_id],
     )?;
     tx.execute(
         "DELETE FROM incomplete_files WHERE release_id = ?1",
         params![release_id],
     )?;
+    tx.execute(
+        "DELETE FROM piece_maps WHERE release_id = ?1",
+        params![release_id],
+    )?;
     tx.commit()?;
     Ok(())
 }
@@ -267,16 +1035,15 @@ pub fn get_releases(conn: &Connection) -> Result<Vec<Release>> {
     let mut statement = conn.prepare(
         "SELECT id, date, name, \
             directory, file_count, size, \
-            torrent_url, verification_outcome, notes, download_url FROM releases",
+            torrent_url, verification_outcome, notes, download_url, escaped_names FROM releases",
     )?;
     let mut rows = statement.query([])?;
     let mut releases = Vec::new();
 
     while let Some(row) = rows.next()? {
         let release_id: String = row.get(0)?;
-        let (missing_files, corrupted_files) =
-            get_incomplete_verification_data(&conn, &release_id)?;
-        let release = Release::from_row(row, &missing_files, &corrupted_files)?;
+        let incomplete_files = get_incomplete_verification_data(conn, &release_id)?;
+        let release = Release::from_row(row, &incomplete_files)?;
         releases.push(release);
     }
     Ok(releases)
@@ -286,7 +1053,7 @@ pub fn get_missing_releases(conn: &Connection) -> Result<Vec<Release>> {
     let mut statement = conn.prepare(
         "SELECT id, date, name, \
             directory, file_count, size, \
-            torrent_url, verification_outcome, notes, download_url FROM \
+            torrent_url, verification_outcome, notes, download_url, escaped_names FROM \
             releases WHERE verification_outcome = 'MISSING'",
     )?;
     let mut rows = statement.query([])?;
@@ -294,9 +1061,8 @@ pub fn get_missing_releases(conn: &Connection) -> Result<Vec<Release>> {
 
     while let Some(row) = rows.next()? {
         let release_id: String = row.get(0)?;
-        let (missing_files, corrupted_files) =
-            get_incomplete_verification_data(&conn, &release_id)?;
-        let release = Release::from_row(row, &missing_files, &corrupted_files)?;
+        let incomplete_files = get_incomplete_verification_data(conn, &release_id)?;
+        let release = Release::from_row(row, &incomplete_files)?;
         releases.push(release);
     }
     Ok(releases)
@@ -307,14 +1073,13 @@ pub fn get_release_by_id(conn: &Connection, release_id: &str) -> Result<Release>
         "SELECT \
         id, date, name, \
         directory, file_count, size, \
-        torrent_url, verification_outcome, notes, download_url FROM releases WHERE id = ?1",
+        torrent_url, verification_outcome, notes, download_url, escaped_names FROM releases WHERE id = ?1",
     )?;
     let mut rows = statement.query(params![release_id])?;
 
     if let Some(row) = rows.next()? {
-        let (missing_files, corrupted_files) =
-            get_incomplete_verification_data(&conn, &release_id)?;
-        let release = Release::from_row(&row, &missing_files, &corrupted_files)?;
+        let incomplete_files = get_incomplete_verification_data(conn, release_id)?;
+        let release = Release::from_row(row, &incomplete_files)?;
         return Ok(release);
     }
     Err(Error::ReleaseNotFound(release_id.to_string()))
@@ -336,29 +1101,35 @@ pub fn get_torrent_content(release_id: &str) -> Result<Vec<u8>> {
 fn get_incomplete_verification_data(
     conn: &Connection,
     release_id: &str,
-) -> Result<(Vec<(PathBuf, u64)>, Vec<(PathBuf, u64)>)> {
+) -> Result<IncompleteFiles> {
     // If the verification result was anything other than INCOMPLETE, the returned lists will be
-    // empty.
-    let mut missing_files = Vec::new();
-    let mut corrupted_files = Vec::new();
-    let mut files_statement =
-        conn.prepare("SELECT file_path, status, size FROM incomplete_files WHERE release_id = ?1")?;
+    // empty, apart from any waived files.
+    let mut incomplete_files = IncompleteFiles::default();
+    let mut files_statement = conn.prepare(
+        "SELECT file_path, status, size, reason FROM incomplete_files WHERE release_id = ?1",
+    )?;
     let files_iter = files_statement.query_map([&release_id], |row| {
         let file_path: String = row.get(0)?;
         let status: String = row.get(1)?;
         let size: u64 = row.get(2)?;
-        Ok((file_path, status, size))
+        let reason: Option<String> = row.get(3)?;
+        Ok((file_path, status, size, reason))
     })?;
     for file_result in files_iter {
-        let (file_path, status, size) = file_result?;
+        let (file_path, status, size, reason) = file_result?;
         let path = PathBuf::from(file_path);
-        if status == "MISSING" {
-            missing_files.push((path, size));
-        } else if status == "CORRUPTED" {
-            corrupted_files.push((path, size));
+        match status.as_str() {
+            "MISSING" => incomplete_files.missing.push((path, size)),
+            "CORRUPTED" => incomplete_files.corrupted.push((path, size)),
+            "VERIFIED" => incomplete_files.verified.push((path, size)),
+            "UNVERIFIABLE" => incomplete_files.unverifiable.push((path, size)),
+            "WAIVED" => incomplete_files
+                .waived
+                .push((path, size, reason.unwrap_or_default())),
+            _ => {}
         }
     }
-    Ok((missing_files.clone(), corrupted_files.clone()))
+    Ok(incomplete_files)
 }
 
 pub fn get_release_14_links(conn: &Connection) -> Result<HashMap<PathBuf, String>> {
@@ -371,10 +1142,8 @@ pub fn get_release_14_links(conn: &Connection) -> Result<HashMap<PathBuf, String
         ))
     })?;
 
-    for row in rows {
-        if let Ok((directory_path, base_url)) = row {
-            map.insert(directory_path, base_url);
-        }
+    for (directory_path, base_url) in rows.flatten() {
+        map.insert(directory_path, base_url);
     }
     Ok(map)
 }
@@ -389,10 +1158,8 @@ pub fn get_release_14_file_links(conn: &Connection) -> Result<HashMap<PathBuf, S
         ))
     })?;
 
-    for row in rows {
-        if let Ok((path, url)) = row {
-            map.insert(path, url);
-        }
+    for (path, url) in rows.flatten() {
+        map.insert(path, url);
     }
     Ok(map)
 }
@@ -412,3 +1179,125 @@ pub fn get_database_path() -> Result<PathBuf> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_a_release_again_keeps_its_verification() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_db_schema(&conn).unwrap();
+        let mut release = Release::new(
+            "2009-01-01".to_string(),
+            "Release".to_string(),
+            Some("Release".to_string()),
+            Some(3),
+            Some(100),
+            None,
+            None,
+        );
+        save_release(&conn, &release).unwrap();
+        release.verification_outcome = Some(VerificationOutcome::Verified);
+        save_verification_result(&mut conn, &release).unwrap();
+        let verified_at = get_verified_at(&conn, &release.id).unwrap();
+        assert!(verified_at.is_some());
+
+        // Reinitialising the releases saves them again with any changes to the static data.
+        release.file_count = Some(4);
+        save_release(&conn, &release).unwrap();
+        let saved = get_release_by_id(&conn, &release.id).unwrap();
+        assert_eq!(saved.file_count, Some(4));
+        assert!(matches!(
+            saved.verification_outcome,
+            Some(VerificationOutcome::Verified)
+        ));
+        assert_eq!(get_verified_at(&conn, &release.id).unwrap(), verified_at);
+    }
+
+    fn saved_release(conn: &Connection) -> Release {
+        let release = Release::new(
+            "2009-01-01".to_string(),
+            "Release".to_string(),
+            Some("Release".to_string()),
+            Some(3),
+            Some(100),
+            None,
+            None,
+        );
+        save_release(conn, &release).unwrap();
+        release
+    }
+
+    #[test]
+    fn gets_release_sources_in_order_of_priority() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_db_schema(&conn).unwrap();
+        let release = saved_release(&conn);
+        let nas = add_release_source(&conn, &release.id, 1, SourceKind::Local, "/mnt/nas").unwrap();
+        let mirror = add_release_source(
+            &conn,
+            &release.id,
+            1,
+            SourceKind::Http,
+            "https://example.com/911datasets.org/",
+        )
+        .unwrap();
+        let zip = add_release_source(
+            &conn,
+            &release.id,
+            -1,
+            SourceKind::Zip,
+            "https://archive.org/download/item/rel.zip",
+        )
+        .unwrap();
+        assert!(add_release_source(&conn, &release.id, 1, SourceKind::Http, "not a url").is_err());
+
+        let sources = get_release_sources(&conn, &release.id).unwrap();
+        // Sources with the same priority stay in the order they were added.
+        assert_eq!(
+            sources.iter().map(|s| s.id).collect::<Vec<i64>>(),
+            vec![zip, nas, mirror]
+        );
+        assert_eq!(sources[0].kind, SourceKind::Zip);
+        assert_eq!(sources[0].priority, -1);
+        assert_eq!(sources[1].location, "/mnt/nas");
+
+        remove_release_source(&conn, nas).unwrap();
+        assert_eq!(get_release_sources(&conn, &release.id).unwrap().len(), 2);
+        assert!(matches!(
+            remove_release_source(&conn, nas),
+            Err(Error::SourceNotFound(id)) if id == nas
+        ));
+    }
+
+    #[test]
+    fn replaces_the_source_recorded_for_a_file() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_db_schema(&conn).unwrap();
+        let release = saved_release(&conn);
+        let a = PathBuf::from("Release/a.jpg");
+        let b = PathBuf::from("Release/b.jpg");
+        save_file_sources(
+            &mut conn,
+            &release.id,
+            &[
+                (a.clone(), "local /mnt/nas".to_string()),
+                (b.clone(), "local /mnt/nas".to_string()),
+            ],
+        )
+        .unwrap();
+        save_file_sources(
+            &mut conn,
+            &release.id,
+            &[(b.clone(), "http https://example.com/".to_string())],
+        )
+        .unwrap();
+
+        let sources = get_file_sources(&conn, &release.id).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[&a].0, "local /mnt/nas");
+        assert_eq!(sources[&b].0, "http https://example.com/");
+        assert!(!sources[&b].1.is_empty());
+    }
+}
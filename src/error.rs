@@ -1,3 +1,4 @@
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -5,26 +6,69 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 #[derive(Debug, Error)]
 #[allow(missing_docs)]
 pub enum Error {
+    #[error("The paths in {0} could be matched to the torrent {1} equally well")]
+    AmbiguousZipLayout(String, String),
     #[error("A 404 response was returned for {0}")]
     ArchiveFileNotFoundError(String),
-    #[error("Error response when downloading file: {0}")]
-    ArchiveDownloadFailed(u16),
+    #[error("{status} response when downloading {url}")]
+    ArchiveDownloadFailed {
+        url: String,
+        status: reqwest::StatusCode,
+        /// How long the server asked us to wait before trying again.
+        retry_after: Option<Duration>,
+    },
     #[error("Could not obtain the user's data directory")]
     CouldNotObtainDataDirectory,
+    #[error("No data was received from {0} for {1} seconds")]
+    DownloadStalled(String, u64),
     #[error("Could not obtain file name from URL")]
     FilenameFromUrlError,
     #[error("Path {0} is not a valid path in the torrent tree")]
     MarkIncompleteInvalidPath(String),
     #[error("To mark a release incomplete either missing or corrupt files must be supplied")]
     MarkIncompleteFilesNotSupplied,
+    #[error("The piece hashing workers stopped before all the pieces were processed")]
+    HashingStopped,
+    #[error("Setting an idle I/O priority is only supported on Linux")]
+    IdleIoPriorityNotSupported,
+    #[error("There is no ignore rule with ID {0}")]
+    IgnoreRuleNotFound(i64),
+    #[error("The ignore pattern '{0}' is not a valid glob: {1}")]
+    InvalidIgnorePattern(String, String),
+    #[error("'{0}' is not a valid rate; use a value like 80MiB/s")]
+    InvalidReadRate(String),
+    #[error("'{0}' is not a valid rate schedule; use a value like 01:00-07:00=unlimited")]
+    InvalidRateSchedule(String),
+    #[error("'{0}' is not a kind of source; use zip, http or local")]
+    InvalidSourceKind(String),
+    #[error("{0} is not a valid key")]
+    InvalidKey(String),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("{0} already exists")]
+    KeyFileExists(String),
+    #[error(transparent)]
     LavaTorrentError(#[from] lava_torrent::LavaTorrentError),
+    #[error("A bitfield for {0} pieces cannot be built from {1} bytes")]
+    MalformedBitfield(usize, usize),
     #[error("The release table has a row that is not correctly formed with 3 columns")]
     MalformedReleaseTable,
+    #[error("Could not parse the Archive manifest: {0}")]
+    ManifestParseError(String),
+    #[error(
+        "{0} isn't marked as incomplete, so there is nothing to repair; check or verify it first"
+    )]
+    NothingToRepair(String),
+    #[error("Peer {0} {1}")]
+    PeerError(String, String),
+    #[error("There are no files under {0} in the release")]
+    PathNotInRelease(String),
     #[error("Cannot parse path segments from torrent URL")]
     PathSegmentsParseError,
+    #[error("{0} already exists in the quarantine directory")]
+    QuarantineFileExists(String),
     #[error("This release is not distributed in a zip")]
     ReleaseNotZipError,
     #[error("Failed to download file in release: {0}")]
@@ -33,18 +77,51 @@ pub enum Error {
     ReleaseDirectoryNotObtained,
     #[error("There is no release with ID {0}")]
     ReleaseNotFound(String),
+    #[error("A release with ID {0} already exists")]
+    ReleaseAlreadyExists(String),
+    #[error("The report signature is not valid, so the report has been altered")]
+    ReportSignatureInvalid,
+    #[error("The report was signed with a different key: {0}")]
+    ReportSignedWithOtherKey(String),
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
+    #[error("There is no download source with ID {0}")]
+    SourceNotFound(i64),
     #[error(transparent)]
     SqlError(#[from] rusqlite::Error),
     #[error(transparent)]
     TemplateError(#[from] indicatif::style::TemplateError),
     #[error("Cannot retrieve torrent files")]
     TorrentFilesError,
+    #[error("Tracker {0}: {1}")]
+    TrackerError(String, String),
+    #[error("Could not parse the v2 torrent: {0}")]
+    TorrentV2ParseError(String),
+    #[error("This is not supported for the torrent's version: {0}")]
+    TorrentVersionNotSupported(String),
     #[error(transparent)]
     UrlParseError(#[from] url::ParseError),
     #[error("Verification report error: {0}")]
     VerificationReportError(String),
     #[error(transparent)]
+    WalkDirError(#[from] walkdir::Error),
+    #[error(transparent)]
     ZipError(#[from] zip::result::ZipError),
 }
+
+impl Error {
+    /// Whether a download that failed with this error might succeed if it's tried again.
+    ///
+    /// Server errors, rate limiting and network failures are transient; a file that doesn't exist
+    /// or that we aren't allowed to download will fail in the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::ArchiveDownloadFailed { status, .. } => {
+                status.is_server_error() || matches!(status.as_u16(), 408 | 425 | 429)
+            }
+            Error::ReqwestError(e) => !e.is_builder() && !e.is_redirect(),
+            Error::DownloadStalled(..) => true,
+            _ => false,
+        }
+    }
+}
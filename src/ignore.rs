@@ -0,0 +1,141 @@
+use crate::error::{Error, Result};
+use glob::{MatchOptions, Pattern};
+use std::fmt;
+use std::path::Path;
+
+/// A glob pattern for files that are allowed to be missing or fail verification.
+///
+/// Some files in the torrents, like `Thumbs.db`, were generated by the operating system of whoever
+/// created the release. They have no value and are often not present on the Archive, so they
+/// shouldn't prevent a release from being considered complete.
+///
+/// A rule with no release ID applies to every release.
+#[derive(Clone, Debug)]
+pub struct IgnoreRule {
+    pub id: i64,
+    pub release_id: Option<String>,
+    pub pattern: String,
+    pub reason: String,
+}
+
+impl fmt::Display for IgnoreRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.pattern, self.reason)
+    }
+}
+
+/// The ignore rules that apply to a release, with their patterns compiled.
+pub struct IgnoreRules {
+    rules: Vec<(Pattern, IgnoreRule)>,
+}
+
+impl IgnoreRules {
+    pub fn new(rules: Vec<IgnoreRule>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| Ok((compile_pattern(&rule.pattern)?, rule)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Returns the first rule that matches the path, if there is one.
+    ///
+    /// A pattern without a `/` is matched against the file name alone, so `Thumbs.db` will match
+    /// at any depth in the tree. A pattern with a `/` is matched against the whole path within the
+    /// torrent. Matching is not case sensitive.
+    pub fn find_match(&self, path: &Path) -> Option<&IgnoreRule> {
+        let options = MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let full_path = path.to_string_lossy().replace('\\', "/");
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.rules
+            .iter()
+            .find(|(pattern, rule)| {
+                if rule.pattern.contains('/') {
+                    pattern.matches_with(&full_path, options)
+                } else {
+                    pattern.matches_with(&file_name, options)
+                }
+            })
+            .map(|(_, rule)| rule)
+    }
+}
+
+/// Checks the pattern is a valid glob.
+pub fn compile_pattern(pattern: &str) -> Result<Pattern> {
+    Pattern::new(pattern)
+        .map_err(|e| Error::InvalidIgnorePattern(pattern.to_string(), e.msg.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> IgnoreRules {
+        IgnoreRules::new(
+            patterns
+                .iter()
+                .enumerate()
+                .map(|(i, pattern)| IgnoreRule {
+                    id: i as i64,
+                    release_id: None,
+                    pattern: pattern.to_string(),
+                    reason: format!("rule {i}"),
+                })
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    fn matched(rules: &IgnoreRules, path: &str) -> Option<i64> {
+        rules.find_match(Path::new(path)).map(|rule| rule.id)
+    }
+
+    #[test]
+    fn matches_file_names_at_any_depth() {
+        let rules = rules(&["Thumbs.db", "*.tmp"]);
+        assert_eq!(matched(&rules, "Thumbs.db"), Some(0));
+        assert_eq!(matched(&rules, "Release/photos/2001/thumbs.DB"), Some(0));
+        assert_eq!(matched(&rules, "Release/notes.tmp"), Some(1));
+        assert_eq!(matched(&rules, "Release/Thumbs.db.bak"), None);
+        // Only the file name is matched, not the directories above it.
+        assert_eq!(matched(&rules, "Release/Thumbs.db/photo.jpg"), None);
+    }
+
+    #[test]
+    fn matches_patterns_with_a_separator_against_the_whole_path() {
+        let rules = rules(&["Release/*.txt", "Release/**/.DS_Store"]);
+        assert_eq!(matched(&rules, "Release/readme.TXT"), Some(0));
+        // A single star doesn't cross into subdirectories.
+        assert_eq!(matched(&rules, "Release/docs/readme.txt"), None);
+        assert_eq!(matched(&rules, "Other/readme.txt"), None);
+        assert_eq!(matched(&rules, "Release/.DS_Store"), Some(1));
+        assert_eq!(matched(&rules, "Release/a/b/.DS_Store"), Some(1));
+        assert_eq!(matched(&rules, "Release\\docs\\.DS_Store"), Some(1));
+    }
+
+    #[test]
+    fn returns_the_first_rule_that_matches() {
+        let rules = rules(&["*.db", "Thumbs.db"]);
+        assert_eq!(matched(&rules, "Release/Thumbs.db"), Some(0));
+        assert_eq!(
+            matched(&IgnoreRules::new(Vec::new()).unwrap(), "Thumbs.db"),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(matches!(
+            compile_pattern("Release/[a-"),
+            Err(Error::InvalidIgnorePattern(pattern, _)) if pattern == "Release/[a-"
+        ));
+        assert!(compile_pattern("***").is_err());
+    }
+}
@@ -1,35 +1,92 @@
+pub mod archive_manifest;
 pub mod db;
 pub mod error;
+pub mod ignore;
+pub mod paths;
+pub mod peer;
+pub mod pieces;
 pub mod release_data;
+pub mod report;
+pub mod retry;
+pub mod sources;
+pub mod throttle;
+pub mod torrent_v2;
+pub mod tracker;
+pub mod zip_source;
 
+use crate::archive_manifest::{
+    match_manifest_files, parse_files_xml, read_files_xml, ManifestStatus,
+};
 use crate::db::{
-    get_torrent_content, save_release_14_file_link, save_release_14_link, save_torrent,
-    torrent_already_saved,
+    delete_piece_map, delete_verification_checkpoint, get_database_path, get_db_connection,
+    get_file_cache, get_file_hashes, get_ignore_rules, get_manifest_entries, get_piece_map,
+    get_release_by_id, get_torrent_content, get_verification_checkpoint, get_verified_at,
+    save_file_hashes, save_file_results, save_file_sources, save_manifest_entries,
+    save_manifest_statuses, save_piece_map, save_release, save_release_14_file_link,
+    save_release_14_link, save_torrent, save_verification_checkpoint, torrent_already_saved,
+    update_file_cache, CachedFile, FileResult, IncompleteFiles, VerificationCheckpoint,
 };
 use crate::error::{Error, Result};
+use crate::ignore::IgnoreRules;
+use crate::paths::{escape_path, PathResolver, ResolvedPath};
+use crate::peer::{download_pieces, SwarmOptions};
+use crate::pieces::{
+    hash_pieces, Bitfield, DownloadResults, DownloadVerifier, FileAttributes, FileDigest,
+    FileState, PieceLayout, PieceMap, TorrentFile,
+};
 use crate::release_data::{
     NIST_FOIA_10_202_RELEASE_11_MAP, RELEASE_14_COLLECTION_LINKS, RELEASE_14_FILE_LINKS,
     RELEASE_DATA,
 };
+use crate::report::{
+    ReportFile, ReportRelease, ReportTorrent, VerificationReport, REPORT_FORMAT_VERSION,
+};
+use crate::retry::{parse_retry_after, RetryPolicy};
+use crate::sources::{DownloadSource, FileLocation};
+use crate::throttle::{DownloadThrottle, ReadThrottle};
+use crate::torrent_v2::{get_info_hashes, get_torrent_version, TorrentVersion, V2Torrent};
+use crate::tracker::get_trackers;
+use crate::zip_source::ZipSource;
 use colored::*;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use lava_torrent::torrent::v1::Torrent;
 use prettytable::{color, Attr, Cell, Row as TableRow, Table};
 use rusqlite::{Connection, Row};
 use sha1::{Digest, Sha1};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, Read, Seek};
+use std::io::BufRead;
 use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tempdir::TempDir;
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::time::{sleep, Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout, Duration};
 use url::Url;
+use walkdir::WalkDir;
 use zip::ZipArchive;
 
 const WRAP_LENGTH: usize = 72;
+/// How many chunks of content can be waiting for the verifier while downloading. The downloads
+/// are held up once it falls this far behind, so the content doesn't pile up in memory.
+const VERIFY_QUEUE_LENGTH: usize = 16;
+/// How much content downloads that are ahead of the verifier can hold in memory between them, so
+/// it doesn't have to be read back from disk once the verifier gets to them.
+const MAX_BUFFERED_AHEAD: usize = 64 * 1024 * 1024;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+/// How long to wait for a connection to a server to be made.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a download can go without receiving anything before it is abandoned and retried.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// The piece map is kept to this many rows by letting each cell cover several pieces.
+const PIECE_MAP_MAX_ROWS: usize = 64;
 
 #[derive(Clone)]
 pub enum VerificationOutcome {
@@ -52,6 +109,84 @@ impl fmt::Display for VerificationOutcome {
     }
 }
 
+/// Settings that control how the files of a release are downloaded from the Archive.
+pub struct DownloadOptions {
+    /// The number of files downloaded at the same time.
+    pub concurrency: usize,
+    /// The number of files downloaded at the same time from any one host. Almost everything is
+    /// on archive.org, so this is what keeps the load on it down.
+    pub max_per_host: usize,
+    /// Hash the pieces as the files are downloaded.
+    pub verify: bool,
+    /// How failed downloads are retried.
+    pub retry: RetryPolicy,
+    /// Only download the files that were found to be missing or corrupt the last time the release
+    /// was checked or verified, then verify them.
+    pub repair: bool,
+    /// Where to copy corrupt files before they are replaced when repairing, keeping their paths
+    /// relative to the target directory.
+    pub backup_directory: Option<PathBuf>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            max_per_host: 2,
+            verify: false,
+            retry: RetryPolicy::default(),
+            repair: false,
+            backup_directory: None,
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Whether the pieces are hashed as the files are downloaded. Only part of the release is
+    /// downloaded when it's being repaired, so the files are verified afterwards instead.
+    fn hash_while_downloading(&self) -> bool {
+        self.verify && !self.repair
+    }
+}
+
+/// Settings that control how the content of a release is read and hashed during verification.
+pub struct VerifyOptions {
+    /// The number of threads used to hash pieces.
+    pub jobs: usize,
+    /// The maximum number of bytes of piece data that can be held in memory while waiting to be
+    /// hashed.
+    pub max_memory: u64,
+    /// Continue from the checkpoint saved by a previous verification that didn't finish.
+    pub resume: bool,
+    /// Verify whatever can be verified when some files are missing, rather than stopping.
+    pub partial: bool,
+    /// Compute and store the SHA-256 and MD5 of every file once a release is verified.
+    pub file_hashes: bool,
+    /// Only hash the pieces that touch files which have changed since they last passed
+    /// verification.
+    pub incremental: bool,
+    /// Rename files that were only found after normalising their names, so they match the
+    /// torrent.
+    pub fix_names: bool,
+    /// The maximum number of bytes per second to read from the release, or `None` for no limit.
+    pub max_read_rate: Option<u64>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_memory: 256 * 1024 * 1024,
+            resume: false,
+            partial: false,
+            file_hashes: true,
+            incremental: false,
+            fix_names: false,
+            max_read_rate: None,
+        }
+    }
+}
+
 pub struct Release {
     pub id: String,
     pub date: String,
@@ -63,6 +198,16 @@ pub struct Release {
     pub torrent_url: Option<Url>,
     pub download_url: Option<Url>,
     pub verification_outcome: Option<VerificationOutcome>,
+    /// Files that passed verification in a release that is otherwise incomplete.
+    pub verified_files: Vec<(PathBuf, u64)>,
+    /// Files that are present, but could not be verified because they share a piece with a file
+    /// that is missing.
+    pub unverifiable_files: Vec<(PathBuf, u64)>,
+    /// Files that were missing or failed a check, but matched an ignore rule, along with the rule
+    /// that waived them.
+    pub waived_files: Vec<(PathBuf, u64, String)>,
+    /// Whether the files are stored with names escaped so they are valid on NTFS and exFAT.
+    pub escaped_names: bool,
 }
 
 impl fmt::Display for Release {
@@ -75,7 +220,7 @@ impl fmt::Display for Release {
             self.file_count
                 .map_or("None".to_string(), |n| n.to_string()),
             self.size
-                .map_or("None".to_string(), |s| bytes_to_human_readable(s))
+                .map_or("None".to_string(), bytes_to_human_readable)
         )
     }
 }
@@ -102,10 +247,14 @@ impl Release {
             torrent_url,
             download_url,
             verification_outcome: None,
+            verified_files: Vec::new(),
+            unverifiable_files: Vec::new(),
+            waived_files: Vec::new(),
+            escaped_names: false,
         }
     }
 
-    pub fn print_status_table(releases: &Vec<Release>) -> Result<()> {
+    pub fn print_status_table(releases: &[Release]) -> Result<()> {
         let mut table = Table::new();
         for release in releases.iter() {
             let title = match release.verification_outcome {
@@ -166,6 +315,9 @@ impl Release {
         println!("{}", self.name);
         println!("Files: {}", self.file_count.unwrap_or(0));
         println!("Size: {}", bytes_to_human_readable(self.size.unwrap_or(0)));
+        if self.escaped_names {
+            println!("Names: escaped for NTFS and exFAT");
+        }
         println!();
 
         match &self.verification_outcome {
@@ -176,17 +328,42 @@ impl Release {
                     )
                 })?;
                 println!("Status: {}", "VERIFIED".bright_green());
-                println!(
-                    "All {} files were verified against the torrent piece hashes",
-                    file_count
-                );
+                if self.waived_files.is_empty() {
+                    println!(
+                        "All {} files were verified against the torrent piece hashes",
+                        file_count
+                    );
+                } else {
+                    println!(
+                        "{} of {} files were verified against the torrent piece hashes",
+                        file_count.saturating_sub(
+                            self.waived_files.len() + self.unverifiable_files.len()
+                        ),
+                        file_count
+                    );
+                }
+                if !self.unverifiable_files.is_empty() {
+                    println!(
+                        "{} files could not be verified because they share pieces with waived \
+                         files that are missing",
+                        self.unverifiable_files.len()
+                    );
+                }
             }
             Some(VerificationOutcome::Complete) => {
                 println!("Status: {}", "COMPLETE".bright_green());
                 println!(
-                    "All significant files are present and sizes match. Some small files could be \
-                     missing, which means full verification could not take place.",
+                    "All significant files are present and sizes match. Some files covered by \
+                     ignore rules could be missing, which means full verification could not take \
+                     place.",
                 );
+                if !self.unverifiable_files.is_empty() {
+                    println!(
+                        "{} files could not be verified because they share pieces with waived \
+                         files that are missing",
+                        self.unverifiable_files.len()
+                    );
+                }
             }
             Some(VerificationOutcome::Incomplete(missing_files, corrupt_files)) => {
                 println!("Status: {}", "INCOMPLETE".cyan());
@@ -195,7 +372,7 @@ impl Release {
                         "an incomplete release must have a file count".to_string(),
                     )
                 })?;
-                if missing_files.len() > 0 {
+                if !missing_files.is_empty() {
                     if show_incomplete {
                         println!("Missing files:");
                         for (path, size) in missing_files.iter() {
@@ -214,7 +391,7 @@ impl Release {
                     let size: u64 = missing_files.iter().map(|(_, size)| size).sum();
                     println!("Size: {}", bytes_to_human_readable(size));
                 }
-                if corrupt_files.len() > 0 {
+                if !corrupt_files.is_empty() {
                     if show_incomplete {
                         println!("Corrupt files:");
                         for (path, size) in corrupt_files.iter() {
@@ -233,6 +410,31 @@ impl Release {
                     let size: u64 = corrupt_files.iter().map(|(_, size)| size).sum();
                     println!("Size: {}", bytes_to_human_readable(size));
                 }
+                if !self.verified_files.is_empty() {
+                    println!(
+                        "{} of {} files were verified against the torrent piece hashes",
+                        self.verified_files.len(),
+                        file_count,
+                    );
+                }
+                if !self.unverifiable_files.is_empty() {
+                    if show_incomplete {
+                        println!("Unverifiable files:");
+                        for (path, size) in self.unverifiable_files.iter() {
+                            println!(
+                                "{} ({})",
+                                path.to_string_lossy(),
+                                bytes_to_human_readable(*size)
+                            );
+                        }
+                    }
+                    println!(
+                        "{} of {} files could not be verified because they share pieces with \
+                         missing files",
+                        self.unverifiable_files.len(),
+                        file_count,
+                    );
+                }
             }
             Some(VerificationOutcome::TorrentMissing) => {
                 println!("Status: {}", "TORRENT MISSING".yellow());
@@ -248,6 +450,23 @@ impl Release {
             }
             None => println!("Status: {}", "UNKNOWN".red()),
         }
+        if !self.waived_files.is_empty() {
+            println!(
+                "{} missing or mismatched files were waived by ignore rules",
+                self.waived_files.len()
+            );
+            if show_incomplete {
+                println!("Waived files:");
+                for (path, size, reason) in self.waived_files.iter() {
+                    println!(
+                        "{} ({}) [{}]",
+                        path.to_string_lossy(),
+                        bytes_to_human_readable(*size),
+                        reason
+                    );
+                }
+            }
+        }
         if let Some(notes) = &self.notes {
             println!();
             println!("Notes:");
@@ -256,6 +475,169 @@ impl Release {
         Ok(())
     }
 
+    /// Prints the result for each piece from the last verification as a coloured grid.
+    ///
+    /// A cell can cover several pieces in a large torrent, in which case it shows the worst result
+    /// among them. The files that begin in each row are listed beside it, so a region of failed
+    /// pieces can be traced back to the files it affects.
+    pub fn print_piece_map(&self, columns: usize) -> Result<()> {
+        let piece_map = match self.torrent_url {
+            Some(_) => self.get_piece_map()?,
+            None => None,
+        };
+        let Some(piece_map) = piece_map else {
+            match self
+                .torrent_url
+                .as_ref()
+                .map(|_| self.get_torrent_version())
+            {
+                Some(Ok(version @ (TorrentVersion::V2 | TorrentVersion::Hybrid))) => println!(
+                    "There is no piece map for {}. Piece maps are only kept when a release is \
+                     verified against v1 pieces, and {} torrents are verified file by file.",
+                    self.name, version
+                ),
+                _ => println!(
+                    "There is no piece map for {}. Verify it against the torrent to create one.",
+                    self.name
+                ),
+            }
+            return Ok(());
+        };
+        let layout = self.get_v1_piece_layout()?;
+        if piece_map.len() != layout.piece_count() {
+            println!(
+                "The piece map for {} doesn't match its torrent. Verify it again to update it.",
+                self.name
+            );
+            return Ok(());
+        }
+        let num_pieces = layout.piece_count();
+        let columns = std::cmp::max(columns, 1);
+        let (pieces_per_cell, rows) =
+            arrange_piece_map(&layout, &piece_map, self.directory.as_deref(), columns);
+
+        println!("{}", self.name);
+        println!(
+            "{} pieces of {}, {} per cell",
+            num_pieces,
+            bytes_to_human_readable(layout.piece_length),
+            pieces_per_cell
+        );
+        println!(
+            "{} passed  {} failed  {} not hashed",
+            "█".green(),
+            "X".red(),
+            "·".dimmed()
+        );
+        println!();
+        for row in rows.iter() {
+            let mut line = format!("{:>8} ", row.first_piece);
+            for cell in row.cells.iter() {
+                let glyph = match cell {
+                    Some(false) => "X".red(),
+                    None => "·".dimmed(),
+                    Some(true) => "█".green(),
+                };
+                line.push_str(&glyph.to_string());
+            }
+            line.push_str(&" ".repeat(columns - row.cells.len()));
+            match row.files.len() {
+                0 => {}
+                1 => line.push_str(&format!("  {}", row.files[0].display())),
+                n => line.push_str(&format!("  {} (+{} more)", row.files[0].display(), n - 1)),
+            }
+            println!("{}", line.trim_end());
+        }
+        println!();
+
+        let results = (0..num_pieces)
+            .map(|i| piece_map.get(i))
+            .collect::<Vec<Option<bool>>>();
+        let failed_regions = results
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| **r == Some(false) && (*i == 0 || results[i - 1] != Some(false)))
+            .count();
+        println!(
+            "{} passed, {} failed in {} regions, {} not hashed",
+            results.iter().filter(|r| **r == Some(true)).count(),
+            results.iter().filter(|r| **r == Some(false)).count(),
+            failed_regions,
+            results.iter().filter(|r| r.is_none()).count()
+        );
+        Ok(())
+    }
+
+    /// Builds a report of the last verification of the release, for handing to someone else
+    /// along with the files.
+    ///
+    /// It identifies the torrent by its info hash and lists the status of every file, along with
+    /// its SHA-256 and MD5 if they were computed.
+    pub fn get_verification_report(&self) -> Result<VerificationReport> {
+        if self.torrent_url.is_none() {
+            return Err(Error::VerificationReportError(
+                "the release has no torrent".to_string(),
+            ));
+        }
+        let torrent_content = get_torrent_content(&self.id)?;
+        let version = get_torrent_version(&torrent_content)?;
+        let info_hashes = get_info_hashes(&torrent_content)?;
+        let (piece_length, piece_count) = match version {
+            TorrentVersion::V2 => {
+                let torrent = V2Torrent::read_from_bytes(&torrent_content)?;
+                (torrent.piece_length, torrent.piece_count())
+            }
+            _ => {
+                let layout = self.get_v1_piece_layout()?;
+                (layout.piece_length, layout.piece_count())
+            }
+        };
+        let layout = self.get_piece_layout()?;
+        let conn = get_db_connection(get_database_path()?)?;
+        let verified_at = get_verified_at(&conn, &self.id)?;
+        let hashes = get_file_hashes(&conn, &self.id)?;
+        let _ = conn.close();
+
+        let results = self.get_file_results(&layout);
+        let files = layout
+            .content_files()
+            .map(|(_, file)| {
+                let result = results.get(&file.path);
+                let digest = hashes.get(&file.path);
+                ReportFile {
+                    path: file.path.to_string_lossy().to_string(),
+                    size: file.length,
+                    status: result.map_or("UNKNOWN", |r| r.status).to_string(),
+                    reason: result.and_then(|r| r.reason.clone()),
+                    sha256: digest.map(|d| d.sha256.clone()),
+                    md5: digest.map(|d| d.md5.clone()),
+                }
+            })
+            .collect::<Vec<ReportFile>>();
+        Ok(VerificationReport {
+            format_version: REPORT_FORMAT_VERSION,
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            release: ReportRelease {
+                id: self.id.clone(),
+                name: self.name.clone(),
+                date: self.date.clone(),
+            },
+            torrent: ReportTorrent {
+                version: version.to_string(),
+                info_hash_v1: info_hashes.v1,
+                info_hash_v2: info_hashes.v2,
+                piece_length,
+                piece_count,
+                file_count: layout.file_count(),
+                size: layout.content_length(),
+            },
+            outcome: self.get_verification_outcome(),
+            verified_at,
+            files,
+        })
+    }
+
     pub fn generate_id(date: &str, name: &str) -> String {
         let mut hasher = Sha1::new();
         hasher.update(date.as_bytes());
@@ -264,11 +646,7 @@ impl Release {
         format!("{:x}", hash)
     }
 
-    pub fn from_row(
-        row: &Row,
-        missing_files: &Vec<(PathBuf, u64)>,
-        corrupt_files: &Vec<(PathBuf, u64)>,
-    ) -> Result<Release> {
+    pub fn from_row(row: &Row, incomplete_files: &IncompleteFiles) -> Result<Release> {
         let id: String = row.get(0)?;
         let date: String = row.get(1)?;
         let name: String = row.get(2)?;
@@ -292,14 +670,15 @@ impl Release {
             "NO TORRENT" => Some(VerificationOutcome::TorrentMissing),
             "MISSING" => Some(VerificationOutcome::AllFilesMissing),
             "INCOMPLETE" => Some(VerificationOutcome::Incomplete(
-                missing_files.clone(),
-                corrupt_files.clone(),
+                incomplete_files.missing.clone(),
+                incomplete_files.corrupted.clone(),
             )),
             "UNKNOWN" => None,
             _ => None,
         };
         let notes: Option<String> = row.get(8)?;
         let download_url: Option<String> = row.get(9)?;
+        let escaped_names: bool = row.get(10)?;
         let download_url = if let Some(url) = download_url {
             if !url.is_empty() {
                 Some(Url::parse(&url).unwrap())
@@ -320,10 +699,14 @@ impl Release {
             torrent_url,
             download_url,
             verification_outcome,
+            verified_files: incomplete_files.verified.clone(),
+            unverifiable_files: incomplete_files.unverifiable.clone(),
+            waived_files: incomplete_files.waived.clone(),
+            escaped_names,
         })
     }
 
-    pub fn reinit_releases(releases: &mut Vec<Release>) -> Result<()> {
+    pub fn reinit_releases(releases: &mut [Release]) -> Result<()> {
         for item in RELEASE_DATA.iter() {
             let date = item.0.to_string();
             let torrent_url = item.1.to_string();
@@ -361,7 +744,7 @@ impl Release {
                                 let mut ancestors = first_file.path.ancestors();
                                 let mut second_to_last = None;
                                 let mut last = ancestors.next();
-                                while let Some(current) = ancestors.next() {
+                                for current in ancestors {
                                     second_to_last = last;
                                     last = Some(current);
                                 }
@@ -371,6 +754,10 @@ impl Release {
                                     .to_string_lossy()
                                     .to_string()
                             };
+                            let files = files
+                                .iter()
+                                .filter(|f| !FileAttributes::from_torrent_file(f).padding)
+                                .collect::<Vec<_>>();
                             let mut size = 0;
                             for file in files.iter() {
                                 size += file.length;
@@ -426,7 +813,7 @@ impl Release {
                             let mut ancestors = first_file.path.ancestors();
                             let mut second_to_last = None;
                             let mut last = ancestors.next();
-                            while let Some(current) = ancestors.next() {
+                            for current in ancestors {
                                 second_to_last = last;
                                 last = Some(current);
                             }
@@ -436,6 +823,10 @@ impl Release {
                                 .to_string_lossy()
                                 .to_string()
                         };
+                        let files = files
+                            .iter()
+                            .filter(|f| !FileAttributes::from_torrent_file(f).padding)
+                            .collect::<Vec<_>>();
                         let mut size = 0;
                         for file in files.iter() {
                             size += file.length;
@@ -460,106 +851,271 @@ impl Release {
         Ok(releases)
     }
 
+    /// Gets the path and size of every file in the torrent. Padding files are left out, since they
+    /// aren't part of the release.
     pub fn get_torrent_tree(&self) -> Result<Vec<(PathBuf, u64)>> {
-        let torrent_content = get_torrent_content(&self.id)?;
-        let torrent = Torrent::read_from_bytes(torrent_content)?;
-        let files = torrent.files.ok_or_else(|| Error::TorrentFilesError)?;
-        let tree = files
-            .iter()
-            .map(|f| (f.path.clone(), f.length as u64))
+        let tree = self
+            .get_torrent_files()?
+            .into_iter()
+            .map(|f| (f.path, f.length))
             .collect::<Vec<(PathBuf, u64)>>();
         Ok(tree)
     }
 
-    pub async fn download_release_14_from_archive(
+    /// Gets the files in the torrent along with their attributes, leaving out padding files.
+    pub fn get_torrent_files(&self) -> Result<Vec<TorrentFile>> {
+        let files = self
+            .get_piece_layout()?
+            .files
+            .into_iter()
+            .filter(|f| !f.attributes.padding)
+            .collect::<Vec<TorrentFile>>();
+        Ok(files)
+    }
+
+    /// Registers a release from a torrent file that isn't part of the static release data.
+    ///
+    /// The torrent is saved in the database along with the release. Its URL is the location of the
+    /// file it was added from.
+    pub fn add_from_torrent(
+        conn: &Connection,
+        date: String,
+        name: String,
+        torrent_path: &Path,
+    ) -> Result<(Release, TorrentVersion)> {
+        let id = Release::generate_id(&date, &name);
+        if get_release_by_id(conn, &id).is_ok() {
+            return Err(Error::ReleaseAlreadyExists(id));
+        }
+        let torrent_content = std::fs::read(torrent_path)?;
+        let version = get_torrent_version(&torrent_content)?;
+        let layout = get_piece_layout_from_bytes(torrent_content.clone())?;
+        // As with the static releases, the directory is the top level of the torrent tree.
+        let directory = layout
+            .files
+            .first()
+            .and_then(|f| f.path.components().next())
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .ok_or(Error::ReleaseDirectoryNotObtained)?;
+        let torrent_path = std::fs::canonicalize(torrent_path)?;
+        let torrent_url =
+            Url::from_file_path(&torrent_path).map_err(|_| Error::FilenameFromUrlError)?;
+        let file_name = torrent_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let release = Release::new(
+            date,
+            name,
+            Some(directory),
+            Some(layout.file_count()),
+            Some(layout.content_length()),
+            Some(torrent_url),
+            None,
+        );
+        save_torrent(conn, &release.id, &file_name, &torrent_content)?;
+        save_release(conn, &release)?;
+        Ok((release, version))
+    }
+
+    pub fn get_torrent_version(&self) -> Result<TorrentVersion> {
+        get_torrent_version(&get_torrent_content(&self.id)?)
+    }
+
+    /// Finds the files in the release directory that aren't in the torrent tree.
+    ///
+    /// These are usually `.part` files left behind by interrupted downloads, duplicate copies, or
+    /// extra files that came out of an Archive zip. The paths are relative to the target
+    /// directory, like the paths in the torrent.
+    pub fn find_extra_files(&self, target_directory: &Path) -> Result<Vec<(PathBuf, u64)>> {
+        let directory = self
+            .directory
+            .as_ref()
+            .ok_or_else(|| Error::ReleaseDirectoryNotObtained)?;
+        let release_directory = target_directory.join(directory);
+        if !release_directory.exists() {
+            return Ok(Vec::new());
+        }
+
+        // Files that are only found after normalising their names are still part of the torrent.
+        let mut layout = self.get_piece_layout()?;
+        layout.resolve_disk_paths(target_directory)?;
+        let tree = layout
+            .files
+            .into_iter()
+            .map(|file| file.disk_path)
+            .collect::<HashSet<PathBuf>>();
+        let mut extra_files = Vec::new();
+        for entry in WalkDir::new(&release_directory).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry
+                .path()
+                .strip_prefix(target_directory)
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|_| entry.path().to_path_buf());
+            if !tree.contains(&path) {
+                extra_files.push((path, entry.metadata()?.len()));
+            }
+        }
+        Ok(extra_files)
+    }
+
+    /// Moves extra files out of the release directory and into a quarantine directory.
+    ///
+    /// Each file keeps its path relative to the target directory, so it can be put back if it
+    /// turns out to be needed. A file that already exists in the quarantine is never overwritten.
+    pub fn quarantine_extra_files(
         &self,
-        release_14_links: HashMap<PathBuf, String>,
-        release_14_file_links: HashMap<PathBuf, String>,
-        base_target_path: &PathBuf,
+        target_directory: &Path,
+        extra_files: &[(PathBuf, u64)],
+        quarantine_directory: &Path,
     ) -> Result<()> {
+        for (path, _) in extra_files.iter() {
+            let source = target_directory.join(path);
+            let destination = quarantine_directory.join(path);
+            if destination.exists() {
+                return Err(Error::QuarantineFileExists(
+                    destination.to_string_lossy().to_string(),
+                ));
+            }
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Renaming fails if the quarantine is on a different file system.
+            if std::fs::rename(&source, &destination).is_err() {
+                std::fs::copy(&source, &destination)?;
+                std::fs::remove_file(&source)?;
+            }
+            println!("Moved {} to {}", path.display(), destination.display());
+        }
+        Ok(())
+    }
+
+    /// Loads an Archive `_files.xml` manifest and saves the entries that match the torrent tree.
+    ///
+    /// The source can be either a local file or a URL. The prefix is the directory in the tree
+    /// that corresponds to the Archive item; if it's not supplied, it will be worked out by
+    /// matching the file names. Returns the number of entries that were matched.
+    pub async fn load_archive_manifest(
+        &self,
+        source: &str,
+        prefix: Option<&Path>,
+    ) -> Result<usize> {
+        let (item, xml) = read_files_xml(source).await?;
+        let files = parse_files_xml(&xml)?;
+        let total = files.len();
         let tree = self.get_torrent_tree()?;
-        let multi_progress = MultiProgress::new();
-        let total_pb = multi_progress.add(ProgressBar::new(tree.len() as u64));
-        total_pb.set_style(
-            ProgressStyle::default_bar()
-                .template("Overall progress: [{bar:40.cyan/blue}] {pos}/{len} files")?
-                .progress_chars("#>-"),
+        let (matched, unmatched) = match_manifest_files(&item, files, &tree, prefix);
+        println!(
+            "{}: {} of {} files in the manifest matched the torrent tree",
+            item,
+            matched.len(),
+            total
         );
+        for file in unmatched.iter() {
+            // The manifests always list some metadata files the Archive generated for the item,
+            // which aren't worth reporting.
+            if !file.name.starts_with(&item) {
+                println!("Not in torrent: {}", file.name);
+            }
+        }
+        let mut conn = get_db_connection(get_database_path()?)?;
+        save_manifest_entries(&mut conn, &self.id, &matched)?;
+        let _ = conn.close();
+        Ok(matched.len())
+    }
 
-        let file_pb = multi_progress.add(ProgressBar::new(0));
-        file_pb.set_style(
+    /// Loads the manifest of every collection that release 14 is scattered across.
+    pub async fn load_release_14_manifests(
+        &self,
+        release_14_links: HashMap<PathBuf, String>,
+    ) -> Result<()> {
+        let mut total = 0;
+        for (directory_path, base_url) in release_14_links.iter() {
+            let item = base_url
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default();
+            let source = format!("{}/{}_files.xml", base_url.trim_end_matches('/'), item);
+            total += self
+                .load_archive_manifest(&source, Some(directory_path))
+                .await?;
+        }
+        println!("Loaded manifest entries for {} files", total);
+        Ok(())
+    }
+
+    /// Checks the files on disk against the checksums in the Archive manifests for the release.
+    ///
+    /// This gives per-file confirmation for files the piece hashes can't verify, e.g. because the
+    /// release is incomplete and they share pieces with missing files. The files are read at no
+    /// more than `max_read_rate` bytes per second, if it's given.
+    pub fn verify_archive_manifest(
+        &self,
+        target_directory: &Path,
+        max_read_rate: Option<u64>,
+    ) -> Result<Vec<(PathBuf, ManifestStatus)>> {
+        let conn = get_db_connection(get_database_path()?)?;
+        let entries = get_manifest_entries(&conn, &self.id)?;
+        let _ = conn.close();
+
+        let bar = ProgressBar::new(entries.len() as u64);
+        bar.set_style(
             ProgressStyle::default_bar()
-                .template("{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec}")?
-                .progress_chars("=> "),
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")?
+                .progress_chars("#>-"),
         );
-
-        println!("Downloading files for {}...", self.name);
-        for (path, _) in tree.iter() {
-            let target_path = base_target_path.join(path);
-            if !target_path.exists() {
-                let file_name = target_path
-                    .clone()
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string();
-                file_pb.set_prefix(format!("Downloading: {}", file_name));
-                file_pb.set_position(0);
-                tokio::fs::create_dir_all(target_path.parent().unwrap()).await?;
-
-                let url = Self::get_release_14_url(
-                    &release_14_links,
-                    &release_14_file_links,
-                    path.clone(),
-                    &file_name,
-                )?;
-                if url.is_none() {
-                    continue;
-                }
-                let url = url.unwrap();
-                let mut retries = 10;
-                loop {
-                    match download_file(&url, &target_path, &file_pb).await {
-                        Ok(_) => {
-                            file_pb.finish_with_message("Download completed");
-                            break;
-                        }
-                        Err(e) => match e {
-                            Error::ArchiveFileNotFoundError(_) => {
-                                file_pb.abandon_with_message("Download failed. File not found.");
-                                break;
-                            }
-                            _ => {
-                                retries -= 1;
-                                if retries == 0 {
-                                    file_pb
-                                        .abandon_with_message("Download failed after 10 retries");
-                                    return Err(e.into());
-                                }
-                                file_pb.abandon_with_message(
-                                    "Download failed. Will retry in 5 seconds.",
-                                );
-                                sleep(Duration::from_secs(5)).await;
-                            }
-                        },
-                    }
-                }
+        let throttle = ReadThrottle::new(max_read_rate);
+        let mut resolver = PathResolver::new(target_directory);
+        let mut statuses = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let disk_path = self.get_disk_path(&entry.file_path);
+            let disk_path = match resolver.resolve(&disk_path)? {
+                ResolvedPath::Normalised(disk_path) => disk_path,
+                _ => disk_path,
+            };
+            let status = entry.check(&target_directory.join(disk_path), &throttle)?;
+            statuses.push((entry.file_path.clone(), status));
+            bar.inc(1);
+            if let Some(rate) = throttle.rate() {
+                bar.set_message(format!("{}/s", bytes_to_human_readable(rate)));
             }
-            total_pb.inc(1);
         }
-        total_pb.finish_with_message("Downloaded all files in the torrent tree");
-        Ok(())
+        bar.finish();
+
+        let mut conn = get_db_connection(get_database_path()?)?;
+        save_manifest_statuses(&mut conn, &self.id, &statuses)?;
+        let _ = conn.close();
+        Ok(statuses)
     }
 
+    /// Downloads the zip a release is distributed in and extracts it.
+    ///
+    /// If `options.repair` is set, the zip isn't downloaded. The Archive serves each file in a zip
+    /// under the URL of the zip, so just the missing and corrupt files are downloaded from there;
+    /// use `verify_repaired_files` afterwards.
     pub async fn download_zip_release_from_archive(
-        &self,
+        &mut self,
         zip_url: &Url,
-        target_path: &PathBuf,
+        target_path: &Path,
+        options: &DownloadOptions,
+        throttle: &DownloadThrottle,
     ) -> Result<()> {
+        if options.repair {
+            let sources = [DownloadSource::Zip(zip_url.clone())];
+            self.download_from_sources(&sources, target_path, options, throttle)
+                .await?;
+            return Ok(());
+        }
         let file_pb = ProgressBar::new(0);
         file_pb.set_style(
             ProgressStyle::default_bar()
-                .template("{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec}")?
+                .template("{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec} {msg}")?
                 .progress_chars("=> "),
         );
 
@@ -567,7 +1123,7 @@ impl Release {
         let mut zip_dest_path = temp_dir.path().to_path_buf();
         let filename = zip_url
             .path_segments()
-            .and_then(|s| s.last())
+            .and_then(|mut s| s.next_back())
             .ok_or_else(|| Error::FilenameFromUrlError)?;
         if PathBuf::from(filename).extension().unwrap() != "zip" {
             return Err(Error::ReleaseNotZipError);
@@ -576,33 +1132,24 @@ impl Release {
 
         println!(
             "Downloading {} to {}...",
-            zip_url.to_string(),
+            zip_url,
             zip_dest_path.to_string_lossy()
         );
-        let mut retries = 10;
-        loop {
-            match download_file(&zip_url, &zip_dest_path, &file_pb).await {
-                Ok(_) => {
-                    file_pb.finish_with_message("Download completed");
-                    break;
-                }
-                Err(e) => match e {
-                    Error::ArchiveFileNotFoundError(_) => {
-                        file_pb.abandon_with_message("Download failed. File not found.");
-                        break;
-                    }
-                    _ => {
-                        retries -= 1;
-                        if retries == 0 {
-                            file_pb.abandon_with_message("Download failed after 10 retries");
-                            return Err(e.into());
-                        }
-                        file_pb.abandon_with_message("Download failed. Will retry in 5 seconds.");
-                        sleep(Duration::from_secs(5)).await;
-                    }
-                },
-            }
+        let client = build_http_client()?;
+        if let Err(e) = download_file(
+            &client,
+            zip_url,
+            &zip_dest_path,
+            &file_pb,
+            throttle,
+            &options.retry,
+        )
+        .await
+        {
+            file_pb.abandon_with_message(format!("Download failed: {e}"));
+            return Err(e);
         }
+        file_pb.finish_with_message("Download completed");
 
         println!(
             "Extracting {} to {}...",
@@ -623,11 +1170,11 @@ impl Release {
         for i in 0..total_files {
             let mut file = archive.by_index(i)?;
             let outpath = match file.enclosed_name() {
-                Some(path) => path.to_owned(),
+                Some(path) => self.get_disk_path(path),
                 None => continue,
             };
 
-            if (&*file.name()).ends_with('/') {
+            if file.name().ends_with('/') {
                 std::fs::create_dir_all(target_path.join(&outpath))?;
             } else {
                 if let Some(p) = outpath.parent() {
@@ -645,94 +1192,626 @@ impl Release {
         Ok(())
     }
 
-    pub async fn download_release_from_archive(
+    /// Works out which files have to be downloaded to repair a release, from the missing and
+    /// corrupt files recorded the last time it was checked or verified.
+    ///
+    /// Each file to download is mapped to the path of the corrupt file it replaces, if any. A
+    /// corrupt file is left where it is until its replacement has been downloaded to a `.part`
+    /// file, which is then renamed over it, so a failed download never leaves it worse off. With
+    /// a backup directory, the corrupt files are copied there first.
+    ///
+    /// Returns `None` if the release isn't being repaired.
+    fn prepare_repair(
         &self,
-        base_url: &Url,
-        base_target_path: &PathBuf,
-    ) -> Result<()> {
-        let tree = self.get_torrent_tree()?;
+        layout: &PieceLayout,
+        base_target_path: &Path,
+        options: &DownloadOptions,
+    ) -> Result<Option<BTreeMap<usize, Option<PathBuf>>>> {
+        if !options.repair {
+            return Ok(None);
+        }
+        let Some(VerificationOutcome::Incomplete(missing, corrupted)) = &self.verification_outcome
+        else {
+            return Err(Error::NothingToRepair(self.name.clone()));
+        };
+        let missing = missing.iter().map(|(p, _)| p).collect::<HashSet<_>>();
+        let corrupted = corrupted.iter().map(|(p, _)| p).collect::<HashSet<_>>();
+        let mut resolver = PathResolver::new(base_target_path);
+        let mut repair = BTreeMap::new();
+        for (file_idx, file) in layout.content_files() {
+            if corrupted.contains(&file.path) {
+                let disk_path = match resolver.resolve(&file.disk_path)? {
+                    ResolvedPath::Exact => Some(file.disk_path.clone()),
+                    ResolvedPath::Normalised(disk_path) => Some(disk_path),
+                    ResolvedPath::Missing => None,
+                };
+                repair.insert(file_idx, disk_path);
+            } else if missing.contains(&file.path) {
+                repair.insert(file_idx, None);
+            }
+        }
+        if let Some(backup_directory) = &options.backup_directory {
+            for path in repair.values().flatten() {
+                let destination = backup_directory.join(path);
+                if destination.exists() {
+                    return Err(Error::QuarantineFileExists(
+                        destination.to_string_lossy().to_string(),
+                    ));
+                }
+                if let Some(parent) = destination.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::copy(base_target_path.join(path), &destination)?;
+                println!("Copied {} to {}", path.display(), destination.display());
+            }
+        }
+        println!(
+            "Repairing {} missing and {} corrupt files",
+            missing.len(),
+            corrupted.len()
+        );
+        Ok(Some(repair))
+    }
+
+    /// Verifies the files that were downloaded to repair a release, along with the files that
+    /// couldn't be verified because they share pieces with files that were missing.
+    ///
+    /// The results are saved in the same way as verifying a path, and the outcome for the release
+    /// is worked out from them.
+    pub fn verify_repaired_files(
+        &mut self,
+        target_directory: &Path,
+        options: &VerifyOptions,
+    ) -> Result<Vec<FileResult>> {
+        let Some(VerificationOutcome::Incomplete(missing, corrupted)) = &self.verification_outcome
+        else {
+            return Err(Error::NothingToRepair(self.name.clone()));
+        };
+        let paths = missing
+            .iter()
+            .chain(corrupted.iter())
+            .chain(self.unverifiable_files.iter())
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<PathBuf>>();
+        self.verify_paths(target_directory, &paths, options)
+    }
+
+    /// Downloads each file in the torrent tree that isn't already in the target directory.
+    ///
+    /// The sources are tried in order for each file, moving on to the next if the file isn't
+    /// there or can't be downloaded from it, and the source each file came from is recorded.
+    ///
+    /// If `options.verify` is set, the pieces are hashed as the files are downloaded, and the
+    /// outcome of verifying the release is returned. If `options.repair` is set, only the missing
+    /// and corrupt files are downloaded; use `verify_repaired_files` afterwards.
+    pub async fn download_from_sources(
+        &mut self,
+        sources: &[DownloadSource],
+        base_target_path: &Path,
+        options: &DownloadOptions,
+        throttle: &DownloadThrottle,
+    ) -> Result<Option<VerificationOutcome>> {
+        let layout = self.get_download_layout(options.hash_while_downloading())?;
+        let repair = self.prepare_repair(&layout, base_target_path, options)?;
+        // A file that is already there under a name that differs in case or Unicode form doesn't
+        // need to be downloaded again.
+        let mut resolver = PathResolver::new(base_target_path);
+        let mut jobs = Vec::new();
+        for (file_idx, file) in layout
+            .content_files()
+            .filter(|(i, _)| repair.as_ref().is_none_or(|files| files.contains_key(i)))
+        {
+            // A corrupt file being repaired is downloaded again under the name it has on disk.
+            let (resolved, target_path) =
+                match repair.as_ref().and_then(|files| files[&file_idx].as_ref()) {
+                    Some(disk_path) => (ResolvedPath::Missing, base_target_path.join(disk_path)),
+                    None => (
+                        resolver.resolve(&file.disk_path)?,
+                        base_target_path.join(&file.disk_path),
+                    ),
+                };
+            let mut locations = Vec::new();
+            if resolved == ResolvedPath::Missing {
+                for (source_idx, source) in sources.iter().enumerate() {
+                    locations.extend(
+                        source
+                            .locations(file)?
+                            .into_iter()
+                            .map(|location| (source_idx, location)),
+                    );
+                }
+            }
+            jobs.push(DownloadJob {
+                file_idx,
+                target_path,
+                resolved,
+                locations,
+            });
+        }
+        println!("Downloading files for {}...", self.name);
+        self.download_files(
+            Arc::new(layout),
+            &jobs,
+            sources,
+            base_target_path,
+            options,
+            throttle,
+        )
+        .await
+    }
+
+    /// Downloads the files that aren't already in the target directory, several at a time.
+    ///
+    /// At most `options.concurrency` files are downloaded at once, and no more than
+    /// `options.max_per_host` of them from the same host. Each download is retried and resumed
+    /// from its `.part` file as before, and has its own progress bar.
+    ///
+    /// The verifier has to see the files in torrent order. The first file that hasn't been dealt
+    /// with yet is streamed to it as it downloads. The other downloads keep their content in
+    /// memory, up to `MAX_BUFFERED_AHEAD` between them, and pass it on when their turn comes, then
+    /// stream the rest. A download that would go over the limit is read back from disk instead
+    /// when its turn comes, as are files that were already present. The verifier runs on a blocking thread, and the downloads
+    /// wait for it if it falls behind. A file that can't be downloaded from any source is reported
+    /// as missing, even if some of it was streamed before the download failed.
+    async fn download_files(
+        &mut self,
+        layout: Arc<PieceLayout>,
+        jobs: &[DownloadJob],
+        sources: &[DownloadSource],
+        base_target_path: &Path,
+        options: &DownloadOptions,
+        throttle: &DownloadThrottle,
+    ) -> Result<Option<VerificationOutcome>> {
+        let verifier = options
+            .hash_while_downloading()
+            .then(|| DownloadVerifier::new(Arc::clone(&layout), true));
+        let client = build_http_client()?;
+        let concurrency = std::cmp::max(options.concurrency, 1);
         let multi_progress = MultiProgress::new();
-        let total_pb = multi_progress.add(ProgressBar::new(tree.len() as u64));
+        let total_pb = multi_progress.add(ProgressBar::new(jobs.len() as u64));
         total_pb.set_style(
             ProgressStyle::default_bar()
                 .template("Overall progress: [{bar:40.cyan/blue}] {pos}/{len} files")?
                 .progress_chars("#>-"),
         );
-
-        let file_pb = multi_progress.add(ProgressBar::new(0));
-        file_pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec}")?
-                .progress_chars("=> "),
+        let file_style = ProgressStyle::default_bar()
+            .template(
+                "{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec} {msg}",
+            )?
+            .progress_chars("=> ");
+        // Each download takes a bar from here while it runs, so there is one for each worker.
+        let file_pbs = Mutex::new(
+            (0..concurrency)
+                .map(|_| {
+                    let file_pb = multi_progress.add(ProgressBar::new(0));
+                    file_pb.set_style(file_style.clone());
+                    file_pb
+                })
+                .collect::<Vec<ProgressBar>>(),
         );
+        let host_limits = jobs
+            .iter()
+            .flat_map(|job| job.locations.iter())
+            .filter_map(|(_, location)| match location {
+                FileLocation::Url(url) => url.host_str(),
+                FileLocation::Path(_) => None,
+            })
+            .map(|host| {
+                (
+                    host.to_string(),
+                    Semaphore::new(std::cmp::max(options.max_per_host, 1)),
+                )
+            })
+            .collect::<HashMap<String, Semaphore>>();
 
-        println!("Downloading files for {}...", self.name);
-        for (path, _) in tree.iter() {
-            let target_path = base_target_path.join(path);
-            if !target_path.exists() {
-                let file_name = target_path.file_name().unwrap().to_string_lossy();
-                file_pb.set_prefix(format!("Downloading: {}", file_name));
-                file_pb.set_position(0);
-                if let Some(parent) = target_path.parent() {
-                    tokio::fs::create_dir_all(parent).await?;
-                }
-                let mut url = base_url.clone();
-                {
-                    let mut path_segments = match url.path_segments_mut() {
-                        Ok(segments) => segments,
-                        Err(_) => return Err(Error::PathSegmentsParseError),
-                    };
-                    path_segments.extend(
-                        path.to_str()
-                            .ok_or_else(|| Error::PathSegmentsParseError)?
-                            .split('/'),
-                    );
-                }
-                let mut retries = 10;
-                loop {
-                    match download_file(&url, &target_path, &file_pb).await {
-                        Ok(_) => {
-                            file_pb.finish_with_message("Download completed");
-                            break;
+        // The index of the first job the verifier hasn't finished with. Only that job can stream
+        // its content to the verifier, since everything before it has already been seen.
+        let next_job = Arc::new(AtomicUsize::new(0));
+        // How much content the jobs after it are holding for the verifier.
+        let buffered = Arc::new(AtomicUsize::new(0));
+        // The path of each file that was downloaded, and the index of the source it came from.
+        let file_sources = Mutex::new(Vec::<(PathBuf, usize)>::new());
+        let (events_tx, events_rx) = channel::<DownloadEvent>(VERIFY_QUEUE_LENGTH);
+        let events_tx = verifier.is_some().then_some(events_tx);
+        let verification = verifier.map(|verifier| {
+            let file_indexes = jobs.iter().map(|job| job.file_idx).collect();
+            let (next_job, buffered) = (Arc::clone(&next_job), Arc::clone(&buffered));
+            tokio::task::spawn_blocking(move || {
+                verify_downloads(verifier, events_rx, file_indexes, &next_job, &buffered)
+            })
+        });
+
+        let downloads = async {
+            let events_tx = events_tx;
+            let (
+                client,
+                layout,
+                events_tx,
+                total_pb,
+                file_pbs,
+                host_limits,
+                next_job,
+                buffered,
+                file_sources,
+            ) = (
+                &client,
+                &*layout,
+                events_tx.as_ref(),
+                &total_pb,
+                &file_pbs,
+                &host_limits,
+                &*next_job,
+                &*buffered,
+                &file_sources,
+            );
+            futures::stream::iter(jobs.iter().enumerate().map(Ok))
+                .try_for_each_concurrent(concurrency, |(job_idx, job)| async move {
+                    let send = |event: DownloadEvent| async move {
+                        if let Some(events_tx) = events_tx {
+                            // This only fails if verification has stopped, and its error will be
+                            // returned instead.
+                            let _ = events_tx.send(event).await;
                         }
-                        Err(e) => match e {
-                            Error::ArchiveFileNotFoundError(_) => {
-                                file_pb.abandon_with_message("Download failed. File not found.");
+                    };
+                    if job.resolved != ResolvedPath::Missing || job.locations.is_empty() {
+                        let path = match &job.resolved {
+                            ResolvedPath::Exact => Some(job.target_path.clone()),
+                            ResolvedPath::Normalised(disk_path) => {
+                                Some(base_target_path.join(disk_path))
+                            }
+                            ResolvedPath::Missing => None,
+                        };
+                        send(DownloadEvent::Done {
+                            job_idx,
+                            path,
+                            streamed: false,
+                            content: None,
+                        })
+                        .await;
+                        total_pb.inc(1);
+                        return Ok(());
+                    }
+                    let file_pb = file_pbs
+                        .lock()
+                        .unwrap()
+                        .pop()
+                        .unwrap_or_else(ProgressBar::hidden);
+                    let file_name = job.target_path.file_name().unwrap().to_string_lossy();
+                    file_pb.reset();
+                    file_pb.set_length(0);
+                    file_pb.set_prefix(format!("Downloading: {}", file_name));
+                    if let Some(parent) = job.target_path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+
+                    let stream = Mutex::new(JobStream::new(job_idx));
+                    let mut on_data =
+                        |offset: u64, data: &[u8]| -> BoxFuture<'static, Result<()>> {
+                            let events = events_tx.map(|events_tx| {
+                                let first_in_line = next_job.load(Ordering::SeqCst) == job_idx;
+                                let events = stream.lock().unwrap().on_data(
+                                    offset,
+                                    data,
+                                    first_in_line,
+                                    buffered,
+                                );
+                                (events_tx.clone(), events)
+                            });
+                            Box::pin(async move {
+                                if let Some((events_tx, events)) = events {
+                                    for event in events {
+                                        let _ = events_tx.send(event).await;
+                                    }
+                                }
+                                Ok(())
+                            })
+                        };
+                    let mut downloaded = false;
+                    for (location_idx, (source_idx, location)) in job.locations.iter().enumerate() {
+                        let result = match location {
+                            FileLocation::Url(url) => {
+                                let _host_permit =
+                                    match url.host_str().and_then(|h| host_limits.get(h)) {
+                                        Some(limit) => limit.acquire().await.ok(),
+                                        None => None,
+                                    };
+                                download_file_streaming(
+                                    client,
+                                    url,
+                                    &job.target_path,
+                                    &file_pb,
+                                    throttle,
+                                    &options.retry,
+                                    &mut on_data,
+                                )
+                                .await
+                            }
+                            FileLocation::Path(path) => {
+                                copy_file_streaming(path, &job.target_path, &file_pb, &mut on_data)
+                                    .await
+                            }
+                        };
+                        match result {
+                            Ok(_) => {
+                                file_pb.finish_with_message("Download completed");
+                                file_sources
+                                    .lock()
+                                    .unwrap()
+                                    .push((layout.files[job.file_idx].path.clone(), *source_idx));
+                                downloaded = true;
                                 break;
                             }
-                            _ => {
-                                retries -= 1;
-                                if retries == 0 {
-                                    file_pb
-                                        .abandon_with_message("Download failed after 10 retries");
-                                    return Err(e.into());
+                            // Another source, or another place in the same source, may have it.
+                            // Whatever came from this one is thrown away, so the file isn't made up
+                            // of content from two sources.
+                            Err(e) if location_idx + 1 < job.locations.len() => {
+                                file_pb.set_message(format!("{e}; trying the next source"));
+                                discard_partial_download(&job.target_path).await?;
+                                let restart = stream.lock().unwrap().restart(buffered);
+                                if let Some(event) = restart {
+                                    send(event).await;
                                 }
-                                file_pb.abandon_with_message(
-                                    "Download failed. Will retry in 5 seconds.",
-                                );
-                                sleep(Duration::from_secs(5)).await;
                             }
-                        },
+                            // The file isn't available, so it's left to be reported as missing.
+                            Err(
+                                e @ (Error::ArchiveFileNotFoundError(_)
+                                | Error::ArchiveDownloadFailed { .. }),
+                            ) if !e.is_transient() => {
+                                file_pb.abandon_with_message(format!("Download failed: {e}"));
+                            }
+                            Err(e) => {
+                                file_pb.abandon_with_message(format!("Download failed: {e}"));
+                                return Err(e);
+                            }
+                        }
                     }
-                }
+                    file_pbs.lock().unwrap().push(file_pb);
+                    let (streamed, content) =
+                        stream.into_inner().unwrap().finish(downloaded, buffered);
+                    send(DownloadEvent::Done {
+                        job_idx,
+                        path: downloaded.then(|| job.target_path.clone()),
+                        streamed,
+                        content,
+                    })
+                    .await;
+                    total_pb.inc(1);
+                    Ok(())
+                })
+                .await
+        };
+
+        let verification = async {
+            match verification {
+                Some(handle) => handle.await.map_err(|_| Error::HashingStopped)?.map(Some),
+                None => Ok(None),
             }
-            total_pb.inc(1);
+        };
+        let result = tokio::try_join!(downloads, verification);
+
+        // The files that were downloaded before anything went wrong are still recorded.
+        let file_sources = file_sources
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|(path, source_idx)| (path, sources[source_idx].describe()))
+            .collect::<Vec<(PathBuf, String)>>();
+        if !file_sources.is_empty() {
+            let mut conn = get_db_connection(get_database_path()?)?;
+            save_file_sources(&mut conn, &self.id, &file_sources)?;
+            let _ = conn.close();
         }
+        let (_, results) = result?;
 
         total_pb.finish_with_message("Downloaded all files in the torrent tree");
-        Ok(())
-    }
-
-    pub fn check(&self, target_directory: &PathBuf) -> Result<VerificationOutcome> {
+        let normalised_count = jobs
+            .iter()
+            .filter(|job| matches!(job.resolved, ResolvedPath::Normalised(_)))
+            .count();
+        if normalised_count > 0 {
+            println!(
+                "{} files were already present under names that differ in case or Unicode form. \
+                 Use check --fix-names to rename them.",
+                normalised_count
+            );
+        }
+        match results {
+            Some(results) => Ok(Some(self.complete_download_verification(&layout, results)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Downloads a release from its BitTorrent swarm, and returns the outcome of verifying it.
+    ///
+    /// The pieces already under the target directory are checked first, so only those that are
+    /// missing or corrupt are downloaded. Each piece is checked against its hash before it's
+    /// written into the files, so the outcome is known as soon as the download finishes. A file
+    /// that overlaps a piece that couldn't be downloaded is reported as missing, unless it was
+    /// already on disk and the piece could only not be checked because of a neighbouring file.
+    ///
+    /// Only the v1 protocol is supported, so this doesn't work for v2 torrents.
+    pub async fn download_via_torrent(
+        &mut self,
+        base_target_path: &Path,
+        options: &SwarmOptions,
+        throttle: &DownloadThrottle,
+    ) -> Result<VerificationOutcome> {
+        let mut layout = self.get_v1_piece_layout()?;
+        layout.resolve_disk_paths(base_target_path)?;
+        let torrent_content = get_torrent_content(&self.id)?;
+        let info_hash: [u8; 20] = get_info_hashes(&torrent_content)?
+            .v1
+            .and_then(|hash| hex::decode(hash).ok())
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| {
+                Error::TorrentVersionNotSupported("a v2 torrent has no v1 info hash".to_string())
+            })?;
+        let trackers = get_trackers(&Torrent::read_from_bytes(torrent_content)?);
+
+        let present = layout
+            .files
+            .iter()
+            .map(|file| base_target_path.join(&file.disk_path).is_file())
+            .collect::<Vec<bool>>();
+        let have = check_existing_pieces(&layout, base_target_path)?;
+        println!("Downloading {} from its swarm...", self.name);
+        let layout = Arc::new(layout);
+        let have = download_pieces(
+            Arc::clone(&layout),
+            &info_hash,
+            &trackers,
+            base_target_path,
+            have,
+            options,
+            throttle,
+        )
+        .await?;
+        finish_downloaded_files(&layout, base_target_path, &have)?;
+
+        let mut piece_map = PieceMap::new(layout.piece_count());
+        for piece_idx in (0..layout.piece_count()).filter(|i| have.get(*i)) {
+            piece_map.set(piece_idx, Some(true));
+        }
+        let missing_file_indexes = get_files_missing_from_swarm(&layout, &have, &present);
+        self.complete_download_verification(
+            &layout,
+            DownloadResults {
+                piece_map,
+                missing_file_indexes,
+                digests: None,
+            },
+        )
+    }
+
+    /// Gets the layout to download a release with. The v1 pieces are needed to verify it as it is
+    /// downloaded, since the files arrive in the order of the v1 file list.
+    fn get_download_layout(&self, verify: bool) -> Result<PieceLayout> {
+        if verify {
+            self.get_v1_piece_layout()
+        } else {
+            self.get_piece_layout()
+        }
+    }
+
+    /// Works out the outcome of a release from the pieces that were hashed as it was downloaded.
+    ///
+    /// The piece map and file hashes are saved. The outcome is returned for the caller to save, as
+    /// with the other verification methods.
+    fn complete_download_verification(
+        &mut self,
+        layout: &PieceLayout,
+        results: DownloadResults,
+    ) -> Result<VerificationOutcome> {
+        self.verified_files.clear();
+        self.unverifiable_files.clear();
+        self.waived_files.clear();
+        let DownloadResults {
+            piece_map,
+            missing_file_indexes,
+            digests,
+        } = results;
+        self.save_piece_map(&piece_map)?;
+        if missing_file_indexes.len() == layout.file_count() {
+            return Ok(VerificationOutcome::AllFilesMissing);
+        }
+
+        let mut corrupt_file_indexes = BTreeSet::new();
+        let mut unverifiable_file_indexes = BTreeSet::new();
+        for piece_idx in 0..layout.piece_count() {
+            match piece_map.get(piece_idx) {
+                Some(false) => corrupt_file_indexes.extend(layout.files_for_piece(piece_idx)),
+                None => unverifiable_file_indexes.extend(layout.files_for_piece(piece_idx)),
+                Some(true) => {}
+            }
+        }
+        let ignore_rules = self.get_ignore_rules()?;
+        let mut missing_files = Vec::new();
+        let mut corrupted_files = Vec::new();
+        let mut verified_files = Vec::new();
+        for (i, file) in layout.content_files() {
+            let is_missing = missing_file_indexes.contains(&i);
+            if is_missing || corrupt_file_indexes.contains(&i) {
+                match ignore_rules.find_match(&file.path) {
+                    Some(rule) => {
+                        self.waived_files
+                            .push((file.path.clone(), file.length, rule.to_string()))
+                    }
+                    None if is_missing => missing_files.push((file.path.clone(), file.length)),
+                    None => corrupted_files.push((file.path.clone(), file.length)),
+                }
+            } else if unverifiable_file_indexes.contains(&i) {
+                self.unverifiable_files
+                    .push((file.path.clone(), file.length));
+            } else {
+                verified_files.push((file.path.clone(), file.length));
+            }
+        }
+        println!(
+            "{} files verified while downloading, {} missing, {} corrupt, {} could not be verified \
+             because a neighbouring file is missing",
+            verified_files.len(),
+            missing_files.len(),
+            corrupted_files.len(),
+            self.unverifiable_files.len()
+        );
+
+        // As with any other verification, a release with files that couldn't be checked is only
+        // complete, even if the files they share pieces with are waived.
+        if missing_files.is_empty()
+            && corrupted_files.is_empty()
+            && !self.unverifiable_files.is_empty()
+        {
+            self.verified_files = verified_files;
+            return Ok(VerificationOutcome::Complete);
+        }
+        if missing_files.is_empty() && corrupted_files.is_empty() {
+            if let Some(digests) = digests {
+                let excluded_file_indexes = corrupt_file_indexes
+                    .union(&missing_file_indexes)
+                    .chain(unverifiable_file_indexes.iter())
+                    .copied()
+                    .chain(
+                        layout
+                            .files
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, f)| f.attributes.padding)
+                            .map(|(i, _)| i),
+                    )
+                    .collect::<BTreeSet<usize>>();
+                self.save_file_hashes(
+                    layout,
+                    None,
+                    digests,
+                    &BTreeSet::new(),
+                    &excluded_file_indexes,
+                )?;
+            }
+            return Ok(VerificationOutcome::Verified);
+        }
+        self.verified_files = verified_files;
+        Ok(VerificationOutcome::Incomplete(
+            missing_files,
+            corrupted_files,
+        ))
+    }
+
+    pub fn check(
+        &mut self,
+        target_directory: &Path,
+        fix_names: bool,
+    ) -> Result<VerificationOutcome> {
         if self.torrent_url.is_none() {
             return Ok(VerificationOutcome::TorrentMissing);
         }
 
-        let torrent_content = get_torrent_content(&self.id)?;
-        let torrent = Torrent::read_from_bytes(torrent_content)?;
-        let files = torrent.files.ok_or_else(|| Error::TorrentFilesError)?;
+        self.waived_files.clear();
+        let mut layout = self.get_piece_layout()?;
+        resolve_file_names(&mut layout, target_directory, fix_names)?;
+        let ignore_rules = self.get_ignore_rules()?;
 
-        let missing_files_pb = ProgressBar::new(files.len() as u64);
+        let file_count = layout.file_count();
+        let missing_files_pb = ProgressBar::new(file_count as u64);
         missing_files_pb.set_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len}")?
@@ -741,157 +1820,1050 @@ impl Release {
 
         let mut missing_files = Vec::new();
         println!("Checking for missing files...");
-        for file in files.iter() {
-            let path = target_directory.join(file.path.clone());
+        for (_, file) in layout.content_files() {
+            let path = target_directory.join(&file.disk_path);
             if !path.exists() {
-                missing_files.push((file.path.clone(), file.length as u64));
+                missing_files.push((file.path.clone(), file.length));
             }
             missing_files_pb.inc(1);
         }
         missing_files_pb.finish_with_message("Completed");
 
         println!("Checking for size mismatches...");
-        let size_mismatch_pb = ProgressBar::new(files.len() as u64);
+        let size_mismatch_pb = ProgressBar::new(file_count as u64);
         size_mismatch_pb.set_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len}")?
                 .progress_chars("#>-"),
         );
         let mut size_mismatches = Vec::new();
-        for file in files.iter() {
-            let path = target_directory.join(file.path.clone());
+        for (_, file) in layout.content_files() {
+            let path = target_directory.join(&file.disk_path);
             if path.exists() {
                 let metadata = std::fs::metadata(&path)?;
                 let size = metadata.len();
-                if size != file.length as u64 {
-                    size_mismatches.push((path, file.length as u64));
+                if size != file.length {
+                    size_mismatches.push((file.path.clone(), file.length));
                 }
                 size_mismatch_pb.inc(1);
             }
         }
         size_mismatch_pb.finish_with_message("Completed");
 
-        if missing_files.len() == files.len() {
-            return Ok(VerificationOutcome::AllFilesMissing);
+        if missing_files.len() == file_count {
+            Ok(VerificationOutcome::AllFilesMissing)
         } else if !missing_files.is_empty() || !size_mismatches.is_empty() {
-            let missing_are_trivial = missing_files.iter().all(|m| {
-                let file_name = m.0.file_name().unwrap().to_string_lossy();
-                if file_name == "Thumbs.db" {
-                    return true;
+            // Files matching an ignore rule don't prevent the release from being complete.
+            let mut waived_files = Vec::new();
+            missing_files.retain(|(path, size)| match ignore_rules.find_match(path) {
+                Some(rule) => {
+                    waived_files.push((path.clone(), *size, rule.to_string()));
+                    false
                 }
-                false
+                None => true,
             });
-            let size_mismatches_are_trivial = size_mismatches.iter().all(|m| {
-                let file_name = m.0.file_name().unwrap().to_string_lossy();
-                if file_name == "Thumbs.db" {
-                    return true;
+            size_mismatches.retain(|(path, size)| match ignore_rules.find_match(path) {
+                Some(rule) => {
+                    waived_files.push((path.clone(), *size, rule.to_string()));
+                    false
                 }
-                false
+                None => true,
             });
-            if missing_are_trivial && size_mismatches_are_trivial {
+            self.waived_files = waived_files;
+            if missing_files.is_empty() && size_mismatches.is_empty() {
                 return Ok(VerificationOutcome::Complete);
             }
-            return Ok(VerificationOutcome::Incomplete(
+            Ok(VerificationOutcome::Incomplete(
                 missing_files,
                 size_mismatches,
-            ));
+            ))
         } else {
-            return Ok(VerificationOutcome::Complete);
+            Ok(VerificationOutcome::Complete)
         }
     }
 
-    pub fn verify(&self, target_directory: &PathBuf) -> Result<VerificationOutcome> {
+    /// Verifies the files in the target directory against the torrent piece hashes.
+    pub fn verify(
+        &mut self,
+        target_directory: &Path,
+        options: &VerifyOptions,
+    ) -> Result<VerificationOutcome> {
         if self.torrent_url.is_none() {
             return Ok(VerificationOutcome::TorrentMissing);
         }
+        let mut layout = self.get_piece_layout()?;
+        resolve_file_names(&mut layout, target_directory, options.fix_names)?;
+        self.verify_directory(&layout, target_directory, None, options)
+    }
 
-        let torrent_content = get_torrent_content(&self.id)?;
-        let torrent = Torrent::read_from_bytes(torrent_content)?;
-        let piece_length = torrent.piece_length;
-        let num_pieces = torrent.pieces.len();
-        let files = torrent.files.ok_or_else(|| Error::TorrentFilesError)?;
+    /// Verifies the files inside a zip against the torrent piece hashes, without extracting it.
+    ///
+    /// The entries are mapped onto the torrent tree by path and read in torrent order, so the
+    /// pieces can be assembled as the zip is streamed. Checkpoints and the file cache only apply
+    /// to files on disk, so they aren't used.
+    pub fn verify_zip(
+        &mut self,
+        zip_path: &Path,
+        options: &VerifyOptions,
+    ) -> Result<VerificationOutcome> {
+        if self.torrent_url.is_none() {
+            return Ok(VerificationOutcome::TorrentMissing);
+        }
+        // The zip can only be streamed in order, which suits the v1 pieces, so a hybrid torrent is
+        // verified with those.
+        let layout = self.get_v1_piece_layout()?;
+        println!("Reading entries from {}...", zip_path.to_string_lossy());
+        let zip = ZipSource::open(zip_path, &layout)?;
+        self.verify_content(&layout, &ContentSource::Zip(zip), None, options)
+    }
 
-        // If any files are missing, we can bail out before attempting to verify the release.
-        let mut missing_files = Vec::new();
-        println!("Checking for missing files...");
-        for file in files.iter() {
-            let path = target_directory.join(file.path.clone());
-            if !path.exists() {
-                missing_files.push((file.path.clone(), file.length as u64));
+    /// Verifies the files under a path in the torrent tree, which can be a single file or a
+    /// directory, and saves the results for those files alone.
+    ///
+    /// The outcome for the release is then worked out from what is known about all of its files.
+    /// It becomes incomplete if any file is missing or corrupt, or verified if every file is now
+    /// known to have been verified. An incomplete release whose problems have all been resolved
+    /// goes back to unknown, since the rest of its files haven't necessarily been checked.
+    /// Otherwise the outcome stays as it was.
+    pub fn verify_path(
+        &mut self,
+        target_directory: &Path,
+        path: &Path,
+        options: &VerifyOptions,
+    ) -> Result<Vec<FileResult>> {
+        self.verify_paths(target_directory, &[path.to_path_buf()], options)
+    }
+
+    /// Verifies the files under several paths in the torrent tree in the same way as
+    /// [`Release::verify_path`].
+    pub fn verify_paths(
+        &mut self,
+        target_directory: &Path,
+        paths: &[PathBuf],
+        options: &VerifyOptions,
+    ) -> Result<Vec<FileResult>> {
+        let not_in_release = || {
+            Error::PathNotInRelease(
+                paths
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+        };
+        if self.torrent_url.is_none() {
+            return Err(not_in_release());
+        }
+        let mut layout = self.get_piece_layout()?;
+        resolve_file_names(&mut layout, target_directory, options.fix_names)?;
+        // A path selects a file if it is the file's path or one of its parent directories, so each
+        // file only needs to look up its own ancestors.
+        let lookup = paths
+            .iter()
+            .map(|p| p.components().collect::<PathBuf>())
+            .collect::<HashSet<PathBuf>>();
+        let selected = layout
+            .content_files()
+            .filter(|(_, f)| f.path.ancestors().any(|a| lookup.contains(a)))
+            .map(|(i, _)| i)
+            .collect::<BTreeSet<usize>>();
+        if selected.is_empty() {
+            return Err(not_in_release());
+        }
+        match paths {
+            [path] => println!(
+                "Verifying {} files under {}",
+                selected.len(),
+                path.display()
+            ),
+            _ => println!("Verifying {} files", selected.len()),
+        }
+
+        let previous_outcome = self.verification_outcome.clone();
+        let mut statuses = self.get_file_results(&layout);
+        let outcome = self.verify_directory(&layout, target_directory, Some(&selected), options)?;
+
+        let (missing, corrupted) = match &outcome {
+            VerificationOutcome::Incomplete(missing, corrupted) => {
+                (missing.clone(), corrupted.clone())
+            }
+            _ => (Vec::new(), Vec::new()),
+        };
+        let missing = missing.iter().map(|(p, _)| p).collect::<HashSet<_>>();
+        let corrupted = corrupted.iter().map(|(p, _)| p).collect::<HashSet<_>>();
+        let unverifiable = self
+            .unverifiable_files
+            .iter()
+            .map(|(p, _)| p)
+            .collect::<HashSet<_>>();
+        let waivers = self
+            .waived_files
+            .iter()
+            .map(|(p, _, reason)| (p, reason))
+            .collect::<HashMap<_, _>>();
+        let results = selected
+            .iter()
+            .map(|i| {
+                let file = &layout.files[*i];
+                let (status, reason) = if let Some(reason) = waivers.get(&file.path) {
+                    ("WAIVED", Some((*reason).clone()))
+                } else if missing.contains(&file.path) {
+                    ("MISSING", None)
+                } else if corrupted.contains(&file.path) {
+                    ("CORRUPTED", None)
+                } else if unverifiable.contains(&file.path) {
+                    ("UNVERIFIABLE", None)
+                } else {
+                    ("VERIFIED", None)
+                };
+                FileResult {
+                    path: file.path.clone(),
+                    size: file.length,
+                    status,
+                    reason,
+                }
+            })
+            .collect::<Vec<FileResult>>();
+        for result in results.iter() {
+            statuses.insert(result.path.clone(), result.clone());
+        }
+
+        let with_status = |status: &str| {
+            layout
+                .content_files()
+                .filter_map(|(_, f)| statuses.get(&f.path))
+                .filter(|r| r.status == status)
+                .map(|r| (r.path.clone(), r.size))
+                .collect::<Vec<(PathBuf, u64)>>()
+        };
+        let missing = with_status("MISSING");
+        let corrupted = with_status("CORRUPTED");
+        let all_known = layout
+            .content_files()
+            .all(|(_, f)| statuses.contains_key(&f.path));
+        self.verified_files = with_status("VERIFIED");
+        self.unverifiable_files = with_status("UNVERIFIABLE");
+        self.waived_files = layout
+            .content_files()
+            .filter_map(|(_, f)| statuses.get(&f.path))
+            .filter(|r| r.status == "WAIVED")
+            .map(|r| (r.path.clone(), r.size, r.reason.clone().unwrap_or_default()))
+            .collect();
+        self.verification_outcome = if !missing.is_empty() || !corrupted.is_empty() {
+            Some(VerificationOutcome::Incomplete(missing, corrupted))
+        } else if all_known {
+            Some(VerificationOutcome::Verified)
+        } else if let Some(VerificationOutcome::Incomplete(_, _)) = previous_outcome {
+            None
+        } else {
+            previous_outcome.clone()
+        };
+
+        // A release that was previously verified has no rows for its verified files, so they
+        // need to be written if it is now incomplete.
+        let mut rows = results.clone();
+        if let (Some(VerificationOutcome::Verified), Some(VerificationOutcome::Incomplete(_, _))) =
+            (&previous_outcome, &self.verification_outcome)
+        {
+            rows.extend(
+                (0..layout.files.len())
+                    .filter(|i| !selected.contains(i))
+                    .filter_map(|i| statuses.get(&layout.files[i].path))
+                    .filter(|r| r.status == "VERIFIED")
+                    .cloned(),
+            );
+        }
+        let mut conn = get_db_connection(get_database_path()?)?;
+        save_file_results(&mut conn, self, &rows)?;
+        let _ = conn.close();
+        Ok(results)
+    }
+
+    /// Gets what is known about each file in the release from the last verification.
+    fn get_file_results(&self, layout: &PieceLayout) -> HashMap<PathBuf, FileResult> {
+        let mut statuses = HashMap::new();
+        let mut insert =
+            |path: &PathBuf, size: u64, status: &'static str, reason: Option<String>| {
+                statuses.insert(
+                    path.clone(),
+                    FileResult {
+                        path: path.clone(),
+                        size,
+                        status,
+                        reason,
+                    },
+                );
+            };
+        match &self.verification_outcome {
+            Some(VerificationOutcome::Verified) => {
+                for (_, file) in layout.content_files() {
+                    insert(&file.path, file.length, "VERIFIED", None);
+                }
+            }
+            Some(VerificationOutcome::Incomplete(missing, corrupted)) => {
+                for (path, size) in missing.iter() {
+                    insert(path, *size, "MISSING", None);
+                }
+                for (path, size) in corrupted.iter() {
+                    insert(path, *size, "CORRUPTED", None);
+                }
+                for (path, size) in self.verified_files.iter() {
+                    insert(path, *size, "VERIFIED", None);
+                }
+            }
+            _ => {}
+        }
+        for (path, size) in self.unverifiable_files.iter() {
+            insert(path, *size, "UNVERIFIABLE", None);
+        }
+        for (path, size, reason) in self.waived_files.iter() {
+            insert(path, *size, "WAIVED", Some(reason.clone()));
+        }
+        statuses
+    }
+
+    fn get_piece_layout(&self) -> Result<PieceLayout> {
+        let mut layout = get_piece_layout_from_bytes(get_torrent_content(&self.id)?)?;
+        if self.escaped_names {
+            layout.escape_disk_paths();
+        }
+        Ok(layout)
+    }
+
+    /// Gets the layout of the v1 pieces, which for a hybrid torrent comes from its v1 file list,
+    /// padding files included. A v2 torrent doesn't have any.
+    fn get_v1_piece_layout(&self) -> Result<PieceLayout> {
+        match self.get_torrent_version()? {
+            TorrentVersion::V1 => self.get_piece_layout(),
+            TorrentVersion::Hybrid => {
+                let mut layout = PieceLayout::from_torrent(&Torrent::read_from_bytes(
+                    get_torrent_content(&self.id)?,
+                )?)?;
+                if self.escaped_names {
+                    layout.escape_disk_paths();
+                }
+                Ok(layout)
+            }
+            TorrentVersion::V2 => Err(Error::TorrentVersionNotSupported(
+                "a v2 torrent has no v1 pieces".to_string(),
+            )),
+        }
+    }
+
+    fn get_piece_map(&self) -> Result<Option<PieceMap>> {
+        let conn = get_db_connection(get_database_path()?)?;
+        let piece_map = get_piece_map(&conn, &self.id)?;
+        let _ = conn.close();
+        Ok(piece_map)
+    }
+
+    fn save_piece_map(&self, piece_map: &PieceMap) -> Result<()> {
+        let conn = get_db_connection(get_database_path()?)?;
+        save_piece_map(&conn, &self.id, piece_map)?;
+        let _ = conn.close();
+        Ok(())
+    }
+
+    /// The path a file in the torrent tree is stored at, which depends on whether the release is
+    /// stored with escaped names.
+    fn get_disk_path(&self, torrent_path: &Path) -> PathBuf {
+        if self.escaped_names {
+            escape_path(torrent_path)
+        } else {
+            torrent_path.to_path_buf()
+        }
+    }
+
+    /// Verifies files on disk with whichever algorithm suits the version of the torrent.
+    ///
+    /// Hybrid torrents have both the v1 pieces and the v2 merkle trees, but the trees are used,
+    /// since they give a result for each file without depending on its neighbours.
+    fn verify_directory(
+        &mut self,
+        layout: &PieceLayout,
+        target_directory: &Path,
+        selected: Option<&BTreeSet<usize>>,
+        options: &VerifyOptions,
+    ) -> Result<VerificationOutcome> {
+        match self.get_torrent_version()? {
+            TorrentVersion::V1 => self.verify_content(
+                layout,
+                &ContentSource::Directory(target_directory),
+                selected,
+                options,
+            ),
+            version => {
+                println!("This is a {version} torrent, so files are verified individually");
+                self.verify_v2(layout, target_directory, selected, options)
             }
         }
-        if missing_files.len() == files.len() {
+    }
+
+    /// Verifies files on disk against the per-file merkle trees of a v2 or hybrid torrent.
+    ///
+    /// No piece spans more than one file, so a missing file doesn't stop any other file being
+    /// verified, and a corrupt file never implicates its neighbours. The files are spread across
+    /// the hashing threads. Checkpoints and the file cache are specific to v1 pieces, so they
+    /// aren't used, and asking to resume or verify incrementally only gets a warning.
+    fn verify_v2(
+        &mut self,
+        layout: &PieceLayout,
+        target_directory: &Path,
+        selected: Option<&BTreeSet<usize>>,
+        options: &VerifyOptions,
+    ) -> Result<VerificationOutcome> {
+        self.verified_files.clear();
+        self.unverifiable_files.clear();
+        self.waived_files.clear();
+        if options.resume {
+            println!(
+                "Warning: v2 verification has no checkpoints, so it will start from the beginning"
+            );
+        }
+        if options.incremental {
+            println!(
+                "Warning: v2 verification doesn't use the file cache, so every file will be hashed"
+            );
+        }
+        let torrent = V2Torrent::read_from_bytes(&get_torrent_content(&self.id)?)?;
+        let ignore_rules = self.get_ignore_rules()?;
+        // A piece map describes the v1 pieces, so one left by verifying a zip is now out of date.
+        if selected.is_none() {
+            let conn = get_db_connection(get_database_path()?)?;
+            delete_piece_map(&conn, &self.id)?;
+            let _ = conn.close();
+        }
+        let files = &layout.files;
+        let in_scope = |i: &usize| selected.is_none_or(|s| s.contains(i));
+
+        println!("Checking for missing files...");
+        let missing_file_indexes = (0..files.len())
+            .filter(|i| !target_directory.join(&files[*i].disk_path).exists())
+            .collect::<BTreeSet<usize>>();
+        if selected.is_none() && missing_file_indexes.len() == files.len() {
             return Ok(VerificationOutcome::AllFilesMissing);
-        } else if missing_files.len() > 0 {
+        }
+        let mut missing_files = Vec::new();
+        for i in missing_file_indexes.iter().filter(|i| in_scope(i)) {
+            let file = &files[*i];
+            match ignore_rules.find_match(&file.path) {
+                Some(rule) => {
+                    self.waived_files
+                        .push((file.path.clone(), file.length, rule.to_string()))
+                }
+                None => missing_files.push((file.path.clone(), file.length)),
+            }
+        }
+        if !missing_files.is_empty() && !options.partial && selected.is_none() {
             return Ok(VerificationOutcome::Incomplete(missing_files, vec![]));
         }
 
-        println!("All files are present. Will now attempt to verify them.");
-        println!("The torrent has {} pieces to verify", num_pieces);
-        let bar = ProgressBar::new(num_pieces as u64);
+        let to_verify = (0..files.len())
+            .filter(|i| in_scope(i) && !missing_file_indexes.contains(i))
+            .collect::<Vec<usize>>();
+        println!(
+            "{} files to verify, using {} hashing threads",
+            to_verify.len(),
+            options.jobs
+        );
+        let bar = ProgressBar::new(to_verify.iter().map(|i| files[*i].length).sum());
         bar.set_style(
             ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len}")?
+                .template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {binary_bytes_per_sec}",
+                )?
                 .progress_chars("#>-"),
         );
+        let throttle = ReadThrottle::new(options.max_read_rate);
+        let next = AtomicUsize::new(0);
+        let results = std::thread::scope(|scope| {
+            let workers = (0..options.jobs.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let n = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(i) = to_verify.get(n) else {
+                                return Ok(results);
+                            };
+                            let path = target_directory.join(&files[*i].disk_path);
+                            let verified =
+                                torrent.verify_file(&torrent.files[*i], &path, &|read| {
+                                    throttle.consume(read);
+                                    bar.inc(read)
+                                })?;
+                            results.push((*i, verified));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut results = Vec::new();
+            for worker in workers {
+                let worker_results: Result<Vec<(usize, bool)>> =
+                    worker.join().map_err(|_| Error::HashingStopped)?;
+                results.extend(worker_results?);
+            }
+            Ok::<_, Error>(results)
+        })?;
+        bar.finish();
 
-        let mut mismatched_files = Vec::new();
-        let mut piece_idx = 0;
-        let mut file_idx = 0;
-        let mut offset: u64 = 0;
+        let mut results = results;
+        results.sort_by_key(|(i, _)| *i);
+        let mut corrupted_files = Vec::new();
+        let mut verified_file_indexes = BTreeSet::new();
+        for (i, verified) in results {
+            let file = &files[i];
+            match verified {
+                true => {
+                    self.verified_files.push((file.path.clone(), file.length));
+                    verified_file_indexes.insert(i);
+                }
+                false => match ignore_rules.find_match(&file.path) {
+                    Some(rule) => {
+                        self.waived_files
+                            .push((file.path.clone(), file.length, rule.to_string()))
+                    }
+                    None => corrupted_files.push((file.path.clone(), file.length)),
+                },
+            }
+        }
+        if !corrupted_files.is_empty() {
+            println!(
+                "{} of {} files failed verification",
+                corrupted_files.len(),
+                to_verify.len()
+            );
+        }
+        if !self.waived_files.is_empty() {
+            println!(
+                "{} missing or corrupt files were waived by ignore rules",
+                self.waived_files.len()
+            );
+        }
+
+        if missing_files.is_empty() && corrupted_files.is_empty() {
+            if options.file_hashes {
+                // Only the files whose content was verified get hashes, so waived files and files
+                // outside the selection are left out.
+                let excluded_file_indexes = (0..files.len())
+                    .filter(|i| !verified_file_indexes.contains(i))
+                    .collect::<BTreeSet<usize>>();
+                self.save_file_hashes(
+                    layout,
+                    Some((&ContentSource::Directory(target_directory), options)),
+                    vec![None; files.len()],
+                    &BTreeSet::new(),
+                    &excluded_file_indexes,
+                )?;
+            }
+            // Like a v1 verification, the verified files are only listed for incomplete releases.
+            self.verified_files.clear();
+            return Ok(VerificationOutcome::Verified);
+        }
+        Ok(VerificationOutcome::Incomplete(
+            missing_files,
+            corrupted_files,
+        ))
+    }
 
-        while piece_idx < num_pieces {
-            let piece_hash = &torrent.pieces[piece_idx];
-            let mut buffer = Vec::new();
+    /// Verifies the content from the source against the piece hashes.
+    ///
+    /// If a selection of files is supplied, only the pieces that overlap them are hashed, and the
+    /// outcome and file lists only cover those files. Pieces at either end of a selected file can
+    /// also contain bytes from its neighbours, so those are read too.
+    fn verify_content(
+        &mut self,
+        layout: &PieceLayout,
+        source: &ContentSource,
+        selected: Option<&BTreeSet<usize>>,
+        options: &VerifyOptions,
+    ) -> Result<VerificationOutcome> {
+        self.verified_files.clear();
+        self.unverifiable_files.clear();
+        self.waived_files.clear();
+        let ignore_rules = self.get_ignore_rules()?;
+        let num_pieces = layout.piece_count();
+        let files = &layout.files;
+        let target_directory = source.directory();
+        let in_scope = |i: &usize| selected.is_none_or(|s| s.contains(i));
 
-            // This loop reads a piece, which can span across multiple files, if the files are
-            // smaller than the piece size. The 'pieces' in the torrent file are with respect to
-            // the whole content rather than any individual file.
-            while buffer.len() < piece_length as usize {
-                let file_info = &files[file_idx];
-                let file_path = target_directory.join(file_info.path.clone());
+        // Unless a partial verification was requested, if any files are missing, we can bail out
+        // before attempting to verify the release. Verifying selected files always works like a
+        // partial verification, since the rest of the release isn't being considered.
+        let mut missing_file_indexes = BTreeSet::new();
+        println!("Checking for missing files...");
+        for i in 0..files.len() {
+            if !source.contains(layout, i) {
+                missing_file_indexes.insert(i);
+            }
+        }
+        let file_count = layout.file_count();
+        if selected.is_none() && missing_file_indexes.len() == file_count {
+            self.save_piece_map(&PieceMap::new(num_pieces))?;
+            return Ok(VerificationOutcome::AllFilesMissing);
+        }
 
-                let mut file = File::open(file_path)?;
-                file.seek(std::io::SeekFrom::Start(offset))?;
+        // Files that match an ignore rule are allowed to be missing or corrupt. Pieces that
+        // overlap a missing file still can't be hashed, but the file doesn't count as missing.
+        let mut waived_file_indexes = BTreeSet::new();
+        for (i, file) in layout.content_files() {
+            if let Some(rule) = ignore_rules.find_match(&file.path) {
+                waived_file_indexes.insert(i);
+                if missing_file_indexes.contains(&i) && in_scope(&i) {
+                    self.waived_files
+                        .push((file.path.clone(), file.length, rule.to_string()));
+                }
+            }
+        }
+        let missing_files = missing_file_indexes
+            .iter()
+            .filter(|i| !waived_file_indexes.contains(i) && in_scope(i))
+            .map(|i| (files[*i].path.clone(), files[*i].length))
+            .collect::<Vec<(PathBuf, u64)>>();
+        if !missing_files.is_empty() && !options.partial && selected.is_none() {
+            self.save_piece_map(&PieceMap::new(num_pieces))?;
+            return Ok(VerificationOutcome::Incomplete(missing_files, vec![]));
+        }
 
-                let remaining = (piece_length as usize) - buffer.len();
-                let mut temp_buf = vec![0; remaining];
-                let read_bytes_len = file.read(&mut temp_buf)?;
+        // A piece can only be hashed if all the files it spans are present.
+        let hashable = (0..num_pieces)
+            .map(|piece_idx| {
+                layout
+                    .files_for_piece(piece_idx)
+                    .iter()
+                    .all(|i| !missing_file_indexes.contains(i))
+            })
+            .collect::<Vec<bool>>();
+        let piece_in_scope = (0..num_pieces)
+            .map(|piece_idx| layout.files_for_piece(piece_idx).iter().any(in_scope))
+            .collect::<Vec<bool>>();
+        if missing_file_indexes.is_empty() {
+            println!("All files are present. Will now attempt to verify them.");
+        } else {
+            println!(
+                "{} of {} files are missing. Pieces that overlap them will not be verified.",
+                missing_file_indexes.len(),
+                file_count
+            );
+        }
 
-                buffer.extend_from_slice(&temp_buf[0..read_bytes_len]);
+        let current_file_states = match target_directory {
+            Some(target_directory) => get_file_states(layout, target_directory)?,
+            None => vec![None; files.len()],
+        };
+        let file_states = get_checkpoint_file_states(layout, &current_file_states);
+        let mut piece_results = Bitfield::new(num_pieces);
+        let mut start_piece = 0;
+        let mut conn = get_db_connection(get_database_path()?)?;
 
-                if ((file_info.length as u64) - offset) as usize <= remaining {
-                    offset = 0;
-                    file_idx += 1;
-                    if file_idx == files.len() {
-                        break;
+        // For an incremental verification, files that are unchanged since they last passed
+        // verification don't need to be read again. Only the pieces that touch a changed file are
+        // hashed; these include the pieces it shares with its neighbours at either end.
+        let incremental = options.incremental && target_directory.is_some();
+        let mut unchanged_file_indexes = BTreeSet::new();
+        if incremental {
+            let cache = get_file_cache(&conn, &self.id)?;
+            for (i, file) in layout.content_files() {
+                if let (Some(state), Some(cached)) =
+                    (&current_file_states[i], cache.get(&file.path))
+                {
+                    if cached.state == *state && cached.pieces == layout.pieces_for_file(i) {
+                        unchanged_file_indexes.insert(i);
                     }
-                } else {
-                    offset += read_bytes_len as u64;
                 }
             }
+            println!(
+                "{} of {} files are unchanged since they were last verified",
+                unchanged_file_indexes.len(),
+                file_count
+            );
+        }
+        let unchanged = (0..num_pieces)
+            .map(|piece_idx| {
+                incremental
+                    && layout
+                        .files_for_piece(piece_idx)
+                        .iter()
+                        .all(|i| unchanged_file_indexes.contains(i))
+            })
+            .collect::<Vec<bool>>();
 
-            // Now that the whole piece has been read, verify it.
-            let mut hasher = Sha1::new();
-            hasher.update(&buffer);
-            let result = hasher.finalize();
-            if result.as_slice() != piece_hash {
-                if file_idx == files.len() {
-                    break;
+        // Checkpoints describe files on disk, so when verifying a zip, any existing checkpoint is
+        // left alone.
+        let use_checkpoints = target_directory.is_some() && selected.is_none();
+        if use_checkpoints && options.resume {
+            match get_verification_checkpoint(&conn, &self.id)? {
+                Some(checkpoint)
+                    if checkpoint.files == file_states
+                        && checkpoint.piece_results.len() == num_pieces =>
+                {
+                    start_piece = checkpoint.last_piece + 1;
+                    piece_results = checkpoint.piece_results;
+                    println!("Resuming verification from piece {}", start_piece);
+                }
+                Some(_) => {
+                    println!(
+                        "Files have changed since the checkpoint was saved. Verification will \
+                         start from the beginning."
+                    );
+                    delete_verification_checkpoint(&mut conn, &self.id)?;
                 }
-                let file = &files[file_idx];
-                mismatched_files.push((file.path.clone(), file.length as u64));
-                // If the content doesn't match at any point, the rest of the hashes won't match,
-                // so we can just bail here. We can really only get information about the file
-                // where the first mismatch occurred; however, there can be other corrupt files.
-                return Ok(VerificationOutcome::Incomplete(vec![], mismatched_files));
+                None => println!("There is no checkpoint to resume from"),
             }
+        } else if use_checkpoints {
+            delete_verification_checkpoint(&mut conn, &self.id)?;
+        }
+        let _ = conn.close();
 
-            piece_idx += 1;
-            bar.inc(1);
+        // Unchanged pieces passed last time, so they are treated as passing again.
+        for (piece_idx, is_unchanged) in unchanged.iter().enumerate() {
+            if *is_unchanged {
+                piece_results.set(piece_idx, true);
+            }
+        }
+        let pieces_to_hash = (start_piece..num_pieces)
+            .filter(|i| hashable[*i] && !unchanged[*i] && piece_in_scope[*i])
+            .collect::<Vec<usize>>();
+        println!(
+            "The torrent has {} pieces to verify, using {} hashing threads",
+            pieces_to_hash.len(),
+            options.jobs
+        );
+        let bar = ProgressBar::new(pieces_to_hash.len() as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")?
+                .progress_chars("#>-"),
+        );
+        let throttle = ReadThrottle::new(options.max_read_rate);
+
+        // Results arrive out of order, so the checkpoint records how far the pieces have been
+        // hashed without any gaps. On a resume, anything after that point is hashed again. Pieces
+        // that can't be hashed, or don't need to be, count as being done.
+        let mut completed = (0..num_pieces)
+            .map(|i| !hashable[i] || unchanged[i] || !piece_in_scope[i])
+            .collect::<Vec<bool>>();
+        let mut next_piece = start_piece;
+        let mut last_checkpoint = Instant::now();
+        let hashing_result = hash_pieces(
+            layout,
+            options.jobs,
+            options.max_memory,
+            |submit| match source {
+                ContentSource::Directory(target_directory) => {
+                    for piece_idx in pieces_to_hash.iter() {
+                        let mut buffer = Vec::with_capacity(layout.piece_length as usize);
+                        layout.read_piece(target_directory, *piece_idx, &mut buffer)?;
+                        throttle.consume(buffer.len() as u64);
+                        submit(*piece_idx, buffer)?;
+                    }
+                    Ok(())
+                }
+                ContentSource::Zip(zip) => {
+                    // The whole zip has to be streamed, but only the pieces that need to be
+                    // verified are submitted.
+                    let to_hash = pieces_to_hash.iter().copied().collect::<BTreeSet<usize>>();
+                    zip.read_pieces(layout, &mut |piece_idx, buffer| {
+                        throttle.consume(buffer.len() as u64);
+                        if !to_hash.contains(&piece_idx) {
+                            return Ok(());
+                        }
+                        submit(piece_idx, buffer)
+                    })
+                }
+            },
+            |piece_idx, matched| {
+                piece_results.set(piece_idx, matched);
+                completed[piece_idx] = true;
+                while next_piece < num_pieces && completed[next_piece] {
+                    next_piece += 1;
+                }
+                bar.inc(1);
+                if let Some(rate) = throttle.rate() {
+                    bar.set_message(format!("{}/s", bytes_to_human_readable(rate)));
+                }
+                if use_checkpoints
+                    && next_piece > 0
+                    && last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL
+                {
+                    self.save_checkpoint(next_piece - 1, &piece_results, &file_states)?;
+                    last_checkpoint = Instant::now();
+                }
+                Ok(())
+            },
+        );
+        if let Err(e) = hashing_result {
+            if use_checkpoints && next_piece > 0 {
+                self.save_checkpoint(next_piece - 1, &piece_results, &file_states)?;
+            }
+            return Err(e);
+        }
+        bar.finish();
+        if use_checkpoints {
+            let mut conn = get_db_connection(get_database_path()?)?;
+            delete_verification_checkpoint(&mut conn, &self.id)?;
+            let _ = conn.close();
+        }
+
+        // When only some files were verified, the rest of the map is kept from last time.
+        let mut piece_map = match selected {
+            Some(_) => self
+                .get_piece_map()?
+                .filter(|m| m.len() == num_pieces)
+                .unwrap_or_else(|| PieceMap::new(num_pieces)),
+            None => PieceMap::new(num_pieces),
+        };
+        for piece_idx in (0..num_pieces).filter(|i| piece_in_scope[*i]) {
+            piece_map.set(
+                piece_idx,
+                hashable[piece_idx].then(|| piece_results.get(piece_idx)),
+            );
         }
+        self.save_piece_map(&piece_map)?;
 
-        Ok(VerificationOutcome::Verified)
+        // A corrupt piece doesn't stop the process. Every piece is checked, and each one that
+        // fails is mapped back to all the files it overlaps, so one run reports every corrupt file
+        // in the release.
+        let mut corrupt_file_indexes = BTreeSet::new();
+        let mut unverifiable_file_indexes = BTreeSet::new();
+        let mut failed_pieces = 0;
+        for (piece_idx, can_hash) in hashable.iter().enumerate() {
+            if !piece_in_scope[piece_idx] {
+                continue;
+            }
+            if !can_hash {
+                unverifiable_file_indexes.extend(layout.files_for_piece(piece_idx));
+            } else if !piece_results.get(piece_idx) {
+                failed_pieces += 1;
+                corrupt_file_indexes.extend(layout.files_for_piece(piece_idx));
+            }
+        }
+        // A boundary piece that fails also implicates the neighbour of a selected file, but the
+        // neighbour hasn't been fully checked, so it isn't reported.
+        corrupt_file_indexes.retain(in_scope);
+        unverifiable_file_indexes.retain(in_scope);
+
+        // The cache is updated on every run of files on disk, not just for incremental ones, so the
+        // next incremental run can skip anything that passed this time.
+        let mut cache_verified = Vec::new();
+        let mut cache_invalidated = Vec::new();
+        for (i, file) in layout.content_files() {
+            if !in_scope(&i) {
+                continue;
+            }
+            if corrupt_file_indexes.contains(&i) {
+                cache_invalidated.push(file.path.clone());
+            } else if !unverifiable_file_indexes.contains(&i) {
+                if let Some(state) = &current_file_states[i] {
+                    cache_verified.push((
+                        file.path.clone(),
+                        CachedFile {
+                            state: state.clone(),
+                            pieces: layout.pieces_for_file(i),
+                        },
+                    ));
+                }
+            }
+        }
+        if target_directory.is_some() {
+            let mut conn = get_db_connection(get_database_path()?)?;
+            update_file_cache(&mut conn, &self.id, &cache_verified, &cache_invalidated)?;
+            let _ = conn.close();
+        }
+
+        for i in corrupt_file_indexes.intersection(&waived_file_indexes) {
+            if let Some(rule) = ignore_rules.find_match(&files[*i].path) {
+                self.waived_files.push((
+                    files[*i].path.clone(),
+                    files[*i].length,
+                    rule.to_string(),
+                ));
+            }
+        }
+        if !self.waived_files.is_empty() {
+            println!(
+                "{} missing or corrupt files were waived by ignore rules",
+                self.waived_files.len()
+            );
+        }
+
+        // Only waived files are allowed to go unhashed. A file that shares a piece with a waived
+        // missing file is present but hasn't been checked, so the release can't be verified.
+        let unhashed_files = unverifiable_file_indexes
+            .iter()
+            .filter(|i| {
+                !waived_file_indexes.contains(i)
+                    && !missing_file_indexes.contains(i)
+                    && !files[**i].attributes.padding
+            })
+            .count();
+        if corrupt_file_indexes.is_subset(&waived_file_indexes)
+            && missing_files.is_empty()
+            && unhashed_files > 0
+        {
+            for (i, file) in layout.content_files() {
+                if unverifiable_file_indexes.contains(&i) && !missing_file_indexes.contains(&i) {
+                    self.unverifiable_files
+                        .push((file.path.clone(), file.length));
+                }
+            }
+            println!(
+                "{} files could not be verified because they share pieces with waived files that \
+                 are missing",
+                unhashed_files
+            );
+            return Ok(VerificationOutcome::Complete);
+        }
+        if corrupt_file_indexes.is_subset(&waived_file_indexes) && missing_files.is_empty() {
+            if options.file_hashes {
+                // Waived files, and any files sharing pieces with missing ones, don't get hashes
+                // because their content hasn't been verified. Padding files aren't part of the
+                // release, so they don't get them either.
+                let excluded_file_indexes = corrupt_file_indexes
+                    .union(&missing_file_indexes)
+                    .chain(unverifiable_file_indexes.iter())
+                    .copied()
+                    .chain(
+                        (0..files.len()).filter(|i| !in_scope(i) || files[*i].attributes.padding),
+                    )
+                    .collect::<BTreeSet<usize>>();
+                self.save_file_hashes(
+                    layout,
+                    Some((source, options)),
+                    vec![None; files.len()],
+                    &unchanged_file_indexes,
+                    &excluded_file_indexes,
+                )?;
+            }
+            for (i, file) in files.iter().enumerate() {
+                if unverifiable_file_indexes.contains(&i) && !missing_file_indexes.contains(&i) {
+                    self.unverifiable_files
+                        .push((file.path.clone(), file.length));
+                }
+            }
+            return Ok(VerificationOutcome::Verified);
+        }
+
+        if failed_pieces > 0 {
+            println!(
+                "{} of {} pieces failed verification, affecting {} files",
+                failed_pieces,
+                num_pieces,
+                corrupt_file_indexes.len()
+            );
+        }
+        let mut corrupted_files = Vec::new();
+        let mut verified_files = Vec::new();
+        let mut unverifiable_files = Vec::new();
+        for (i, file) in layout.content_files() {
+            if missing_file_indexes.contains(&i) || !in_scope(&i) {
+                continue;
+            }
+            if corrupt_file_indexes.contains(&i) {
+                if waived_file_indexes.contains(&i) {
+                    continue;
+                }
+                corrupted_files.push((file.path.clone(), file.length));
+            } else if unverifiable_file_indexes.contains(&i) {
+                unverifiable_files.push((file.path.clone(), file.length));
+            } else {
+                verified_files.push((file.path.clone(), file.length));
+            }
+        }
+        if !missing_files.is_empty() {
+            println!(
+                "{} files verified, {} corrupt, {} could not be verified because a neighbouring \
+                 file is missing",
+                verified_files.len(),
+                corrupted_files.len(),
+                unverifiable_files.len()
+            );
+        }
+        self.verified_files = verified_files;
+        self.unverifiable_files = unverifiable_files;
+        Ok(VerificationOutcome::Incomplete(
+            missing_files,
+            corrupted_files,
+        ))
+    }
+
+    /// Saves the SHA-256 and MD5 of every file in a verified release, apart from the excluded ones.
+    ///
+    /// This only happens once a release is verified, so the content is read again to compute them,
+    /// spread across the hashing threads. Files that were skipped by an incremental verification
+    /// keep the hashes they already have, and digests that were computed while downloading are used
+    /// as they are. When there is no source to read from, files without a digest are left out.
+    fn save_file_hashes(
+        &self,
+        layout: &PieceLayout,
+        source: Option<(&ContentSource, &VerifyOptions)>,
+        digests: Vec<Option<FileDigest>>,
+        unchanged_file_indexes: &BTreeSet<usize>,
+        excluded_file_indexes: &BTreeSet<usize>,
+    ) -> Result<()> {
+        let mut conn = get_db_connection(get_database_path()?)?;
+        let mut stored = get_file_hashes(&conn, &self.id)?;
+        let mut digests = digests
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !excluded_file_indexes.contains(i))
+            .map(|(i, digest)| {
+                let digest = digest.or_else(|| {
+                    if unchanged_file_indexes.contains(&i) {
+                        stored.remove(&layout.files[i].path)
+                    } else {
+                        None
+                    }
+                });
+                (i, digest)
+            })
+            .collect::<BTreeMap<usize, Option<FileDigest>>>();
+        let remaining = digests
+            .iter()
+            .filter(|(_, d)| d.is_none())
+            .map(|(i, _)| *i)
+            .collect::<Vec<usize>>();
+        if let (Some((source, options)), false) = (source, remaining.is_empty()) {
+            println!("Computing file hashes for {} files...", remaining.len());
+            for (i, digest) in compute_file_digests(layout, source, &remaining, options)? {
+                digests.insert(i, Some(digest));
+            }
+        }
+        let hashes = digests
+            .into_iter()
+            .filter_map(|(i, digest)| {
+                let file = &layout.files[i];
+                digest.map(|digest| (file.path.clone(), file.length, digest))
+            })
+            .collect::<Vec<_>>();
+        save_file_hashes(&mut conn, &self.id, &hashes)?;
+        let _ = conn.close();
+        println!("Saved SHA-256 and MD5 hashes for {} files", hashes.len());
+        Ok(())
+    }
+
+    fn get_ignore_rules(&self) -> Result<IgnoreRules> {
+        let conn = get_db_connection(get_database_path()?)?;
+        let rules = get_ignore_rules(&conn, Some(&self.id))?;
+        let _ = conn.close();
+        IgnoreRules::new(rules)
+    }
+
+    fn save_checkpoint(
+        &self,
+        last_piece: usize,
+        piece_results: &Bitfield,
+        file_states: &[(PathBuf, u64, i64)],
+    ) -> Result<()> {
+        let checkpoint = VerificationCheckpoint {
+            last_piece,
+            piece_results: piece_results.clone(),
+            files: file_states.to_vec(),
+        };
+        let mut conn = get_db_connection(get_database_path()?)?;
+        save_verification_checkpoint(&mut conn, &self.id, &checkpoint)?;
+        let _ = conn.close();
+        Ok(())
     }
 
     pub fn mark_incomplete(
@@ -920,81 +2892,618 @@ impl Release {
         Ok(())
     }
 
-    pub fn mark_missing(&mut self) -> Result<()> {
-        self.verification_outcome = Some(VerificationOutcome::AllFilesMissing);
-        Ok(())
+    pub fn mark_missing(&mut self) -> Result<()> {
+        self.verification_outcome = Some(VerificationOutcome::AllFilesMissing);
+        Ok(())
+    }
+
+    fn read_file_lines_as_paths_in_tree(
+        path: &Path,
+        tree: &[(PathBuf, u64)],
+    ) -> Result<Vec<(PathBuf, u64)>> {
+        let file = File::open(path)?;
+        let reader = std::io::BufReader::new(file);
+        let mut paths: Vec<(PathBuf, u64)> = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let path_in_file = PathBuf::from(line);
+            let entry_in_tree = tree.iter().find(|f| f.0 == path_in_file).ok_or_else(|| {
+                Error::MarkIncompleteInvalidPath(path_in_file.to_string_lossy().to_string())
+            })?;
+            paths.push(entry_in_tree.clone());
+        }
+        Ok(paths)
+    }
+
+    pub(crate) fn get_release_14_url(
+        release_14_links: &HashMap<PathBuf, String>,
+        release_14_file_links: &HashMap<PathBuf, String>,
+        path: PathBuf,
+        file_name: &str,
+    ) -> Result<Option<Url>> {
+        if let Some(link) = release_14_file_links.get(&path) {
+            let url = Url::parse(link)?;
+            return Ok(Some(url));
+        }
+        let parent = path.parent().unwrap();
+        if let Some(link) = release_14_links.get(parent) {
+            let base_url = Url::parse(link)?;
+            let mut url = base_url.clone();
+            {
+                let mut path_segments = match url.path_segments_mut() {
+                    Ok(segments) => segments,
+                    Err(_) => return Err(Error::PathSegmentsParseError),
+                };
+                path_segments.push(file_name);
+            }
+            return Ok(Some(url));
+        } else {
+            // This is to attempt to get a link for the slightly rarer cases where there are sub
+            // directories, e.g., ABCatlanta/ABC Atlanta Broadcast 10 to noon/.
+            let grand_parent = parent.parent().unwrap();
+            if let Some(link) = release_14_links.get(grand_parent) {
+                let base_url = Url::parse(link)?;
+                let mut url = base_url.clone();
+                {
+                    let mut path_segments = match url.path_segments_mut() {
+                        Ok(segments) => segments,
+                        Err(_) => return Err(Error::PathSegmentsParseError),
+                    };
+                    let mut parent_name = parent.file_stem().unwrap().to_string_lossy().to_string();
+                    let parent_name = if parent_name.starts_with("ABC NIST Dub") {
+                        // This is another annoying special case. On the torrent, the directory
+                        // name refers to each sub directory using a '#' character, but it's not
+                        // present on the Archive.
+                        parent_name.retain(|c| c != '#');
+                        parent_name
+                    } else {
+                        parent_name
+                    };
+                    path_segments.push(&parent_name);
+                    path_segments.push(file_name);
+                }
+                return Ok(Some(url));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A file in the torrent tree to be fetched by `Release::download_files`.
+struct DownloadJob {
+    file_idx: usize,
+    target_path: PathBuf,
+    /// Where the file was found on disk. Only a missing file is downloaded.
+    resolved: ResolvedPath,
+    /// Where the file can be fetched from, along with the index of the source each place belongs
+    /// to. They are tried in order until one succeeds. It's empty if there's nowhere to get it.
+    locations: Vec<(usize, FileLocation)>,
+}
+
+/// What the verifier is told about the files of a release as they are downloaded.
+enum DownloadEvent {
+    /// Content of the file being streamed to the verifier, with the index of its job and the
+    /// offset in the file.
+    Data(usize, u64, Vec<u8>),
+    /// The file being streamed is starting again from another source, so the content that was
+    /// already streamed has to be forgotten.
+    Restart(usize),
+    /// A file has been dealt with. Its content is at the path if it's on disk, and has already
+    /// been seen if it was streamed. A file that was downloaded ahead of its turn may come with
+    /// its content, so it doesn't have to be read back.
+    Done {
+        job_idx: usize,
+        path: Option<PathBuf>,
+        streamed: bool,
+        content: Option<Vec<u8>>,
+    },
+}
+
+/// Decides how a download passes its content to the verifier.
+///
+/// A download that isn't first in line holds on to its content for as long as it fits within
+/// `MAX_BUFFERED_AHEAD`, counting what the other downloads hold. Once it's first in line, what it
+/// held is passed on and the rest is streamed.
+struct JobStream {
+    job_idx: usize,
+    streaming: bool,
+    /// The content from the start of the file, or `None` once it has given up holding it.
+    buffer: Option<Vec<u8>>,
+}
+
+impl JobStream {
+    fn new(job_idx: usize) -> Self {
+        Self {
+            job_idx,
+            streaming: false,
+            buffer: Some(Vec::new()),
+        }
+    }
+
+    /// Returns what the verifier should be sent for content at an offset in the file.
+    fn on_data(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        first_in_line: bool,
+        buffered: &AtomicUsize,
+    ) -> Vec<DownloadEvent> {
+        if self.streaming {
+            return vec![DownloadEvent::Data(self.job_idx, offset, data.to_vec())];
+        }
+        let Some(buffer) = &mut self.buffer else {
+            return Vec::new();
+        };
+        let offset = offset as usize;
+        // A retry passes on the content of the `.part` file again from the start.
+        if offset < buffer.len() {
+            buffered.fetch_sub(buffer.len() - offset, Ordering::SeqCst);
+            buffer.truncate(offset);
+        }
+        if first_in_line && offset == buffer.len() {
+            self.streaming = true;
+            let buffer = self.buffer.take().unwrap();
+            buffered.fetch_sub(buffer.len(), Ordering::SeqCst);
+            let mut events = Vec::new();
+            if !buffer.is_empty() {
+                events.push(DownloadEvent::Data(self.job_idx, 0, buffer));
+            }
+            events.push(DownloadEvent::Data(
+                self.job_idx,
+                offset as u64,
+                data.to_vec(),
+            ));
+            return events;
+        }
+        let total = buffered.fetch_add(data.len(), Ordering::SeqCst) + data.len();
+        if offset == buffer.len() && total <= MAX_BUFFERED_AHEAD {
+            buffer.extend_from_slice(data);
+        } else {
+            buffered.fetch_sub(buffer.len() + data.len(), Ordering::SeqCst);
+            self.buffer = None;
+        }
+        Vec::new()
+    }
+
+    /// Forgets the content so far, because the download is starting again from another source.
+    /// Returns the event that tells the verifier, if it has seen any of it.
+    fn restart(&mut self, buffered: &AtomicUsize) -> Option<DownloadEvent> {
+        if self.streaming {
+            return Some(DownloadEvent::Restart(self.job_idx));
+        }
+        if let Some(buffer) = &self.buffer {
+            buffered.fetch_sub(buffer.len(), Ordering::SeqCst);
+        }
+        self.buffer = Some(Vec::new());
+        None
+    }
+
+    /// Returns whether the content was streamed, and the content that was held if it wasn't and
+    /// the download succeeded.
+    fn finish(self, downloaded: bool, buffered: &AtomicUsize) -> (bool, Option<Vec<u8>>) {
+        match self.buffer {
+            Some(buffer) if downloaded && !self.streaming => (false, Some(buffer)),
+            Some(buffer) => {
+                buffered.fetch_sub(buffer.len(), Ordering::SeqCst);
+                (self.streaming, None)
+            }
+            None => (self.streaming, None),
+        }
+    }
+}
+
+/// Where the content of a release is read from when it is verified.
+enum ContentSource<'a> {
+    Directory(&'a Path),
+    Zip(ZipSource),
+}
+
+impl ContentSource<'_> {
+    fn directory(&self) -> Option<&Path> {
+        match self {
+            ContentSource::Directory(target_directory) => Some(target_directory),
+            ContentSource::Zip(_) => None,
+        }
+    }
+
+    /// Whether the source has content for the file at the index in the torrent.
+    ///
+    /// Padding files are never stored, but their content is always known to be zeros.
+    fn contains(&self, layout: &PieceLayout, file_idx: usize) -> bool {
+        if layout.files[file_idx].attributes.padding {
+            return true;
+        }
+        match self {
+            ContentSource::Directory(target_directory) => target_directory
+                .join(&layout.files[file_idx].disk_path)
+                .exists(),
+            ContentSource::Zip(zip) => zip.entries[file_idx].is_some(),
+        }
+    }
+}
+
+/// Computes the SHA-256 and MD5 of the files at the given indexes, reading them from the source.
+///
+/// The files are spread across the hashing threads, each of which reads and hashes whole files, so
+/// both digests are computed in parallel rather than on the thread doing the reading.
+fn compute_file_digests(
+    layout: &PieceLayout,
+    source: &ContentSource,
+    file_indexes: &[usize],
+    options: &VerifyOptions,
+) -> Result<Vec<(usize, FileDigest)>> {
+    let bar = ProgressBar::new(file_indexes.iter().map(|i| layout.files[*i].length).sum());
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {binary_bytes_per_sec}",
+            )?
+            .progress_chars("#>-"),
+    );
+    let throttle = ReadThrottle::new(options.max_read_rate);
+    let on_read = |read: u64| {
+        throttle.consume(read);
+        bar.inc(read);
+    };
+    let next = AtomicUsize::new(0);
+    let digests = std::thread::scope(|scope| {
+        let workers = (0..options.jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    // Each thread needs its own handle on a zip to read entries from it.
+                    let mut archive = match source {
+                        ContentSource::Zip(zip) => Some(zip.open_archive()?),
+                        ContentSource::Directory(_) => None,
+                    };
+                    let mut digests = Vec::new();
+                    loop {
+                        let n = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(i) = file_indexes.get(n) else {
+                            return Ok(digests);
+                        };
+                        let path = &layout.files[*i].disk_path;
+                        let digest = match (source, archive.as_mut()) {
+                            (ContentSource::Directory(target_directory), _) => {
+                                let mut file = File::open(target_directory.join(path))?;
+                                Some(FileDigest::from_reader(&mut file, &on_read)?)
+                            }
+                            (ContentSource::Zip(zip), Some(archive)) => {
+                                zip.file_digest(archive, *i, &on_read)?
+                            }
+                            (ContentSource::Zip(_), None) => None,
+                        };
+                        if let Some(digest) = digest {
+                            digests.push((*i, digest));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut digests = Vec::new();
+        for worker in workers {
+            let worker_digests: Result<Vec<(usize, FileDigest)>> =
+                worker.join().map_err(|_| Error::HashingStopped)?;
+            digests.extend(worker_digests?);
+        }
+        Ok::<_, Error>(digests)
+    })?;
+    bar.finish();
+    Ok(digests)
+}
+
+/// Feeds the verifier the content of the files as `Release::download_files` downloads them, and
+/// returns what it found once the downloads are over.
+///
+/// Each job is dealt with in order, once it's done: a file that was streamed has already been
+/// seen, one whose content was held in memory is pushed from there, and any other file that's on
+/// disk is read back. It runs on a blocking thread, since the
+/// hashing would otherwise hold up the downloads sharing the runtime.
+fn verify_downloads(
+    mut verifier: DownloadVerifier,
+    mut events_rx: Receiver<DownloadEvent>,
+    file_indexes: Vec<usize>,
+    next_job: &AtomicUsize,
+    buffered: &AtomicUsize,
+) -> Result<DownloadResults> {
+    let mut done = HashMap::new();
+    while let Some(event) = events_rx.blocking_recv() {
+        match event {
+            DownloadEvent::Data(job_idx, offset, data) => {
+                verifier.push(file_indexes[job_idx], offset, &data)?
+            }
+            DownloadEvent::Restart(job_idx) => verifier.restart(file_indexes[job_idx])?,
+            DownloadEvent::Done {
+                job_idx,
+                path,
+                streamed,
+                content,
+            } => {
+                done.insert(job_idx, (path, streamed, content));
+            }
+        }
+        loop {
+            let job_idx = next_job.load(Ordering::SeqCst);
+            let Some((path, streamed, content)) = done.remove(&job_idx) else {
+                break;
+            };
+            match (path, streamed, content) {
+                (Some(_), false, Some(content)) => {
+                    verifier.push(file_indexes[job_idx], 0, &content)?;
+                    buffered.fetch_sub(content.len(), Ordering::SeqCst);
+                }
+                (Some(path), false, None) => {
+                    push_file_to_verifier(&mut verifier, file_indexes[job_idx], &path)?
+                }
+                // Part of the file may have been seen before its download failed.
+                (None, true, _) => verifier.abandon(file_indexes[job_idx])?,
+                _ => {}
+            }
+            next_job.store(job_idx + 1, Ordering::SeqCst);
+        }
+    }
+    verifier.finish()
+}
+
+/// Pushes the content of a file on disk to the verifier.
+fn push_file_to_verifier(
+    verifier: &mut DownloadVerifier,
+    file_idx: usize,
+    path: &Path,
+) -> Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut offset = 0;
+    loop {
+        let read = std::io::Read::read(&mut file, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        verifier.push(file_idx, offset, &buffer[..read])?;
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+/// Finds the pieces that are already present under the target directory, so they don't have to
+/// be downloaded again. Pieces that overlap a file that doesn't exist aren't read.
+fn check_existing_pieces(layout: &PieceLayout, target_directory: &Path) -> Result<Bitfield> {
+    let mut have = Bitfield::new(layout.piece_count());
+    let present = layout
+        .files
+        .iter()
+        .map(|file| target_directory.join(&file.disk_path).is_file())
+        .collect::<Vec<bool>>();
+    let pieces_to_hash = (0..layout.piece_count())
+        .filter(|piece_idx| {
+            layout
+                .files_for_piece(*piece_idx)
+                .iter()
+                .all(|file_idx| present[*file_idx])
+        })
+        .collect::<Vec<usize>>();
+    if pieces_to_hash.is_empty() {
+        return Ok(have);
+    }
+    println!(
+        "Checking {} pieces that are already on disk...",
+        pieces_to_hash.len()
+    );
+    let options = VerifyOptions::default();
+    hash_pieces(
+        layout,
+        options.jobs,
+        options.max_memory,
+        |submit| {
+            for piece_idx in pieces_to_hash.iter() {
+                let mut buffer = Vec::with_capacity(layout.piece_length as usize);
+                layout.read_piece(target_directory, *piece_idx, &mut buffer)?;
+                submit(*piece_idx, buffer)?;
+            }
+            Ok(())
+        },
+        |piece_idx, matched| {
+            have.set(piece_idx, matched);
+            Ok(())
+        },
+    )?;
+    Ok(have)
+}
+
+/// A row of a piece map, as it is printed.
+struct PieceMapRow<'a> {
+    first_piece: usize,
+    /// The result for each cell, which is the worst result among its pieces: a failure, then a
+    /// piece that wasn't hashed.
+    cells: Vec<Option<bool>>,
+    /// The files that begin in the row, relative to the release directory.
+    files: Vec<&'a Path>,
+}
+
+/// Arranges a piece map into rows of `columns` cells, putting as many pieces in each cell as it
+/// takes to fit in `PIECE_MAP_MAX_ROWS` rows. Returns the number of pieces in each cell along with
+/// the rows.
+fn arrange_piece_map<'a>(
+    layout: &'a PieceLayout,
+    piece_map: &PieceMap,
+    directory: Option<&str>,
+    columns: usize,
+) -> (usize, Vec<PieceMapRow<'a>>) {
+    let num_pieces = layout.piece_count();
+    let pieces_per_cell = std::cmp::max(num_pieces.div_ceil(columns * PIECE_MAP_MAX_ROWS), 1);
+    let cells = (0..num_pieces)
+        .step_by(pieces_per_cell)
+        .map(|start| {
+            let results = (start..std::cmp::min(start + pieces_per_cell, num_pieces))
+                .map(|i| piece_map.get(i))
+                .collect::<Vec<Option<bool>>>();
+            if results.contains(&Some(false)) {
+                Some(false)
+            } else if results.contains(&None) {
+                None
+            } else {
+                Some(true)
+            }
+        })
+        .collect::<Vec<Option<bool>>>();
+    let mut rows = cells
+        .chunks(columns)
+        .enumerate()
+        .map(|(row_idx, cells)| PieceMapRow {
+            first_piece: row_idx * columns * pieces_per_cell,
+            cells: cells.to_vec(),
+            files: Vec::new(),
+        })
+        .collect::<Vec<PieceMapRow>>();
+    for (i, file) in layout.content_files() {
+        let pieces = layout.pieces_for_file(i);
+        if !pieces.is_empty() {
+            let path = match directory {
+                Some(directory) => file.path.strip_prefix(directory).unwrap_or(&file.path),
+                None => &file.path,
+            };
+            rows[pieces.start / pieces_per_cell / columns]
+                .files
+                .push(path);
+        }
+    }
+    (pieces_per_cell, rows)
+}
+
+/// Finds the files that are missing after a release was downloaded from its swarm, given the
+/// pieces it has and the files that were on disk beforehand.
+///
+/// A file that overlaps a piece that wasn't obtained is missing, except one that was already on
+/// disk when the piece also spans a file that wasn't. That piece could never be checked, so the
+/// file keeps its content and can't be verified, just as when the release is verified.
+fn get_files_missing_from_swarm(
+    layout: &PieceLayout,
+    have: &Bitfield,
+    present: &[bool],
+) -> BTreeSet<usize> {
+    let mut missing_file_indexes = BTreeSet::new();
+    for piece_idx in (0..layout.piece_count()).filter(|i| !have.get(*i)) {
+        let file_indexes = layout.files_for_piece(piece_idx);
+        let checked = file_indexes.iter().all(|i| present[*i]);
+        missing_file_indexes.extend(file_indexes.iter().filter(|i| checked || !present[**i]));
     }
+    missing_file_indexes
+}
 
-    fn read_file_lines_as_paths_in_tree(
-        path: &Path,
-        tree: &Vec<(PathBuf, u64)>,
-    ) -> Result<Vec<(PathBuf, u64)>> {
-        let file = File::open(path)?;
-        let reader = std::io::BufReader::new(file);
-        let mut paths: Vec<(PathBuf, u64)> = Vec::new();
-        for line in reader.lines() {
-            let line = line?;
-            let path_in_file = PathBuf::from(line);
-            let entry_in_tree = tree.iter().find(|f| f.0 == path_in_file).ok_or_else(|| {
-                Error::MarkIncompleteInvalidPath(path_in_file.to_string_lossy().to_string())
-            })?;
-            paths.push(entry_in_tree.clone());
+/// Tidies up the files of a release after its pieces were downloaded.
+///
+/// Pieces are written into files that may have been there before, so a file that was too long
+/// is cut to the right length once all of its pieces are present. Empty files aren't covered by
+/// any piece, so they are created here.
+fn finish_downloaded_files(
+    layout: &PieceLayout,
+    target_directory: &Path,
+    have: &Bitfield,
+) -> Result<()> {
+    for (file_idx, file) in layout.content_files() {
+        let path = target_directory.join(&file.disk_path);
+        if file.length == 0 {
+            if !path.exists() {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                File::create(&path)?;
+            }
+            continue;
+        }
+        if !layout
+            .pieces_for_file(file_idx)
+            .all(|piece_idx| have.get(piece_idx))
+        {
+            continue;
+        }
+        let on_disk = std::fs::OpenOptions::new().write(true).open(&path)?;
+        if on_disk.metadata()?.len() > file.length {
+            on_disk.set_len(file.length)?;
         }
-        Ok(paths)
     }
+    Ok(())
+}
 
-    fn get_release_14_url(
-        release_14_links: &HashMap<PathBuf, String>,
-        release_14_file_links: &HashMap<PathBuf, String>,
-        path: PathBuf,
-        file_name: &str,
-    ) -> Result<Option<Url>> {
-        if let Some(link) = release_14_file_links.get(&path) {
-            let url = Url::parse(link)?;
-            return Ok(Some(url));
+/// Reads the layout of a torrent of any version.
+///
+/// The files of a hybrid torrent are taken from its v2 file tree, which is what it is verified
+/// against, and which doesn't have the padding files of the v1 file list.
+fn get_piece_layout_from_bytes(torrent_content: Vec<u8>) -> Result<PieceLayout> {
+    match get_torrent_version(&torrent_content)? {
+        TorrentVersion::V1 => {
+            PieceLayout::from_torrent(&Torrent::read_from_bytes(torrent_content)?)
         }
-        let parent = path.parent().unwrap();
-        if let Some(link) = release_14_links.get(parent) {
-            let base_url = Url::parse(link)?;
-            let mut url = base_url.clone();
-            {
-                let mut path_segments = match url.path_segments_mut() {
-                    Ok(segments) => segments,
-                    Err(_) => return Err(Error::PathSegmentsParseError),
-                };
-                path_segments.push(&file_name);
-            }
-            return Ok(Some(url));
-        } else {
-            // This is to attempt to get a link for the slightly rarer cases where there are sub
-            // directories, e.g., ABCatlanta/ABC Atlanta Broadcast 10 to noon/.
-            let grand_parent = parent.parent().unwrap();
-            if let Some(link) = release_14_links.get(grand_parent) {
-                let base_url = Url::parse(link)?;
-                let mut url = base_url.clone();
-                {
-                    let mut path_segments = match url.path_segments_mut() {
-                        Ok(segments) => segments,
-                        Err(_) => return Err(Error::PathSegmentsParseError),
-                    };
-                    let mut parent_name = parent.file_stem().unwrap().to_string_lossy().to_string();
-                    let parent_name = if parent_name.starts_with("ABC NIST Dub") {
-                        // This is another annoying special case. On the torrent, the directory
-                        // name refers to each sub directory using a '#' character, but it's not
-                        // present on the Archive.
-                        parent_name.retain(|c| c != '#');
-                        parent_name
-                    } else {
-                        parent_name
-                    };
-                    path_segments.push(&parent_name);
-                    path_segments.push(&file_name);
-                }
-                return Ok(Some(url));
-            }
+        _ => Ok(PieceLayout::from_v2_torrent(&V2Torrent::read_from_bytes(
+            &torrent_content,
+        )?)),
+    }
+}
+
+/// Finds the files in the layout on disk, and reports any that were only found after normalising
+/// their names.
+///
+/// If `fix_names` is set, those files are renamed to match the torrent; otherwise they are read
+/// from where they were found.
+fn resolve_file_names(
+    layout: &mut PieceLayout,
+    target_directory: &Path,
+    fix_names: bool,
+) -> Result<()> {
+    let normalised = layout.resolve_disk_paths(target_directory)?;
+    if normalised.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "{} files were only found after normalising the case or Unicode form of their names:",
+        normalised.len()
+    );
+    let renames = normalised
+        .iter()
+        .map(|(i, expected_path)| (expected_path.clone(), layout.files[*i].disk_path.clone()))
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    for (expected_path, disk_path) in renames.iter() {
+        println!("{} -> {}", disk_path.display(), expected_path.display());
+    }
+    if fix_names {
+        paths::fix_names(target_directory, &renames)?;
+        for (i, expected_path) in normalised {
+            layout.files[i].disk_path = expected_path;
         }
-        Ok(None)
+        println!("Renamed {} files to match the torrent", renames.len());
+    } else {
+        println!("Use --fix-names to rename them to match the torrent");
     }
+    Ok(())
+}
+
+/// Gets the state of each file in the torrent tree, or `None` for files that are missing.
+fn get_file_states(
+    layout: &PieceLayout,
+    target_directory: &Path,
+) -> Result<Vec<Option<FileState>>> {
+    layout
+        .files
+        .iter()
+        .map(|file| FileState::read(&target_directory.join(&file.disk_path)))
+        .collect()
+}
+
+/// Converts file states to the form they are stored in a verification checkpoint.
+///
+/// A missing file is recorded with a size of 0 and a modification time of -1.
+fn get_checkpoint_file_states(
+    layout: &PieceLayout,
+    file_states: &[Option<FileState>],
+) -> Vec<(PathBuf, u64, i64)> {
+    layout
+        .files
+        .iter()
+        .zip(file_states.iter())
+        .map(|(file, state)| match state {
+            Some(state) => (file.path.clone(), state.size, state.mtime),
+            None => (file.path.clone(), 0, -1),
+        })
+        .collect()
 }
 
 pub fn bytes_to_human_readable(bytes: u64) -> String {
@@ -1020,53 +3529,269 @@ fn get_file_name_from_url(url: &Url) -> Result<String> {
     let file_name = url
         .path_segments()
         .ok_or(Error::PathSegmentsParseError)?
-        .last()
+        .next_back()
         .ok_or(Error::PathSegmentsParseError)?;
     Ok(file_name.to_string())
 }
 
-pub async fn download_file(url: &Url, target_path: &PathBuf, file_pb: &ProgressBar) -> Result<()> {
-    let client = reqwest::Client::new();
+/// Builds the HTTP client that downloads share, so connections to the same host are reused.
+///
+/// Only connecting has a timeout here, since a large file can take hours to download. A download
+/// that stops receiving data is caught by `READ_TIMEOUT` instead.
+pub fn build_http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?)
+}
+
+pub async fn download_file(
+    client: &reqwest::Client,
+    url: &Url,
+    target_path: &PathBuf,
+    file_pb: &ProgressBar,
+    throttle: &DownloadThrottle,
+    retry: &RetryPolicy,
+) -> Result<()> {
+    download_file_streaming(
+        client,
+        url,
+        target_path,
+        file_pb,
+        throttle,
+        retry,
+        &mut |_, _| Box::pin(async { Ok(()) }),
+    )
+    .await
+}
+
+/// Receives the content of a file as it is downloaded, along with the offset of each chunk. The
+/// download waits for the future it returns before carrying on.
+pub type OnData<'a> = dyn FnMut(u64, &[u8]) -> BoxFuture<'static, Result<()>> + 'a;
+
+/// Downloads a file, passing its content to `on_data` along with the offset of each chunk. The
+/// download waits for the future it returns, so it can be held up until the content is dealt with.
+///
+/// If the download resumes from a `.part` file, the content already in it is passed first, so
+/// `on_data` always sees the file from the start. It will see that content again if the download
+/// is retried. The `.part` suffix is added to the whole file name, so files that only differ in
+/// their extension can be downloaded at the same time.
+///
+/// Each chunk is passed through the throttle as it arrives, so the download is slowed down to the
+/// rate it allows. Transient failures are retried according to the retry policy, resuming from
+/// the `.part` file each time; the error is returned once the policy gives up.
+pub async fn download_file_streaming(
+    client: &reqwest::Client,
+    url: &Url,
+    target_path: &PathBuf,
+    file_pb: &ProgressBar,
+    throttle: &DownloadThrottle,
+    retry: &RetryPolicy,
+    on_data: &mut OnData<'_>,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        match download_file_attempt(client, url, target_path, file_pb, throttle, on_data).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                let Some(delay) = retry.next_delay(&e, attempt) else {
+                    return Err(e);
+                };
+                file_pb.set_message(format!(
+                    "Attempt {attempt} failed: {e}. Retrying in {}s.",
+                    delay.as_secs()
+                ));
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn download_file_attempt(
+    client: &reqwest::Client,
+    url: &Url,
+    target_path: &PathBuf,
+    file_pb: &ProgressBar,
+    throttle: &DownloadThrottle,
+    on_data: &mut OnData<'_>,
+) -> Result<()> {
     let mut request_builder = client.get(url.clone());
-    let tmp_path = target_path.with_extension("part");
+    let tmp_path = part_path(target_path);
+    let source_path = part_source_path(target_path);
+    adopt_legacy_part_file(target_path).await?;
 
+    // A partial download is only resumed from the place it came from. One without a record of
+    // where it came from was left by an older version, which only downloaded from the Archive.
+    if let Ok(source) = tokio::fs::read_to_string(&source_path).await {
+        if source != url.as_str() {
+            discard_partial_download(target_path).await?;
+        }
+    }
     let mut start = 0;
     if tmp_path.exists() {
         start = tokio::fs::metadata(&tmp_path).await?.len() as usize;
-        file_pb.set_position(start as u64);
         request_builder = request_builder.header("Range", format!("bytes={}-", start));
     }
+    file_pb.set_position(start as u64);
 
-    let mut response = request_builder.send().await?;
+    let stalled = || Error::DownloadStalled(url.to_string(), READ_TIMEOUT.as_secs());
+    let mut response = timeout(READ_TIMEOUT, request_builder.send())
+        .await
+        .map_err(|_| stalled())??;
     if response.status() == 404 {
         return Err(Error::ArchiveFileNotFoundError(url.to_string()));
     }
     if !response.status().is_success() {
-        return Err(Error::ArchiveDownloadFailed(response.status().into()));
+        let retry_after = match response.status().as_u16() {
+            429 | 503 => response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+            _ => None,
+        };
+        return Err(Error::ArchiveDownloadFailed {
+            url: url.to_string(),
+            status: response.status(),
+            retry_after,
+        });
     }
+    file_pb.set_message("");
 
     if let Some(len) = response.content_length() {
-        file_pb.set_length(len);
+        file_pb.set_length(start as u64 + len);
     }
     let file = if start > 0 {
+        let mut part = tokio::fs::File::open(&tmp_path).await?;
+        let mut buffer = vec![0; 1024 * 1024];
+        let mut offset = 0;
+        while offset < start as u64 {
+            let read = part.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            on_data(offset, &buffer[..read]).await?;
+            offset += read as u64;
+        }
         OpenOptions::new().append(true).open(&tmp_path).await?
     } else {
         tokio::fs::File::create(&tmp_path).await?
     };
+    tokio::fs::write(&source_path, url.as_str()).await?;
 
     let mut writer = BufWriter::new(file);
-    while let Some(chunk) = response.chunk().await? {
+    let mut offset = start as u64;
+    while let Some(chunk) = timeout(READ_TIMEOUT, response.chunk())
+        .await
+        .map_err(|_| stalled())??
+    {
         writer.write_all(&chunk).await?;
+        on_data(offset, &chunk).await?;
+        offset += chunk.len() as u64;
         file_pb.inc(chunk.len() as u64);
+        throttle.consume(chunk.len() as u64).await;
+    }
+
+    writer.flush().await?;
+    tokio::fs::rename(&tmp_path, target_path).await?;
+    tokio::fs::remove_file(&source_path).await?;
+
+    Ok(())
+}
+
+/// Copies a file from a local source, passing its content to `on_data` in the same way as
+/// [`download_file_streaming`].
+///
+/// It is copied to a `.part` file first, so an interrupted copy is never mistaken for a
+/// downloaded file. The copy always starts from the beginning, and isn't throttled.
+async fn copy_file_streaming(
+    source_path: &Path,
+    target_path: &Path,
+    file_pb: &ProgressBar,
+    on_data: &mut OnData<'_>,
+) -> Result<()> {
+    let tmp_path = part_path(target_path);
+    let part_source_path = part_source_path(target_path);
+
+    let mut source = tokio::fs::File::open(source_path).await?;
+    file_pb.set_position(0);
+    file_pb.set_length(source.metadata().await?.len());
+    file_pb.set_message("");
+
+    let mut writer = BufWriter::new(tokio::fs::File::create(&tmp_path).await?);
+    // This stops a download from a URL resuming the copy if it is interrupted.
+    tokio::fs::write(&part_source_path, source_path.to_string_lossy().as_bytes()).await?;
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut offset = 0;
+    loop {
+        let read = source.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read]).await?;
+        on_data(offset, &buffer[..read]).await?;
+        offset += read as u64;
+        file_pb.inc(read as u64);
     }
 
     writer.flush().await?;
     tokio::fs::rename(&tmp_path, target_path).await?;
+    tokio::fs::remove_file(&part_source_path).await?;
+
+    Ok(())
+}
+
+/// The path a file is downloaded to until it is complete.
+fn part_path(target_path: &Path) -> PathBuf {
+    let mut path = target_path.to_path_buf().into_os_string();
+    path.push(".part");
+    PathBuf::from(path)
+}
 
+/// The path that records where the content of a `.part` file came from.
+fn part_source_path(target_path: &Path) -> PathBuf {
+    let mut path = target_path.to_path_buf().into_os_string();
+    path.push(".part.source");
+    PathBuf::from(path)
+}
+
+/// Renames a partial download left by an older version, which replaced the extension of the file
+/// with `.part`, so it can be resumed. A `.part` file with a record of where it came from belongs
+/// to another file, since older versions didn't keep one.
+async fn adopt_legacy_part_file(target_path: &Path) -> Result<()> {
+    let legacy_path = target_path.with_extension("part");
+    let tmp_path = part_path(target_path);
+    let mut legacy_source_path = legacy_path.clone().into_os_string();
+    legacy_source_path.push(".source");
+    if legacy_path == tmp_path
+        || tmp_path.exists()
+        || !legacy_path.is_file()
+        || Path::new(&legacy_source_path).exists()
+    {
+        return Ok(());
+    }
+    tokio::fs::rename(&legacy_path, &tmp_path).await?;
+    Ok(())
+}
+
+/// Deletes a partial download, and the record of where it came from, so the file can be
+/// downloaded again from the start.
+async fn discard_partial_download(target_path: &Path) -> Result<()> {
+    for path in [part_path(target_path), part_source_path(target_path)] {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
     Ok(())
 }
 
-pub async fn download_torrents(conn: &Connection, target_path: &PathBuf) -> Result<()> {
+pub async fn download_torrents(
+    conn: &Connection,
+    target_path: &Path,
+    throttle: &DownloadThrottle,
+    retry: &RetryPolicy,
+) -> Result<()> {
     println!(
         "Saving torrents to temporary directory at {}",
         target_path.to_string_lossy()
@@ -1083,18 +3808,19 @@ pub async fn download_torrents(conn: &Connection, target_path: &PathBuf) -> Resu
     file_pb.set_style(
         ProgressStyle::default_bar()
             .template(
-                "{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec}",
+                "{prefix:.bold.dim} [{bar:30.green/blue}] {bytes}/{total_bytes} {bytes_per_sec} {msg}",
             )?
             .progress_chars("=> "),
     );
 
+    let client = build_http_client()?;
     for item in RELEASE_DATA.iter() {
         let date = item.0.to_string();
         let torrent_url = item.1.to_string();
         let name = item.2.to_string();
         let release_id = Release::generate_id(&date, &name);
 
-        if torrent_url.is_empty() || torrent_already_saved(&conn, &release_id)? {
+        if torrent_url.is_empty() || torrent_already_saved(conn, &release_id)? {
             total_pb.inc(1);
             continue;
         }
@@ -1106,11 +3832,19 @@ pub async fn download_torrents(conn: &Connection, target_path: &PathBuf) -> Resu
         file_pb.set_prefix(format!("Downloading: {}", file_name));
         file_pb.set_position(0);
 
-        download_file(&torrent_url, &torrent_path, &file_pb).await?;
+        download_file(
+            &client,
+            &torrent_url,
+            &torrent_path,
+            &file_pb,
+            throttle,
+            retry,
+        )
+        .await?;
         file_pb.finish_with_message("Download completed");
 
         let content = std::fs::read(&torrent_path)?;
-        save_torrent(&conn, &release_id, &file_name, &content)?;
+        save_torrent(conn, &release_id, &file_name, &content)?;
 
         total_pb.inc(1);
     }
@@ -1160,7 +3894,7 @@ pub fn build_release_14_file_links(conn: &Connection) -> Result<()> {
 
 pub fn build_partial_release_11_from_nist_202(
     release11: &Release,
-    target_directory_path: &PathBuf,
+    target_directory_path: &Path,
 ) -> Result<()> {
     let release11_tree = release11.get_torrent_tree()?;
     for (path, _) in release11_tree.iter() {
@@ -1183,3 +3917,597 @@ pub fn build_partial_release_11_from_nist_202(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_ignore_rule, create_db_schema, get_file_sources, save_torrent};
+    use crate::pieces::build_v1_torrent;
+    use std::sync::OnceLock;
+    use tokio::net::TcpListener;
+
+    /// The library opens the database at `DATASETS_DB_PATH` whenever it needs it, so the tests
+    /// that use it share one database and take turns with it.
+    fn database() -> &'static tokio::sync::Mutex<()> {
+        static DATABASE: OnceLock<(TempDir, tokio::sync::Mutex<()>)> = OnceLock::new();
+        let (_, lock) = DATABASE.get_or_init(|| {
+            let dir = TempDir::new("datasets-db").unwrap();
+            let path = dir.path().join("releases.db");
+            std::env::set_var("DATASETS_DB_PATH", &path);
+            create_db_schema(&get_db_connection(&path).unwrap()).unwrap();
+            (dir, tokio::sync::Mutex::new(()))
+        });
+        lock
+    }
+
+    /// Saves a release to the test database. The database has to be locked first.
+    fn save_test_release(name: &str) -> Release {
+        let release = Release::new(
+            "2001-09-11".to_string(),
+            name.to_string(),
+            Some(name.to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        let conn = get_db_connection(get_database_path().unwrap()).unwrap();
+        save_release(&conn, &release).unwrap();
+        let _ = conn.close();
+        release
+    }
+
+    /// Saves a v1 torrent for the files to the test database.
+    fn save_test_torrent(release: &Release, files: &[(&str, &[u8])], piece_length: u64) {
+        let conn = get_db_connection(get_database_path().unwrap()).unwrap();
+        let torrent = build_v1_torrent(files, piece_length);
+        save_torrent(&conn, &release.id, "test.torrent", &torrent).unwrap();
+        let _ = conn.close();
+    }
+
+    /// A file served by [`serve`]. If it's cut short, the response still claims the full length
+    /// but the connection is closed after that many bytes.
+    struct ServedFile {
+        path: &'static str,
+        content: Vec<u8>,
+        cut: Option<usize>,
+    }
+
+    /// Serves files over HTTP on a local port, honouring range requests, and returns the URL they
+    /// are under along with the path and starting offset of every request.
+    async fn serve(files: Vec<ServedFile>) -> (Url, Arc<Mutex<Vec<(String, usize)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let path = request.split_whitespace().nth(1).unwrap_or("/")[1..].to_string();
+                let start = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim_end_matches('-').parse().ok())
+                    .unwrap_or(0);
+                seen.lock().unwrap().push((path.clone(), start));
+                let response = match files.iter().find(|f| f.path.to_lowercase() == path) {
+                    Some(file) => {
+                        let status = if start > 0 {
+                            "206 Partial Content"
+                        } else {
+                            "200 OK"
+                        };
+                        let end = file.cut.unwrap_or(file.content.len());
+                        let mut response = format!(
+                            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            file.content.len() - start
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(&file.content[start..end]);
+                        response
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                let _ = stream.write_all(&response).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        (url, requests)
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &[u8])]) {
+        for (path, content) in files.iter() {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_file_next_to_a_waived_missing_file_stops_the_release_being_verified() {
+        let _db = database().blocking_lock();
+        let mut release = save_test_release("Waived neighbour");
+        let conn = get_db_connection(get_database_path().unwrap()).unwrap();
+        add_ignore_rule(&conn, Some(&release.id), "*.tmp", "Temp files").unwrap();
+        let _ = conn.close();
+
+        // The waived file shares the first piece with the one after it, and the last file is on
+        // its own.
+        let files: [(&str, &[u8]); 3] = [
+            ("Rel/cache.tmp", &[1; 10]),
+            ("Rel/a.bin", &[2; 22]),
+            ("Rel/b.bin", &[3; 16]),
+        ];
+        let layout = PieceLayout::from_content(&files, 16);
+        let dir = TempDir::new("release").unwrap();
+        write_files(dir.path(), &files[1..]);
+        // The queue of pieces waiting to be hashed is sized from the memory limit, so it has to
+        // be kept down with pieces this small.
+        let options = VerifyOptions {
+            max_memory: 1024,
+            ..Default::default()
+        };
+
+        let outcome = release
+            .verify_content(
+                &layout,
+                &ContentSource::Directory(dir.path()),
+                None,
+                &options,
+            )
+            .unwrap();
+        assert!(matches!(outcome, VerificationOutcome::Complete));
+        assert_eq!(
+            release.unverifiable_files,
+            vec![(PathBuf::from("Rel/a.bin"), 22)]
+        );
+        assert_eq!(release.waived_files.len(), 1);
+
+        // Once the waived file is there, everything can be hashed.
+        write_files(dir.path(), &files[..1]);
+        let outcome = release
+            .verify_content(
+                &layout,
+                &ContentSource::Directory(dir.path()),
+                None,
+                &options,
+            )
+            .unwrap();
+        assert!(matches!(outcome, VerificationOutcome::Verified));
+        assert!(release.unverifiable_files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_file_that_fails_partway_is_downloaded_again_from_the_next_source() {
+        let _db = database().lock().await;
+        let a = (0..5000).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+        let b = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+        let files: [(&str, &[u8]); 2] = [("Rel/a.bin", &a), ("Rel/b.bin", &b)];
+        let release = save_test_release("Two sources");
+        save_test_torrent(&release, &files, 1024);
+        let mut release = get_release_by_id(
+            &get_db_connection(get_database_path().unwrap()).unwrap(),
+            &release.id,
+        )
+        .unwrap();
+
+        // The first source has a different version of the first file, and drops the connection
+        // partway through it.
+        let (first, _) = serve(vec![
+            ServedFile {
+                path: "Rel/a.bin",
+                content: vec![0xff; 5000],
+                cut: Some(2500),
+            },
+            ServedFile {
+                path: "Rel/b.bin",
+                content: b.clone(),
+                cut: None,
+            },
+        ])
+        .await;
+        let (second, requests) = serve(vec![ServedFile {
+            path: "Rel/a.bin",
+            content: a.clone(),
+            cut: None,
+        }])
+        .await;
+        let dir = TempDir::new("release").unwrap();
+        let options = DownloadOptions {
+            verify: true,
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let outcome = release
+            .download_from_sources(
+                &[
+                    DownloadSource::Http(first.clone()),
+                    DownloadSource::Http(second.clone()),
+                ],
+                dir.path(),
+                &options,
+                &DownloadThrottle::new(None, vec![]),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(outcome, Some(VerificationOutcome::Verified)));
+        assert_eq!(std::fs::read(dir.path().join("Rel/a.bin")).unwrap(), a);
+        assert!(!part_path(&dir.path().join("Rel/a.bin")).exists());
+        assert!(!part_source_path(&dir.path().join("Rel/a.bin")).exists());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![("rel/a.bin".to_string(), 0)]
+        );
+        let conn = get_db_connection(get_database_path().unwrap()).unwrap();
+        let sources = get_file_sources(&conn, &release.id).unwrap();
+        assert_eq!(sources[Path::new("Rel/b.bin")].0, format!("http {first}"));
+        assert_eq!(sources[Path::new("Rel/a.bin")].0, format!("http {second}"));
+    }
+
+    #[tokio::test]
+    async fn a_part_file_is_only_resumed_from_where_it_came_from() {
+        let content = (0..4000).map(|i| (i / 3) as u8).collect::<Vec<u8>>();
+        let (url, requests) = serve(vec![ServedFile {
+            path: "a.bin",
+            content: content.clone(),
+            cut: None,
+        }])
+        .await;
+        let url = url.join("a.bin").unwrap();
+        let dir = TempDir::new("download").unwrap();
+        let target_path = dir.path().join("a.bin");
+        let download = || async {
+            download_file(
+                &build_http_client().unwrap(),
+                &url,
+                &target_path,
+                &ProgressBar::hidden(),
+                &DownloadThrottle::new(None, vec![]),
+                &RetryPolicy::default(),
+            )
+            .await
+            .unwrap();
+        };
+
+        std::fs::write(part_path(&target_path), &content[..1000]).unwrap();
+        std::fs::write(part_source_path(&target_path), url.as_str()).unwrap();
+        download().await;
+        std::fs::write(part_path(&target_path), [0xff; 1000]).unwrap();
+        std::fs::write(part_source_path(&target_path), "http://elsewhere/a.bin").unwrap();
+        download().await;
+
+        assert_eq!(std::fs::read(&target_path).unwrap(), content);
+        assert!(!part_source_path(&target_path).exists());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![("a.bin".to_string(), 1000), ("a.bin".to_string(), 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_a_part_file_named_by_an_older_version() {
+        let content = (0..4000).map(|i| (i / 5) as u8).collect::<Vec<u8>>();
+        let (url, requests) = serve(vec![ServedFile {
+            path: "a.bin",
+            content: content.clone(),
+            cut: None,
+        }])
+        .await;
+        let dir = TempDir::new("download").unwrap();
+        let target_path = dir.path().join("a.bin");
+        std::fs::write(dir.path().join("a.part"), &content[..1000]).unwrap();
+
+        download_file(
+            &build_http_client().unwrap(),
+            &url.join("a.bin").unwrap(),
+            &target_path,
+            &ProgressBar::hidden(),
+            &DownloadThrottle::new(None, vec![]),
+            &RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&target_path).unwrap(), content);
+        assert!(!dir.path().join("a.part").exists());
+        assert_eq!(*requests.lock().unwrap(), vec![("a.bin".to_string(), 1000)]);
+    }
+
+    #[test]
+    fn a_download_holds_its_content_until_it_is_first_in_line() {
+        let buffered = AtomicUsize::new(0);
+        let mut stream = JobStream::new(1);
+        assert!(stream.on_data(0, &[1, 2, 3], false, &buffered).is_empty());
+        // A retry starts again from the content of the `.part` file.
+        assert!(stream.on_data(0, &[1, 2], false, &buffered).is_empty());
+        assert!(stream.on_data(2, &[3], false, &buffered).is_empty());
+        assert_eq!(buffered.load(Ordering::SeqCst), 3);
+
+        let events = stream.on_data(3, &[4], true, &buffered);
+        assert!(matches!(
+            events.as_slice(),
+            [DownloadEvent::Data(1, 0, held), DownloadEvent::Data(1, 3, data)]
+                if held == &[1, 2, 3] && data == &[4]
+        ));
+        assert_eq!(buffered.load(Ordering::SeqCst), 0);
+        assert!(matches!(
+            stream.on_data(4, &[5], false, &buffered).as_slice(),
+            [DownloadEvent::Data(1, 4, _)]
+        ));
+        assert!(matches!(
+            stream.restart(&buffered),
+            Some(DownloadEvent::Restart(1))
+        ));
+        assert_eq!(stream.finish(true, &buffered), (true, None));
+    }
+
+    #[test]
+    fn a_download_that_finishes_ahead_hands_over_its_content() {
+        let buffered = AtomicUsize::new(0);
+        let mut stream = JobStream::new(2);
+        stream.on_data(0, &[1, 2], false, &buffered);
+        assert!(stream.restart(&buffered).is_none());
+        assert_eq!(buffered.load(Ordering::SeqCst), 0);
+        stream.on_data(0, &[3, 4], false, &buffered);
+        assert_eq!(stream.finish(true, &buffered), (false, Some(vec![3, 4])));
+        // The verifier gives back what it was handed once it has pushed it.
+        assert_eq!(buffered.load(Ordering::SeqCst), 2);
+
+        // Content that leaves a gap can't be held, so the file is read back instead.
+        let mut stream = JobStream::new(3);
+        stream.on_data(0, &[1, 2], false, &buffered);
+        stream.on_data(5, &[3], false, &buffered);
+        assert!(stream.on_data(6, &[4], true, &buffered).is_empty());
+        assert_eq!(buffered.load(Ordering::SeqCst), 2);
+        assert_eq!(stream.finish(true, &buffered), (false, None));
+    }
+
+    #[test]
+    fn a_file_on_disk_next_to_a_piece_from_nowhere_is_unverifiable() {
+        let (a, b, c) = (vec![1; 1500], vec![2; 1000], vec![3; 1500]);
+        let files: [(&str, &[u8]); 3] = [("Rel/a", &a), ("Rel/b", &b), ("Rel/c", &c)];
+        let layout = PieceLayout::from_content(&files, 1024);
+        // Piece 1 spans a and b, and piece 2 spans b and c. Neither was obtained.
+        let mut have = Bitfield::new(layout.piece_count());
+        for piece_idx in [0, 3] {
+            have.set(piece_idx, true);
+        }
+
+        // b was never on disk, so a and c are only unverifiable.
+        let missing = get_files_missing_from_swarm(&layout, &have, &[true, false, true]);
+        assert_eq!(missing, BTreeSet::from([1]));
+        // a was downloaded, so its part of piece 1 isn't there.
+        let missing = get_files_missing_from_swarm(&layout, &have, &[false, false, true]);
+        assert_eq!(missing, BTreeSet::from([0, 1]));
+        // Everything was on disk, so the pieces failed and none of their files can be trusted.
+        let missing = get_files_missing_from_swarm(&layout, &have, &[true, true, true]);
+        assert_eq!(missing, BTreeSet::from([0, 1, 2]));
+    }
+
+    /// Saves a release of three files that fill a piece each, and marks it as incomplete with the
+    /// first verified, the second corrupt and the third missing.
+    fn save_release_to_repair(name: &str, files: &[(&str, &[u8])]) -> Release {
+        let release = save_test_release(name);
+        save_test_torrent(&release, files, 1024);
+        let mut release = get_release_by_id(
+            &get_db_connection(get_database_path().unwrap()).unwrap(),
+            &release.id,
+        )
+        .unwrap();
+        release.torrent_url = Some(Url::parse("https://example.com/test.torrent").unwrap());
+        release.verified_files = vec![(PathBuf::from("Rel/a.bin"), 1024)];
+        release.verification_outcome = Some(VerificationOutcome::Incomplete(
+            vec![(PathBuf::from("Rel/c.bin"), 1024)],
+            vec![(PathBuf::from("Rel/b.bin"), 1024)],
+        ));
+        release
+    }
+
+    #[test]
+    fn only_missing_and_corrupt_files_are_repaired() {
+        let _db = database().blocking_lock();
+        let (a, b, c) = (vec![1; 1024], vec![2; 1024], vec![3; 1024]);
+        let files: [(&str, &[u8]); 3] = [("Rel/a.bin", &a), ("Rel/b.bin", &b), ("Rel/c.bin", &c)];
+        let mut release = save_release_to_repair("Repair jobs", &files);
+        let layout = release.get_piece_layout().unwrap();
+        let dir = TempDir::new("release").unwrap();
+        // The corrupt file is on disk under a name that differs in case.
+        write_files(dir.path(), &[("Rel/a.bin", &a), ("Rel/B.bin", &[9; 1024])]);
+
+        let options = DownloadOptions::default();
+        assert!(release
+            .prepare_repair(&layout, dir.path(), &options)
+            .unwrap()
+            .is_none());
+        let options = DownloadOptions {
+            repair: true,
+            ..Default::default()
+        };
+        let repair = release
+            .prepare_repair(&layout, dir.path(), &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            repair,
+            BTreeMap::from([(1, Some(PathBuf::from("Rel/B.bin"))), (2, None)])
+        );
+
+        release.verification_outcome = Some(VerificationOutcome::Verified);
+        assert!(matches!(
+            release.prepare_repair(&layout, dir.path(), &options),
+            Err(Error::NothingToRepair(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_file_is_kept_until_its_replacement_has_downloaded() {
+        let _db = database().lock().await;
+        let (a, b, c) = (vec![1; 1024], vec![2; 1024], vec![3; 1024]);
+        let files: [(&str, &[u8]); 3] = [("Rel/a.bin", &a), ("Rel/b.bin", &b), ("Rel/c.bin", &c)];
+        let mut release = save_release_to_repair("Repair", &files);
+        let dir = TempDir::new("release").unwrap();
+        let backup = TempDir::new("backup").unwrap();
+        let corrupt = vec![9; 1024];
+        write_files(dir.path(), &[("Rel/a.bin", &a), ("Rel/b.bin", &corrupt)]);
+
+        // The replacement is cut off partway, so the corrupt file has to stay.
+        let (failing, _) = serve(vec![ServedFile {
+            path: "Rel/b.bin",
+            content: b.clone(),
+            cut: Some(500),
+        }])
+        .await;
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        let options = DownloadOptions {
+            repair: true,
+            backup_directory: Some(backup.path().to_path_buf()),
+            retry: retry.clone(),
+            ..Default::default()
+        };
+        let result = release
+            .download_from_sources(
+                &[DownloadSource::Http(failing)],
+                dir.path(),
+                &options,
+                &DownloadThrottle::new(None, vec![]),
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            std::fs::read(dir.path().join("Rel/b.bin")).unwrap(),
+            corrupt
+        );
+        assert_eq!(
+            std::fs::read(backup.path().join("Rel/b.bin")).unwrap(),
+            corrupt
+        );
+
+        let (working, requests) = serve(vec![
+            ServedFile {
+                path: "Rel/b.bin",
+                content: b.clone(),
+                cut: None,
+            },
+            ServedFile {
+                path: "Rel/c.bin",
+                content: c.clone(),
+                cut: None,
+            },
+        ])
+        .await;
+        let options = DownloadOptions {
+            repair: true,
+            retry,
+            ..Default::default()
+        };
+        release
+            .download_from_sources(
+                &[DownloadSource::Http(working)],
+                dir.path(),
+                &options,
+                &DownloadThrottle::new(None, vec![]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(dir.path().join("Rel/b.bin")).unwrap(), b);
+        assert_eq!(std::fs::read(dir.path().join("Rel/c.bin")).unwrap(), c);
+        let mut requests = requests.lock().unwrap().clone();
+        requests.sort();
+        assert_eq!(
+            requests,
+            vec![("rel/b.bin".to_string(), 0), ("rel/c.bin".to_string(), 0)]
+        );
+
+        let options = VerifyOptions {
+            max_memory: 1024,
+            ..Default::default()
+        };
+        let results = release.verify_repaired_files(dir.path(), &options).unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|r| (r.path.to_str().unwrap(), r.status))
+                .collect::<Vec<_>>(),
+            vec![("Rel/b.bin", "VERIFIED"), ("Rel/c.bin", "VERIFIED")]
+        );
+        assert!(matches!(
+            release.verification_outcome,
+            Some(VerificationOutcome::Verified)
+        ));
+    }
+
+    #[test]
+    fn arranges_a_piece_map_into_rows() {
+        let files: [(&str, &[u8]); 3] = [
+            ("Rel/a.bin", &[1; 50]),
+            ("Rel/empty", &[]),
+            ("Rel/sub/b.bin", &[2; 14]),
+        ];
+        let layout = PieceLayout::from_content(&files, 16);
+        let mut piece_map = PieceMap::new(layout.piece_count());
+        for (piece_idx, result) in [(0, true), (1, false), (3, true)] {
+            piece_map.set(piece_idx, Some(result));
+        }
+
+        let (pieces_per_cell, rows) = arrange_piece_map(&layout, &piece_map, Some("Rel"), 3);
+        assert_eq!(pieces_per_cell, 1);
+        assert_eq!(
+            rows.iter()
+                .map(|row| (row.first_piece, row.cells.clone(), row.files.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    0,
+                    vec![Some(true), Some(false), None],
+                    vec![Path::new("a.bin")]
+                ),
+                (3, vec![Some(true)], vec![Path::new("sub/b.bin")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn shows_the_worst_result_when_a_cell_covers_several_pieces() {
+        let files: [(&str, &[u8]); 2] = [("Rel/a.bin", &[1; 1600]), ("Rel/b.bin", &[2; 480])];
+        let layout = PieceLayout::from_content(&files, 16);
+        let mut piece_map = PieceMap::new(layout.piece_count());
+        for piece_idx in 0..layout.piece_count() {
+            piece_map.set(piece_idx, Some(piece_idx != 100));
+        }
+        piece_map.set(5, None);
+
+        // 130 pieces don't fit in 64 rows of one cell, so each cell has 3.
+        let (pieces_per_cell, rows) = arrange_piece_map(&layout, &piece_map, None, 1);
+        assert_eq!(pieces_per_cell, 3);
+        assert_eq!(rows.len(), 44);
+        assert_eq!(rows[1].cells, vec![None]);
+        assert_eq!(rows[33].cells, vec![Some(false)]);
+        assert_eq!(rows[33].first_piece, 99);
+        assert_eq!(rows[43].cells, vec![Some(true)]);
+        assert_eq!(rows[0].files, vec![Path::new("Rel/a.bin")]);
+        assert_eq!(rows[33].files, vec![Path::new("Rel/b.bin")]);
+        assert!(rows
+            .iter()
+            .skip(1)
+            .filter(|r| r.first_piece != 99)
+            .all(|r| r.files.is_empty()));
+    }
+}
@@ -1,13 +1,26 @@
 use clap::{Parser, Subcommand};
 use color_eyre::{eyre::eyre, Result};
 use dialoguer::Editor;
+use rusqlite::Connection;
+use sept11_datasets::archive_manifest::ManifestStatus;
 use sept11_datasets::db::*;
-use sept11_datasets::error::Error;
+use sept11_datasets::peer::SwarmOptions;
+use sept11_datasets::report::{
+    generate_signing_key, read_signing_key, read_verifying_key, verify_signed_report,
+};
+use sept11_datasets::retry::RetryPolicy;
+use sept11_datasets::sources::{DownloadSource, ReleaseSource, SourceKind};
+use sept11_datasets::throttle::{
+    parse_rate, parse_rate_window, set_idle_io_priority, DownloadThrottle, RateWindow,
+};
 use sept11_datasets::{
     build_partial_release_11_from_nist_202, build_release_14_file_links, build_release_14_links,
-    bytes_to_human_readable, download_torrents, Release, VerificationOutcome,
+    bytes_to_human_readable, download_torrents, DownloadOptions, Release, VerificationOutcome,
+    VerifyOptions,
 };
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tempdir::TempDir;
 
 const RELEASE_14_UNCOMPRESSED_ID: &str = "968d5cdf934f01bb9efcf631c999fde5a617f4a9";
@@ -22,6 +35,22 @@ struct Opt {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
+    /// Register a release from a torrent file that isn't part of the static release data
+    ///
+    /// The torrent can be a v1, v2 or hybrid torrent. It's stored in the database along with the
+    /// release, which can then be checked and verified like any other.
+    #[clap(name = "add-release", verbatim_doc_comment)]
+    AddRelease {
+        /// Path to the torrent file
+        #[arg(long)]
+        torrent: PathBuf,
+        /// The date of the release, in the same form as the other releases, e.g. 2007-09-01
+        #[arg(long)]
+        date: String,
+        /// The name of the release
+        #[arg(long)]
+        name: String,
+    },
     /// Copy release 11 files from the NIST 10 202 release.
     ///
     /// Many files that were in the NIST FOIA 10 202 are duplicates of what was in release 11.
@@ -44,6 +73,34 @@ enum Commands {
         /// Path to the directory containing the files for the release
         #[arg(long, env = "DATASETS_PATH")]
         target_path: PathBuf,
+        /// List the files in the release directory that are not in the torrent
+        #[arg(long)]
+        extras: bool,
+        /// Move the files that are not in the torrent into this directory
+        #[arg(long, requires = "extras")]
+        quarantine: Option<PathBuf>,
+        /// Rename files that only match the torrent after normalising the case or Unicode form of
+        /// their names
+        #[arg(long)]
+        fix_names: bool,
+        /// Store the release with names escaped so they are valid on NTFS and exFAT.
+        ///
+        /// Characters like `:` and `"`, and dots or spaces at the end of names, are replaced by `%`
+        /// followed by their hex code. This is recorded for the release, so it only needs to be
+        /// given once.
+        #[arg(long)]
+        escape_names: bool,
+        /// The maximum rate to read the release at, e.g. `80MiB/s`.
+        ///
+        /// Checking only reads the metadata of each file, which comes nowhere near this, but it's
+        /// accepted so the same options can be given to check and verify.
+        #[arg(long, value_parser = parse_rate)]
+        max_read_rate: Option<u64>,
+        /// Read the release at the idle I/O priority, so other processes using the disk come first.
+        ///
+        /// This is only available on Linux, and has no effect on network shares.
+        #[arg(long)]
+        idle_io: bool,
     },
     /// Download a release from the Internet Archive
     ///
@@ -51,7 +108,11 @@ enum Commands {
     /// URL prefix of the release on the archive, we can use the information from the torrent to
     /// download all the files individually.
     ///
-    /// To avoid abuse of the archive, the files are downloaded sequentially.
+    /// Several files are downloaded at the same time. To avoid abuse of the archive, the number
+    /// of files downloaded from any one host at once is also limited.
+    ///
+    /// If other sources have been added for the release with the source command, each file is
+    /// fetched from the first source that has it, in order of priority.
     #[clap(name = "download-release", verbatim_doc_comment)]
     DownloadRelease {
         /// The ID of the release to download
@@ -60,6 +121,98 @@ enum Commands {
         /// Path specifying where the files should be downloaded
         #[arg(long, env = "DATASETS_PATH")]
         target_path: PathBuf,
+        /// Store the release with names escaped so they are valid on NTFS and exFAT.
+        ///
+        /// Characters like `:` and `"`, and dots or spaces at the end of names, are replaced by `%`
+        /// followed by their hex code. This is recorded for the release, so it only needs to be
+        /// given once.
+        #[arg(long)]
+        escape_names: bool,
+        /// Hash the pieces as the files are downloaded, and save the verification outcome at the
+        /// end, so there's no need to read the release back from disk with the verify command.
+        ///
+        /// Files that are already present are read from disk. Files that are downloaded at the
+        /// same time have to be hashed in order, so those that get ahead are held in memory, up to
+        /// 64 MiB in all, and any beyond that are read back from disk once they're done. This
+        /// doesn't apply to releases that are downloaded as a zip.
+        #[arg(long)]
+        verify: bool,
+        /// The number of files to download at the same time
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+        /// The number of files to download at the same time from any one host.
+        ///
+        /// Almost every file is on archive.org, so this is what limits downloads from it, while
+        /// the rest of the concurrency is left for other sources. Please keep it low.
+        #[arg(long, default_value_t = 2)]
+        max_per_host: usize,
+        /// The maximum rate to download at, e.g. `2MiB/s`.
+        ///
+        /// The rate applies to all the files being downloaded together. Units can be binary, like
+        /// `MiB`, or decimal, like `MB`.
+        #[arg(long, value_parser = parse_rate)]
+        max_rate: Option<u64>,
+        /// A time of day with a different rate, e.g. `01:00-07:00=unlimited`.
+        ///
+        /// The times are local. If the rate is left off, there is no limit during the window.
+        /// Outside the windows, the maximum rate applies. Can be used more than once.
+        #[arg(long, value_parser = parse_rate_window)]
+        rate_schedule: Vec<RateWindow>,
+        /// The number of times to try downloading a file before giving up
+        #[arg(long, default_value_t = 10)]
+        max_attempts: u32,
+        /// The number of seconds to wait before the first retry.
+        ///
+        /// The wait doubles after each failed attempt, up to --max-retry-delay. If the server asks
+        /// for a particular wait when it's rate limiting, that's used instead, as long as it's no
+        /// longer than --max-retry-delay.
+        #[arg(long, default_value_t = 5)]
+        retry_delay: u64,
+        /// The longest wait between attempts, in seconds
+        #[arg(long, default_value_t = 300)]
+        max_retry_delay: u64,
+        /// Only download the files that were found to be missing or corrupt the last time the
+        /// release was checked or verified, then verify them.
+        ///
+        /// Corrupt files are only replaced once a new copy has been downloaded. For a release that is
+        /// distributed as a zip, the files are downloaded individually from inside the zip.
+        #[arg(long, conflicts_with = "verify")]
+        repair: bool,
+        /// Copy corrupt files to this directory before they are replaced when repairing.
+        ///
+        /// They keep their paths relative to the target path.
+        #[arg(long, requires = "repair")]
+        backup: Option<PathBuf>,
+        /// When verifying the repaired files, rename files that only match the torrent after
+        /// normalising the case or Unicode form of their names
+        #[arg(long, requires = "repair")]
+        fix_names: bool,
+        /// Download the release from its BitTorrent swarm rather than the Archive.
+        ///
+        /// The pieces already on disk are checked first, and only the rest are downloaded. Every
+        /// piece is verified as it arrives, and the outcome is saved at the end. This only works
+        /// for v1 and hybrid torrents.
+        #[arg(long, conflicts_with_all = ["verify", "repair"])]
+        via_torrent: bool,
+        /// A peer to download from when using --via-torrent, as host:port.
+        ///
+        /// The trackers aren't asked for peers when this is given. Can be used more than once.
+        #[arg(long, requires = "via_torrent")]
+        peer: Vec<String>,
+        /// The number of peers to download from at the same time when using --via-torrent, 30 by
+        /// default
+        #[arg(long, requires = "via_torrent")]
+        max_peers: Option<usize>,
+    },
+    /// Manage rules for files that are allowed to be missing or corrupt
+    ///
+    /// Some files in the torrents, like Thumbs.db, have no value and are often not on the Archive.
+    /// Files matching a rule are waived by the check and verify commands, so they don't prevent a
+    /// release from being considered complete.
+    #[clap(name = "ignore", verbatim_doc_comment)]
+    Ignore {
+        #[command(subcommand)]
+        command: IgnoreCommands,
     },
     /// Build the release database from the static data in the binary
     ///
@@ -67,7 +220,51 @@ enum Commands {
     ///
     /// If the database already exists, running this command again will add any new schema that
     /// needs to be created.
-    Init {},
+    Init {
+        /// The maximum rate to download the torrents at, e.g. `2MiB/s`
+        #[arg(long, value_parser = parse_rate)]
+        max_rate: Option<u64>,
+        /// A time of day with a different rate, e.g. `01:00-07:00=unlimited`.
+        ///
+        /// The times are local. If the rate is left off, there is no limit during the window.
+        /// Outside the windows, the maximum rate applies. Can be used more than once.
+        #[arg(long, value_parser = parse_rate_window)]
+        rate_schedule: Vec<RateWindow>,
+        /// The number of times to try downloading a torrent before giving up
+        #[arg(long, default_value_t = 10)]
+        max_attempts: u32,
+        /// The number of seconds to wait before the first retry.
+        ///
+        /// The wait doubles after each failed attempt, up to --max-retry-delay.
+        #[arg(long, default_value_t = 5)]
+        retry_delay: u64,
+        /// The longest wait between attempts, in seconds
+        #[arg(long, default_value_t = 300)]
+        max_retry_delay: u64,
+    },
+    /// Load an Internet Archive files manifest for a release
+    ///
+    /// Each item on the Archive has an <item>_files.xml manifest with the size, MD5 and SHA-1 of
+    /// every file. The entries that match files in the torrent tree are saved, so the files can
+    /// be checked with the verify-manifest command.
+    ///
+    /// For release 14, if no source is supplied, the manifests for all of its collections will be
+    /// downloaded.
+    #[clap(name = "load-manifest", verbatim_doc_comment)]
+    LoadManifest {
+        /// The ID of the release
+        #[arg(long)]
+        id: String,
+        /// Path or URL of the manifest
+        #[arg(long)]
+        source: Option<String>,
+        /// The directory in the torrent tree that corresponds to the Archive item.
+        ///
+        /// If not supplied, it will be worked out by matching the file names in the manifest to
+        /// the torrent tree.
+        #[arg(long)]
+        prefix: Option<PathBuf>,
+    },
     /// Print the list of releases
     Ls {
         /// Set to print the directory of the release rather than the name
@@ -83,6 +280,14 @@ enum Commands {
         /// Display file sizes in bytes rather than human readable text
         #[arg(long)]
         use_bytes: bool,
+        /// Display the SHA-256 and MD5 of each file.
+        ///
+        /// These are computed when the release is verified.
+        #[arg(long)]
+        hashes: bool,
+        /// Display the source each file was downloaded from, and when
+        #[arg(long)]
+        sources: bool,
     },
     /// Mark a release as incomplete
     ///
@@ -118,6 +323,44 @@ enum Commands {
         #[arg(long)]
         id: String,
     },
+    /// Display the result for each piece from the last verification of a release
+    ///
+    /// Pieces are drawn as a grid of cells, with the files that begin in each row listed beside
+    /// it, to show whether any corruption is in one region or scattered across the release.
+    #[clap(name = "piece-map", verbatim_doc_comment)]
+    PieceMap {
+        /// The id of the release
+        #[arg(long)]
+        id: String,
+        /// The number of cells in each row of the grid
+        #[arg(long, default_value_t = 64)]
+        columns: usize,
+    },
+    /// Produce a signed report of the last verification of a release
+    ///
+    /// The report is JSON, with the info hash and piece count of the torrent, the outcome and when
+    /// it was saved, and the size, status and checksums of every file. It's signed with an ed25519
+    /// key, so someone else can check it hasn't been altered using `report verify` and the public
+    /// key. Use `report keygen` to create a key.
+    #[clap(
+        name = "report",
+        verbatim_doc_comment,
+        args_conflicts_with_subcommands = true,
+        subcommand_negates_reqs = true
+    )]
+    Report {
+        #[command(subcommand)]
+        command: Option<ReportCommands>,
+        /// The ID of the release
+        #[arg(long, required = true)]
+        id: Option<String>,
+        /// The signing key created by `report keygen`
+        #[arg(long, required = true)]
+        sign: Option<PathBuf>,
+        /// Write the report to this file rather than printing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
     /// Reset the verification result for a release
     Reset {
         /// Only reset the release with the specified ID.
@@ -126,6 +369,17 @@ enum Commands {
         #[arg(long)]
         id: Option<String>,
     },
+    /// Manage the places a release can be downloaded from
+    ///
+    /// As well as its download URL, a release can have other sources: a zip or a collection on the
+    /// Archive, a mirror like an S3 bucket or someone else's HTTP server, or a directory on a NAS.
+    /// The download-release command tries the sources in order of priority for each file, and
+    /// records which one each file came from.
+    #[clap(name = "source", verbatim_doc_comment)]
+    Source {
+        #[command(subcommand)]
+        command: SourceCommands,
+    },
     /// Print the current verification status for releases
     Status {
         /// Display the status of a particular release.
@@ -137,6 +391,32 @@ enum Commands {
         #[arg(long)]
         show_incomplete: bool,
     },
+    /// Verify individual files against the checksums in the Archive manifests for a release
+    ///
+    /// This gives per-file confirmation where verification against the piece hashes isn't
+    /// possible. Use the load-manifest command first.
+    #[clap(name = "verify-manifest", verbatim_doc_comment)]
+    VerifyManifest {
+        /// The ID of the release
+        #[arg(long)]
+        id: String,
+        /// Path to the directory containing the files for the release
+        #[arg(long, env = "DATASETS_PATH")]
+        target_path: PathBuf,
+        /// Print the status of each file
+        #[arg(long)]
+        show_files: bool,
+        /// The maximum rate to read the release at, e.g. `80MiB/s`.
+        ///
+        /// Units can be binary, like `MiB`, or decimal, like `MB`.
+        #[arg(long, value_parser = parse_rate)]
+        max_read_rate: Option<u64>,
+        /// Read the release at the idle I/O priority, so other processes using the disk come first.
+        ///
+        /// This is only available on Linux, and has no effect on network shares.
+        #[arg(long)]
+        idle_io: bool,
+    },
     /// Verify releases against their corresponding torrents
     Verify {
         /// The ID of the release to verify.
@@ -145,8 +425,168 @@ enum Commands {
         #[arg(long)]
         id: Option<String>,
         /// Path to the directory containing the files for the release
-        #[arg(long, env = "DATASETS_PATH")]
-        target_path: PathBuf,
+        #[arg(long, env = "DATASETS_PATH", required_unless_present = "from_zip")]
+        target_path: Option<PathBuf>,
+        /// Verify the release from the zip it is distributed in, without extracting it.
+        ///
+        /// The entries in the zip are matched to the torrent tree by path and read in torrent
+        /// order, so the pieces are hashed as the zip is streamed.
+        #[arg(
+            long,
+            requires = "id",
+            conflicts_with_all = ["resume", "incremental", "fix_names"]
+        )]
+        from_zip: Option<PathBuf>,
+        /// Only verify the file at this path in the torrent tree, or the files under it.
+        ///
+        /// The pieces that overlap the selected files are hashed, including the bytes they share
+        /// with neighbouring files. Only the results for the selected files are updated.
+        #[arg(long, requires = "id", conflicts_with_all = ["from_zip", "resume"])]
+        path: Option<PathBuf>,
+        /// The number of threads to use for hashing pieces.
+        ///
+        /// If not supplied, one thread per available CPU will be used.
+        #[arg(long)]
+        jobs: Option<usize>,
+        /// The maximum amount of piece data, in MiB, to hold in memory while it waits to be hashed
+        #[arg(long, default_value_t = 256)]
+        max_memory: u64,
+        /// Resume from the checkpoint saved by a previous verification that didn't finish.
+        ///
+        /// A checkpoint is saved periodically while a release is being verified. It can only be
+        /// used if none of the files in the release have changed size or been modified since then.
+        #[arg(long)]
+        resume: bool,
+        /// Verify as much of the release as possible when some of its files are missing.
+        ///
+        /// Every piece that doesn't overlap a missing file is hashed. The files that are present
+        /// are reported as verified, corrupt, or unverifiable because they share a piece with a
+        /// missing file.
+        #[arg(long)]
+        partial: bool,
+        /// Don't compute the SHA-256 and MD5 of each file when a release is verified
+        #[arg(long)]
+        no_file_hashes: bool,
+        /// Only re-hash the pieces that touch files which have changed since they last passed
+        /// verification
+        #[arg(long)]
+        incremental: bool,
+        /// Rename files that only match the torrent after normalising the case or Unicode form of
+        /// their names
+        #[arg(long)]
+        fix_names: bool,
+        /// Store the release with names escaped so they are valid on NTFS and exFAT.
+        ///
+        /// Characters like `:` and `"`, and dots or spaces at the end of names, are replaced by `%`
+        /// followed by their hex code. This is recorded for the release, so it only needs to be
+        /// given once.
+        #[arg(long, requires = "id", conflicts_with = "from_zip")]
+        escape_names: bool,
+        /// The maximum rate to read the release at, e.g. `80MiB/s`.
+        ///
+        /// Units can be binary, like `MiB`, or decimal, like `MB`. The rate applies to all the
+        /// hashing threads together.
+        #[arg(long, value_parser = parse_rate)]
+        max_read_rate: Option<u64>,
+        /// Read the release at the idle I/O priority, so other processes using the disk come first.
+        ///
+        /// This is only available on Linux, and has no effect on network shares.
+        #[arg(long)]
+        idle_io: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum IgnoreCommands {
+    /// Add an ignore rule
+    Add {
+        /// A glob pattern, e.g. `Thumbs.db` or `*.tmp`.
+        ///
+        /// A pattern without a `/` is matched against file names at any depth in the tree.
+        /// Otherwise it is matched against the whole path of the file within the release.
+        #[arg(long)]
+        pattern: String,
+        /// Why files matching the pattern can be ignored
+        #[arg(long)]
+        reason: String,
+        /// Only apply the rule to the release with this ID.
+        ///
+        /// If not supplied, the rule applies to every release.
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// List ignore rules
+    Ls {
+        /// Only list the global rules and those for the release with this ID
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Remove an ignore rule
+    Rm {
+        /// The ID of the rule, as shown by `ignore ls`
+        #[arg(long)]
+        rule_id: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SourceCommands {
+    /// Add a source for a release
+    Add {
+        /// The ID of the release
+        #[arg(long)]
+        id: String,
+        /// The kind of source: zip, http or local.
+        ///
+        /// A zip is a zip on the Archive, whose files can be fetched individually. For http and
+        /// local, the files must be laid out as in the torrent under the URL or directory.
+        #[arg(long)]
+        kind: SourceKind,
+        /// The URL of the source, or the path of the directory for a local source
+        #[arg(long)]
+        location: String,
+        /// Sources are tried from the lowest priority to the highest.
+        ///
+        /// The download URL of the release has a priority of 0, so use a negative priority to try
+        /// a source before it.
+        #[arg(long, default_value_t = 1, allow_negative_numbers = true)]
+        priority: i64,
+    },
+    /// List the sources for a release in the order they are tried
+    Ls {
+        /// The ID of the release
+        #[arg(long)]
+        id: String,
+    },
+    /// Remove a source
+    Rm {
+        /// The ID of the source, as shown by `source ls`
+        #[arg(long)]
+        source_id: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ReportCommands {
+    /// Create a key for signing reports
+    ///
+    /// The public key is saved next to it, with `.pub` added to the name. It's the public key that
+    /// should be given to anyone who needs to check the reports.
+    Keygen {
+        /// Where to save the signing key
+        #[arg(long)]
+        key: PathBuf,
+    },
+    /// Check the signature on a report
+    ///
+    /// Only the report and the public key are needed, so this works offline without a database.
+    Verify {
+        /// The signed report
+        #[arg(long)]
+        report: PathBuf,
+        /// The public key of whoever is supposed to have signed the report
+        #[arg(long)]
+        public_key: PathBuf,
     },
 }
 
@@ -156,24 +596,52 @@ async fn main() -> Result<()> {
 
     let opt = Opt::parse();
     match opt.command {
+        Some(Commands::AddRelease {
+            torrent,
+            date,
+            name,
+        }) => {
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            let (release, version) = Release::add_from_torrent(&conn, date, name, &torrent)?;
+            let _ = conn.close();
+            println!("Added {} from a {} torrent", release, version);
+            println!("ID: {}", release.id);
+            Ok(())
+        }
         Some(Commands::BuildPartialRelease11 { target_path }) => {
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
-            let release11 = get_release_by_id(&conn, &RELEASE_11_ID)?;
+            let release11 = get_release_by_id(&conn, RELEASE_11_ID)?;
             let _ = conn.close();
             build_partial_release_11_from_nist_202(&release11, &target_path)?;
             Ok(())
         }
-        Some(Commands::Check { id, target_path }) => {
+        Some(Commands::Check {
+            id,
+            target_path,
+            extras,
+            quarantine,
+            fix_names,
+            escape_names,
+            max_read_rate: _,
+            idle_io,
+        }) => {
+            if idle_io {
+                use_idle_io_priority();
+            }
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
             let mut release = get_release_by_id(&conn, &id)?;
+            if escape_names {
+                mark_escaped_names(&conn, &mut release)?;
+            }
             let _ = conn.close();
             let outcome = if let Some(verification_outcome) = &release.verification_outcome {
                 println!("This release was previously verified");
                 verification_outcome.clone()
             } else {
-                let outcome = release.check(&target_path)?;
+                let outcome = release.check(&target_path, fix_names)?;
                 release.verification_outcome = Some(outcome.clone());
                 let mut conn = get_db_connection(get_database_path()?)?;
                 save_verification_result(&mut conn, &release)?;
@@ -194,50 +662,174 @@ async fn main() -> Result<()> {
                     println!("Outcome: {}", outcome);
                 }
             }
+            if extras {
+                let extra_files = release.find_extra_files(&target_path)?;
+                if extra_files.is_empty() {
+                    println!(
+                        "There are no files in the release directory that are not in the torrent"
+                    );
+                    return Ok(());
+                }
+                println!();
+                println!("Files not in the torrent:");
+                let mut total_size = 0;
+                for (path, size) in extra_files.iter() {
+                    println!("{} ({})", path.display(), bytes_to_human_readable(*size));
+                    total_size += size;
+                }
+                println!(
+                    "{} files not in the torrent, totalling {}",
+                    extra_files.len(),
+                    bytes_to_human_readable(total_size)
+                );
+                if let Some(quarantine) = quarantine {
+                    release.quarantine_extra_files(&target_path, &extra_files, &quarantine)?;
+                }
+            }
             Ok(())
         }
-        Some(Commands::DownloadRelease { id, target_path }) => {
+        Some(Commands::DownloadRelease {
+            id,
+            target_path,
+            escape_names,
+            verify,
+            concurrency,
+            max_per_host,
+            max_rate,
+            rate_schedule,
+            max_attempts,
+            retry_delay,
+            max_retry_delay,
+            repair,
+            backup,
+            fix_names,
+            via_torrent,
+            peer,
+            max_peers,
+        }) => {
+            let options = DownloadOptions {
+                concurrency,
+                max_per_host,
+                verify,
+                retry: RetryPolicy {
+                    max_attempts,
+                    initial_delay: Duration::from_secs(retry_delay),
+                    max_delay: Duration::from_secs(max_retry_delay),
+                },
+                repair,
+                backup_directory: backup,
+            };
+            let throttle = DownloadThrottle::new(max_rate, rate_schedule);
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
-            let release = get_release_by_id(&conn, &id)?;
-            if id == RELEASE_14_UNCOMPRESSED_ID {
-                // Release 14 is a special case for which the data is scattered across different
-                // collections.
-                let release_14_links = get_release_14_links(&conn)?;
-                let release_14_file_links = get_release_14_file_links(&conn)?;
+            let mut release = get_release_by_id(&conn, &id)?;
+            if escape_names {
+                mark_escaped_names(&conn, &mut release)?;
+            }
+            if via_torrent {
                 let _ = conn.close();
-                release
-                    .download_release_14_from_archive(
-                        release_14_links,
-                        release_14_file_links,
-                        &target_path,
-                    )
+                let defaults = SwarmOptions::default();
+                let options = SwarmOptions {
+                    peers: peer,
+                    max_peers: max_peers.unwrap_or(defaults.max_peers),
+                };
+                let outcome = release
+                    .download_via_torrent(&target_path, &options, &throttle)
                     .await?;
+                save_download_outcome(&mut release, Some(outcome))?;
                 return Ok(());
             }
+            let sources = get_download_sources(&conn, &release)?
+                .into_iter()
+                .map(|(_, source)| source)
+                .collect::<Vec<DownloadSource>>();
             let _ = conn.close();
+            if sources.is_empty() {
+                return Err(eyre!(
+                    "This release does not have a download URL or any other sources"
+                ));
+            }
 
-            let url = if let Some(url) = release.download_url.as_ref() {
-                url
-            } else {
-                return Err(eyre!("This release does not have a download URL"));
-            };
-            let filename = url
-                .path_segments()
-                .and_then(|s| s.last())
-                .ok_or_else(|| Error::FilenameFromUrlError)?;
-            if PathBuf::from(filename).extension().unwrap() == "zip" {
+            // A zip on its own is downloaded whole and extracted, unless only some of the files
+            // are needed.
+            if let ([DownloadSource::Zip(url)], false) = (sources.as_slice(), repair) {
+                if verify {
+                    println!(
+                        "This release is downloaded as a zip, so it can't be verified while it is \
+                         downloaded. Use the verify command once it has been extracted."
+                    );
+                }
                 release
-                    .download_zip_release_from_archive(&url, &target_path)
+                    .download_zip_release_from_archive(url, &target_path, &options, &throttle)
                     .await?;
             } else {
-                release
-                    .download_release_from_archive(&url, &target_path)
+                let outcome = release
+                    .download_from_sources(&sources, &target_path, &options, &throttle)
                     .await?;
+                save_download_outcome(&mut release, outcome)?;
+            }
+            if repair {
+                let verify_options = VerifyOptions {
+                    fix_names,
+                    ..Default::default()
+                };
+                verify_repaired_release(&mut release, &target_path, &verify_options)?;
             }
             Ok(())
         }
-        Some(Commands::Init {}) => {
+        Some(Commands::Ignore { command }) => {
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            match command {
+                IgnoreCommands::Add {
+                    pattern,
+                    reason,
+                    id,
+                } => {
+                    if let Some(id) = &id {
+                        // This will fail if there is no such release.
+                        get_release_by_id(&conn, id)?;
+                    }
+                    let rule_id = add_ignore_rule(&conn, id.as_deref(), &pattern, &reason)?;
+                    println!("Added ignore rule {rule_id}");
+                }
+                IgnoreCommands::Ls { id } => {
+                    let rules = if let Some(id) = &id {
+                        get_ignore_rules(&conn, Some(id))?
+                    } else {
+                        get_all_ignore_rules(&conn)?
+                    };
+                    for rule in rules.iter() {
+                        println!(
+                            "{}: {} [{}] {}",
+                            rule.id,
+                            rule.pattern,
+                            rule.release_id.as_deref().unwrap_or("global"),
+                            rule.reason
+                        );
+                    }
+                }
+                IgnoreCommands::Rm { rule_id } => {
+                    remove_ignore_rule(&conn, rule_id)?;
+                    println!("Removed ignore rule {rule_id}");
+                }
+            }
+            let _ = conn.close();
+            Ok(())
+        }
+        Some(Commands::Init {
+            max_rate,
+            rate_schedule,
+            max_attempts,
+            retry_delay,
+            max_retry_delay,
+        }) => {
+            let throttle = DownloadThrottle::new(max_rate, rate_schedule);
+            let retry = RetryPolicy {
+                max_attempts,
+                initial_delay: Duration::from_secs(retry_delay),
+                max_delay: Duration::from_secs(max_retry_delay),
+            };
             let db_path = get_database_path()?;
             if db_path.exists() {
                 let conn = get_db_connection(&db_path)?;
@@ -247,7 +839,7 @@ async fn main() -> Result<()> {
 
                 let conn = get_db_connection(&db_path)?;
                 let temp_dir = TempDir::new("torrents")?;
-                download_torrents(&conn, &temp_dir.into_path()).await?;
+                download_torrents(&conn, &temp_dir.into_path(), &throttle, &retry).await?;
 
                 // The purpose of this is to save any additional data that was added to the static
                 // release data. It should leave verification results unchanged.
@@ -255,11 +847,9 @@ async fn main() -> Result<()> {
                 let mut releases = get_releases(&conn)?;
                 Release::reinit_releases(&mut releases)?;
                 let count = releases.len();
-                let mut i = 1;
-                for release in releases.iter() {
-                    println!("Saving release {} of {}", i, count);
-                    save_release(&conn, &release)?;
-                    i += 1;
+                for (i, release) in releases.iter().enumerate() {
+                    println!("Saving release {} of {}", i + 1, count);
+                    save_release(&conn, release)?;
                 }
                 let _ = conn.close();
 
@@ -283,7 +873,7 @@ async fn main() -> Result<()> {
 
             let temp_dir = TempDir::new("torrents")?;
             let temp_path = temp_dir.into_path().clone();
-            download_torrents(&conn, &temp_path).await?;
+            download_torrents(&conn, &temp_path, &throttle, &retry).await?;
 
             println!("Building releases from static data...");
             let releases = Release::init_releases(temp_path)?;
@@ -293,7 +883,7 @@ async fn main() -> Result<()> {
 
             println!("Saving releases...");
             for release in releases.iter() {
-                save_release(&conn, &release)?;
+                save_release(&conn, release)?;
             }
             let _ = conn.close();
             println!("Done");
@@ -311,6 +901,28 @@ async fn main() -> Result<()> {
 
             Ok(())
         }
+        Some(Commands::LoadManifest { id, source, prefix }) => {
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            let release = get_release_by_id(&conn, &id)?;
+            match source {
+                Some(source) => {
+                    let _ = conn.close();
+                    release
+                        .load_archive_manifest(&source, prefix.as_deref())
+                        .await?;
+                }
+                None if id == RELEASE_14_UNCOMPRESSED_ID => {
+                    let release_14_links = get_release_14_links(&conn)?;
+                    let _ = conn.close();
+                    release.load_release_14_manifests(release_14_links).await?;
+                }
+                None => {
+                    return Err(eyre!("A manifest source must be supplied for this release"));
+                }
+            }
+            Ok(())
+        }
         Some(Commands::Ls { directory }) => {
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
@@ -335,21 +947,55 @@ async fn main() -> Result<()> {
             }
             Ok(())
         }
-        Some(Commands::LsFiles { id, use_bytes }) => {
+        Some(Commands::LsFiles {
+            id,
+            use_bytes,
+            hashes,
+            sources,
+        }) => {
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
             let release = get_release_by_id(&conn, &id)?;
-            let files = release.get_torrent_tree()?;
-            for (path, size) in files.iter() {
-                if use_bytes {
-                    println!("{} ({})", path.to_string_lossy(), *size);
+            let files = release.get_torrent_files()?;
+            let file_hashes = if hashes {
+                get_file_hashes(&conn, &id)?
+            } else {
+                HashMap::new()
+            };
+            let file_sources = if sources {
+                get_file_sources(&conn, &id)?
+            } else {
+                HashMap::new()
+            };
+            for file in files.iter() {
+                let path = &file.path;
+                let mut size = if use_bytes {
+                    file.length.to_string()
                 } else {
-                    println!(
-                        "{} ({})",
-                        path.to_string_lossy(),
-                        bytes_to_human_readable(*size)
-                    );
+                    bytes_to_human_readable(file.length)
+                };
+                let attributes = file.attributes.names();
+                if !attributes.is_empty() {
+                    size = format!("{}, {}", size, attributes.join(", "));
                 }
+                let mut line = format!("{} ({})", path.to_string_lossy(), size);
+                if hashes {
+                    match file_hashes.get(path) {
+                        Some(digest) => {
+                            line.push_str(&format!(" sha256:{} md5:{}", digest.sha256, digest.md5))
+                        }
+                        None => line.push_str(" no hashes"),
+                    }
+                }
+                if sources {
+                    match file_sources.get(path) {
+                        Some((source, downloaded_at)) => {
+                            line.push_str(&format!(" from {} at {}", source, downloaded_at))
+                        }
+                        None => line.push_str(" no source recorded"),
+                    }
+                }
+                println!("{line}");
             }
             let _ = conn.close();
             Ok(())
@@ -364,7 +1010,7 @@ async fn main() -> Result<()> {
             let mut release = get_release_by_id(&conn, &id)?;
             release
                 .mark_incomplete(missing_files_path.as_deref(), corrupt_files_path.as_deref())?;
-            save_verification_result(&mut conn, &mut release)?;
+            save_verification_result(&mut conn, &release)?;
             println!("Marked {} as incomplete", release.name);
             Ok(())
         }
@@ -373,7 +1019,7 @@ async fn main() -> Result<()> {
             let mut conn = get_db_connection(&db_path)?;
             let mut release = get_release_by_id(&conn, &id)?;
             release.mark_missing()?;
-            save_verification_result(&mut conn, &mut release)?;
+            save_verification_result(&mut conn, &release)?;
             println!("Marked {} as missing", release.name);
             Ok(())
         }
@@ -389,6 +1035,71 @@ async fn main() -> Result<()> {
             }
             Ok(())
         }
+        Some(Commands::PieceMap { id, columns }) => {
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            let release = get_release_by_id(&conn, &id)?;
+            let _ = conn.close();
+            release.print_piece_map(columns)?;
+            Ok(())
+        }
+        Some(Commands::Report {
+            command,
+            id,
+            sign,
+            output,
+        }) => {
+            match command {
+                Some(ReportCommands::Keygen { key }) => {
+                    let (public_key, public_key_path) = generate_signing_key(&key)?;
+                    println!("Saved the signing key to {}", key.display());
+                    println!(
+                        "Saved the public key to {}: {}",
+                        public_key_path.display(),
+                        hex::encode(public_key.as_bytes())
+                    );
+                }
+                Some(ReportCommands::Verify { report, public_key }) => {
+                    let public_key = read_verifying_key(&public_key)?;
+                    let report =
+                        verify_signed_report(&std::fs::read_to_string(report)?, &public_key)?;
+                    println!("The signature is valid");
+                    println!("Release: {} ({})", report.release.name, report.release.id);
+                    if let Some(info_hash) = &report.torrent.info_hash_v1 {
+                        println!("Info hash (v1): {info_hash}");
+                    }
+                    if let Some(info_hash) = &report.torrent.info_hash_v2 {
+                        println!("Info hash (v2): {info_hash}");
+                    }
+                    println!("Outcome: {}", report.outcome);
+                    println!(
+                        "Verified at: {}",
+                        report.verified_at.as_deref().unwrap_or("unknown")
+                    );
+                    println!("Produced by: {} {}", report.tool, report.tool_version);
+                }
+                None => {
+                    // Both are required unless a subcommand is given.
+                    let (Some(id), Some(sign)) = (id, sign) else {
+                        return Err(eyre!("A release ID and a signing key must be supplied"));
+                    };
+                    let key = read_signing_key(&sign)?;
+                    let db_path = get_database_path()?;
+                    let conn = get_db_connection(&db_path)?;
+                    let release = get_release_by_id(&conn, &id)?;
+                    let _ = conn.close();
+                    let report = release.get_verification_report()?.sign(&key)?;
+                    match output {
+                        Some(output) => {
+                            std::fs::write(&output, format!("{report}\n"))?;
+                            println!("Saved the signed report to {}", output.display());
+                        }
+                        None => println!("{report}"),
+                    }
+                }
+            }
+            Ok(())
+        }
         Some(Commands::Reset { id }) => {
             let db_path = get_database_path()?;
             let mut conn = get_db_connection(&db_path)?;
@@ -404,6 +1115,50 @@ async fn main() -> Result<()> {
             }
             Ok(())
         }
+        Some(Commands::Source { command }) => {
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            match command {
+                SourceCommands::Add {
+                    id,
+                    kind,
+                    location,
+                    priority,
+                } => {
+                    // This will fail if there is no such release.
+                    get_release_by_id(&conn, &id)?;
+                    let source_id = add_release_source(&conn, &id, priority, kind, &location)?;
+                    println!("Added source {source_id}");
+                }
+                SourceCommands::Ls { id } => {
+                    let release = get_release_by_id(&conn, &id)?;
+                    let mut file_counts = HashMap::new();
+                    for (source, _) in get_file_sources(&conn, &id)?.into_values() {
+                        *file_counts.entry(source).or_insert(0) += 1;
+                    }
+                    for (source, download_source) in get_download_sources(&conn, &release)? {
+                        let description = download_source.describe();
+                        let (label, priority) = match source {
+                            Some(source) => (source.id.to_string(), source.priority),
+                            None => ("download URL".to_string(), 0),
+                        };
+                        println!(
+                            "{}: {} [priority {}] {} files downloaded",
+                            label,
+                            description,
+                            priority,
+                            file_counts.get(&description).unwrap_or(&0)
+                        );
+                    }
+                }
+                SourceCommands::Rm { source_id } => {
+                    remove_release_source(&conn, source_id)?;
+                    println!("Removed source {source_id}");
+                }
+            }
+            let _ = conn.close();
+            Ok(())
+        }
         Some(Commands::Status {
             id,
             show_incomplete,
@@ -413,6 +1168,14 @@ async fn main() -> Result<()> {
             if let Some(id) = id {
                 let release = get_release_by_id(&conn, &id)?;
                 release.print_verification_status(show_incomplete)?;
+                let entries = get_manifest_entries(&conn, &id)?;
+                if !entries.is_empty() {
+                    println!();
+                    println!("Archive manifest:");
+                    print_manifest_summary(
+                        &entries.into_iter().map(|e| e.status).collect::<Vec<_>>(),
+                    );
+                }
             } else {
                 let releases = get_releases(&conn)?;
                 Release::print_status_table(&releases)?;
@@ -425,20 +1188,15 @@ async fn main() -> Result<()> {
                     .iter()
                     .filter(|x| x.get_verification_outcome() == "INCOMPLETE")
                     .map(|x| {
-                        let size = if let Some(outcome) = &x.verification_outcome {
-                            match outcome {
-                                VerificationOutcome::Incomplete(missing, size_mismatch) => {
-                                    let missing_size: u64 = missing.iter().map(|x| x.1).sum();
-                                    let mismatch_size: u64 =
-                                        size_mismatch.iter().map(|x| x.1).sum();
-                                    missing_size + mismatch_size
-                                }
-                                _ => 0,
-                            }
+                        if let Some(VerificationOutcome::Incomplete(missing, size_mismatch)) =
+                            &x.verification_outcome
+                        {
+                            let missing_size: u64 = missing.iter().map(|x| x.1).sum();
+                            let mismatch_size: u64 = size_mismatch.iter().map(|x| x.1).sum();
+                            missing_size + mismatch_size
                         } else {
                             0
-                        };
-                        size
+                        }
                     })
                     .sum();
                 if missing_bytes_remaining > 0 || incomplete_bytes_remaining > 0 {
@@ -458,22 +1216,102 @@ async fn main() -> Result<()> {
             }
             Ok(())
         }
-        Some(Commands::Verify { id, target_path }) => {
+        Some(Commands::VerifyManifest {
+            id,
+            target_path,
+            show_files,
+            max_read_rate,
+            idle_io,
+        }) => {
+            if idle_io {
+                use_idle_io_priority();
+            }
+            let db_path = get_database_path()?;
+            let conn = get_db_connection(&db_path)?;
+            let release = get_release_by_id(&conn, &id)?;
+            let _ = conn.close();
+            let statuses = release.verify_archive_manifest(&target_path, max_read_rate)?;
+            if statuses.is_empty() {
+                return Err(eyre!(
+                    "There are no manifest entries for this release. Use load-manifest first."
+                ));
+            }
+            if show_files {
+                for (path, status) in statuses.iter() {
+                    println!("{}: {}", path.to_string_lossy(), status);
+                }
+            }
+            print_manifest_summary(&statuses.into_iter().map(|(_, s)| s).collect::<Vec<_>>());
+            Ok(())
+        }
+        Some(Commands::Verify {
+            id,
+            target_path,
+            jobs,
+            max_memory,
+            resume,
+            partial,
+            no_file_hashes,
+            incremental,
+            from_zip,
+            path,
+            fix_names,
+            escape_names,
+            max_read_rate,
+            idle_io,
+        }) => {
+            if idle_io {
+                use_idle_io_priority();
+            }
             // The release verification process can potentially take a very long time, so the
             // database connection will not be left open while that's running.
             // We'll open a new connection at the end of verification and use that to save the
             // result.
+            let mut options = VerifyOptions {
+                max_memory: max_memory * 1024 * 1024,
+                resume,
+                partial,
+                file_hashes: !no_file_hashes,
+                incremental,
+                fix_names,
+                max_read_rate,
+                ..Default::default()
+            };
+            if let Some(jobs) = jobs {
+                options.jobs = jobs;
+            }
+            let source = match (from_zip, target_path) {
+                (Some(zip_path), _) => VerifySource::Zip(zip_path),
+                (None, Some(target_path)) => VerifySource::Directory(target_path),
+                (None, None) => {
+                    return Err(eyre!("Either a target path or a zip must be supplied"))
+                }
+            };
             let db_path = get_database_path()?;
             let conn = get_db_connection(&db_path)?;
-            if let Some(id) = id {
+            if let (Some(id), Some(path), VerifySource::Directory(target_path)) =
+                (&id, &path, &source)
+            {
+                let mut release = get_release_by_id(&conn, id)?;
+                if escape_names {
+                    mark_escaped_names(&conn, &mut release)?;
+                }
+                let _ = conn.close();
+                let results = release.verify_path(target_path, path, &options)?;
+                print_file_results(&results);
+                println!("Release outcome: {}", release.get_verification_outcome());
+            } else if let Some(id) = id {
                 let mut release = get_release_by_id(&conn, &id)?;
+                if escape_names {
+                    mark_escaped_names(&conn, &mut release)?;
+                }
                 let _ = conn.close();
-                verify_release(&mut release, &target_path)?;
+                verify_release(&mut release, &source, &options)?;
             } else {
                 let mut releases = get_releases(&conn)?;
                 let _ = conn.close();
-                for mut release in releases.iter_mut() {
-                    verify_release(&mut release, &target_path)?;
+                for release in releases.iter_mut() {
+                    verify_release(release, &source, &options)?;
                 }
             }
             Ok(())
@@ -482,17 +1320,147 @@ async fn main() -> Result<()> {
     }
 }
 
-fn verify_release(release: &mut Release, target_path: &PathBuf) -> Result<()> {
+/// Lowers the I/O priority of the process, or carries on at the normal priority if that fails.
+fn use_idle_io_priority() {
+    match set_idle_io_priority() {
+        Ok(()) => println!("Reading at the idle I/O priority"),
+        Err(e) => println!("{e}; reading at the normal I/O priority"),
+    }
+}
+
+/// Records that a release is stored with escaped names, if it isn't already.
+fn mark_escaped_names(conn: &Connection, release: &mut Release) -> Result<()> {
+    if !release.escaped_names {
+        save_escaped_names(conn, &release.id, true)?;
+        release.escaped_names = true;
+        println!("The release will now be stored with escaped names");
+    }
+    Ok(())
+}
+
+/// Gets the sources to download a release from, in the order they should be tried, along with
+/// the row each came from. The download URL of the release has no row.
+///
+/// The download URL, or the collections for release 14, has a priority of 0, so it's tried
+/// before any source added with the default priority.
+fn get_download_sources(
+    conn: &Connection,
+    release: &Release,
+) -> Result<Vec<(Option<ReleaseSource>, DownloadSource)>> {
+    let mut sources = Vec::new();
+    if release.id == RELEASE_14_UNCOMPRESSED_ID {
+        // Release 14 is a special case for which the data is scattered across different
+        // collections.
+        sources.push((
+            None,
+            DownloadSource::Release14 {
+                links: get_release_14_links(conn)?,
+                file_links: get_release_14_file_links(conn)?,
+            },
+        ));
+    } else if let Some(url) = &release.download_url {
+        sources.push((None, DownloadSource::from_download_url(url)));
+    }
+    for source in get_release_sources(conn, &release.id)? {
+        let download_source = DownloadSource::from_release_source(&source)?;
+        sources.push((Some(source), download_source));
+    }
+    // The sort is stable, so the download URL stays ahead of sources added with a priority of 0.
+    sources.sort_by_key(|(source, _)| source.as_ref().map_or(0, |s| s.priority));
+    Ok(sources)
+}
+
+fn print_manifest_summary(statuses: &[ManifestStatus]) {
+    let count = |status: ManifestStatus| statuses.iter().filter(|s| **s == status).count();
+    println!(
+        "{} files matched the Archive checksums",
+        count(ManifestStatus::Matched)
+    );
+    println!(
+        "{} files did not match the Archive checksums",
+        count(ManifestStatus::Mismatched)
+    );
+    println!("{} files are missing", count(ManifestStatus::Missing));
+    println!(
+        "{} files have not been checked",
+        count(ManifestStatus::Unchecked)
+    );
+}
+
+enum VerifySource {
+    Directory(PathBuf),
+    Zip(PathBuf),
+}
+
+/// Saves and prints the outcome of verifying a release while it was downloaded, if it was.
+fn save_download_outcome(
+    release: &mut Release,
+    outcome: Option<VerificationOutcome>,
+) -> Result<()> {
+    let Some(outcome) = outcome else {
+        return Ok(());
+    };
+    release.verification_outcome = Some(outcome.clone());
+    let mut conn = get_db_connection(get_database_path()?)?;
+    save_verification_result(&mut conn, release)?;
+    let _ = conn.close();
+    match outcome {
+        VerificationOutcome::Incomplete(missing, corrupted) => {
+            println!("Outcome: INCOMPLETE");
+            println!("Missing files: {missing:#?}");
+            println!("Corrupted files: {corrupted:#?}");
+        }
+        _ => {
+            println!("Outcome: {}", outcome);
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the files that were downloaded to repair a release, and prints their results.
+fn verify_repaired_release(
+    release: &mut Release,
+    target_path: &Path,
+    options: &VerifyOptions,
+) -> Result<()> {
+    let results = release.verify_repaired_files(target_path, options)?;
+    print_file_results(&results);
+    println!("Release outcome: {}", release.get_verification_outcome());
+    Ok(())
+}
+
+fn print_file_results(results: &[FileResult]) {
+    for result in results.iter() {
+        match &result.reason {
+            Some(reason) => println!(
+                "{}: {} [{}]",
+                result.path.to_string_lossy(),
+                result.status,
+                reason
+            ),
+            None => println!("{}: {}", result.path.to_string_lossy(), result.status),
+        }
+    }
+}
+
+fn verify_release(
+    release: &mut Release,
+    source: &VerifySource,
+    options: &VerifyOptions,
+) -> Result<()> {
     println!("Processing release: {}", release.name);
     let outcome = if let Some(verification_outcome) = &release.verification_outcome {
         println!("This release was previously verified");
         verification_outcome.clone()
     } else {
-        let outcome = release.verify(target_path)?;
+        let outcome = match source {
+            VerifySource::Directory(target_path) => release.verify(target_path, options)?,
+            VerifySource::Zip(zip_path) => release.verify_zip(zip_path, options)?,
+        };
         release.verification_outcome = Some(outcome.clone());
 
         let mut conn = get_db_connection(get_database_path()?)?;
-        save_verification_result(&mut conn, &release)?;
+        save_verification_result(&mut conn, release)?;
         let _ = conn.close();
         outcome
     };
@@ -508,3 +1476,43 @@ fn verify_release(release: &mut Release, target_path: &PathBuf) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    #[test]
+    fn tries_the_download_url_before_sources_with_the_same_priority() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_db_schema(&conn).unwrap();
+        let release = Release::new(
+            "2009-01-01".to_string(),
+            "Release".to_string(),
+            Some("Release".to_string()),
+            Some(3),
+            Some(100),
+            None,
+            Some(Url::parse("https://archive.org/download/item/rel.zip").unwrap()),
+        );
+        save_release(&conn, &release).unwrap();
+        add_release_source(&conn, &release.id, 1, SourceKind::Local, "/mnt/nas").unwrap();
+        add_release_source(&conn, &release.id, 0, SourceKind::Local, "/mnt/same").unwrap();
+        add_release_source(&conn, &release.id, -1, SourceKind::Local, "/mnt/first").unwrap();
+
+        let sources = get_download_sources(&conn, &release)
+            .unwrap()
+            .into_iter()
+            .map(|(_, source)| source.describe())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            sources,
+            vec![
+                "local /mnt/first",
+                "zip https://archive.org/download/item/rel.zip",
+                "local /mnt/same",
+                "local /mnt/nas",
+            ]
+        );
+    }
+}
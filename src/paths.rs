@@ -0,0 +1,252 @@
+use crate::error::Result;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Where a file in the torrent tree was found on disk.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolvedPath {
+    /// The file is at the path given in the torrent.
+    Exact,
+    /// The file was only found after normalising the names in its path. The path is relative to
+    /// the target directory, like the torrent path.
+    Normalised(PathBuf),
+    Missing,
+}
+
+/// Finds files on disk whose names differ from the torrent only in case or Unicode normalisation.
+///
+/// Copying a release through a macOS or Windows share can change the case of names, or switch them
+/// between the composed (NFC) and decomposed (NFD) forms. Each directory is only listed the first
+/// time a name in it can't be found as it is, so resolving every file in a release stays cheap.
+pub struct PathResolver {
+    root: PathBuf,
+    listings: HashMap<PathBuf, HashMap<String, OsString>>,
+}
+
+impl PathResolver {
+    pub fn new(root: &Path) -> PathResolver {
+        PathResolver {
+            root: root.to_path_buf(),
+            listings: HashMap::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, torrent_path: &Path) -> Result<ResolvedPath> {
+        if self.root.join(torrent_path).exists() {
+            return Ok(ResolvedPath::Exact);
+        }
+        let mut resolved = PathBuf::new();
+        for component in torrent_path.components() {
+            let Component::Normal(name) = component else {
+                return Ok(ResolvedPath::Missing);
+            };
+            if self.root.join(&resolved).join(name).exists() {
+                resolved.push(name);
+                continue;
+            }
+            let listing = self.listing(&resolved)?;
+            match listing.get(&normalise_name(&name.to_string_lossy())) {
+                Some(actual_name) => resolved.push(actual_name),
+                None => return Ok(ResolvedPath::Missing),
+            }
+        }
+        Ok(ResolvedPath::Normalised(resolved))
+    }
+
+    /// The entries in a directory under the root, keyed by their normalised names.
+    ///
+    /// If two entries only differ by case or normalisation, the first one listed is used.
+    fn listing(&mut self, directory: &Path) -> Result<&HashMap<String, OsString>> {
+        if !self.listings.contains_key(directory) {
+            let mut names = HashMap::new();
+            let full_path = self.root.join(directory);
+            if full_path.is_dir() {
+                for entry in std::fs::read_dir(&full_path)? {
+                    let name = entry?.file_name();
+                    names
+                        .entry(normalise_name(&name.to_string_lossy()))
+                        .or_insert(name);
+                }
+            }
+            self.listings.insert(directory.to_path_buf(), names);
+        }
+        Ok(&self.listings[directory])
+    }
+}
+
+/// Lower-cases a name and converts it to NFC, so names that only differ by case or normalisation
+/// compare equal.
+pub fn normalise_name(name: &str) -> String {
+    name.to_lowercase().nfc().collect()
+}
+
+/// Renames files found under normalised names to the paths they were expected to be at.
+///
+/// Each pair is an expected path and the path the file was found at. Directories in the path are
+/// renamed as well, which also fixes the other files inside them.
+pub fn fix_names(root: &Path, renames: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for (expected_path, disk_path) in renames.iter() {
+        let mut fixed = PathBuf::new();
+        for (expected_name, disk_name) in expected_path.components().zip(disk_path.components()) {
+            let from = root.join(&fixed).join(disk_name);
+            let to = root.join(&fixed).join(expected_name);
+            fixed.push(expected_name);
+            // A directory may already have been renamed while fixing another file inside it.
+            if expected_name != disk_name && from.exists() {
+                std::fs::rename(&from, &to)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Characters that can't be used in names on NTFS or exFAT.
+const INVALID_CHARS: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// Names that Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Escapes a name so it can be stored on NTFS or exFAT.
+///
+/// Invalid characters, control characters, and dots or spaces at the end of the name are replaced
+/// by `%` and the two hex digits of the character. A reserved device name like `CON` has its first
+/// character escaped. Since `%` is always escaped too, the original name can be recovered with
+/// [`unescape_name`].
+pub fn escape_name(name: &str) -> String {
+    let trailing_start = name.trim_end_matches(['.', ' ']).len();
+    let stem = name.split('.').next().unwrap_or(name);
+    let reserved = RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem));
+    let mut escaped = String::with_capacity(name.len());
+    for (i, c) in name.char_indices() {
+        if c == '%'
+            || INVALID_CHARS.contains(&c)
+            || (c as u32) < 0x20
+            || i >= trailing_start
+            || (reserved && i == 0)
+        {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape_name`].
+pub fn unescape_name(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(idx) = rest.find('%') {
+        unescaped.push_str(&rest[..idx]);
+        let code = rest
+            .get(idx + 1..idx + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[idx + 3..];
+            }
+            None => {
+                unescaped.push('%');
+                rest = &rest[idx + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Escapes each name in a torrent path with [`escape_name`].
+pub fn escape_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => PathBuf::from(escape_name(&name.to_string_lossy())),
+            other => PathBuf::from(other.as_os_str()),
+        })
+        .collect()
+}
+
+/// Reverses [`escape_path`].
+pub fn unescape_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => PathBuf::from(unescape_name(&name.to_string_lossy())),
+            other => PathBuf::from(other.as_os_str()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_names_windows_cannot_store() {
+        assert_eq!(escape_name("plain name.txt"), "plain name.txt");
+        assert_eq!(escape_name("café ☃.txt"), "café ☃.txt");
+        assert_eq!(escape_name("a<b>c:d.txt"), "a%3Cb%3Ec%3Ad.txt");
+        assert_eq!(escape_name("\"q\"|w?e*\\"), "%22q%22%7Cw%3Fe%2A%5C");
+        assert_eq!(escape_name("tab\there\n"), "tab%09here%0A");
+        assert_eq!(escape_name("100%"), "100%25");
+        assert_eq!(escape_name("%41"), "%2541");
+    }
+
+    #[test]
+    fn escapes_trailing_dots_and_spaces() {
+        assert_eq!(escape_name("name. ."), "name%2E%20%2E");
+        assert_eq!(escape_name("name.txt "), "name.txt%20");
+        assert_eq!(escape_name(" a.b"), " a.b");
+        assert_eq!(escape_name("..."), "%2E%2E%2E");
+    }
+
+    #[test]
+    fn escapes_reserved_device_names() {
+        assert_eq!(escape_name("CON"), "%43ON");
+        assert_eq!(escape_name("con.txt"), "%63on.txt");
+        assert_eq!(escape_name("Lpt9.tar.gz"), "%4Cpt9.tar.gz");
+        assert_eq!(escape_name("CONSOLE"), "CONSOLE");
+        assert_eq!(escape_name("COM10"), "COM10");
+        assert_eq!(escape_name("my CON"), "my CON");
+    }
+
+    #[test]
+    fn unescapes_what_was_escaped() {
+        for name in [
+            "plain.txt",
+            "a<b>c:d.txt",
+            "100% done. ",
+            "%41",
+            "CON.txt",
+            "nul",
+            "line\r\nbreak",
+            "café ☃.txt",
+            "",
+        ] {
+            assert_eq!(unescape_name(&escape_name(name)), name);
+        }
+    }
+
+    #[test]
+    fn leaves_stray_percent_signs_when_unescaping() {
+        assert_eq!(unescape_name("100%"), "100%");
+        assert_eq!(unescape_name("%zz%4"), "%zz%4");
+        assert_eq!(unescape_name("a%2"), "a%2");
+        assert_eq!(unescape_name("%%41"), "%A");
+    }
+
+    #[test]
+    fn escapes_each_name_in_a_path() {
+        let path = Path::new("Release/AUX/notes: part 1?.txt");
+        let escaped = escape_path(path);
+        assert_eq!(
+            escaped,
+            PathBuf::from("Release/%41UX/notes%3A part 1%3F.txt")
+        );
+        assert_eq!(unescape_path(&escaped), path);
+    }
+}
@@ -0,0 +1,1004 @@
+use crate::build_http_client;
+use crate::error::{Error, Result};
+use crate::pieces::{Bitfield, PieceLayout};
+use crate::throttle::DownloadThrottle;
+use crate::tracker::{announce, Announce};
+use indicatif::{ProgressBar, ProgressStyle};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+
+/// The size of the blocks that pieces are requested in. Peers are allowed to refuse requests for
+/// anything larger.
+const BLOCK_SIZE: u64 = 16 * 1024;
+
+/// The number of block requests kept outstanding with each peer.
+const PIPELINE_DEPTH: usize = 16;
+
+/// The largest message we accept from a peer. A piece message is a little larger than a block,
+/// and a bitfield has a bit for every piece.
+const MAX_MESSAGE_LENGTH: usize = 4 * 1024 * 1024;
+
+/// The number of messages read from a peer that can wait to be handled. Once this many are
+/// waiting, nothing more is read from the connection, which limits the memory a fast peer can use.
+const MESSAGE_QUEUE_LENGTH: usize = 4;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers send a keep-alive at least every two minutes, so one that has been quiet for longer has
+/// gone away. A peer that hasn't unchoked us or sent a block for this long is also disconnected,
+/// so it doesn't hold up the end of a round.
+const PEER_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// A peer that sends this many pieces that fail their hash check is disconnected.
+const MAX_BAD_PIECES: usize = 3;
+
+/// How long to wait before connecting to the swarm again after a round in which pieces were
+/// downloaded but some are still needed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// The port given to trackers. Nothing listens on it, since pieces are only downloaded.
+const ANNOUNCE_PORT: u16 = 6881;
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Settings that control how a release is downloaded from its BitTorrent swarm.
+pub struct SwarmOptions {
+    /// Peers to connect to, as `host:port`. If there are any, the trackers aren't asked for more.
+    pub peers: Vec<String>,
+    /// The number of peers to download from at the same time.
+    pub max_peers: usize,
+}
+
+impl Default for SwarmOptions {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            max_peers: 30,
+        }
+    }
+}
+
+/// The messages of the peer wire protocol, as defined in BEP 3.
+enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Have(usize),
+    Bitfield(Vec<u8>),
+    Piece {
+        index: usize,
+        begin: u64,
+        block: Vec<u8>,
+    },
+    /// A message we don't act on, like a request from a peer. We never unchoke anyone, so they
+    /// shouldn't send us requests.
+    Other,
+}
+
+/// The state shared by the connections to every peer.
+struct Swarm<'a> {
+    /// The layout and target directory are shared with the blocking tasks that write pieces.
+    layout: Arc<PieceLayout>,
+    target_directory: PathBuf,
+    throttle: &'a DownloadThrottle,
+    progress: ProgressBar,
+    state: Mutex<SwarmState>,
+    /// Set once every piece is present, so connections that are waiting on a peer can stop.
+    complete: watch::Sender<bool>,
+}
+
+struct SwarmState {
+    /// The pieces that have been verified, either on disk before the download or as they arrived.
+    have: Bitfield,
+    /// The number of connections that are downloading each piece. Near the end of a download, the
+    /// same piece can be downloaded from several peers, and the first copy to arrive is kept.
+    in_progress: HashMap<usize, usize>,
+    connected: usize,
+}
+
+/// A piece that is being downloaded from a peer.
+struct PieceDownload {
+    index: usize,
+    data: Vec<u8>,
+    requested: Vec<bool>,
+    received: Vec<bool>,
+}
+
+impl PieceDownload {
+    fn new(index: usize, length: u64) -> Self {
+        let blocks = length.div_ceil(BLOCK_SIZE) as usize;
+        Self {
+            index,
+            data: vec![0; length as usize],
+            requested: vec![false; blocks],
+            received: vec![false; blocks],
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.requested
+            .iter()
+            .zip(self.received.iter())
+            .filter(|(requested, received)| **requested && !**received)
+            .count()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+}
+
+/// Downloads the pieces of a torrent that aren't in `have` from the peers in its swarm, verifying
+/// each against its hash and writing it into the files under the target directory.
+///
+/// The peers are either the ones in the options, or those the trackers know about. They are all
+/// connected to, up to `max_peers` at a time, until either every piece has been downloaded or they
+/// have all disconnected. If any pieces arrived and some are still needed, the swarm is joined
+/// again, since peers that were busy or choking us may now have room. Returns the pieces that are
+/// now present.
+pub async fn download_pieces(
+    layout: Arc<PieceLayout>,
+    info_hash: &[u8; 20],
+    trackers: &[String],
+    target_directory: &Path,
+    have: Bitfield,
+    options: &SwarmOptions,
+    throttle: &DownloadThrottle,
+) -> Result<Bitfield> {
+    let peer_id = generate_peer_id();
+    let client = build_http_client()?;
+    let progress = ProgressBar::new(layout.content_length());
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} {msg}",
+            )?
+            .progress_chars("#>-"),
+    );
+    let swarm = Swarm {
+        layout,
+        target_directory: target_directory.to_path_buf(),
+        throttle,
+        progress,
+        state: Mutex::new(SwarmState {
+            have,
+            in_progress: HashMap::new(),
+            connected: 0,
+        }),
+        complete: watch::channel(false).0,
+    };
+    swarm.progress.set_position(swarm.bytes_present());
+
+    while swarm.pieces_needed() > 0 {
+        let peers = if options.peers.is_empty() {
+            find_peers(&client, trackers, info_hash, &peer_id, swarm.bytes_needed()).await
+        } else {
+            resolve_peers(&options.peers).await?
+        };
+        if peers.is_empty() {
+            swarm.progress.println("No peers were found");
+            break;
+        }
+        let needed = swarm.pieces_needed();
+        let failures = Mutex::new(Vec::new());
+        futures::StreamExt::for_each_concurrent(
+            futures::stream::iter(peers.iter()),
+            std::cmp::max(options.max_peers, 1),
+            |address| {
+                let (swarm, failures) = (&swarm, &failures);
+                async move {
+                    if let Err(e) = swarm
+                        .download_from_peer(*address, info_hash, &peer_id)
+                        .await
+                    {
+                        failures.lock().unwrap().push((*address, e));
+                    }
+                }
+            },
+        )
+        .await;
+        let failures = failures.into_inner().unwrap();
+        for (address, e) in failures.iter() {
+            // Most of the peers a tracker gives are unreachable, so only failures of peers that
+            // were asked for are worth showing.
+            if !options.peers.is_empty() {
+                match e {
+                    Error::PeerError(..) => swarm.progress.println(e.to_string()),
+                    _ => swarm.progress.println(format!("Peer {address}: {e}")),
+                }
+            }
+        }
+        swarm.progress.println(format!(
+            "Finished with {} peers; {} could not be connected to or dropped the connection",
+            peers.len(),
+            failures.len()
+        ));
+        let still_needed = swarm.pieces_needed();
+        if still_needed == 0 || still_needed == needed {
+            break;
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+
+    let have = swarm.state.into_inner().unwrap().have;
+    if (0..have.len()).all(|piece_idx| have.get(piece_idx)) {
+        swarm.progress.finish_with_message("Downloaded all pieces");
+    } else {
+        swarm
+            .progress
+            .abandon_with_message("Some pieces could not be downloaded");
+    }
+    Ok(have)
+}
+
+/// Asks every tracker for peers, and returns all the peers they know about between them.
+async fn find_peers(
+    client: &reqwest::Client,
+    trackers: &[String],
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    left: u64,
+) -> Vec<SocketAddr> {
+    let request = Announce {
+        info_hash,
+        peer_id,
+        port: ANNOUNCE_PORT,
+        left,
+    };
+    let request = &request;
+    let responses = futures::future::join_all(
+        trackers
+            .iter()
+            .map(|tracker| async move { (tracker, announce(client, tracker, request).await) }),
+    )
+    .await;
+    let mut peers = Vec::new();
+    for (tracker, response) in responses {
+        match response {
+            Ok(tracker_peers) => {
+                println!("{}: {} peers", tracker, tracker_peers.len());
+                for peer in tracker_peers {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            Err(e @ Error::TrackerError(..)) => println!("{e}"),
+            Err(e) => println!("Tracker {tracker}: {e}"),
+        }
+    }
+    peers
+}
+
+async fn resolve_peers(peers: &[String]) -> Result<Vec<SocketAddr>> {
+    let mut addresses = Vec::new();
+    for peer in peers.iter() {
+        let address = tokio::net::lookup_host(peer.as_str())
+            .await?
+            .next()
+            .ok_or_else(|| Error::PeerError(peer.clone(), "could not be resolved".to_string()))?;
+        addresses.push(address);
+    }
+    Ok(addresses)
+}
+
+impl Swarm<'_> {
+    fn pieces_needed(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        (0..state.have.len())
+            .filter(|piece_idx| !state.have.get(*piece_idx))
+            .count()
+    }
+
+    fn bytes_needed(&self) -> u64 {
+        self.layout.content_length() - self.bytes_present()
+    }
+
+    /// The size of the content in the pieces that are present. Padding isn't counted, so this is
+    /// comparable with the content length.
+    fn bytes_present(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        (0..state.have.len())
+            .filter(|piece_idx| state.have.get(*piece_idx))
+            .map(|piece_idx| self.content_in_piece(piece_idx))
+            .sum()
+    }
+
+    fn content_in_piece(&self, piece_idx: usize) -> u64 {
+        let bounds = self.layout.piece_bounds(piece_idx);
+        self.layout
+            .files_for_piece(piece_idx)
+            .into_iter()
+            .map(|file_idx| {
+                let file = &self.layout.files[file_idx];
+                let start = std::cmp::max(bounds.start, file.offset);
+                let end = std::cmp::min(bounds.end, file.offset + file.length);
+                end - start
+            })
+            .sum()
+    }
+
+    /// Chooses the next piece to download from a peer with the given pieces.
+    ///
+    /// A piece nobody else is downloading is chosen if there is one. Otherwise, to stop a slow peer
+    /// holding up the end of the download, the peer helps with a piece that is already being
+    /// downloaded. Pieces that already failed their hash check when downloaded from the peer are
+    /// left for other peers.
+    fn choose_piece(&self, peer_has: &Bitfield, failed: &HashSet<usize>) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let wanted = |piece_idx: &usize| {
+            !state.have.get(*piece_idx) && peer_has.get(*piece_idx) && !failed.contains(piece_idx)
+        };
+        let piece_idx = (0..state.have.len())
+            .filter(wanted)
+            .find(|piece_idx| !state.in_progress.contains_key(piece_idx))
+            .or_else(|| (0..state.have.len()).find(wanted))?;
+        *state.in_progress.entry(piece_idx).or_insert(0) += 1;
+        Some(piece_idx)
+    }
+
+    /// Whether a peer with the given pieces has any we still need.
+    fn needs_any(&self, peer_has: &Bitfield, failed: &HashSet<usize>) -> bool {
+        let state = self.state.lock().unwrap();
+        (0..state.have.len()).any(|piece_idx| {
+            !state.have.get(piece_idx) && peer_has.get(piece_idx) && !failed.contains(&piece_idx)
+        })
+    }
+
+    fn release_piece(&self, piece_idx: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.in_progress.get_mut(&piece_idx) {
+            *count -= 1;
+            if *count == 0 {
+                state.in_progress.remove(&piece_idx);
+            }
+        }
+    }
+
+    fn has_piece(&self, piece_idx: usize) -> bool {
+        self.state.lock().unwrap().have.get(piece_idx)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces_needed() == 0
+    }
+
+    /// Checks a downloaded piece against its hash and writes it to disk. Returns whether it
+    /// matched.
+    ///
+    /// Hashing and writing are done on a blocking thread, so they don't hold up the connections to
+    /// other peers.
+    async fn complete_piece(&self, piece: PieceDownload) -> Result<bool> {
+        let piece_idx = piece.index;
+        // Another peer may have got there first near the end of the download, in which case the
+        // piece is only checked. If two copies are written at once, they are the same.
+        let write = !self.has_piece(piece_idx);
+        let layout = Arc::clone(&self.layout);
+        let target_directory = self.target_directory.clone();
+        let matched = tokio::task::spawn_blocking(move || -> Result<bool> {
+            let mut hasher = Sha1::new();
+            hasher.update(&piece.data);
+            if hasher.finalize().as_slice() != layout.pieces[piece.index] {
+                return Ok(false);
+            }
+            if write {
+                layout.write_piece(&target_directory, piece.index, &piece.data)?;
+            }
+            Ok(true)
+        })
+        .await
+        .map_err(|_| Error::HashingStopped)??;
+        if !matched || !write {
+            return Ok(matched);
+        }
+        let newly_present = {
+            let mut state = self.state.lock().unwrap();
+            let newly_present = !state.have.get(piece_idx);
+            state.have.set(piece_idx, true);
+            newly_present
+        };
+        if !newly_present {
+            return Ok(true);
+        }
+        self.progress.inc(self.content_in_piece(piece_idx));
+        if self.is_complete() {
+            self.complete.send_replace(true);
+        }
+        Ok(true)
+    }
+
+    fn set_connected(&self, change: isize) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = state.connected.saturating_add_signed(change);
+        self.progress
+            .set_message(format!("{} peers connected", state.connected));
+    }
+
+    async fn download_from_peer(
+        &self,
+        address: SocketAddr,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+    ) -> Result<()> {
+        let peer_error = |message: &str| Error::PeerError(address.to_string(), message.to_string());
+        let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| peer_error("timed out connecting"))??;
+        let mut handshake = Vec::with_capacity(68);
+        handshake.push(PROTOCOL.len() as u8);
+        handshake.extend_from_slice(PROTOCOL);
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(info_hash);
+        handshake.extend_from_slice(peer_id);
+        stream.write_all(&handshake).await?;
+        let mut response = [0u8; 68];
+        timeout(PEER_TIMEOUT, stream.read_exact(&mut response))
+            .await
+            .map_err(|_| peer_error("timed out waiting for the handshake"))??;
+        if response[0] as usize != PROTOCOL.len() || &response[1..20] != PROTOCOL {
+            return Err(peer_error("doesn't speak the BitTorrent protocol"));
+        }
+        if &response[28..48] != info_hash {
+            return Err(peer_error("isn't sharing this torrent"));
+        }
+
+        // Reading a message can't be interrupted part of the way through, so messages are read
+        // on their own task, and passed back through a channel that can be waited on with a
+        // timeout.
+        let (mut reader, mut writer) = stream.into_split();
+        let (messages_tx, mut messages_rx) = channel(MESSAGE_QUEUE_LENGTH);
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let message = read_message(&mut reader, address).await;
+                let failed = message.is_err();
+                if messages_tx.send(message).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        self.set_connected(1);
+        let mut current: Option<PieceDownload> = None;
+        let result = self
+            .exchange_messages(&mut writer, &mut messages_rx, &mut current, address)
+            .await;
+        self.set_connected(-1);
+        reader_task.abort();
+        if let Some(piece) = current {
+            self.release_piece(piece.index);
+        }
+        result
+    }
+
+    /// Downloads pieces from a connected peer until there is nothing more it can give us.
+    async fn exchange_messages(
+        &self,
+        writer: &mut OwnedWriteHalf,
+        messages: &mut Receiver<Result<Message>>,
+        current: &mut Option<PieceDownload>,
+        address: SocketAddr,
+    ) -> Result<()> {
+        let peer_error = |message: &str| Error::PeerError(address.to_string(), message.to_string());
+        // We are always interested, since the peer will tell us which pieces it has.
+        writer.write_all(&frame(2, &[])).await?;
+        let mut peer_has = Bitfield::new(self.layout.piece_count());
+        let mut choked = true;
+        let mut failed = HashSet::new();
+        let mut heard_from = false;
+        let mut last_progress = Instant::now();
+        let mut complete = self.complete.subscribe();
+        loop {
+            if let Some(piece) = current.as_ref() {
+                if self.has_piece(piece.index) {
+                    self.release_piece(piece.index);
+                    *current = None;
+                }
+            }
+            if self.is_complete() {
+                return Ok(());
+            }
+            // Peers announce their pieces as soon as they connect, so once we've heard from one
+            // that has nothing we need, it can be left for someone else. Any pieces it gets later
+            // will be found in the next round.
+            if heard_from && current.is_none() && !self.needs_any(&peer_has, &failed) {
+                return Ok(());
+            }
+            if !choked {
+                if current.is_none() {
+                    *current = self.choose_piece(&peer_has, &failed).map(|piece_idx| {
+                        let bounds = self.layout.piece_bounds(piece_idx);
+                        PieceDownload::new(piece_idx, bounds.end - bounds.start)
+                    });
+                }
+                if let Some(piece) = current.as_mut() {
+                    send_requests(writer, piece).await?;
+                }
+            }
+
+            let message = tokio::select! {
+                message = timeout_at(last_progress + PEER_TIMEOUT, messages.recv()) => match message {
+                    Ok(Some(message)) => message?,
+                    Ok(None) => return Err(peer_error("disconnected")),
+                    Err(_) if choked => return Err(peer_error("never unchoked us")),
+                    Err(_) => return Err(peer_error("stopped sending pieces")),
+                },
+                _ = complete.wait_for(|complete| *complete) => return Ok(()),
+            };
+            heard_from = true;
+            match message {
+                Message::KeepAlive | Message::Other => {}
+                Message::Choke => {
+                    choked = true;
+                    // The peer discards our requests when it chokes us, so they need to be sent
+                    // again once we're unchoked.
+                    if let Some(piece) = current.as_mut() {
+                        piece.requested = piece.received.clone();
+                    }
+                }
+                Message::Unchoke => {
+                    choked = false;
+                    last_progress = Instant::now();
+                }
+                Message::Have(piece_idx) => {
+                    if piece_idx < peer_has.len() {
+                        peer_has.set(piece_idx, true);
+                    }
+                }
+                Message::Bitfield(bytes) => {
+                    peer_has = Bitfield::from_bytes(self.layout.piece_count(), &bytes)?;
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    self.throttle.consume(block.len() as u64).await;
+                    last_progress = Instant::now();
+                    let Some(piece) = current.as_mut().filter(|piece| piece.index == index) else {
+                        // A block we stopped waiting for, because another peer finished the
+                        // piece first.
+                        continue;
+                    };
+                    let block_idx = (begin / BLOCK_SIZE) as usize;
+                    let end = begin as usize + block.len();
+                    if begin % BLOCK_SIZE != 0 || end > piece.data.len() {
+                        return Err(peer_error("sent a block we didn't ask for"));
+                    }
+                    piece.data[begin as usize..end].copy_from_slice(&block);
+                    piece.received[block_idx] = true;
+                    if piece.is_complete() {
+                        let piece = current.take().unwrap();
+                        let piece_idx = piece.index;
+                        self.release_piece(piece_idx);
+                        if !self.complete_piece(piece).await? {
+                            failed.insert(piece_idx);
+                            if failed.len() >= MAX_BAD_PIECES {
+                                return Err(peer_error("sent too many pieces that failed"));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Requests the blocks of a piece that haven't been asked for, keeping no more than
+/// `PIPELINE_DEPTH` outstanding.
+async fn send_requests(writer: &mut OwnedWriteHalf, piece: &mut PieceDownload) -> Result<()> {
+    let mut outstanding = piece.outstanding();
+    for block_idx in 0..piece.requested.len() {
+        if outstanding >= PIPELINE_DEPTH {
+            break;
+        }
+        if piece.requested[block_idx] {
+            continue;
+        }
+        let begin = block_idx as u64 * BLOCK_SIZE;
+        let length = std::cmp::min(BLOCK_SIZE, piece.data.len() as u64 - begin);
+        let mut request = Vec::with_capacity(12);
+        request.extend_from_slice(&(piece.index as u32).to_be_bytes());
+        request.extend_from_slice(&(begin as u32).to_be_bytes());
+        request.extend_from_slice(&(length as u32).to_be_bytes());
+        writer.write_all(&frame(6, &request)).await?;
+        piece.requested[block_idx] = true;
+        outstanding += 1;
+    }
+    Ok(())
+}
+
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    address: SocketAddr,
+) -> Result<Message> {
+    let peer_error = |message: &str| Error::PeerError(address.to_string(), message.to_string());
+    let length = reader.read_u32().await? as usize;
+    if length == 0 {
+        return Ok(Message::KeepAlive);
+    }
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(peer_error(&format!("sent a message of {length} bytes")));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    let read_u32 = |offset: usize| -> Result<u32> {
+        payload
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| peer_error("sent a short message"))
+    };
+    let message = match payload[0] {
+        0 => Message::Choke,
+        1 => Message::Unchoke,
+        4 => Message::Have(read_u32(1)? as usize),
+        5 => Message::Bitfield(payload[1..].to_vec()),
+        7 => Message::Piece {
+            index: read_u32(1)? as usize,
+            begin: read_u32(5)? as u64,
+            block: payload[9..].to_vec(),
+        },
+        _ => Message::Other,
+    };
+    Ok(message)
+}
+
+/// Generates a peer ID in the Azureus style, with a client code and version followed by random
+/// bytes.
+fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-SD0100-");
+    // Peer IDs only need to differ between clients, so a failure isn't important.
+    let _ = getrandom::getrandom(&mut peer_id[8..]);
+    peer_id
+}
+
+/// Frames a message to send to a peer, with its length and ID.
+fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    message.push(id);
+    message.extend_from_slice(payload);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempdir::TempDir;
+    use tokio::net::TcpListener;
+
+    const PIECE_LENGTH: u64 = 32 * 1024;
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    async fn read(bytes: &[u8]) -> Result<Message> {
+        read_message(&mut &bytes[..], address()).await
+    }
+
+    #[test]
+    fn frame_adds_length_and_id() {
+        assert_eq!(frame(2, &[]), vec![0, 0, 0, 1, 2]);
+        assert_eq!(frame(6, &[1, 2, 3]), vec![0, 0, 0, 4, 6, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn read_message_parses_each_kind() {
+        assert!(matches!(read(&[0, 0, 0, 0]).await, Ok(Message::KeepAlive)));
+        assert!(matches!(read(&frame(0, &[])).await, Ok(Message::Choke)));
+        assert!(matches!(read(&frame(1, &[])).await, Ok(Message::Unchoke)));
+        assert!(matches!(
+            read(&frame(4, &7u32.to_be_bytes())).await,
+            Ok(Message::Have(7))
+        ));
+        assert!(matches!(
+            read(&frame(5, &[0b1010_0000])).await,
+            Ok(Message::Bitfield(bytes)) if bytes == [0b1010_0000]
+        ));
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&3u32.to_be_bytes());
+        payload.extend_from_slice(&16384u32.to_be_bytes());
+        payload.extend_from_slice(b"data");
+        assert!(matches!(
+            read(&frame(7, &payload)).await,
+            Ok(Message::Piece { index: 3, begin: 16384, block }) if block == b"data"
+        ));
+        // Requests and other messages we don't act on.
+        assert!(matches!(
+            read(&frame(6, &[0; 12])).await,
+            Ok(Message::Other)
+        ));
+        assert!(matches!(read(&frame(20, &[1])).await, Ok(Message::Other)));
+    }
+
+    #[tokio::test]
+    async fn read_message_rejects_malformed_messages() {
+        assert!(matches!(
+            read(&frame(4, &[0, 1])).await,
+            Err(Error::PeerError(..))
+        ));
+        assert!(matches!(
+            read(&frame(7, &[0; 6])).await,
+            Err(Error::PeerError(..))
+        ));
+        let too_long = (MAX_MESSAGE_LENGTH as u32 + 1).to_be_bytes();
+        assert!(matches!(read(&too_long).await, Err(Error::PeerError(..))));
+        // A message that is cut off by the connection closing.
+        assert!(matches!(read(&[0, 0, 0, 5, 4, 0]).await, Err(Error::Io(_))));
+    }
+
+    /// How a test seeder behaves.
+    #[derive(Clone, Default)]
+    struct Seeder {
+        /// The pieces the seeder has.
+        has: Vec<usize>,
+        /// Announce the pieces with a have message for each, rather than a bitfield.
+        announce_with_have: bool,
+        /// Send every piece with its content corrupted.
+        corrupt: bool,
+        /// Choke the first time a block is requested, and unchoke a little later.
+        choke_once: bool,
+        /// Reply to the handshake with a different info hash.
+        wrong_info_hash: bool,
+    }
+
+    /// Starts a seeder that serves a single connection, and returns its address along with a count
+    /// of the blocks it sends.
+    async fn start_seeder(
+        seeder: Seeder,
+        info_hash: [u8; 20],
+        content: Arc<Vec<u8>>,
+        piece_count: usize,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_by_seeder = Arc::clone(&sent);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(&handshake[..20], &[&[19], PROTOCOL].concat()[..]);
+            assert_eq!(handshake[28..48], info_hash);
+            if seeder.wrong_info_hash {
+                handshake[28] ^= 1;
+            }
+            handshake[48..68].copy_from_slice(b"-TS0001-000000000000");
+            stream.write_all(&handshake).await.unwrap();
+            if seeder.announce_with_have {
+                for piece_idx in seeder.has.iter() {
+                    let have = frame(4, &(*piece_idx as u32).to_be_bytes());
+                    stream.write_all(&have).await.unwrap();
+                }
+            } else {
+                let mut bitfield = Bitfield::new(piece_count);
+                for piece_idx in seeder.has.iter() {
+                    bitfield.set(*piece_idx, true);
+                }
+                stream
+                    .write_all(&frame(5, bitfield.as_bytes()))
+                    .await
+                    .unwrap();
+            }
+
+            let mut choked_once = false;
+            loop {
+                let Ok(length) = stream.read_u32().await else {
+                    return;
+                };
+                let mut payload = vec![0; length as usize];
+                if length == 0 || stream.read_exact(&mut payload).await.is_err() {
+                    continue;
+                }
+                match payload[0] {
+                    2 => stream.write_all(&frame(1, &[])).await.unwrap(),
+                    6 => {
+                        if seeder.choke_once && !choked_once {
+                            // The request is dropped, so it has to be sent again.
+                            choked_once = true;
+                            stream.write_all(&frame(0, &[])).await.unwrap();
+                            sleep(Duration::from_millis(100)).await;
+                            stream.write_all(&frame(1, &[])).await.unwrap();
+                            continue;
+                        }
+                        let field = |i: usize| {
+                            u32::from_be_bytes(payload[1 + i * 4..5 + i * 4].try_into().unwrap())
+                        };
+                        let (index, begin, length) = (field(0), field(1), field(2));
+                        let start = index as usize * PIECE_LENGTH as usize + begin as usize;
+                        let mut block = content[start..start + length as usize].to_vec();
+                        if seeder.corrupt {
+                            block[0] ^= 1;
+                        }
+                        let mut piece = Vec::new();
+                        piece.extend_from_slice(&index.to_be_bytes());
+                        piece.extend_from_slice(&begin.to_be_bytes());
+                        piece.extend_from_slice(&block);
+                        if stream.write_all(&frame(7, &piece)).await.is_err() {
+                            return;
+                        }
+                        sent_by_seeder.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (address, sent)
+    }
+
+    /// Two files that share a piece, where the last piece, and its only block, are shorter than
+    /// usual.
+    fn test_content() -> (Vec<u8>, Vec<u8>) {
+        let a = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let b = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn downloads_pieces_from_seeders() {
+        let (a, b) = test_content();
+        let layout = Arc::new(PieceLayout::from_content(
+            &[("a.bin", &a), ("b.bin", &b)],
+            PIECE_LENGTH,
+        ));
+        let piece_count = layout.piece_count();
+        let last_piece = layout.piece_bounds(piece_count - 1);
+        assert!(last_piece.end - last_piece.start < BLOCK_SIZE);
+        let content = Arc::new([a.clone(), b.clone()].concat());
+        let info_hash = [7; 20];
+
+        // A good seeder that keeps us choked for a moment at the start, so the corrupt one gets
+        // some of the pieces.
+        let (good, _) = start_seeder(
+            Seeder {
+                has: (0..piece_count).collect(),
+                announce_with_have: true,
+                choke_once: true,
+                ..Default::default()
+            },
+            info_hash,
+            Arc::clone(&content),
+            piece_count,
+        )
+        .await;
+        let (corrupt, corrupt_sent) = start_seeder(
+            Seeder {
+                has: (0..piece_count).collect(),
+                corrupt: true,
+                ..Default::default()
+            },
+            info_hash,
+            Arc::clone(&content),
+            piece_count,
+        )
+        .await;
+
+        let target = TempDir::new("peer").unwrap();
+        let options = SwarmOptions {
+            peers: vec![good.to_string(), corrupt.to_string()],
+            max_peers: 2,
+        };
+        let have = download_pieces(
+            Arc::clone(&layout),
+            &info_hash,
+            &[],
+            target.path(),
+            Bitfield::new(piece_count),
+            &options,
+            &DownloadThrottle::new(None, Vec::new()),
+        )
+        .await
+        .unwrap();
+
+        assert!((0..piece_count).all(|piece_idx| have.get(piece_idx)));
+        assert!(corrupt_sent.load(Ordering::Relaxed) > 0);
+        assert_eq!(std::fs::read(target.path().join("a.bin")).unwrap(), a);
+        assert_eq!(std::fs::read(target.path().join("b.bin")).unwrap(), b);
+    }
+
+    #[tokio::test]
+    async fn only_downloads_pieces_that_are_needed() {
+        let (a, b) = test_content();
+        let layout = Arc::new(PieceLayout::from_content(
+            &[("a.bin", &a), ("b.bin", &b)],
+            PIECE_LENGTH,
+        ));
+        let piece_count = layout.piece_count();
+        let content = Arc::new([a.clone(), b.clone()].concat());
+        let info_hash = [7; 20];
+        let (seeder, sent) = start_seeder(
+            Seeder {
+                has: (0..piece_count).collect(),
+                ..Default::default()
+            },
+            info_hash,
+            content,
+            piece_count,
+        )
+        .await;
+
+        // Every piece but the last is present, so only its one block should be requested.
+        let mut have = Bitfield::new(piece_count);
+        for piece_idx in 0..piece_count - 1 {
+            have.set(piece_idx, true);
+        }
+        let target = TempDir::new("peer").unwrap();
+        let options = SwarmOptions {
+            peers: vec![seeder.to_string()],
+            max_peers: 1,
+        };
+        let have = download_pieces(
+            Arc::clone(&layout),
+            &info_hash,
+            &[],
+            target.path(),
+            have,
+            &options,
+            &DownloadThrottle::new(None, Vec::new()),
+        )
+        .await
+        .unwrap();
+
+        assert!(have.get(piece_count - 1));
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+        let b_on_disk = std::fs::read(target.path().join("b.bin")).unwrap();
+        let last_piece_start = layout.piece_bounds(piece_count - 1).start - a.len() as u64;
+        assert_eq!(
+            &b_on_disk[last_piece_start as usize..],
+            &b[last_piece_start as usize..]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_peer_sharing_another_torrent() {
+        let (a, b) = test_content();
+        let layout = Arc::new(PieceLayout::from_content(
+            &[("a.bin", &a), ("b.bin", &b)],
+            PIECE_LENGTH,
+        ));
+        let piece_count = layout.piece_count();
+        let info_hash = [7; 20];
+        let (seeder, sent) = start_seeder(
+            Seeder {
+                has: (0..piece_count).collect(),
+                wrong_info_hash: true,
+                ..Default::default()
+            },
+            info_hash,
+            Arc::new([a, b].concat()),
+            piece_count,
+        )
+        .await;
+
+        let target = TempDir::new("peer").unwrap();
+        let options = SwarmOptions {
+            peers: vec![seeder.to_string()],
+            max_peers: 1,
+        };
+        let have = download_pieces(
+            layout,
+            &info_hash,
+            &[],
+            target.path(),
+            Bitfield::new(piece_count),
+            &options,
+            &DownloadThrottle::new(None, Vec::new()),
+        )
+        .await
+        .unwrap();
+
+        assert!((0..piece_count).all(|piece_idx| !have.get(piece_idx)));
+        assert_eq!(sent.load(Ordering::Relaxed), 0);
+    }
+}
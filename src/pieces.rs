@@ -0,0 +1,1141 @@
+use crate::error::{Error, Result};
+use crate::paths::{escape_path, PathResolver, ResolvedPath};
+use crate::torrent_v2::V2Torrent;
+use lava_torrent::bencode::BencodeElem;
+use lava_torrent::torrent::v1::{File as LavaFile, Torrent};
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+/// A file in the torrent, along with where its content begins in the torrent's byte stream.
+#[derive(Clone, Debug)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    /// Where the file is on disk, relative to the target directory. This is the same as the path
+    /// in the torrent, unless the file was found under a name that differs in case or Unicode
+    /// normalisation.
+    pub disk_path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+    pub attributes: FileAttributes,
+}
+
+/// The attributes of a file in the torrent, as described in BEP 47.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileAttributes {
+    /// The file is zero-filled data that aligns the next file with a piece boundary. It is never
+    /// stored on disk.
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Parses the `attr` field, which is a string with a letter for each attribute.
+    pub fn from_attr(attr: &str) -> FileAttributes {
+        FileAttributes {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+
+    /// Gets the attributes of a file in a v1 torrent.
+    ///
+    /// Some clients added padding files before BEP 47 defined the `attr` field, so files in a
+    /// `.pad` directory, or named like BitComet's padding files, are also treated as padding.
+    pub fn from_torrent_file(file: &LavaFile) -> FileAttributes {
+        let attr = file
+            .extra_fields
+            .as_ref()
+            .and_then(|fields| match fields.get("attr") {
+                Some(BencodeElem::String(attr)) => Some(attr.as_str()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let mut attributes = FileAttributes::from_attr(attr);
+        let is_legacy_padding = file.path.components().any(|c| c.as_os_str() == ".pad")
+            || file
+                .path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("_____padding_file_"));
+        attributes.padding |= is_legacy_padding;
+        attributes
+    }
+
+    /// The names of the attributes that are set, other than padding, for display.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.executable, "executable"),
+            (self.hidden, "hidden"),
+            (self.symlink, "symlink"),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// Describes how the pieces of a torrent map onto the files in its tree.
+///
+/// The pieces relate to the content of all the files concatenated together, in the order they are
+/// listed in the torrent. A piece can therefore span several small files, while a large file can
+/// be covered by many thousands of pieces.
+///
+/// Padding files are kept in the layout so the offsets of the files after them are correct, but
+/// they are read as zeros and never belong to a piece.
+pub struct PieceLayout {
+    pub files: Vec<TorrentFile>,
+    pub piece_length: u64,
+    pub pieces: Vec<Vec<u8>>,
+    pub total_length: u64,
+}
+
+impl PieceLayout {
+    pub fn from_torrent(torrent: &Torrent) -> Result<PieceLayout> {
+        let torrent_files = torrent.files.as_ref().ok_or(Error::TorrentFilesError)?;
+        let mut files = Vec::with_capacity(torrent_files.len());
+        let mut offset = 0;
+        for file in torrent_files.iter() {
+            files.push(TorrentFile {
+                path: file.path.clone(),
+                disk_path: file.path.clone(),
+                length: file.length as u64,
+                offset,
+                attributes: FileAttributes::from_torrent_file(file),
+            });
+            offset += file.length as u64;
+        }
+        Ok(PieceLayout {
+            files,
+            piece_length: torrent.piece_length as u64,
+            pieces: torrent.pieces.clone(),
+            total_length: offset,
+        })
+    }
+
+    /// Sets the disk path of every file to its torrent path with the names escaped, for releases
+    /// that are stored on NTFS or exFAT.
+    pub fn escape_disk_paths(&mut self) {
+        for file in self.files.iter_mut() {
+            file.disk_path = escape_path(&file.path);
+        }
+    }
+
+    /// Finds each file on disk, allowing for names that differ from the expected disk path in case
+    /// or Unicode normalisation.
+    ///
+    /// The disk path is updated for each file that was only found under a normalised name. The
+    /// indexes of those files are returned, along with the paths they were expected to be at.
+    pub fn resolve_disk_paths(&mut self, target_directory: &Path) -> Result<Vec<(usize, PathBuf)>> {
+        let mut resolver = PathResolver::new(target_directory);
+        let mut normalised = Vec::new();
+        for (i, file) in self.files.iter_mut().enumerate() {
+            if file.attributes.padding {
+                continue;
+            }
+            if let ResolvedPath::Normalised(disk_path) = resolver.resolve(&file.disk_path)? {
+                let expected_path = std::mem::replace(&mut file.disk_path, disk_path);
+                normalised.push((i, expected_path));
+            }
+        }
+        Ok(normalised)
+    }
+
+    /// Builds the layout for a v2 torrent, which only describes its files.
+    ///
+    /// The pieces of a v2 torrent never span files, and each file is verified against its own
+    /// merkle tree instead, so the layout has no piece hashes.
+    pub fn from_v2_torrent(torrent: &V2Torrent) -> PieceLayout {
+        let mut files = Vec::with_capacity(torrent.files.len());
+        let mut offset = 0;
+        for file in torrent.files.iter() {
+            files.push(TorrentFile {
+                path: file.path.clone(),
+                disk_path: file.path.clone(),
+                length: file.length,
+                offset,
+                attributes: file.attributes.clone(),
+            });
+            offset += file.length;
+        }
+        PieceLayout {
+            files,
+            piece_length: torrent.piece_length,
+            pieces: Vec::new(),
+            total_length: offset,
+        }
+    }
+
+    /// The files that are stored on disk, which excludes padding files.
+    pub fn content_files(&self) -> impl Iterator<Item = (usize, &TorrentFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.attributes.padding)
+    }
+
+    /// The number of files in the torrent, not counting padding files.
+    pub fn file_count(&self) -> usize {
+        self.content_files().count()
+    }
+
+    /// The total size of the files in the torrent, not counting padding files.
+    pub fn content_length(&self) -> u64 {
+        self.content_files().map(|(_, f)| f.length).sum()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// The range of bytes in the torrent's byte stream that are covered by the piece.
+    pub fn piece_bounds(&self, piece_idx: usize) -> Range<u64> {
+        let start = piece_idx as u64 * self.piece_length;
+        let end = std::cmp::min(start + self.piece_length, self.total_length);
+        start..end
+    }
+
+    /// The indexes of the files that have content inside the piece.
+    ///
+    /// Zero-length files never have content inside a piece, and padding files only have zeros, so
+    /// neither are included.
+    pub fn files_for_piece(&self, piece_idx: usize) -> Vec<usize> {
+        let bounds = self.piece_bounds(piece_idx);
+        let first = self
+            .files
+            .partition_point(|f| f.offset + f.length <= bounds.start);
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, f)| f.offset < bounds.end)
+            .filter(|(_, f)| f.length > 0 && !f.attributes.padding)
+            .map(|(i, _)| first + i)
+            .collect()
+    }
+
+    /// The range of pieces that contain content from the file.
+    ///
+    /// The range is empty for zero-length files.
+    pub fn pieces_for_file(&self, file_idx: usize) -> Range<usize> {
+        let file = &self.files[file_idx];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = (file.offset / self.piece_length) as usize;
+        let last = ((file.offset + file.length - 1) / self.piece_length) as usize;
+        first..last + 1
+    }
+
+    /// Reads the content of a piece from the files under the target directory.
+    ///
+    /// The buffer is cleared before the piece is read into it. The parts of the piece that belong to
+    /// padding files are filled with zeros.
+    pub fn read_piece(
+        &self,
+        target_directory: &Path,
+        piece_idx: usize,
+        buffer: &mut Vec<u8>,
+    ) -> Result<()> {
+        let bounds = self.piece_bounds(piece_idx);
+        buffer.clear();
+        let mut position = bounds.start;
+        for file_idx in self.files_for_piece(piece_idx) {
+            let file_info = &self.files[file_idx];
+            let start = std::cmp::max(bounds.start, file_info.offset);
+            let end = std::cmp::min(bounds.end, file_info.offset + file_info.length);
+            buffer.resize(buffer.len() + (start - position) as usize, 0);
+            position = end;
+            let mut file = File::open(target_directory.join(&file_info.disk_path))?;
+            file.seek(std::io::SeekFrom::Start(start - file_info.offset))?;
+            // A file that is shorter than the torrent says it should be will produce a short
+            // read, which will then cause the piece hash not to match.
+            file.take(end - start).read_to_end(buffer)?;
+        }
+        buffer.resize(buffer.len() + (bounds.end - position) as usize, 0);
+        Ok(())
+    }
+
+    /// Writes the content of a piece to the files under the target directory, creating them and
+    /// their directories if they don't exist.
+    ///
+    /// The rest of each file is left alone, so pieces can be written in any order. The parts of the
+    /// piece that belong to padding files aren't written.
+    pub fn write_piece(
+        &self,
+        target_directory: &Path,
+        piece_idx: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        let bounds = self.piece_bounds(piece_idx);
+        for file_idx in self.files_for_piece(piece_idx) {
+            let file_info = &self.files[file_idx];
+            let start = std::cmp::max(bounds.start, file_info.offset);
+            let end = std::cmp::min(bounds.end, file_info.offset + file_info.length);
+            let path = target_directory.join(&file_info.disk_path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.seek(std::io::SeekFrom::Start(start - file_info.offset))?;
+            file.write_all(&data[(start - bounds.start) as usize..(end - bounds.start) as usize])?;
+        }
+        Ok(())
+    }
+}
+
+/// Hashes pieces on a pool of worker threads.
+///
+/// The `read` function runs on its own thread and submits piece buffers, in whatever order it
+/// reads them, through the callback it is given. The workers hash the buffers and compare them
+/// against the piece hashes in the layout, and `on_result` is then called on the current thread
+/// with the index of each piece and whether it matched.
+///
+/// At most `max_memory` bytes of piece buffers will be in flight at any one time, though there
+/// will always be room for at least one buffer per worker.
+pub fn hash_pieces<R, F>(
+    layout: &PieceLayout,
+    jobs: usize,
+    max_memory: u64,
+    read: R,
+    mut on_result: F,
+) -> Result<()>
+where
+    R: FnOnce(&mut dyn FnMut(usize, Vec<u8>) -> Result<()>) -> Result<()> + Send,
+    F: FnMut(usize, bool) -> Result<()>,
+{
+    let jobs = std::cmp::max(jobs, 1);
+    let buffer_count = std::cmp::max(max_memory / std::cmp::max(layout.piece_length, 1), 1);
+    let queue_length = std::cmp::max(buffer_count as usize, jobs + 1) - jobs;
+
+    let (work_tx, work_rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(queue_length);
+    let (result_tx, result_rx) = mpsc::channel::<(usize, bool)>();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(move || {
+            let mut submit = |piece_idx: usize, buffer: Vec<u8>| -> Result<()> {
+                work_tx
+                    .send((piece_idx, buffer))
+                    .map_err(|_| Error::HashingStopped)
+            };
+            read(&mut submit)
+        });
+
+        for _ in 0..jobs {
+            let work_rx = Arc::clone(&work_rx);
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let next = work_rx.lock().unwrap().recv();
+                let (piece_idx, buffer) = match next {
+                    Ok(work) => work,
+                    Err(_) => break,
+                };
+                let mut hasher = Sha1::new();
+                hasher.update(&buffer);
+                let matched = hasher.finalize().as_slice() == layout.pieces[piece_idx];
+                if result_tx.send((piece_idx, matched)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+        drop(work_rx);
+
+        let mut outcome = Ok(());
+        for (piece_idx, matched) in result_rx.iter() {
+            if let Err(e) = on_result(piece_idx, matched) {
+                outcome = Err(e);
+                break;
+            }
+        }
+        drop(result_rx);
+        let read_outcome = reader.join().map_err(|_| Error::HashingStopped)?;
+        outcome.and(read_outcome)
+    })
+}
+
+/// A compact record of a true/false value for each piece.
+///
+/// The bits are stored most significant first, which is the same layout the BitTorrent wire
+/// protocol uses for its bitfield message.
+#[derive(Clone)]
+pub struct Bitfield {
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            bytes: vec![0; len.div_ceil(8)],
+        }
+    }
+
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(Error::MalformedBitfield(len, bytes.len()));
+        }
+        Ok(Self {
+            len,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> bool {
+        self.bytes[idx / 8] & (0x80 >> (idx % 8)) != 0
+    }
+
+    pub fn set(&mut self, idx: usize, value: bool) {
+        if value {
+            self.bytes[idx / 8] |= 0x80 >> (idx % 8);
+        } else {
+            self.bytes[idx / 8] &= !(0x80 >> (idx % 8));
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The result for each piece from the last time a release was verified.
+///
+/// A piece either passed, failed, or wasn't hashed, e.g. because it overlaps a missing file.
+#[derive(Clone)]
+pub struct PieceMap {
+    pub hashed: Bitfield,
+    /// Whether each piece that was hashed matched its hash in the torrent.
+    pub passed: Bitfield,
+}
+
+impl PieceMap {
+    /// Creates a map where none of the pieces have been hashed.
+    pub fn new(len: usize) -> Self {
+        Self {
+            hashed: Bitfield::new(len),
+            passed: Bitfield::new(len),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashed.is_empty()
+    }
+
+    /// Whether the piece passed, or `None` if it wasn't hashed.
+    pub fn get(&self, idx: usize) -> Option<bool> {
+        self.hashed.get(idx).then(|| self.passed.get(idx))
+    }
+
+    pub fn set(&mut self, idx: usize, result: Option<bool>) {
+        self.hashed.set(idx, result.is_some());
+        self.passed.set(idx, result.unwrap_or(false));
+    }
+}
+
+/// The SHA-256 and MD5 digests of a whole file, as lowercase hex strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDigest {
+    pub sha256: String,
+    pub md5: String,
+}
+
+impl FileDigest {
+    /// Computes the digests by reading everything from the reader. The number of bytes read is
+    /// passed to `on_read` as it goes.
+    pub fn from_reader(reader: &mut dyn Read, on_read: &dyn Fn(u64)) -> Result<FileDigest> {
+        let mut sha256 = Sha256::new();
+        let mut md5 = Md5::new();
+        let mut buffer = vec![0; 1024 * 1024];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            sha256.update(&buffer[..read]);
+            md5.update(&buffer[..read]);
+            on_read(read as u64);
+        }
+        Ok(FileDigest {
+            sha256: format!("{:x}", sha256.finalize()),
+            md5: format!("{:x}", md5.finalize()),
+        })
+    }
+}
+
+/// Computes whole-file digests from piece buffers as they are read.
+///
+/// This allows the per-file hashes to be obtained while a release is downloaded, without reading
+/// all the content a second time. The pieces must be supplied in order for a file's digest to be
+/// computed; any file whose content is seen out of order, or only partially, won't get a digest.
+pub struct FileDigester {
+    states: Vec<Option<(Sha256, Md5, u64)>>,
+}
+
+impl FileDigester {
+    pub fn new(layout: &PieceLayout) -> Self {
+        Self {
+            states: layout
+                .files
+                .iter()
+                .map(|_| Some((Sha256::new(), Md5::new(), 0)))
+                .collect(),
+        }
+    }
+
+    pub fn update(&mut self, layout: &PieceLayout, piece_idx: usize, buffer: &[u8]) {
+        let bounds = layout.piece_bounds(piece_idx);
+        for file_idx in layout.files_for_piece(piece_idx) {
+            let file = &layout.files[file_idx];
+            let start = std::cmp::max(bounds.start, file.offset);
+            let end = std::cmp::min(bounds.end, file.offset + file.length);
+            let buffer_start = std::cmp::min((start - bounds.start) as usize, buffer.len());
+            let buffer_end = std::cmp::min((end - bounds.start) as usize, buffer.len());
+            let Some((_, _, hashed)) = &self.states[file_idx] else {
+                continue;
+            };
+            // A piece is seen again when the file after it is restarted.
+            if *hashed >= end - file.offset {
+                continue;
+            }
+            if *hashed != start - file.offset {
+                self.states[file_idx] = None;
+                continue;
+            }
+            if let Some((sha256, md5, hashed)) = self.states[file_idx].as_mut() {
+                sha256.update(&buffer[buffer_start..buffer_end]);
+                md5.update(&buffer[buffer_start..buffer_end]);
+                *hashed += (buffer_end - buffer_start) as u64;
+            }
+        }
+    }
+
+    /// Starts a file again from the beginning.
+    pub fn restart(&mut self, file_idx: usize) {
+        self.states[file_idx] = Some((Sha256::new(), Md5::new(), 0));
+    }
+
+    /// Returns the digest of each file that was seen in full.
+    pub fn finish(self, layout: &PieceLayout) -> Vec<Option<FileDigest>> {
+        self.states
+            .into_iter()
+            .zip(layout.files.iter())
+            .map(|(state, file)| match state {
+                Some((sha256, md5, hashed)) if hashed == file.length => Some(FileDigest {
+                    sha256: format!("{:x}", sha256.finalize()),
+                    md5: format!("{:x}", md5.finalize()),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The size, modification time and inode of a file on disk.
+///
+/// This is used to detect whether a file has changed since it was last verified. The modification
+/// time is in nanoseconds since the Unix epoch. The inode is always 0 on platforms that don't have
+/// them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: i64,
+    pub inode: u64,
+}
+
+impl FileState {
+    /// Reads the state of the file, or returns `None` if it doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<FileState>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let metadata = std::fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as i64);
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(Some(FileState {
+            size: metadata.len(),
+            mtime,
+            inode,
+        }))
+    }
+}
+
+/// Builds pieces from the content of a torrent that arrives as a stream.
+///
+/// The content must be pushed in torrent order, i.e., the files concatenated in the order they
+/// are listed in the torrent. Each time a piece is filled, it is handed to the callback. Any data
+/// pushed after the last piece is full is ignored.
+///
+/// The same layout must be given to every call.
+pub struct PieceAssembler {
+    piece_idx: usize,
+    buffer: Vec<u8>,
+}
+
+impl PieceAssembler {
+    pub fn new(layout: &PieceLayout) -> Self {
+        Self {
+            piece_idx: 0,
+            buffer: Vec::with_capacity(layout.piece_length as usize),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        layout: &PieceLayout,
+        mut data: &[u8],
+        on_piece: &mut dyn FnMut(usize, Vec<u8>) -> Result<()>,
+    ) -> Result<()> {
+        while !data.is_empty() && self.piece_idx < layout.piece_count() {
+            let bounds = layout.piece_bounds(self.piece_idx);
+            let piece_size = (bounds.end - bounds.start) as usize;
+            let take = std::cmp::min(piece_size - self.buffer.len(), data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == piece_size {
+                let buffer = std::mem::replace(
+                    &mut self.buffer,
+                    Vec::with_capacity(layout.piece_length as usize),
+                );
+                on_piece(self.piece_idx, buffer)?;
+                self.piece_idx += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The results of verifying a release as it was downloaded.
+pub struct DownloadResults {
+    pub piece_map: PieceMap,
+    /// The indexes of the files that didn't arrive.
+    pub missing_file_indexes: BTreeSet<usize>,
+    /// The digest of each file that arrived in full, if they were requested.
+    pub digests: Option<Vec<Option<FileDigest>>>,
+}
+
+/// Verifies the pieces of a torrent from the content of its files as they are downloaded.
+///
+/// The content must be pushed in torrent order, with the offset it starts at in its file, and each
+/// piece is hashed as soon as it's complete, so the release doesn't have to be read back from disk
+/// afterwards. Content that was already pushed is skipped, so a download that is retried or resumed
+/// can push its file again from the start. A download that starts again from another source
+/// restarts its file first, so the content it already pushed is forgotten.
+///
+/// When content is pushed for a later file, the files before it are finished, and any part of them
+/// that didn't arrive is filled with zeros to keep the pieces after them aligned. The pieces that
+/// overlap a file that didn't arrive at all aren't hashed; a file that is cut short will cause the
+/// pieces it overlaps to fail, unless it is abandoned.
+///
+/// It holds its own reference to the layout, so it can be moved to a blocking thread to do the
+/// hashing.
+pub struct DownloadVerifier {
+    layout: Arc<PieceLayout>,
+    assembler: PieceAssembler,
+    digester: Option<FileDigester>,
+    file_idx: usize,
+    /// How much of the current file has been pushed.
+    position: u64,
+    /// The piece the current file starts in, and how much of that piece comes before it.
+    file_start: (usize, usize),
+    /// The part of the piece the current file starts in that comes before it, kept once that
+    /// piece has been hashed, so the file can be restarted.
+    start_prefix: Option<Vec<u8>>,
+    missing_file_indexes: BTreeSet<usize>,
+    piece_map: PieceMap,
+}
+
+impl DownloadVerifier {
+    pub fn new(layout: Arc<PieceLayout>, file_hashes: bool) -> Self {
+        Self {
+            assembler: PieceAssembler::new(&layout),
+            digester: file_hashes.then(|| FileDigester::new(&layout)),
+            file_idx: 0,
+            position: 0,
+            file_start: (0, 0),
+            start_prefix: None,
+            missing_file_indexes: BTreeSet::new(),
+            piece_map: PieceMap::new(layout.piece_count()),
+            layout,
+        }
+    }
+
+    pub fn push(&mut self, file_idx: usize, offset: u64, data: &[u8]) -> Result<()> {
+        self.finish_files_before(file_idx)?;
+        if file_idx < self.file_idx {
+            return Ok(());
+        }
+        let length = self.layout.files[file_idx].length;
+        // A gap can only be left by a server that ignored a range request, and the pieces that
+        // overlap it will fail.
+        if offset > self.position {
+            self.push_zeros(std::cmp::min(offset, length) - self.position)?;
+        }
+        let end = std::cmp::min(offset + data.len() as u64, length);
+        if end <= self.position {
+            return Ok(());
+        }
+        let data = &data[(self.position - offset) as usize..(end - offset) as usize];
+        self.push_content(data)?;
+        self.position = end;
+        Ok(())
+    }
+
+    /// Gives up on a file that couldn't be downloaded after some of its content was pushed. It is
+    /// reported as missing, like a file that never arrived, rather than having the rest filled
+    /// with zeros and being reported as corrupt.
+    pub fn abandon(&mut self, file_idx: usize) -> Result<()> {
+        self.finish_files_before(file_idx)?;
+        if file_idx == self.file_idx {
+            self.missing_file_indexes.insert(file_idx);
+        }
+        Ok(())
+    }
+
+    /// Forgets the content that was pushed for the current file, so it can be pushed again from
+    /// the start. This does nothing for a file that has already been finished.
+    pub fn restart(&mut self, file_idx: usize) -> Result<()> {
+        if file_idx != self.file_idx || self.position == 0 {
+            return Ok(());
+        }
+        let (piece_idx, before) = self.file_start;
+        if self.assembler.piece_idx == piece_idx {
+            self.assembler.buffer.truncate(before);
+        } else {
+            self.assembler.piece_idx = piece_idx;
+            self.assembler.buffer.clear();
+            if let Some(prefix) = self.start_prefix.take() {
+                self.assembler.buffer.extend_from_slice(&prefix);
+            }
+        }
+        if let Some(digester) = self.digester.as_mut() {
+            digester.restart(file_idx);
+        }
+        self.position = 0;
+        Ok(())
+    }
+
+    /// Finishes every file and returns what was found.
+    pub fn finish(mut self) -> Result<DownloadResults> {
+        self.finish_files_before(self.layout.files.len())?;
+        Ok(DownloadResults {
+            piece_map: self.piece_map,
+            missing_file_indexes: self.missing_file_indexes,
+            digests: self.digester.map(|digester| digester.finish(&self.layout)),
+        })
+    }
+
+    fn finish_files_before(&mut self, file_idx: usize) -> Result<()> {
+        while self.file_idx < file_idx {
+            let file = &self.layout.files[self.file_idx];
+            let length = file.length;
+            if self.position == 0 && length > 0 && !file.attributes.padding {
+                self.missing_file_indexes.insert(self.file_idx);
+            }
+            self.push_zeros(length - self.position)?;
+            self.file_idx += 1;
+            self.position = 0;
+            self.file_start = (self.assembler.piece_idx, self.assembler.buffer.len());
+            self.start_prefix = None;
+        }
+        Ok(())
+    }
+
+    fn push_zeros(&mut self, len: u64) -> Result<()> {
+        let zeros = vec![0; std::cmp::min(len, 1024 * 1024) as usize];
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = std::cmp::min(remaining, zeros.len() as u64) as usize;
+            self.push_content(&zeros[..chunk])?;
+            remaining -= chunk as u64;
+        }
+        self.position += len;
+        Ok(())
+    }
+
+    fn push_content(&mut self, data: &[u8]) -> Result<()> {
+        let layout = &*self.layout;
+        let missing_file_indexes = &self.missing_file_indexes;
+        let piece_map = &mut self.piece_map;
+        let digester = &mut self.digester;
+        let (start_piece, before) = self.file_start;
+        let start_prefix = &mut self.start_prefix;
+        self.assembler.push(layout, data, &mut |piece_idx, buffer| {
+            if piece_idx == start_piece && before > 0 {
+                *start_prefix = Some(buffer[..before].to_vec());
+            }
+            let files = layout.files_for_piece(piece_idx);
+            if files.iter().any(|i| missing_file_indexes.contains(i)) {
+                return Ok(());
+            }
+            if let Some(digester) = digester.as_mut() {
+                digester.update(layout, piece_idx, &buffer);
+            }
+            let mut hasher = Sha1::new();
+            hasher.update(&buffer);
+            piece_map.set(
+                piece_idx,
+                Some(hasher.finalize().as_slice() == layout.pieces[piece_idx]),
+            );
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+impl PieceLayout {
+    /// Builds a layout for files with the given content, hashing the pieces from it. Files under
+    /// `.pad` are treated as padding files, so their content should be zeros.
+    pub(crate) fn from_content(files: &[(&str, &[u8])], piece_length: u64) -> PieceLayout {
+        let mut layout_files = Vec::with_capacity(files.len());
+        let mut stream = Vec::new();
+        for (path, content) in files.iter() {
+            layout_files.push(TorrentFile {
+                path: PathBuf::from(path),
+                disk_path: PathBuf::from(path),
+                length: content.len() as u64,
+                offset: stream.len() as u64,
+                attributes: FileAttributes {
+                    padding: path.starts_with(".pad/"),
+                    ..Default::default()
+                },
+            });
+            stream.extend_from_slice(content);
+        }
+        let pieces = stream
+            .chunks(piece_length as usize)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        PieceLayout {
+            files: layout_files,
+            piece_length,
+            pieces,
+            total_length: stream.len() as u64,
+        }
+    }
+}
+
+/// Builds a v1 torrent for files with the given content.
+#[cfg(test)]
+pub(crate) fn build_v1_torrent(files: &[(&str, &[u8])], piece_length: u64) -> Vec<u8> {
+    use std::collections::HashMap;
+
+    let layout = PieceLayout::from_content(files, piece_length);
+    let torrent_files = files
+        .iter()
+        .map(|(path, content)| {
+            let path = path
+                .split('/')
+                .map(|c| BencodeElem::String(c.to_string()))
+                .collect();
+            BencodeElem::Dictionary(HashMap::from([
+                (
+                    "length".to_string(),
+                    BencodeElem::Integer(content.len() as i64),
+                ),
+                ("path".to_string(), BencodeElem::List(path)),
+            ]))
+        })
+        .collect();
+    let info = HashMap::from([
+        ("name".to_string(), BencodeElem::String("test".to_string())),
+        (
+            "piece length".to_string(),
+            BencodeElem::Integer(piece_length as i64),
+        ),
+        (
+            "pieces".to_string(),
+            BencodeElem::Bytes(layout.pieces.concat()),
+        ),
+        ("files".to_string(), BencodeElem::List(torrent_files)),
+    ]);
+    BencodeElem::Dictionary(HashMap::from([(
+        "info".to_string(),
+        BencodeElem::Dictionary(info),
+    )]))
+    .encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn content(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    #[test]
+    fn stores_bits_most_significant_first() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.get(0) && bitfield.get(9));
+        assert!((1..9).all(|i| !bitfield.get(i)));
+        bitfield.set(0, false);
+        bitfield.set(0, false);
+        assert_eq!(bitfield.as_bytes(), &[0, 0b0100_0000]);
+    }
+
+    #[test]
+    fn reads_bitfields_of_the_right_size() {
+        let bitfield = Bitfield::from_bytes(9, &[0b0000_0001, 0b1000_0000]).unwrap();
+        assert_eq!(bitfield.len(), 9);
+        assert!(bitfield.get(7) && bitfield.get(8));
+        assert!(matches!(
+            Bitfield::from_bytes(9, &[0xff]),
+            Err(Error::MalformedBitfield(9, 1))
+        ));
+        assert!(matches!(
+            Bitfield::from_bytes(8, &[0xff, 0]),
+            Err(Error::MalformedBitfield(8, 2))
+        ));
+        let empty = Bitfield::from_bytes(0, &[]).unwrap();
+        assert!(empty.is_empty());
+        assert!(empty.as_bytes().is_empty());
+    }
+
+    #[test]
+    fn finds_the_files_in_each_piece() {
+        let layout = PieceLayout::from_content(
+            &[
+                ("a", &content(1500, 1)),
+                ("empty", &[]),
+                // Ends on a piece boundary.
+                ("b", &content(548, 2)),
+                ("c", &content(500, 3)),
+            ],
+            1024,
+        );
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.piece_bounds(1), 1024..2048);
+        assert_eq!(layout.piece_bounds(2), 2048..2548);
+        assert_eq!(layout.files_for_piece(0), vec![0]);
+        assert_eq!(layout.files_for_piece(1), vec![0, 2]);
+        assert_eq!(layout.files_for_piece(2), vec![3]);
+        assert_eq!(layout.pieces_for_file(0), 0..2);
+        assert_eq!(layout.pieces_for_file(1), 0..0);
+        assert_eq!(layout.pieces_for_file(2), 1..2);
+        assert_eq!(layout.pieces_for_file(3), 2..3);
+    }
+
+    #[test]
+    fn leaves_out_zero_length_files_at_either_end() {
+        let layout = PieceLayout::from_content(
+            &[("first", &[]), ("a", &content(2048, 1)), ("last", &[])],
+            1024,
+        );
+        assert_eq!(layout.piece_count(), 2);
+        assert_eq!(layout.files_for_piece(0), vec![1]);
+        assert_eq!(layout.files_for_piece(1), vec![1]);
+        assert_eq!(layout.pieces_for_file(0), 0..0);
+        assert_eq!(layout.pieces_for_file(1), 0..2);
+        assert_eq!(layout.pieces_for_file(2), 0..0);
+    }
+
+    /// Files that are padded to the start of the next piece, as BEP 47 has it.
+    fn padded_layout() -> (PieceLayout, Vec<u8>, Vec<u8>) {
+        let (a, b) = (content(1000, 1), content(1500, 2));
+        let layout = PieceLayout::from_content(
+            &[
+                ("a", &a),
+                (".pad/24", &[0; 24]),
+                ("b", &b),
+                (".pad/548", &[0; 548]),
+                ("empty", &[]),
+            ],
+            1024,
+        );
+        (layout, a, b)
+    }
+
+    #[test]
+    fn leaves_padding_files_out_of_pieces() {
+        let (layout, _, _) = padded_layout();
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.files_for_piece(0), vec![0]);
+        assert_eq!(layout.files_for_piece(1), vec![2]);
+        assert_eq!(layout.files_for_piece(2), vec![2]);
+        assert_eq!(layout.pieces_for_file(2), 1..3);
+        assert_eq!(
+            layout
+                .content_files()
+                .map(|(i, _)| i)
+                .collect::<Vec<usize>>(),
+            vec![0, 2, 4]
+        );
+        assert_eq!(layout.file_count(), 3);
+        assert_eq!(layout.content_length(), 2500);
+    }
+
+    #[test]
+    fn reads_and_writes_padding_as_zeros() {
+        let (layout, a, b) = padded_layout();
+        let dir = TempDir::new("pieces").unwrap();
+        let mut buffer = Vec::new();
+        for piece_idx in 0..layout.piece_count() {
+            let bounds = layout.piece_bounds(piece_idx);
+            let mut piece = [&a[..], &[0; 24], &b[..], &[0; 548]].concat();
+            piece.truncate(bounds.end as usize);
+            layout
+                .write_piece(dir.path(), piece_idx, &piece[bounds.start as usize..])
+                .unwrap();
+        }
+        // Only the content files are written.
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), a);
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b);
+        assert!(!dir.path().join(".pad").exists());
+        for piece_idx in 0..layout.piece_count() {
+            layout
+                .read_piece(dir.path(), piece_idx, &mut buffer)
+                .unwrap();
+            assert_eq!(Sha1::digest(&buffer).as_slice(), layout.pieces[piece_idx]);
+        }
+    }
+
+    #[test]
+    fn verifies_files_pushed_in_order() {
+        let (a, b) = (content(5000, 1), content(3000, 2));
+        let layout = Arc::new(PieceLayout::from_content(&[("a", &a), ("b", &b)], 1024));
+        let mut verifier = DownloadVerifier::new(Arc::clone(&layout), true);
+        verifier.push(0, 0, &a[..2000]).unwrap();
+        // Content that was already pushed is skipped when a download is retried.
+        verifier.push(0, 0, &a).unwrap();
+        verifier.push(1, 0, &b).unwrap();
+        let results = verifier.finish().unwrap();
+        assert!((0..layout.piece_count()).all(|i| results.piece_map.get(i) == Some(true)));
+        assert!(results.missing_file_indexes.is_empty());
+        let digests = results.digests.unwrap();
+        assert_eq!(
+            digests[1].as_ref().unwrap().sha256,
+            hex::encode(Sha256::digest(&b))
+        );
+    }
+
+    #[test]
+    fn reports_files_that_never_arrived_as_missing() {
+        let (a, b, c) = (content(5000, 1), content(3000, 2), content(4096, 3));
+        let layout = Arc::new(PieceLayout::from_content(
+            &[("a", &a), ("b", &b), ("c", &c)],
+            1024,
+        ));
+        let mut verifier = DownloadVerifier::new(Arc::clone(&layout), false);
+        verifier.push(0, 0, &a).unwrap();
+        verifier.push(2, 0, &c).unwrap();
+        let results = verifier.finish().unwrap();
+        assert_eq!(results.missing_file_indexes, BTreeSet::from([1]));
+        // The pieces that overlap the missing file aren't hashed.
+        for piece_idx in 0..layout.piece_count() {
+            let expected = (!layout.files_for_piece(piece_idx).contains(&1)).then_some(true);
+            assert_eq!(results.piece_map.get(piece_idx), expected);
+        }
+    }
+
+    #[test]
+    fn reports_a_file_cut_short_as_corrupt() {
+        let (a, b) = (content(5000, 1), content(3000, 2));
+        let layout = Arc::new(PieceLayout::from_content(&[("a", &a), ("b", &b)], 1024));
+        let mut verifier = DownloadVerifier::new(Arc::clone(&layout), false);
+        verifier.push(0, 0, &a[..2500]).unwrap();
+        verifier.push(1, 0, &b).unwrap();
+        let results = verifier.finish().unwrap();
+        assert!(results.missing_file_indexes.is_empty());
+        assert_eq!(results.piece_map.get(1), Some(true));
+        assert_eq!(results.piece_map.get(2), Some(false));
+    }
+
+    #[test]
+    fn reports_an_abandoned_file_as_missing() {
+        let (a, b) = (content(5000, 1), content(3000, 2));
+        let layout = Arc::new(PieceLayout::from_content(&[("a", &a), ("b", &b)], 1024));
+        let mut verifier = DownloadVerifier::new(Arc::clone(&layout), true);
+        verifier.push(0, 0, &a[..2500]).unwrap();
+        verifier.abandon(0).unwrap();
+        verifier.push(1, 0, &b).unwrap();
+        let results = verifier.finish().unwrap();
+        assert_eq!(results.missing_file_indexes, BTreeSet::from([0]));
+        // The pieces that arrived in full still passed, and none failed.
+        assert_eq!(results.piece_map.get(1), Some(true));
+        assert!((0..layout.piece_count()).all(|i| results.piece_map.get(i) != Some(false)));
+        assert!(results.digests.unwrap()[0].is_none());
+    }
+
+    #[test]
+    fn builds_a_layout_from_a_v1_torrent() {
+        let (a, b) = (content(3000, 1), content(2000, 2));
+        let files: [(&str, &[u8]); 2] = [("Rel/a.bin", &a), ("Rel/sub/b.bin", &b)];
+        let torrent = Torrent::read_from_bytes(build_v1_torrent(&files, 1024)).unwrap();
+        let layout = PieceLayout::from_torrent(&torrent).unwrap();
+        let expected = PieceLayout::from_content(&files, 1024);
+        assert_eq!(
+            layout.files.iter().map(|f| &f.path).collect::<Vec<_>>(),
+            expected.files.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(layout.pieces, expected.pieces);
+    }
+
+    #[test]
+    fn forgets_a_restarted_file() {
+        let (a, b, c) = (content(5000, 1), content(3000, 2), content(2000, 3));
+        let files: [(&str, &[u8]); 3] = [("a", &a), ("b", &b), ("c", &c)];
+        let layout = Arc::new(PieceLayout::from_content(&files, 1024));
+        let expected = {
+            let mut verifier = DownloadVerifier::new(Arc::clone(&layout), true);
+            for (file_idx, (_, content)) in files.iter().enumerate() {
+                verifier.push(file_idx, 0, content).unwrap();
+            }
+            verifier.finish().unwrap().digests.unwrap()
+        };
+
+        // Restarting before and after the piece that the file starts in has been hashed.
+        for cut in [10, 2000] {
+            let mut verifier = DownloadVerifier::new(Arc::clone(&layout), true);
+            verifier.push(0, 0, &a).unwrap();
+            verifier.push(1, 0, &content(cut, 9)).unwrap();
+            verifier.restart(1).unwrap();
+            verifier.push(1, 0, &b).unwrap();
+            verifier.push(2, 0, &c).unwrap();
+            let results = verifier.finish().unwrap();
+            assert!(results.missing_file_indexes.is_empty());
+            assert!((0..layout.piece_count()).all(|i| results.piece_map.get(i) == Some(true)));
+            assert_eq!(results.digests.unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::error::{Error, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The version of the report format. It changes if a field is removed or its meaning changes.
+pub const REPORT_FORMAT_VERSION: u32 = 1;
+
+/// A statement of what was found when a copy of a release was verified, which can be handed to
+/// someone else along with the files.
+#[derive(Deserialize, Serialize)]
+pub struct VerificationReport {
+    pub format_version: u32,
+    pub tool: String,
+    pub tool_version: String,
+    pub release: ReportRelease,
+    pub torrent: ReportTorrent,
+    pub outcome: String,
+    /// When the outcome was saved, as an ISO 8601 UTC timestamp. It's only missing for a release
+    /// that was last verified before this was recorded.
+    pub verified_at: Option<String>,
+    pub files: Vec<ReportFile>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReportRelease {
+    pub id: String,
+    pub name: String,
+    pub date: String,
+}
+
+/// The torrent the release was verified against.
+#[derive(Deserialize, Serialize)]
+pub struct ReportTorrent {
+    pub version: String,
+    pub info_hash_v1: Option<String>,
+    pub info_hash_v2: Option<String>,
+    pub piece_length: u64,
+    pub piece_count: usize,
+    /// The number of files, not counting padding files.
+    pub file_count: usize,
+    pub size: u64,
+}
+
+/// A file in the torrent, with its status from the last verification.
+#[derive(Deserialize, Serialize)]
+pub struct ReportFile {
+    pub path: String,
+    pub size: u64,
+    pub status: String,
+    /// The ignore rule that waived the file, if it was waived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+/// A report along with the public key it was signed with and the signature.
+///
+/// The signature covers the report in a canonical form: compact JSON with the keys of every
+/// object in sorted order. The file can therefore be reformatted without breaking the signature,
+/// but any change to a value will.
+#[derive(Deserialize, Serialize)]
+pub struct SignedReport<R> {
+    pub report: R,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl VerificationReport {
+    /// Signs the report and returns it as pretty-printed JSON.
+    pub fn sign(&self, key: &SigningKey) -> Result<String> {
+        let payload = canonical_bytes(&serde_json::to_value(self)?)?;
+        let signed = SignedReport {
+            report: self,
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: hex::encode(key.sign(&payload).to_bytes()),
+        };
+        Ok(serde_json::to_string_pretty(&signed)?)
+    }
+}
+
+/// Checks that a signed report was signed with the given public key and hasn't been changed since.
+///
+/// Nothing else is needed, so this can be done offline by someone who doesn't have the database.
+pub fn verify_signed_report(
+    content: &str,
+    public_key: &VerifyingKey,
+) -> Result<VerificationReport> {
+    let signed: SignedReport<Value> = serde_json::from_str(content)?;
+    let signer = parse_verifying_key(&signed.public_key)?;
+    if signer != *public_key {
+        return Err(Error::ReportSignedWithOtherKey(signed.public_key));
+    }
+    let signature = hex::decode(signed.signature.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(Error::ReportSignatureInvalid)?;
+    public_key
+        .verify_strict(&canonical_bytes(&signed.report)?, &signature)
+        .map_err(|_| Error::ReportSignatureInvalid)?;
+    Ok(serde_json::from_value(signed.report)?)
+}
+
+/// Generates a new signing key and saves it as hex at the given path, with the public key saved
+/// next to it under the same name with `.pub` added.
+///
+/// Neither file is overwritten if it already exists.
+pub fn generate_signing_key(key_path: &Path) -> Result<(VerifyingKey, PathBuf)> {
+    let public_key_path = get_public_key_path(key_path);
+    for path in [key_path, public_key_path.as_path()] {
+        if path.exists() {
+            return Err(Error::KeyFileExists(path.to_string_lossy().to_string()));
+        }
+    }
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(std::io::Error::from)?;
+    let key = SigningKey::from_bytes(&secret);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(key_path)?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    let public_key = key.verifying_key();
+    std::fs::write(
+        &public_key_path,
+        format!("{}\n", hex::encode(public_key.as_bytes())),
+    )?;
+    Ok((public_key, public_key_path))
+}
+
+/// Reads a signing key saved by [`generate_signing_key`].
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let content = std::fs::read_to_string(path)?;
+    let secret: [u8; 32] = hex::decode(content.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidKey(path.to_string_lossy().to_string()))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Reads a public key saved by [`generate_signing_key`].
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let content = std::fs::read_to_string(path)?;
+    parse_verifying_key(&content).map_err(|_| Error::InvalidKey(path.to_string_lossy().to_string()))
+}
+
+fn parse_verifying_key(key: &str) -> Result<VerifyingKey> {
+    hex::decode(key.trim())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| Error::InvalidKey(key.to_string()))
+}
+
+fn get_public_key_path(key_path: &Path) -> PathBuf {
+    let mut path = OsString::from(key_path.as_os_str());
+    path.push(".pub");
+    PathBuf::from(path)
+}
+
+/// Encodes a value as compact JSON with the keys of every object sorted, so the same report
+/// always produces the same bytes to sign, however it was formatted.
+fn canonical_bytes(value: &Value) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&sort_keys(value))?)
+}
+
+fn sort_keys(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<(&String, &Value)>>();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), sort_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(sort_keys).collect()),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    fn report() -> VerificationReport {
+        VerificationReport {
+            format_version: REPORT_FORMAT_VERSION,
+            tool: "sept11-datasets".to_string(),
+            tool_version: "0.1.0".to_string(),
+            release: ReportRelease {
+                id: "NIST0001".to_string(),
+                name: "Test release".to_string(),
+                date: "2001-09-11".to_string(),
+            },
+            torrent: ReportTorrent {
+                version: "v1".to_string(),
+                info_hash_v1: Some("ab".repeat(20)),
+                info_hash_v2: None,
+                piece_length: 16384,
+                piece_count: 2,
+                file_count: 2,
+                size: 20000,
+            },
+            outcome: "Incomplete".to_string(),
+            verified_at: Some("2001-09-11T12:46:00Z".to_string()),
+            files: vec![
+                ReportFile {
+                    path: "Rel/a.txt".to_string(),
+                    size: 10000,
+                    status: "VERIFIED".to_string(),
+                    reason: None,
+                    sha256: Some("00".repeat(32)),
+                    md5: Some("11".repeat(16)),
+                },
+                ReportFile {
+                    path: "Rel/b.txt".to_string(),
+                    size: 10000,
+                    status: "MISSING".to_string(),
+                    reason: None,
+                    sha256: None,
+                    md5: None,
+                },
+            ],
+        }
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn verifies_a_signed_report_even_after_it_is_reformatted() {
+        let signed = report().sign(&key(1)).unwrap();
+        let verified = verify_signed_report(&signed, &key(1).verifying_key()).unwrap();
+        assert_eq!(verified.release.id, "NIST0001");
+        assert_eq!(verified.files[1].status, "MISSING");
+
+        let compact = serde_json::to_string(&serde_json::from_str::<Value>(&signed).unwrap());
+        assert!(verify_signed_report(&compact.unwrap(), &key(1).verifying_key()).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_report_that_was_changed_after_it_was_signed() {
+        let signed = report().sign(&key(1)).unwrap();
+        let mut value: Value = serde_json::from_str(&signed).unwrap();
+        value["report"]["files"][1]["status"] = json!("VERIFIED");
+        assert!(matches!(
+            verify_signed_report(&value.to_string(), &key(1).verifying_key()),
+            Err(Error::ReportSignatureInvalid)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_report_signed_with_another_key() {
+        let signed = report().sign(&key(2)).unwrap();
+        match verify_signed_report(&signed, &key(1).verifying_key()) {
+            Err(Error::ReportSignedWithOtherKey(public_key)) => {
+                assert_eq!(public_key, hex::encode(key(2).verifying_key().as_bytes()))
+            }
+            _ => panic!("expected the report to be rejected as signed with another key"),
+        }
+    }
+
+    #[test]
+    fn sorts_the_keys_of_nested_objects() {
+        let value = json!({"b": {"d": 1, "c": [{"z": 1, "y": null}]}, "a": "x"});
+        assert_eq!(
+            String::from_utf8(canonical_bytes(&value).unwrap()).unwrap(),
+            r#"{"a":"x","b":{"c":[{"y":null,"z":1}],"d":1}}"#
+        );
+    }
+
+    #[test]
+    fn reads_back_a_generated_key_and_never_overwrites_it() {
+        let dir = TempDir::new("report").unwrap();
+        let key_path = dir.path().join("signing.key");
+        let (public_key, public_key_path) = generate_signing_key(&key_path).unwrap();
+        assert_eq!(public_key_path, dir.path().join("signing.key.pub"));
+        assert_eq!(
+            read_signing_key(&key_path).unwrap().verifying_key(),
+            public_key
+        );
+        assert_eq!(read_verifying_key(&public_key_path).unwrap(), public_key);
+        assert!(matches!(
+            generate_signing_key(&key_path),
+            Err(Error::KeyFileExists(_))
+        ));
+    }
+}
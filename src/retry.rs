@@ -0,0 +1,162 @@
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// Decides whether a failed download should be tried again, and how long to wait first.
+///
+/// The delay doubles after each failure, starting from `initial_delay` and going no higher than
+/// `max_delay`, and a random amount of up to half of it is taken off so that downloads that
+/// failed together don't all retry at the same moment. If the server sent a `Retry-After` header
+/// with a 429 or 503 response, that is how long it waits instead, though never longer than
+/// `max_delay`.
+///
+/// Only transient failures are retried: dropped connections, timeouts, server errors and rate
+/// limiting. A file that doesn't exist or that we aren't allowed to download fails straight away.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The number of times a download is tried before giving up, including the first.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before the next attempt, given the error from attempt number
+    /// `attempt`, counting from 1. Returns `None` if the download shouldn't be tried again.
+    pub fn next_delay(&self, error: &Error, attempt: u32) -> Option<Duration> {
+        if !error.is_transient() || attempt >= self.max_attempts {
+            return None;
+        }
+        if let Error::ArchiveDownloadFailed {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            return Some(std::cmp::min(*retry_after, self.max_delay));
+        }
+        let exponent = std::cmp::min(attempt - 1, 31);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        Some(delay.mul_f64(1.0 - random_fraction() / 2.0))
+    }
+}
+
+/// Parses the value of a `Retry-After` header, which is either a number of seconds or an HTTP
+/// date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means the download can be tried again straight away.
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// A random number between 0 and 1.
+fn random_fraction() -> f64 {
+    let mut bytes = [0u8; 4];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return 0.5;
+    }
+    u32::from_le_bytes(bytes) as f64 / u32::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(4),
+            max_delay: Duration::from_secs(20),
+        }
+    }
+
+    fn server_error(retry_after: Option<Duration>) -> Error {
+        Error::ArchiveDownloadFailed {
+            url: "https://archive.org/download/item/file".to_string(),
+            status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backs_off_with_jitter_up_to_the_maximum() {
+        let policy = policy();
+        for (attempt, full) in [(1, 4), (2, 8), (3, 16), (4, 20)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..20 {
+                let delay = policy.next_delay(&server_error(None), attempt).unwrap();
+                assert!(delay <= full && delay >= full / 2, "{attempt}: {delay:?}");
+            }
+        }
+        assert_eq!(policy.next_delay(&server_error(None), 5), None);
+    }
+
+    #[test]
+    fn does_not_overflow_after_many_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            initial_delay: Duration::from_secs(u64::MAX / 2),
+            max_delay: Duration::from_secs(300),
+        };
+        let delay = policy.next_delay(&server_error(None), 1000).unwrap();
+        assert!(delay <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn waits_as_long_as_the_server_asks_up_to_the_maximum() {
+        let policy = policy();
+        let error = server_error(Some(Duration::from_secs(7)));
+        assert_eq!(policy.next_delay(&error, 1), Some(Duration::from_secs(7)));
+        let error = server_error(Some(Duration::from_secs(86400)));
+        assert_eq!(policy.next_delay(&error, 1), Some(Duration::from_secs(20)));
+        assert_eq!(policy.next_delay(&error, 5), None);
+    }
+
+    #[test]
+    fn only_retries_transient_failures() {
+        let not_found = Error::ArchiveFileNotFoundError("file".to_string());
+        assert_eq!(policy().next_delay(&not_found, 1), None);
+        let forbidden = Error::ArchiveDownloadFailed {
+            url: "file".to_string(),
+            status: reqwest::StatusCode::FORBIDDEN,
+            retry_after: None,
+        };
+        assert_eq!(policy().next_delay(&forbidden, 1), None);
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let later = (Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let delay = parse_retry_after(&later).unwrap();
+        assert!(delay > Duration::from_secs(85) && delay <= Duration::from_secs(90));
+        for value in ["", "-5", "1.5", "soon", "Wed, 32 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(parse_retry_after(value), None, "{value}");
+        }
+    }
+}
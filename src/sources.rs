@@ -0,0 +1,308 @@
+use crate::error::{Error, Result};
+use crate::pieces::TorrentFile;
+use crate::Release;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+/// The kinds of place the files of a release can be downloaded from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind {
+    /// A zip on the Archive. The Archive serves each file in a zip individually under the URL of
+    /// the zip, so the files can be downloaded one at a time.
+    Zip,
+    /// A URL under which the files are laid out as in the torrent tree, like an Archive
+    /// collection, an S3 bucket, or someone's own HTTP server.
+    Http,
+    /// A directory with the files laid out as in the torrent tree, for example on a NAS.
+    Local,
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceKind::Zip => write!(f, "zip"),
+            SourceKind::Http => write!(f, "http"),
+            SourceKind::Local => write!(f, "local"),
+        }
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind {
+            "zip" => Ok(SourceKind::Zip),
+            "http" => Ok(SourceKind::Http),
+            "local" => Ok(SourceKind::Local),
+            _ => Err(Error::InvalidSourceKind(kind.to_string())),
+        }
+    }
+}
+
+/// A place a release can be downloaded from, in addition to its download URL in the static data.
+///
+/// Sources are tried in order of priority, lowest first. The download URL of the release has a
+/// priority of 0.
+#[derive(Clone, Debug)]
+pub struct ReleaseSource {
+    pub id: i64,
+    pub release_id: String,
+    pub priority: i64,
+    pub kind: SourceKind,
+    /// A URL, or a path for a local source.
+    pub location: String,
+}
+
+impl fmt::Display for ReleaseSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.location)
+    }
+}
+
+/// Somewhere the files of a release can be downloaded from, ready to be used by the downloader.
+pub enum DownloadSource {
+    Zip(Url),
+    Http(Url),
+    Local(PathBuf),
+    /// The collections release 14 is scattered across, with links for each directory and for
+    /// files that aren't in the collection for their directory.
+    Release14 {
+        links: HashMap<PathBuf, String>,
+        file_links: HashMap<PathBuf, String>,
+    },
+}
+
+/// Where a file can be fetched from.
+pub(crate) enum FileLocation {
+    Url(Url),
+    Path(PathBuf),
+}
+
+impl DownloadSource {
+    /// The source for the download URL of a release, which is either a zip or an Archive
+    /// collection.
+    pub fn from_download_url(url: &Url) -> Self {
+        if url.path().ends_with(".zip") {
+            DownloadSource::Zip(url.clone())
+        } else {
+            DownloadSource::Http(url.clone())
+        }
+    }
+
+    pub fn from_release_source(source: &ReleaseSource) -> Result<Self> {
+        Ok(match source.kind {
+            SourceKind::Zip => DownloadSource::Zip(Url::parse(&source.location)?),
+            SourceKind::Http => DownloadSource::Http(Url::parse(&source.location)?),
+            SourceKind::Local => DownloadSource::Local(PathBuf::from(&source.location)),
+        })
+    }
+
+    /// A description of the source, which is recorded for each file downloaded from it.
+    pub fn describe(&self) -> String {
+        match self {
+            DownloadSource::Zip(url) => format!("zip {url}"),
+            DownloadSource::Http(url) => format!("http {url}"),
+            DownloadSource::Local(path) => format!("local {}", path.display()),
+            DownloadSource::Release14 { .. } => "release 14 collections".to_string(),
+        }
+    }
+
+    /// The places a file can be fetched from in this source, in the order they should be tried.
+    ///
+    /// A file in a local source is only included if it exists.
+    pub(crate) fn locations(&self, file: &TorrentFile) -> Result<Vec<FileLocation>> {
+        let locations = match self {
+            DownloadSource::Zip(url) => {
+                // Some zips contain the top level directory of the torrent and some don't, so if
+                // the file isn't found at its full path, it's tried without the top level
+                // directory.
+                let mut urls = vec![join_url_path(url, &file.path)?];
+                let mut components = file.path.components();
+                components.next();
+                if components.clone().next().is_some() {
+                    urls.push(join_url_path(url, components.as_path())?);
+                }
+                urls.into_iter().map(FileLocation::Url).collect()
+            }
+            DownloadSource::Http(url) => vec![FileLocation::Url(join_url_path(url, &file.path)?)],
+            DownloadSource::Local(directory) => {
+                let mut paths = vec![directory.join(&file.path)];
+                if file.disk_path != file.path {
+                    paths.push(directory.join(&file.disk_path));
+                }
+                paths
+                    .into_iter()
+                    .filter(|path| path.is_file())
+                    .take(1)
+                    .map(FileLocation::Path)
+                    .collect()
+            }
+            DownloadSource::Release14 { links, file_links } => {
+                let file_name = file
+                    .path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                Release::get_release_14_url(links, file_links, file.path.clone(), &file_name)?
+                    .into_iter()
+                    .map(FileLocation::Url)
+                    .collect()
+            }
+        };
+        Ok(locations)
+    }
+}
+
+/// Appends a path in the torrent tree to a URL, one segment per component. A URL that ends with a
+/// `/` gets the path after it, rather than after an empty segment.
+pub(crate) fn join_url_path(base_url: &Url, path: &Path) -> Result<Url> {
+    let mut url = base_url.clone();
+    {
+        let mut path_segments = match url.path_segments_mut() {
+            Ok(segments) => segments,
+            Err(_) => return Err(Error::PathSegmentsParseError),
+        };
+        path_segments.pop_if_empty().extend(
+            path.to_str()
+                .ok_or_else(|| Error::PathSegmentsParseError)?
+                .split('/'),
+        );
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pieces::FileAttributes;
+    use tempdir::TempDir;
+
+    fn file(path: &str, disk_path: &str) -> TorrentFile {
+        TorrentFile {
+            path: PathBuf::from(path),
+            disk_path: PathBuf::from(disk_path),
+            length: 0,
+            offset: 0,
+            attributes: FileAttributes::default(),
+        }
+    }
+
+    fn urls(locations: Vec<FileLocation>) -> Vec<String> {
+        locations
+            .into_iter()
+            .map(|location| match location {
+                FileLocation::Url(url) => url.to_string(),
+                FileLocation::Path(path) => path.display().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn source_kinds_round_trip_through_their_names() {
+        for kind in [SourceKind::Zip, SourceKind::Http, SourceKind::Local] {
+            assert_eq!(kind.to_string().parse::<SourceKind>().unwrap(), kind);
+        }
+        assert!(matches!(
+            "ftp".parse::<SourceKind>(),
+            Err(Error::InvalidSourceKind(kind)) if kind == "ftp"
+        ));
+    }
+
+    #[test]
+    fn encodes_each_component_of_a_path_in_a_url() {
+        let base = Url::parse("https://archive.org/download/item").unwrap();
+        let url = join_url_path(&base, Path::new("Dir/with space/#1?.jpg")).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://archive.org/download/item/Dir/with%20space/%231%3F.jpg"
+        );
+        let base = Url::parse("https://example.com/911datasets.org/").unwrap();
+        let url = join_url_path(&base, Path::new("Dir/a.jpg")).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://example.com/911datasets.org/Dir/a.jpg"
+        );
+    }
+
+    #[test]
+    fn tries_a_zip_without_its_top_level_directory() {
+        let zip =
+            DownloadSource::Zip(Url::parse("https://archive.org/download/item/rel.zip").unwrap());
+        assert_eq!(
+            urls(
+                zip.locations(&file("Rel/sub/a.jpg", "Rel/sub/a.jpg"))
+                    .unwrap()
+            ),
+            vec![
+                "https://archive.org/download/item/rel.zip/Rel/sub/a.jpg",
+                "https://archive.org/download/item/rel.zip/sub/a.jpg",
+            ]
+        );
+        // A file at the top of the tree has no directory to leave out.
+        assert_eq!(
+            urls(zip.locations(&file("a.jpg", "a.jpg")).unwrap()),
+            vec!["https://archive.org/download/item/rel.zip/a.jpg"]
+        );
+    }
+
+    #[test]
+    fn finds_a_local_file_under_its_name_on_disk() {
+        let dir = TempDir::new("source").unwrap();
+        let local = DownloadSource::Local(dir.path().to_path_buf());
+        let torrent_file = file("Rel/Café.jpg", "Rel/cafe\u{301}.jpg");
+        assert!(local.locations(&torrent_file).unwrap().is_empty());
+
+        std::fs::create_dir(dir.path().join("Rel")).unwrap();
+        std::fs::write(dir.path().join(&torrent_file.disk_path), b"").unwrap();
+        assert_eq!(
+            urls(local.locations(&torrent_file).unwrap()),
+            vec![dir
+                .path()
+                .join(&torrent_file.disk_path)
+                .display()
+                .to_string()]
+        );
+
+        // The name in the torrent is preferred when both exist.
+        std::fs::write(dir.path().join(&torrent_file.path), b"").unwrap();
+        assert_eq!(
+            urls(local.locations(&torrent_file).unwrap()),
+            vec![dir.path().join(&torrent_file.path).display().to_string()]
+        );
+    }
+
+    #[test]
+    fn uses_the_release_14_links_for_a_file_or_its_directory() {
+        let release_14 = DownloadSource::Release14 {
+            links: HashMap::from([(
+                PathBuf::from("Rel/CNN"),
+                "https://archive.org/download/cnn".to_string(),
+            )]),
+            file_links: HashMap::from([(
+                PathBuf::from("Rel/CNN/moved.mpg"),
+                "https://archive.org/download/other/moved.mpg".to_string(),
+            )]),
+        };
+        assert_eq!(
+            urls(
+                release_14
+                    .locations(&file("Rel/CNN/a b.mpg", "Rel/CNN/a b.mpg"))
+                    .unwrap()
+            ),
+            vec!["https://archive.org/download/cnn/a%20b.mpg"]
+        );
+        assert_eq!(
+            urls(
+                release_14
+                    .locations(&file("Rel/CNN/moved.mpg", "Rel/CNN/moved.mpg"))
+                    .unwrap()
+            ),
+            vec!["https://archive.org/download/other/moved.mpg"]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::error::{Error, Result};
+use chrono::NaiveTime;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the effective read rate is recalculated.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Limits the rate at which content is read from disk, and measures the rate that is achieved.
+///
+/// The throttle is shared by every thread that reads for a verification. Each read is given a slot
+/// in a schedule that advances by the time the bytes should take at the maximum rate, and the
+/// reader sleeps until its slot has passed. Time spent idle doesn't build up any credit, so there
+/// is never a burst above the maximum rate.
+pub struct ReadThrottle {
+    max_rate: Option<u64>,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    next_slot: Instant,
+    window_start: Instant,
+    window_bytes: u64,
+    rate: Option<u64>,
+}
+
+impl ReadThrottle {
+    /// Creates a throttle that allows `max_rate` bytes per second, or any rate if it's `None`.
+    pub fn new(max_rate: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            max_rate,
+            state: Mutex::new(ThrottleState {
+                next_slot: now,
+                window_start: now,
+                window_bytes: 0,
+                rate: None,
+            }),
+        }
+    }
+
+    /// Records that `bytes` have been read, then waits for as long as it takes to bring the rate
+    /// back down to the maximum.
+    pub fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.window_bytes += bytes;
+            let elapsed = now - state.window_start;
+            if elapsed >= RATE_WINDOW {
+                state.rate = Some((state.window_bytes as f64 / elapsed.as_secs_f64()) as u64);
+                state.window_start = now;
+                state.window_bytes = 0;
+            }
+            match self.max_rate {
+                Some(max_rate) => {
+                    let duration = Duration::from_secs_f64(bytes as f64 / max_rate as f64);
+                    state.next_slot = std::cmp::max(state.next_slot, now) + duration;
+                    state.next_slot - now
+                }
+                None => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// The effective read rate in bytes per second, or `None` until it has been measured.
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+}
+
+/// Limits the rate at which files are downloaded, across every download in the process.
+///
+/// This is a token bucket that holds up to a second's worth of bytes at the current rate. Each
+/// chunk that arrives is taken out of the bucket, and if that leaves it short, the download waits
+/// until it has refilled. The bucket can go into debt, so a download that has to wait also holds
+/// back the others that come after it, and the total stays at the rate however many are running.
+///
+/// The rate can vary with the local time of day according to a schedule. Outside the windows in
+/// the schedule, the maximum rate applies.
+pub struct DownloadThrottle {
+    max_rate: Option<u64>,
+    schedule: Vec<RateWindow>,
+    bucket: Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A period of the day during which downloads are limited to a different rate.
+#[derive(Clone, Debug)]
+pub struct RateWindow {
+    pub start: NaiveTime,
+    /// The end of the window. If it's before the start, the window runs past midnight.
+    pub end: NaiveTime,
+    /// The maximum number of bytes per second, or `None` for no limit.
+    pub rate: Option<u64>,
+}
+
+impl RateWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl DownloadThrottle {
+    /// Creates a throttle that allows `max_rate` bytes per second, or any rate if it's `None`,
+    /// except during the windows in the schedule. The first window that contains the current time
+    /// is the one that applies.
+    pub fn new(max_rate: Option<u64>, schedule: Vec<RateWindow>) -> Self {
+        Self {
+            max_rate,
+            schedule,
+            bucket: Mutex::new(TokenBucket {
+                rate: None,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// The rate that applies at the moment, in bytes per second, or `None` if there is no limit.
+    pub fn current_rate(&self) -> Option<u64> {
+        if self.schedule.is_empty() {
+            return self.max_rate;
+        }
+        let now = chrono::Local::now().time();
+        self.schedule
+            .iter()
+            .find(|window| window.contains(now))
+            .map_or(self.max_rate, |window| window.rate)
+    }
+
+    /// Records that `bytes` have been downloaded, then waits until the bucket has enough in it to
+    /// cover them.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.current_rate();
+            if rate != bucket.rate {
+                // Start the new rate with a full bucket, rather than paying off any debt built up
+                // at the old one.
+                bucket.rate = rate;
+                bucket.tokens = rate.unwrap_or(0) as f64;
+                bucket.last_refill = now;
+            }
+            match rate {
+                Some(rate) => {
+                    let rate = rate as f64;
+                    let elapsed = (now - bucket.last_refill).as_secs_f64();
+                    bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+                    bucket.last_refill = now;
+                    if bucket.tokens < 0.0 {
+                        Duration::from_secs_f64(-bucket.tokens / rate)
+                    } else {
+                        Duration::ZERO
+                    }
+                }
+                None => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parses a window of a rate schedule, such as `01:00-07:00=unlimited` or `09:00-17:00=500KiB/s`.
+///
+/// The times are local, and the rate takes the same form as [`parse_rate`]. If the rate is left
+/// off, there is no limit during the window.
+pub fn parse_rate_window(window: &str) -> Result<RateWindow> {
+    let invalid = || Error::InvalidRateSchedule(window.to_string());
+    let (times, rate) = match window.split_once('=') {
+        Some((times, rate)) => (times, Some(rate.trim())),
+        None => (window, None),
+    };
+    let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+    let parse_time =
+        |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+    let start = parse_time(start)?;
+    let end = parse_time(end)?;
+    if start == end {
+        return Err(invalid());
+    }
+    let rate = match rate {
+        None | Some("unlimited") => None,
+        Some(rate) => Some(parse_rate(rate)?),
+    };
+    Ok(RateWindow { start, end, rate })
+}
+
+/// Parses a rate such as `80MiB/s`, `500KB/s` or `1G` into bytes per second.
+///
+/// The `/s` is optional. Binary units like `MiB` are powers of 1024, decimal units like `MB` are
+/// powers of 1000, and a unit with a single letter is treated as binary. A plain number is in
+/// bytes.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let invalid = || Error::InvalidReadRate(rate.to_string());
+    let value = rate.trim();
+    let value = value.strip_suffix("/s").unwrap_or(value).trim_end();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "kb" => 1000,
+        "m" | "mib" => 1024 * 1024,
+        "mb" => 1000 * 1000,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        "gb" => 1000 * 1000 * 1000,
+        _ => return Err(invalid()),
+    };
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err(invalid());
+    }
+    Ok(bytes)
+}
+
+/// Moves this process into the idle I/O scheduling class, so its disk reads are only serviced
+/// when no other process wants the disk.
+///
+/// This relies on the I/O scheduler of the local block device, so it has no effect on network
+/// shares, and it's only available on Linux.
+#[cfg(target_os = "linux")]
+pub fn set_idle_io_priority() -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    // A `who` of 0 means the calling thread. Threads inherit the priority when they are created,
+    // so this has to be called before the reading and hashing threads are spawned.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_idle_io_priority() -> Result<()> {
+    Err(Error::IdleIoPriorityNotSupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("80MiB/s").unwrap(), 80 * 1024 * 1024);
+        assert_eq!(parse_rate("500KB/s").unwrap(), 500_000);
+        assert_eq!(parse_rate("1G").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_rate("2gb").unwrap(), 2_000_000_000);
+        assert_eq!(parse_rate("1.5M").unwrap(), 1024 * 1024 * 3 / 2);
+        assert_eq!(parse_rate(" 2 mb/s ").unwrap(), 2_000_000);
+        assert_eq!(parse_rate("4096").unwrap(), 4096);
+        assert_eq!(parse_rate("100B/s").unwrap(), 100);
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in [
+            "", "/s", "MiB/s", "fast", "-5M", "1.2.3M", "5XB", "10 MiB/h", "0", "0.0001K",
+        ] {
+            assert!(
+                matches!(parse_rate(rate), Err(Error::InvalidReadRate(r)) if r == rate),
+                "{rate}"
+            );
+        }
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parses_rate_windows() {
+        let window = parse_rate_window("09:00-17:00=500KiB/s").unwrap();
+        assert_eq!((window.start, window.end), (time("09:00"), time("17:00")));
+        assert_eq!(window.rate, Some(500 * 1024));
+        assert_eq!(
+            parse_rate_window("01:00-07:00=unlimited").unwrap().rate,
+            None
+        );
+        assert_eq!(parse_rate_window("22:30-06:00").unwrap().rate, None);
+        let window = parse_rate_window(" 8:05 - 9:10 = 1M ").unwrap();
+        assert_eq!((window.start, window.end), (time("08:05"), time("09:10")));
+        assert_eq!(window.rate, Some(1024 * 1024));
+    }
+
+    #[test]
+    fn rejects_invalid_rate_windows() {
+        for window in [
+            "",
+            "09:00",
+            "9-17",
+            "25:00-01:00",
+            "09:00-17:60",
+            "09:00-09:00=1M",
+            "09:00=17:00",
+        ] {
+            assert!(
+                matches!(parse_rate_window(window), Err(Error::InvalidRateSchedule(w)) if w == window),
+                "{window}"
+            );
+        }
+        assert!(matches!(
+            parse_rate_window("09:00-17:00=fast"),
+            Err(Error::InvalidReadRate(_))
+        ));
+    }
+
+    #[test]
+    fn windows_can_run_past_midnight() {
+        let day = parse_rate_window("09:00-17:00").unwrap();
+        assert!(day.contains(time("09:00")));
+        assert!(day.contains(time("16:59")));
+        assert!(!day.contains(time("17:00")));
+        assert!(!day.contains(time("08:59")));
+        let night = parse_rate_window("22:00-06:00").unwrap();
+        assert!(night.contains(time("22:00")));
+        assert!(night.contains(time("00:00")));
+        assert!(night.contains(time("05:59")));
+        assert!(!night.contains(time("06:00")));
+        assert!(!night.contains(time("12:00")));
+    }
+
+    #[test]
+    fn picks_the_rate_for_the_current_time() {
+        assert_eq!(
+            DownloadThrottle::new(Some(100), Vec::new()).current_rate(),
+            Some(100)
+        );
+        let now = chrono::Local::now().time();
+        let hours = |h| now.overflowing_add_signed(chrono::Duration::hours(h)).0;
+        let later = RateWindow {
+            start: hours(1),
+            end: hours(2),
+            rate: Some(5),
+        };
+        let current = RateWindow {
+            start: hours(-1),
+            end: hours(1),
+            rate: None,
+        };
+        let throttle = DownloadThrottle::new(Some(100), vec![later.clone()]);
+        assert_eq!(throttle.current_rate(), Some(100));
+        let throttle = DownloadThrottle::new(Some(100), vec![later, current]);
+        assert_eq!(throttle.current_rate(), None);
+    }
+
+    #[test]
+    fn reads_are_held_to_the_maximum_rate() {
+        let throttle = ReadThrottle::new(Some(1_000_000));
+        let start = Instant::now();
+        for _ in 0..5 {
+            throttle.consume(20_000);
+        }
+        // Each read waits for its own share of time, so five take 100ms between them.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let unlimited = ReadThrottle::new(None);
+        let start = Instant::now();
+        unlimited.consume(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(unlimited.rate(), None);
+    }
+}
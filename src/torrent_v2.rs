@@ -0,0 +1,571 @@
+use crate::error::{Error, Result};
+use crate::pieces::FileAttributes;
+use lava_torrent::bencode::BencodeElem;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The size of the blocks that form the leaves of a v2 merkle tree.
+pub const BLOCK_SIZE: u64 = 16 * 1024;
+
+/// The version of the BitTorrent protocol a torrent was created for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TorrentVersion {
+    V1,
+    V2,
+    /// Has both the v1 pieces and the v2 merkle trees, so it can be verified either way.
+    Hybrid,
+}
+
+impl fmt::Display for TorrentVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentVersion::V1 => write!(f, "v1"),
+            TorrentVersion::V2 => write!(f, "v2"),
+            TorrentVersion::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+/// Works out the version of a torrent from its `info` dictionary.
+///
+/// A v2 torrent has a `meta version` of 2, and a hybrid torrent also has the v1 `pieces`.
+pub fn get_torrent_version(torrent_content: &[u8]) -> Result<TorrentVersion> {
+    let root = decode_root(torrent_content)?;
+    let info = get_dict(&root, "info")?;
+    let is_v2 = matches!(info.get("meta version"), Some(BencodeElem::Integer(2)));
+    match (is_v2, info.contains_key("pieces")) {
+        (true, true) => Ok(TorrentVersion::Hybrid),
+        (true, false) => Ok(TorrentVersion::V2),
+        _ => Ok(TorrentVersion::V1),
+    }
+}
+
+/// The info hashes of a torrent as lowercase hex.
+///
+/// A v1 torrent is identified by the SHA-1 of its `info` dictionary and a v2 torrent by the
+/// SHA-256 of it, so a hybrid torrent has both.
+pub struct InfoHashes {
+    pub v1: Option<String>,
+    pub v2: Option<String>,
+}
+
+/// Computes the info hashes of a torrent.
+///
+/// The `info` dictionary is encoded again after it's decoded. Bencoded dictionaries must have
+/// sorted keys, so this gives the same bytes as the original for any well-formed torrent.
+pub fn get_info_hashes(torrent_content: &[u8]) -> Result<InfoHashes> {
+    let version = get_torrent_version(torrent_content)?;
+    let root = decode_root(torrent_content)?;
+    let info = root
+        .get("info")
+        .ok_or_else(|| Error::TorrentV2ParseError("missing info".to_string()))?
+        .encode();
+    let v1 = (version != TorrentVersion::V2).then(|| format!("{:x}", Sha1::digest(&info)));
+    let v2 = (version != TorrentVersion::V1).then(|| format!("{:x}", Sha256::digest(&info)));
+    Ok(InfoHashes { v1, v2 })
+}
+
+/// A file in a v2 torrent.
+pub struct V2File {
+    /// The path of the file, relative to the directory named by the torrent.
+    pub path: PathBuf,
+    pub length: u64,
+    /// The root of the merkle tree over the file's content. Empty files don't have one.
+    pub pieces_root: Option<Vec<u8>>,
+    /// The hashes of the file's pieces, taken from the piece layers. A file that fits in a single
+    /// piece doesn't have any, since its root covers all of it.
+    pub piece_hashes: Vec<Vec<u8>>,
+    pub attributes: FileAttributes,
+}
+
+/// The parts of a v2 or hybrid torrent that are needed for verification.
+///
+/// Each file has its own merkle tree of SHA-256 hashes, with 16 KiB blocks at the leaves, so
+/// unlike v1, pieces never span files and every file can be verified on its own.
+pub struct V2Torrent {
+    pub name: String,
+    pub piece_length: u64,
+    pub files: Vec<V2File>,
+}
+
+impl V2Torrent {
+    pub fn read_from_bytes(torrent_content: &[u8]) -> Result<V2Torrent> {
+        let root = decode_root(torrent_content)?;
+        let info = get_dict(&root, "info")?;
+        let name = match info.get("name") {
+            Some(BencodeElem::String(name)) => name.clone(),
+            _ => return Err(Error::TorrentV2ParseError("missing name".to_string())),
+        };
+        let piece_length = match info.get("piece length") {
+            Some(BencodeElem::Integer(length)) if *length >= BLOCK_SIZE as i64 => *length as u64,
+            _ => {
+                return Err(Error::TorrentV2ParseError(
+                    "missing or invalid piece length".to_string(),
+                ))
+            }
+        };
+        let file_tree = get_dict(info, "file tree")?;
+        let mut files = Vec::new();
+        collect_files(file_tree, Path::new(""), &mut files)?;
+
+        let piece_layers = match root.get("piece layers") {
+            Some(BencodeElem::Dictionary(layers)) => layers
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect::<HashMap<Vec<u8>, &BencodeElem>>(),
+            Some(BencodeElem::RawDictionary(layers)) => layers
+                .iter()
+                .map(|(k, v)| (k.clone(), v))
+                .collect::<HashMap<Vec<u8>, &BencodeElem>>(
+            ),
+            _ => HashMap::new(),
+        };
+        for file in files.iter_mut() {
+            if file.length <= piece_length {
+                continue;
+            }
+            let pieces_root = file.pieces_root.as_ref().ok_or_else(|| {
+                Error::TorrentV2ParseError(format!("{} has no pieces root", file.path.display()))
+            })?;
+            let layer = piece_layers
+                .get(pieces_root)
+                .and_then(|layer| get_bytes(layer))
+                .ok_or_else(|| {
+                    Error::TorrentV2ParseError(format!(
+                        "{} has no piece layer",
+                        file.path.display()
+                    ))
+                })?;
+            if layer.len() % 32 != 0 {
+                return Err(Error::TorrentV2ParseError(format!(
+                    "the piece layer for {} is not a whole number of hashes",
+                    file.path.display()
+                )));
+            }
+            file.piece_hashes = layer.chunks(32).map(|hash| hash.to_vec()).collect();
+        }
+
+        Ok(V2Torrent {
+            name,
+            piece_length,
+            files,
+        })
+    }
+
+    /// The number of pieces across all the files. Each file starts at a new piece, so this is
+    /// usually more than the total size divided by the piece length.
+    pub fn piece_count(&self) -> usize {
+        self.files
+            .iter()
+            .map(|f| f.length.div_ceil(self.piece_length) as usize)
+            .sum()
+    }
+
+    /// Verifies a file on disk against its merkle tree, returning whether it matched.
+    ///
+    /// The number of bytes read is passed to `on_read` as the file is read.
+    pub fn verify_file(&self, file: &V2File, path: &Path, on_read: &dyn Fn(u64)) -> Result<bool> {
+        if std::fs::metadata(path)?.len() != file.length {
+            return Ok(false);
+        }
+        let mut reader = File::open(path)?;
+        let mut leaves = Vec::with_capacity(file.length.div_ceil(BLOCK_SIZE) as usize);
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        loop {
+            let read = read_block(&mut reader, &mut block)?;
+            if read == 0 {
+                break;
+            }
+            leaves.push(Sha256::digest(&block[..read]).to_vec());
+            on_read(read as u64);
+        }
+        let Some(pieces_root) = file.pieces_root.as_ref() else {
+            // Only an empty file has no root.
+            return Ok(leaves.is_empty());
+        };
+
+        let blocks_per_piece = (self.piece_length / BLOCK_SIZE) as usize;
+        let root = if file.length <= self.piece_length {
+            merkle_root(leaves, vec![0; 32])
+        } else {
+            let piece_hashes = leaves
+                .chunks(blocks_per_piece)
+                .map(|chunk| {
+                    let mut chunk = chunk.to_vec();
+                    chunk.resize(blocks_per_piece, vec![0; 32]);
+                    merkle_root(chunk, vec![0; 32])
+                })
+                .collect::<Vec<Vec<u8>>>();
+            if piece_hashes != file.piece_hashes {
+                return Ok(false);
+            }
+            let pad = merkle_root(vec![vec![0; 32]; blocks_per_piece], vec![0; 32]);
+            merkle_root(piece_hashes, pad)
+        };
+        Ok(root == *pieces_root)
+    }
+}
+
+/// Computes the root of a merkle tree, padding the layer to a power of two with the given hash.
+fn merkle_root(mut layer: Vec<Vec<u8>>, pad: Vec<u8>) -> Vec<u8> {
+    if layer.is_empty() {
+        return pad;
+    }
+    layer.resize(layer.len().next_power_of_two(), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(&pair[0]);
+                hasher.update(&pair[1]);
+                hasher.finalize().to_vec()
+            })
+            .collect();
+    }
+    layer.remove(0)
+}
+
+/// Fills the block from the reader, so that only the last block of a file is ever short.
+fn read_block(reader: &mut File, block: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < block.len() {
+        let read = reader.read(&mut block[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+/// Walks the file tree, where each file is a dictionary whose only key is the empty string.
+///
+/// Bencoded dictionaries are sorted by key, so the names are sorted to keep the files in the same
+/// order as the torrent.
+fn collect_files(
+    tree: &HashMap<String, BencodeElem>,
+    prefix: &Path,
+    files: &mut Vec<V2File>,
+) -> Result<()> {
+    let mut names = tree.keys().collect::<Vec<&String>>();
+    names.sort();
+    for name in names {
+        let path = prefix.join(name);
+        let node = match &tree[name] {
+            BencodeElem::Dictionary(node) => node,
+            _ => {
+                return Err(Error::TorrentV2ParseError(format!(
+                    "{} is not a directory or a file",
+                    path.display()
+                )))
+            }
+        };
+        match node.get("") {
+            Some(BencodeElem::Dictionary(leaf)) => {
+                let length = match leaf.get("length") {
+                    Some(BencodeElem::Integer(length)) if *length >= 0 => *length as u64,
+                    _ => {
+                        return Err(Error::TorrentV2ParseError(format!(
+                            "{} has no length",
+                            path.display()
+                        )))
+                    }
+                };
+                let pieces_root = leaf
+                    .get("pieces root")
+                    .and_then(get_bytes)
+                    .map(|root| root.to_vec());
+                let attributes = match leaf.get("attr") {
+                    Some(BencodeElem::String(attr)) => FileAttributes::from_attr(attr),
+                    _ => FileAttributes::default(),
+                };
+                files.push(V2File {
+                    path,
+                    length,
+                    pieces_root,
+                    piece_hashes: Vec::new(),
+                    attributes,
+                });
+            }
+            _ => collect_files(node, &path, files)?,
+        }
+    }
+    Ok(())
+}
+
+fn decode_root(torrent_content: &[u8]) -> Result<HashMap<String, BencodeElem>> {
+    let mut elements = BencodeElem::from_bytes(torrent_content)?;
+    match elements.pop() {
+        Some(BencodeElem::Dictionary(root)) if elements.is_empty() => Ok(root),
+        _ => Err(Error::TorrentV2ParseError(
+            "the torrent is not a single dictionary".to_string(),
+        )),
+    }
+}
+
+fn get_dict<'a>(
+    dict: &'a HashMap<String, BencodeElem>,
+    key: &str,
+) -> Result<&'a HashMap<String, BencodeElem>> {
+    match dict.get(key) {
+        Some(BencodeElem::Dictionary(value)) => Ok(value),
+        _ => Err(Error::TorrentV2ParseError(format!(
+            "missing {key} dictionary"
+        ))),
+    }
+}
+
+/// Hashes are decoded as strings if they happen to be valid UTF-8.
+fn get_bytes(element: &BencodeElem) -> Option<&[u8]> {
+    match element {
+        BencodeElem::Bytes(bytes) => Some(bytes),
+        BencodeElem::String(string) => Some(string.as_bytes()),
+        _ => None,
+    }
+}
+
+/// Builds a v2 torrent for files with the given content, with the v1 pieces as well if it's a
+/// hybrid. The v1 pieces are only there to mark it as a hybrid, and don't account for padding.
+#[cfg(test)]
+pub(crate) fn build_v2_torrent(
+    files: &[(&str, &[u8])],
+    piece_length: u64,
+    hybrid: bool,
+) -> Vec<u8> {
+    let blocks_per_piece = (piece_length / BLOCK_SIZE) as usize;
+    let mut file_tree = HashMap::new();
+    let mut piece_layers = HashMap::new();
+    for (path, content) in files.iter() {
+        let mut leaf = HashMap::from([(
+            "length".to_string(),
+            BencodeElem::Integer(content.len() as i64),
+        )]);
+        if !content.is_empty() {
+            let leaves = content
+                .chunks(BLOCK_SIZE as usize)
+                .map(|block| Sha256::digest(block).to_vec())
+                .collect::<Vec<Vec<u8>>>();
+            let root = if content.len() as u64 <= piece_length {
+                merkle_root(leaves, vec![0; 32])
+            } else {
+                let piece_hashes = leaves
+                    .chunks(blocks_per_piece)
+                    .map(|chunk| {
+                        let mut chunk = chunk.to_vec();
+                        chunk.resize(blocks_per_piece, vec![0; 32]);
+                        merkle_root(chunk, vec![0; 32])
+                    })
+                    .collect::<Vec<Vec<u8>>>();
+                let pad = merkle_root(vec![vec![0; 32]; blocks_per_piece], vec![0; 32]);
+                let root = merkle_root(piece_hashes.clone(), pad);
+                piece_layers.insert(root.clone(), BencodeElem::Bytes(piece_hashes.concat()));
+                root
+            };
+            leaf.insert("pieces root".to_string(), BencodeElem::Bytes(root));
+        }
+        let mut node = BencodeElem::Dictionary(HashMap::from([(
+            String::new(),
+            BencodeElem::Dictionary(leaf),
+        )]));
+        let components = path.split('/').collect::<Vec<&str>>();
+        for name in components[1..].iter().rev() {
+            node = BencodeElem::Dictionary(HashMap::from([(name.to_string(), node)]));
+        }
+        insert_node(&mut file_tree, components[0], node);
+    }
+    let mut info = HashMap::from([
+        ("name".to_string(), BencodeElem::String("test".to_string())),
+        (
+            "piece length".to_string(),
+            BencodeElem::Integer(piece_length as i64),
+        ),
+        ("meta version".to_string(), BencodeElem::Integer(2)),
+        ("file tree".to_string(), BencodeElem::Dictionary(file_tree)),
+    ]);
+    if hybrid {
+        info.insert("pieces".to_string(), BencodeElem::Bytes(vec![0; 20]));
+    }
+    BencodeElem::Dictionary(HashMap::from([
+        ("info".to_string(), BencodeElem::Dictionary(info)),
+        (
+            "piece layers".to_string(),
+            BencodeElem::RawDictionary(piece_layers),
+        ),
+    ]))
+    .encode()
+}
+
+/// Adds a node to a file tree, merging it with any directory of the same name.
+#[cfg(test)]
+fn insert_node(tree: &mut HashMap<String, BencodeElem>, name: &str, node: BencodeElem) {
+    match (tree.get_mut(name), node) {
+        (Some(BencodeElem::Dictionary(existing)), BencodeElem::Dictionary(node)) => {
+            for (child, node) in node {
+                insert_node(existing, &child, node);
+            }
+        }
+        (_, node) => {
+            tree.insert(name.to_string(), node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pieces::build_v1_torrent;
+    use tempdir::TempDir;
+
+    const PIECE_LENGTH: u64 = 2 * BLOCK_SIZE;
+
+    fn content(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn pads_a_merkle_tree_to_a_power_of_two() {
+        let pad = vec![0; 32];
+        let leaves = (0..3u8).map(|i| vec![i; 32]).collect::<Vec<Vec<u8>>>();
+        assert_eq!(merkle_root(Vec::new(), pad.clone()), pad);
+        assert_eq!(merkle_root(leaves[..1].to_vec(), pad.clone()), leaves[0]);
+        assert_eq!(
+            merkle_root(leaves.clone(), pad.clone()),
+            hash_pair(
+                &hash_pair(&leaves[0], &leaves[1]),
+                &hash_pair(&leaves[2], &pad)
+            )
+        );
+    }
+
+    #[test]
+    fn reads_the_files_and_piece_layers() {
+        let (small, large) = (content(100, 1), content(5 * BLOCK_SIZE as usize + 10, 2));
+        let torrent = build_v2_torrent(
+            &[
+                ("Rel/sub/small.txt", &small),
+                ("Rel/empty", &[]),
+                ("Rel/large.bin", &large),
+            ],
+            PIECE_LENGTH,
+            false,
+        );
+        let torrent = V2Torrent::read_from_bytes(&torrent).unwrap();
+        assert_eq!(torrent.name, "test");
+        assert_eq!(torrent.piece_length, PIECE_LENGTH);
+        // The files come out in the sorted order of the tree.
+        assert_eq!(
+            torrent
+                .files
+                .iter()
+                .map(|f| (f.path.to_str().unwrap(), f.length))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Rel/empty", 0),
+                ("Rel/large.bin", large.len() as u64),
+                ("Rel/sub/small.txt", 100)
+            ]
+        );
+        assert!(torrent.files[0].pieces_root.is_none());
+        assert_eq!(torrent.files[1].piece_hashes.len(), 3);
+        assert!(torrent.files[2].piece_hashes.is_empty());
+        assert_eq!(torrent.piece_count(), 4);
+    }
+
+    #[test]
+    fn rejects_a_torrent_without_a_piece_layer_for_a_large_file() {
+        let large = content(3 * BLOCK_SIZE as usize, 1);
+        let mut root = BencodeElem::from_bytes(build_v2_torrent(
+            &[("Rel/large.bin", &large)],
+            PIECE_LENGTH,
+            false,
+        ))
+        .unwrap()
+        .remove(0);
+        if let BencodeElem::Dictionary(root) = &mut root {
+            root.remove("piece layers");
+        }
+        assert!(matches!(
+            V2Torrent::read_from_bytes(&root.encode()),
+            Err(Error::TorrentV2ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_files_against_their_merkle_trees() {
+        let dir = TempDir::new("v2").unwrap();
+        let files = [
+            ("Rel/small.txt", content(100, 1)),
+            ("Rel/block.bin", content(BLOCK_SIZE as usize, 2)),
+            ("Rel/piece.bin", content(PIECE_LENGTH as usize - 1, 3)),
+            ("Rel/large.bin", content(5 * BLOCK_SIZE as usize + 10, 4)),
+            ("Rel/empty", Vec::new()),
+        ];
+        let torrent = build_v2_torrent(
+            &files
+                .iter()
+                .map(|(path, content)| (*path, content.as_slice()))
+                .collect::<Vec<_>>(),
+            PIECE_LENGTH,
+            false,
+        );
+        let torrent = V2Torrent::read_from_bytes(&torrent).unwrap();
+        for (path, content) in files.iter() {
+            let disk_path = dir.path().join(path);
+            std::fs::create_dir_all(disk_path.parent().unwrap()).unwrap();
+            std::fs::write(&disk_path, content).unwrap();
+            let file = torrent
+                .files
+                .iter()
+                .find(|f| f.path == Path::new(path))
+                .unwrap();
+            assert!(torrent.verify_file(file, &disk_path, &|_| {}).unwrap());
+            if content.is_empty() {
+                continue;
+            }
+
+            let mut corrupt = content.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            std::fs::write(&disk_path, &corrupt).unwrap();
+            assert!(!torrent.verify_file(file, &disk_path, &|_| {}).unwrap());
+            std::fs::write(&disk_path, &content[1..]).unwrap();
+            assert!(!torrent.verify_file(file, &disk_path, &|_| {}).unwrap());
+        }
+    }
+
+    #[test]
+    fn tells_the_torrent_versions_apart() {
+        let files: [(&str, &[u8]); 1] = [("Rel/a.txt", b"content")];
+        let v1 = build_v1_torrent(&files, BLOCK_SIZE);
+        let v2 = build_v2_torrent(&files, BLOCK_SIZE, false);
+        let hybrid = build_v2_torrent(&files, BLOCK_SIZE, true);
+        assert_eq!(get_torrent_version(&v1).unwrap(), TorrentVersion::V1);
+        assert_eq!(get_torrent_version(&v2).unwrap(), TorrentVersion::V2);
+        assert_eq!(
+            get_torrent_version(&hybrid).unwrap(),
+            TorrentVersion::Hybrid
+        );
+
+        let hashes = get_info_hashes(&v1).unwrap();
+        assert!(hashes.v1.is_some() && hashes.v2.is_none());
+        let hashes = get_info_hashes(&v2).unwrap();
+        assert!(hashes.v1.is_none() && hashes.v2.is_some());
+        let hashes = get_info_hashes(&hybrid).unwrap();
+        assert_eq!(hashes.v1.unwrap().len(), 40);
+        assert_eq!(hashes.v2.unwrap().len(), 64);
+    }
+}
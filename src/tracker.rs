@@ -0,0 +1,372 @@
+use crate::error::{Error, Result};
+use lava_torrent::bencode::BencodeElem;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use url::Url;
+
+/// How long to wait for a tracker to respond.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The magic number that starts a connect request to a UDP tracker, as defined in BEP 15.
+const UDP_PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+/// What we tell a tracker about ourselves when asking it for peers.
+pub struct Announce<'a> {
+    pub info_hash: &'a [u8; 20],
+    pub peer_id: &'a [u8; 20],
+    /// The port we say we're listening on. Nothing listens on it, since we only download, but
+    /// trackers require one.
+    pub port: u16,
+    /// The number of bytes we still need.
+    pub left: u64,
+}
+
+/// Asks a tracker for the peers in the swarm for a torrent.
+///
+/// Both HTTP trackers and UDP trackers (BEP 15) are supported. HTTP trackers are asked using the
+/// given client.
+pub async fn announce(
+    client: &reqwest::Client,
+    tracker: &str,
+    request: &Announce<'_>,
+) -> Result<Vec<SocketAddr>> {
+    let url = Url::parse(tracker)?;
+    match url.scheme() {
+        "http" | "https" => announce_http(client, &url, request).await,
+        "udp" => announce_udp(&url, request).await,
+        scheme => Err(Error::TrackerError(
+            tracker.to_string(),
+            format!("{scheme} trackers are not supported"),
+        )),
+    }
+}
+
+/// Gets the trackers listed in a torrent, in the order they should be tried, without duplicates.
+pub fn get_trackers(torrent: &lava_torrent::torrent::v1::Torrent) -> Vec<String> {
+    let mut trackers = Vec::new();
+    let tiers = torrent.announce_list.iter().flatten().flatten();
+    for tracker in torrent.announce.iter().chain(tiers) {
+        if !trackers.contains(tracker) {
+            trackers.push(tracker.clone());
+        }
+    }
+    trackers
+}
+
+async fn announce_http(
+    client: &reqwest::Client,
+    url: &Url,
+    request: &Announce<'_>,
+) -> Result<Vec<SocketAddr>> {
+    // The info hash and peer ID are raw bytes, which the url crate would encode as UTF-8.
+    let query = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1&event=started",
+        percent_encode(request.info_hash),
+        percent_encode(request.peer_id),
+        request.port,
+        request.left
+    );
+    let mut url = url.clone();
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{existing}&{query}"),
+        _ => query,
+    };
+    url.set_query(Some(&query));
+
+    let response = client
+        .get(url.clone())
+        .timeout(TRACKER_TIMEOUT)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(Error::TrackerError(
+            url.to_string(),
+            format!("{} response", response.status()),
+        ));
+    }
+    let body = response.bytes().await?;
+    parse_http_response(url.as_str(), &body)
+}
+
+/// Parses the bencoded response from an HTTP tracker.
+///
+/// The peers can either be a list of dictionaries or a compact string with 6 bytes for each peer,
+/// and IPv6 peers can also be given in a compact `peers6` string with 18 bytes for each.
+fn parse_http_response(tracker: &str, body: &[u8]) -> Result<Vec<SocketAddr>> {
+    let error = |message: &str| Error::TrackerError(tracker.to_string(), message.to_string());
+    let mut elements = BencodeElem::from_bytes(body).map_err(|_| error("malformed response"))?;
+    let response = match elements.pop() {
+        Some(BencodeElem::Dictionary(response)) => response,
+        Some(BencodeElem::RawDictionary(response)) => response
+            .into_iter()
+            .map(|(key, value)| (String::from_utf8_lossy(&key).to_string(), value))
+            .collect::<HashMap<String, BencodeElem>>(),
+        _ => return Err(error("malformed response")),
+    };
+    if let Some(reason) = response.get("failure reason") {
+        return Err(error(&String::from_utf8_lossy(
+            bencode_bytes(reason).unwrap_or_default(),
+        )));
+    }
+    let mut peers = Vec::new();
+    match response.get("peers") {
+        Some(BencodeElem::List(list)) => {
+            for peer in list.iter() {
+                let BencodeElem::Dictionary(peer) = peer else {
+                    continue;
+                };
+                let ip = peer
+                    .get("ip")
+                    .and_then(bencode_bytes)
+                    .and_then(|ip| String::from_utf8_lossy(ip).parse::<IpAddr>().ok());
+                let port = match peer.get("port") {
+                    Some(BencodeElem::Integer(port)) => u16::try_from(*port).ok(),
+                    _ => None,
+                };
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    peers.push(SocketAddr::new(ip, port));
+                }
+            }
+        }
+        Some(compact) => {
+            let bytes = bencode_bytes(compact).ok_or_else(|| error("malformed peer list"))?;
+            peers.extend(parse_compact_peers(bytes));
+        }
+        None => {}
+    }
+    if let Some(compact) = response.get("peers6").and_then(bencode_bytes) {
+        for peer in compact.chunks_exact(18) {
+            let ip: [u8; 16] = peer[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([peer[16], peer[17]]);
+            peers.push(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port));
+        }
+    }
+    Ok(peers)
+}
+
+/// Gets the bytes of a bencoded string, which the decoder gives as a `String` if they happen to
+/// be valid UTF-8.
+fn bencode_bytes(element: &BencodeElem) -> Option<&[u8]> {
+    match element {
+        BencodeElem::String(string) => Some(string.as_bytes()),
+        BencodeElem::Bytes(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+/// Parses IPv4 peers in the compact form, with 4 bytes for the address followed by 2 for the port.
+fn parse_compact_peers(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(6)
+        .map(|peer| {
+            let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+            SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([peer[4], peer[5]]))
+        })
+        .collect()
+}
+
+async fn announce_udp(url: &Url, request: &Announce<'_>) -> Result<Vec<SocketAddr>> {
+    let error = |message: &str| Error::TrackerError(url.to_string(), message.to_string());
+    let host = url.host_str().ok_or_else(|| error("no host"))?;
+    let port = url.port().ok_or_else(|| error("no port"))?;
+    let address = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| error("the host could not be resolved"))?;
+    let bind_address: SocketAddr = if address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(address).await?;
+
+    let transaction_id = random_u32();
+    let mut connect = Vec::with_capacity(16);
+    connect.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+    connect.extend_from_slice(&0u32.to_be_bytes());
+    connect.extend_from_slice(&transaction_id.to_be_bytes());
+    let response = udp_exchange(&socket, &connect, transaction_id, 0, url).await?;
+    if response.len() < 16 {
+        return Err(error("malformed connect response"));
+    }
+    let connection_id = &response[8..16];
+
+    let transaction_id = random_u32();
+    let mut announce = Vec::with_capacity(98);
+    announce.extend_from_slice(connection_id);
+    announce.extend_from_slice(&1u32.to_be_bytes());
+    announce.extend_from_slice(&transaction_id.to_be_bytes());
+    announce.extend_from_slice(request.info_hash);
+    announce.extend_from_slice(request.peer_id);
+    announce.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+    announce.extend_from_slice(&request.left.to_be_bytes());
+    announce.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+    announce.extend_from_slice(&2u32.to_be_bytes()); // the started event
+    announce.extend_from_slice(&0u32.to_be_bytes()); // our IP address, as seen by the tracker
+    announce.extend_from_slice(&random_u32().to_be_bytes());
+    announce.extend_from_slice(&(-1i32).to_be_bytes()); // as many peers as it will give
+    announce.extend_from_slice(&request.port.to_be_bytes());
+    let response = udp_exchange(&socket, &announce, transaction_id, 1, url).await?;
+    if response.len() < 20 {
+        return Err(error("malformed announce response"));
+    }
+    // The interval and the numbers of seeders and leechers come before the peers.
+    Ok(parse_compact_peers(&response[20..]))
+}
+
+/// Sends a request to a UDP tracker and waits for the response with the same transaction ID,
+/// trying again if there isn't one in time.
+async fn udp_exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    transaction_id: u32,
+    action: u32,
+    url: &Url,
+) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; 65536];
+    for _ in 0..2 {
+        socket.send(request).await?;
+        let received = timeout(TRACKER_TIMEOUT, async {
+            loop {
+                let len = socket.recv(&mut buffer).await?;
+                if len >= 8 && buffer[4..8] == transaction_id.to_be_bytes() {
+                    return Ok::<usize, Error>(len);
+                }
+            }
+        })
+        .await;
+        let len = match received {
+            Ok(len) => len?,
+            Err(_) => continue,
+        };
+        let response = &buffer[..len];
+        let response_action = u32::from_be_bytes(response[..4].try_into().unwrap());
+        // An action of 3 is an error, with a message after the transaction ID.
+        if response_action == 3 {
+            return Err(Error::TrackerError(
+                url.to_string(),
+                String::from_utf8_lossy(&response[8..]).to_string(),
+            ));
+        }
+        if response_action != action {
+            return Err(Error::TrackerError(
+                url.to_string(),
+                "unexpected response".to_string(),
+            ));
+        }
+        return Ok(response.to_vec());
+    }
+    Err(Error::TrackerError(
+        url.to_string(),
+        "no response".to_string(),
+    ))
+}
+
+/// Percent-encodes every byte that isn't unreserved in a URL.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for byte in bytes.iter() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(byte) {
+            encoded.push(*byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    // Transaction IDs only need to differ between requests, so a failure isn't important.
+    let _ = getrandom::getrandom(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACKER: &str = "http://tracker.example/announce";
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let body = b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e";
+        assert_eq!(
+            parse_http_response(TRACKER, body).unwrap(),
+            vec![address("127.0.0.1:6881"), address("10.0.0.2:80")]
+        );
+    }
+
+    #[test]
+    fn parses_dictionary_peers() {
+        let body = b"d5:peersld2:ip9:127.0.0.17:peer id20:xxxxxxxxxxxxxxxxxxxx4:porti6881eed2:ip3:::14:porti80eed2:ip7:invalid4:porti1eed2:ip8:10.0.0.34:porti70000eeee";
+        assert_eq!(
+            parse_http_response(TRACKER, body).unwrap(),
+            vec![address("127.0.0.1:6881"), address("[::1]:80")]
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_peers() {
+        let mut body = b"d5:peers0:6:peers618:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&6881u16.to_be_bytes());
+        body.push(b'e');
+        assert_eq!(
+            parse_http_response(TRACKER, &body).unwrap(),
+            vec![address("[::1]:6881")]
+        );
+    }
+
+    #[test]
+    fn reports_failure_reason() {
+        let result = parse_http_response(TRACKER, b"d14:failure reason11:not allowede");
+        assert!(matches!(
+            result,
+            Err(Error::TrackerError(tracker, reason)) if tracker == TRACKER && reason == "not allowed"
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        for body in [
+            &b""[..],
+            b"<html>Not found</html>",
+            b"li1ee",
+            b"d5:peersi1ee",
+        ] {
+            assert!(matches!(
+                parse_http_response(TRACKER, body),
+                Err(Error::TrackerError(..))
+            ));
+        }
+        assert!(parse_http_response(TRACKER, b"d8:intervali1800ee")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn ignores_trailing_bytes_of_compact_peers() {
+        assert_eq!(
+            parse_compact_peers(b"\x7f\x00\x00\x01\x1a\xe1\x0a\x00"),
+            vec![address("127.0.0.1:6881")]
+        );
+        assert!(parse_compact_peers(b"").is_empty());
+    }
+
+    #[test]
+    fn percent_encodes_reserved_bytes() {
+        assert_eq!(percent_encode(b"aZ09-._~"), "aZ09-._~");
+        assert_eq!(percent_encode(b" /?&="), "%20%2F%3F%26%3D");
+        assert_eq!(percent_encode(&[0x00, 0xab, 0xff]), "%00%AB%FF");
+        assert_eq!(percent_encode(b""), "");
+    }
+}
@@ -0,0 +1,311 @@
+use crate::error::{Error, Result};
+use crate::pieces::{FileDigest, PieceAssembler, PieceLayout};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// A zip containing the files of a release, mapped onto the torrent tree.
+///
+/// The zips on the Archive usually have the same tree as the torrent, but some of them either have
+/// an extra directory at the top, or are missing the top level directory of the torrent. Every
+/// way of mapping a file in the torrent onto an entry in the zip is tried against all the other
+/// files, and the one that finds the most of them is used. If two mappings find the same number,
+/// the zip is rejected rather than picking one of them arbitrarily.
+pub struct ZipSource {
+    pub zip_path: PathBuf,
+    /// The index of the zip entry for each file in the torrent, or `None` if it's not in the zip.
+    pub entries: Vec<Option<usize>>,
+}
+
+impl ZipSource {
+    pub fn open(zip_path: &Path, layout: &PieceLayout) -> Result<ZipSource> {
+        let file = File::open(zip_path)?;
+        let mut archive = ZipArchive::new(file)?;
+        let mut names = BTreeMap::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+            if let Some(path) = entry.enclosed_name() {
+                names.insert(path.to_path_buf(), i);
+            }
+        }
+
+        let torrent_paths = layout
+            .content_files()
+            .map(|(_, file)| file.path.as_path())
+            .collect::<Vec<&Path>>();
+        let mapping = PathMapping::find(&torrent_paths, &names)
+            .map_err(|mappings| {
+                Error::AmbiguousZipLayout(zip_path.display().to_string(), mappings.join(" or "))
+            })?
+            .unwrap_or(PathMapping::Same);
+        let entries = layout
+            .files
+            .iter()
+            .map(|file| {
+                if file.attributes.padding {
+                    return None;
+                }
+                mapping
+                    .to_zip_path(&file.path)
+                    .and_then(|path| names.get(&path).copied())
+            })
+            .collect();
+        Ok(ZipSource {
+            zip_path: zip_path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Opens the zip so entries can be read from it directly. Each thread reading from the zip
+    /// needs its own.
+    pub fn open_archive(&self) -> Result<ZipArchive<File>> {
+        Ok(ZipArchive::new(File::open(&self.zip_path)?)?)
+    }
+
+    /// Computes the SHA-256 and MD5 of the entry for a file in the torrent, or returns `None` if
+    /// the file isn't in the zip.
+    pub fn file_digest(
+        &self,
+        archive: &mut ZipArchive<File>,
+        file_idx: usize,
+        on_read: &dyn Fn(u64),
+    ) -> Result<Option<FileDigest>> {
+        let Some(entry_idx) = self.entries[file_idx] else {
+            return Ok(None);
+        };
+        let mut entry = archive.by_index(entry_idx)?;
+        Ok(Some(FileDigest::from_reader(&mut entry, on_read)?))
+    }
+
+    /// Streams the content of every file in torrent order and assembles it into pieces.
+    ///
+    /// Padding files and files that aren't in the zip are replaced by zeros, and entries that are
+    /// shorter or longer than the torrent says are padded or truncated, so the pieces after them
+    /// stay aligned. The pieces they overlap will then fail to match their hashes.
+    pub fn read_pieces(
+        &self,
+        layout: &PieceLayout,
+        on_piece: &mut dyn FnMut(usize, Vec<u8>) -> Result<()>,
+    ) -> Result<()> {
+        let mut archive = self.open_archive()?;
+        let mut assembler = PieceAssembler::new(layout);
+        let mut buffer = vec![0; 1024 * 1024];
+        for (file, entry_idx) in layout.files.iter().zip(self.entries.iter()) {
+            let mut remaining = file.length;
+            if let Some(entry_idx) = entry_idx {
+                let mut entry = archive.by_index(*entry_idx)?;
+                while remaining > 0 {
+                    let wanted = std::cmp::min(remaining, buffer.len() as u64) as usize;
+                    let read = entry.read(&mut buffer[..wanted])?;
+                    if read == 0 {
+                        break;
+                    }
+                    assembler.push(layout, &buffer[..read], on_piece)?;
+                    remaining -= read as u64;
+                }
+            }
+            if remaining > 0 {
+                let zeros = vec![0; std::cmp::min(remaining, 1024 * 1024) as usize];
+                while remaining > 0 {
+                    let len = std::cmp::min(remaining, zeros.len() as u64) as usize;
+                    assembler.push(layout, &zeros[..len], on_piece)?;
+                    remaining -= len as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How paths in the torrent relate to the paths of the entries in the zip.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum PathMapping {
+    Same,
+    /// The zip has this extra directory above the torrent tree.
+    AddPrefix(PathBuf),
+    /// The torrent paths start with this directory, but the zip doesn't have it.
+    StripPrefix(PathBuf),
+}
+
+impl PathMapping {
+    /// Finds the mapping that matches the most torrent paths to names in the zip.
+    ///
+    /// Returns `None` if no path can be matched at all, and the descriptions of the best mappings
+    /// as an error if more than one of them matches the same number of paths.
+    fn find(
+        torrent_paths: &[&Path],
+        names: &BTreeMap<PathBuf, usize>,
+    ) -> std::result::Result<Option<PathMapping>, Vec<String>> {
+        // Only names with the same file name as a torrent path can be a match for it.
+        let mut names_by_file_name = HashMap::<_, Vec<&PathBuf>>::new();
+        for name in names.keys() {
+            if let Some(file_name) = name.file_name() {
+                names_by_file_name.entry(file_name).or_default().push(name);
+            }
+        }
+        let mut candidates = BTreeSet::new();
+        for torrent_path in torrent_paths.iter() {
+            let Some(file_name) = torrent_path.file_name() else {
+                continue;
+            };
+            for name in names_by_file_name.get(file_name).into_iter().flatten() {
+                candidates.extend(PathMapping::between(torrent_path, name));
+            }
+        }
+
+        let mut best = Vec::new();
+        let mut best_count = 0;
+        for candidate in candidates {
+            let count = torrent_paths
+                .iter()
+                .filter(|path| {
+                    candidate
+                        .to_zip_path(path)
+                        .is_some_and(|path| names.contains_key(&path))
+                })
+                .count();
+            if count > best_count {
+                best_count = count;
+                best.clear();
+            }
+            if count == best_count {
+                best.push(candidate);
+            }
+        }
+        if best.len() > 1 {
+            return Err(best.iter().map(PathMapping::describe).collect());
+        }
+        Ok(best.pop())
+    }
+
+    /// The mapping that takes the torrent path to the name in the zip, if there is one.
+    fn between(torrent_path: &Path, name: &Path) -> Option<PathMapping> {
+        let torrent_depth = torrent_path.components().count();
+        let name_depth = name.components().count();
+        if name == torrent_path {
+            Some(PathMapping::Same)
+        } else if name_depth > torrent_depth && name.ends_with(torrent_path) {
+            let prefix = name.components().take(name_depth - torrent_depth).collect();
+            Some(PathMapping::AddPrefix(prefix))
+        } else if torrent_depth > name_depth && torrent_path.ends_with(name) {
+            let prefix = torrent_path
+                .components()
+                .take(torrent_depth - name_depth)
+                .collect();
+            Some(PathMapping::StripPrefix(prefix))
+        } else {
+            None
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            PathMapping::Same => "as they are".to_string(),
+            PathMapping::AddPrefix(prefix) => format!("under {}", prefix.display()),
+            PathMapping::StripPrefix(prefix) => format!("without {}", prefix.display()),
+        }
+    }
+
+    fn to_zip_path(&self, torrent_path: &Path) -> Option<PathBuf> {
+        match self {
+            PathMapping::Same => Some(torrent_path.to_path_buf()),
+            PathMapping::AddPrefix(prefix) => Some(prefix.join(torrent_path)),
+            PathMapping::StripPrefix(prefix) => torrent_path
+                .strip_prefix(prefix)
+                .ok()
+                .map(|p| p.to_path_buf()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(
+        torrent_paths: &[&str],
+        zip_names: &[&str],
+    ) -> std::result::Result<Option<PathMapping>, Vec<String>> {
+        let torrent_paths = torrent_paths.iter().map(Path::new).collect::<Vec<&Path>>();
+        let names = zip_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (PathBuf::from(name), i))
+            .collect();
+        PathMapping::find(&torrent_paths, &names)
+    }
+
+    const TORRENT: &[&str] = &["Rel/a.txt", "Rel/sub/b.txt", "Rel/c.txt"];
+
+    #[test]
+    fn finds_how_the_zip_relates_to_the_torrent() {
+        assert_eq!(find(TORRENT, TORRENT), Ok(Some(PathMapping::Same)));
+        assert_eq!(
+            find(TORRENT, &["extra/Rel/a.txt", "extra/Rel/sub/b.txt"]),
+            Ok(Some(PathMapping::AddPrefix(PathBuf::from("extra"))))
+        );
+        assert_eq!(
+            find(TORRENT, &["a.txt", "sub/b.txt", "c.txt"]),
+            Ok(Some(PathMapping::StripPrefix(PathBuf::from("Rel"))))
+        );
+        assert_eq!(find(TORRENT, &["other.txt", "Rel/d.txt"]), Ok(None));
+    }
+
+    #[test]
+    fn uses_the_mapping_that_matches_the_most_files() {
+        // A stray copy of one file under another directory doesn't change the mapping, wherever
+        // it is in the zip.
+        let zip = [
+            "Rel/a.txt",
+            "Rel/c.txt",
+            "backup/Rel/sub/b.txt",
+            "z/Rel/a.txt",
+        ];
+        assert_eq!(find(TORRENT, &zip), Ok(Some(PathMapping::Same)));
+        let zip = ["copy/Rel/a.txt", "a.txt", "sub/b.txt", "c.txt"];
+        assert_eq!(
+            find(TORRENT, &zip),
+            Ok(Some(PathMapping::StripPrefix(PathBuf::from("Rel"))))
+        );
+    }
+
+    #[test]
+    fn rejects_a_zip_that_matches_in_more_than_one_way() {
+        let zip = [
+            "one/Rel/a.txt",
+            "one/Rel/c.txt",
+            "two/Rel/a.txt",
+            "two/Rel/c.txt",
+        ];
+        assert_eq!(
+            find(TORRENT, &zip),
+            Err(vec!["under one".to_string(), "under two".to_string()])
+        );
+    }
+
+    #[test]
+    fn maps_torrent_paths_to_zip_paths() {
+        let path = Path::new("Rel/sub/b.txt");
+        assert_eq!(
+            PathMapping::Same.to_zip_path(path),
+            Some(path.to_path_buf())
+        );
+        assert_eq!(
+            PathMapping::AddPrefix(PathBuf::from("extra")).to_zip_path(path),
+            Some(PathBuf::from("extra/Rel/sub/b.txt"))
+        );
+        assert_eq!(
+            PathMapping::StripPrefix(PathBuf::from("Rel")).to_zip_path(path),
+            Some(PathBuf::from("sub/b.txt"))
+        );
+        assert_eq!(
+            PathMapping::StripPrefix(PathBuf::from("Other")).to_zip_path(path),
+            None
+        );
+    }
+}